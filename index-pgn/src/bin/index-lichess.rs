@@ -1,12 +1,24 @@
 use std::{
-    cmp::min, ffi::OsStr, fs::File, io, mem, num::Wrapping, path::PathBuf, thread, time::Duration, cmp::max,
+    cmp::{max, min},
+    collections::{hash_map::Entry, HashMap, HashSet},
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead as _, BufReader, BufWriter, Write as _},
+    mem,
+    num::Wrapping,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::Duration,
 };
+
 use clap::Parser;
-use pgn_reader::{BufferedReader, Color, Outcome, RawHeader, SanPlus, Skip, Visitor};
-use serde::Serialize;
+use pgn_reader::{BufferedReader, Color, Outcome, RawComment, RawHeader, SanPlus, Skip, Visitor};
+use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr, SpaceSeparator, StringWithSeparator};
 
-#[derive(Debug, Serialize, Copy, Clone)]
+#[derive(Debug, Serialize, Copy, Clone, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 enum Speed {
     UltraBullet,
@@ -37,30 +49,155 @@ impl Speed {
     }
 
     fn from_bytes(bytes: &[u8]) -> Result<Speed, ()> {
-        if bytes == b"-" {
-            return Ok(Speed::Correspondence);
-        }
+        Ok(match time_control(bytes)? {
+            Some((seconds, increment)) => Speed::from_seconds_and_increment(seconds, increment),
+            None => Speed::Correspondence,
+        })
+    }
+}
 
-        let mut parts = bytes.splitn(2, |ch| *ch == b'+');
-        let seconds = btoi::btou(parts.next().ok_or(())?).map_err(|_| ())?;
-        let increment = btoi::btou(parts.next().ok_or(())?).map_err(|_| ())?;
-        Ok(Speed::from_seconds_and_increment(seconds, increment))
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Speed, String> {
+        Ok(match s {
+            "ultraBullet" => Speed::UltraBullet,
+            "bullet" => Speed::Bullet,
+            "blitz" => Speed::Blitz,
+            "rapid" => Speed::Rapid,
+            "classical" => Speed::Classical,
+            "correspondence" => Speed::Correspondence,
+            _ => return Err(format!("invalid speed: {}", s)),
+        })
     }
 }
 
+fn time_control(bytes: &[u8]) -> Result<Option<(u64, u64)>, ()> {
+    if bytes == b"-" {
+        return Ok(None);
+    }
+
+    let mut parts = bytes.splitn(2, |ch| *ch == b'+');
+    let seconds = btoi::btou(parts.next().ok_or(())?).map_err(|_| ())?;
+    let increment = btoi::btou(parts.next().ok_or(())?).map_err(|_| ())?;
+    Ok(Some((seconds, increment)))
+}
+
+fn clock_seconds(comment: &[u8]) -> Option<u64> {
+    // Parse [%clk h:mm:ss], ignoring fractional seconds.
+    let start = comment.windows(6).position(|w| w == b"[%clk ")? + 6;
+    let end = start + comment[start..].iter().position(|ch| *ch == b']')?;
+    let mut seconds = 0;
+    for part in comment[start..end].split(|ch| *ch == b':') {
+        let whole = part.splitn(2, |ch| *ch == b'.').next()?;
+        seconds = seconds * 60 + btoi::btou::<u64>(whole).ok()?;
+    }
+    Some(seconds)
+}
+
 struct Batch {
     filename: PathBuf,
     games: Vec<Game>,
 }
 
-struct Importer {
+/// Acceptance criteria from the command line.
+struct Filter {
+    /// Lowercase names.
+    only_players: Option<HashSet<String>>,
+    min_rating: u16,
+    max_rating_diff: u16,
+    speeds: Vec<Speed>,
+    include_bots: bool,
+}
+
+impl Filter {
+    fn from_args(args: &Args) -> io::Result<Filter> {
+        Ok(Filter {
+            only_players: match args.only_players {
+                Some(ref path) => {
+                    let mut names = HashSet::new();
+                    for line in BufReader::new(File::open(path)?).lines() {
+                        let name = line?.trim().to_lowercase();
+                        if !name.is_empty() {
+                            names.insert(name);
+                        }
+                    }
+                    Some(names)
+                }
+                None => None,
+            },
+            min_rating: args.min_rating,
+            max_rating_diff: args.max_rating_diff,
+            speeds: args.speeds.clone(),
+            include_bots: args.include_bots,
+        })
+    }
+
+    fn contains_player(&self, game: &Game) -> bool {
+        self.only_players.as_ref().map_or(true, |names| {
+            [&game.white.name, &game.black.name]
+                .into_iter()
+                .flatten()
+                .any(|name| names.contains(&name.to_lowercase()))
+        })
+    }
+}
+
+/// Games skipped because of malformed headers, so that a single bad game
+/// does not abort a long import.
+struct Rejects {
+    writer: Option<BufWriter<File>>,
+    count: u64,
+}
+
+impl Rejects {
+    fn new(path: Option<&Path>) -> io::Result<Rejects> {
+        Ok(Rejects {
+            writer: match path {
+                Some(path) => Some(BufWriter::new(
+                    OpenOptions::new().create(true).append(true).open(path)?,
+                )),
+                None => None,
+            },
+            count: 0,
+        })
+    }
+
+    fn log(&mut self, filename: &Path, id: Option<&str>, reason: &str) -> io::Result<()> {
+        self.count += 1;
+        let line = format!("{}\t{}\t{}", filename.display(), id.unwrap_or("?"), reason);
+        match self.writer {
+            Some(ref mut writer) => writeln!(writer, "{}", line),
+            None => {
+                eprintln!("rejected: {}", line);
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer {
+            Some(ref mut writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Importer<'a> {
     tx: crossbeam::channel::Sender<Batch>,
     filename: PathBuf,
     batch_size: usize,
+    filter: Arc<Filter>,
+    rejects: &'a mut Rejects,
 
     current: Game,
     skip: bool,
+    /// First malformed header of the current game.
+    rejected: Option<String>,
     batch: Vec<Game>,
+
+    time_control: Option<(u64, u64)>,
+    clocks: [Option<u64>; 2],
 }
 
 #[serde_as]
@@ -68,6 +205,8 @@ struct Importer {
 struct Game {
     variant: Option<String>,
     speed: Option<Speed>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock: Option<String>,
     fen: Option<String>,
     id: Option<String>,
     date: Option<String>,
@@ -77,27 +216,39 @@ struct Game {
     winner: Option<Color>,
     #[serde_as(as = "StringWithSeparator<SpaceSeparator, SanPlus>")]
     moves: Vec<SanPlus>,
+    #[serde_as(as = "StringWithSeparator<SpaceSeparator, u64>")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    times: Vec<u64>,
 }
 
 #[derive(Default, Serialize, Debug)]
 struct Player {
     name: Option<String>,
     rating: Option<u16>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    bot: bool,
 }
 
-impl Importer {
+impl Importer<'_> {
     fn new(
         tx: crossbeam::channel::Sender<Batch>,
         filename: PathBuf,
         batch_size: usize,
-    ) -> Importer {
+        filter: Arc<Filter>,
+        rejects: &mut Rejects,
+    ) -> Importer<'_> {
         Importer {
             tx,
             filename,
             batch_size,
+            filter,
+            rejects,
             current: Game::default(),
             skip: false,
+            rejected: None,
             batch: Vec::with_capacity(batch_size),
+            time_control: None,
+            clocks: [None; 2],
         }
     }
 
@@ -109,51 +260,100 @@ impl Importer {
             })
             .expect("send");
     }
+
+    fn reject(&mut self, key: &str, value: RawHeader<'_>) {
+        self.skip = true;
+        if self.rejected.is_none() {
+            self.rejected = Some(format!(
+                "invalid {}: {:?}",
+                key,
+                String::from_utf8_lossy(value.as_bytes())
+            ));
+        }
+    }
+
+    fn decode(&mut self, key: &str, value: RawHeader<'_>) -> Option<String> {
+        match value.decode_utf8() {
+            Ok(decoded) => Some(decoded.into_owned()),
+            Err(_) => {
+                self.reject(key, value);
+                None
+            }
+        }
+    }
+
+    fn rating(&mut self, key: &str, value: RawHeader<'_>) -> Option<u16> {
+        if value.as_bytes() == b"?" {
+            return None;
+        }
+        match btoi::btoi(value.as_bytes()) {
+            Ok(rating) => Some(rating),
+            Err(_) => {
+                self.reject(key, value);
+                None
+            }
+        }
+    }
 }
 
-impl Visitor for Importer {
+impl Visitor for Importer<'_> {
     type Result = ();
 
     fn begin_game(&mut self) {
         self.skip = false;
+        self.rejected = None;
         self.current = Game::default();
+        self.time_control = None;
+        self.clocks = [None; 2];
     }
 
     fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
         if key == b"White" {
-            self.current.white.name = Some(value.decode_utf8().expect("White").into_owned());
+            self.current.white.name = self.decode("White", value);
         } else if key == b"Black" {
-            self.current.black.name = Some(value.decode_utf8().expect("Black").into_owned());
+            self.current.black.name = self.decode("Black", value);
         } else if key == b"WhiteElo" {
-            if value.as_bytes() != b"?" {
-                self.current.white.rating = Some(btoi::btoi(value.as_bytes()).expect("WhiteElo"));
-            }
+            self.current.white.rating = self.rating("WhiteElo", value);
         } else if key == b"BlackElo" {
-            if value.as_bytes() != b"?" {
-                self.current.black.rating = Some(btoi::btoi(value.as_bytes()).expect("BlackElo"));
-            }
+            self.current.black.rating = self.rating("BlackElo", value);
         } else if key == b"TimeControl" {
-            self.current.speed = Some(Speed::from_bytes(value.as_bytes()).expect("TimeControl"));
+            match (
+                Speed::from_bytes(value.as_bytes()),
+                time_control(value.as_bytes()),
+            ) {
+                (Ok(speed), Ok(time_control)) => {
+                    self.current.speed = Some(speed);
+                    self.time_control = time_control;
+                    self.current.clock = time_control
+                        .map(|(seconds, increment)| format!("{}+{}", seconds, increment));
+                }
+                _ => self.reject("TimeControl", value),
+            }
         } else if key == b"Variant" {
-            self.current.variant = Some(value.decode_utf8().expect("Variant").into_owned());
+            self.current.variant = self.decode("Variant", value);
         } else if key == b"Date" || key == b"UTCDate" {
-            self.current.date = Some(value.decode_utf8().expect("Date").into_owned());
+            self.current.date = self.decode("Date", value);
         } else if key == b"WhiteTitle" || key == b"BlackTitle" {
             if value.as_bytes() == b"BOT" {
-                self.skip = true;
+                if key == b"WhiteTitle" {
+                    self.current.white.bot = true;
+                } else {
+                    self.current.black.bot = true;
+                }
+                if !self.filter.include_bots {
+                    self.skip = true;
+                }
             }
         } else if key == b"Site" {
-            self.current.id = Some(
-                String::from_utf8(
-                    value
-                        .as_bytes()
-                        .rsplitn(2, |ch| *ch == b'/')
-                        .next()
-                        .expect("Site")
-                        .to_owned(),
-                )
-                .expect("Site"),
-            );
+            let id = value
+                .as_bytes()
+                .rsplit(|ch| *ch == b'/')
+                .next()
+                .unwrap_or_default();
+            match std::str::from_utf8(id) {
+                Ok(id) => self.current.id = Some(id.to_owned()),
+                Err(_) => self.reject("Site", value),
+            }
         } else if key == b"Result" {
             match Outcome::from_ascii(value.as_bytes()) {
                 Ok(outcome) => self.current.winner = outcome.winner(),
@@ -164,7 +364,7 @@ impl Visitor for Importer {
                 // https://github.com/ornicar/lichess-db/issues/40
                 self.current.fen = None;
             } else {
-                self.current.fen = Some(value.decode_utf8().expect("FEN").into_owned());
+                self.current.fen = self.decode("FEN", value);
             }
         }
     }
@@ -179,7 +379,17 @@ impl Visitor for Importer {
             .as_ref()
             .map_or(true, |name| name == "Standard");
 
-        let probability = if standard {
+        let probability = if !self.filter.speeds.is_empty() {
+            if self
+                .filter
+                .speeds
+                .contains(&self.current.speed.unwrap_or(Speed::Correspondence))
+            {
+                100
+            } else {
+                0
+            }
+        } else if standard {
             match self.current.speed.unwrap_or(Speed::Correspondence) {
                 Speed::Correspondence | Speed::Classical => 0,
 
@@ -215,8 +425,9 @@ impl Visitor for Importer {
         let accept = min(
             self.current.white.rating.unwrap_or(0),
             self.current.black.rating.unwrap_or(0),
-        ) >= 1501
-            && max(self.current.white.rating.unwrap_or(0), self.current.black.rating.unwrap_or(0)) - min(self.current.white.rating.unwrap_or(0), self.current.black.rating.unwrap_or(0)) < 150
+        ) >= self.filter.min_rating
+            && max(self.current.white.rating.unwrap_or(0), self.current.black.rating.unwrap_or(0)) - min(self.current.white.rating.unwrap_or(0), self.current.black.rating.unwrap_or(0)) <= self.filter.max_rating_diff
+            && self.filter.contains_player(&self.current)
             && self
                 .current
                 .id
@@ -232,11 +443,42 @@ impl Visitor for Importer {
         self.current.moves.push(san);
     }
 
+    fn comment(&mut self, comment: RawComment<'_>) {
+        // Time spent on the move is the previous clock of the same side,
+        // plus increment, minus the current clock.
+        let ply = self.current.moves.len();
+        if ply == 0 || self.current.times.len() + 1 != ply {
+            return;
+        }
+        if let (Some((initial, increment)), Some(clock)) =
+            (self.time_control, clock_seconds(comment.as_bytes()))
+        {
+            let side = &mut self.clocks[(ply - 1) % 2];
+            let before = side.unwrap_or(initial);
+            self.current
+                .times
+                .push((before + increment).saturating_sub(clock));
+            *side = Some(clock);
+        }
+    }
+
     fn begin_variation(&mut self) -> Skip {
         Skip(true) // stay in the mainline
     }
 
     fn end_game(&mut self) {
+        if let Some(reason) = self.rejected.take() {
+            self.rejects
+                .log(&self.filename, self.current.id.as_deref(), &reason)
+                .expect("log rejected game");
+            return;
+        }
+
+        if self.current.times.len() != self.current.moves.len() {
+            // Incomplete clock information.
+            self.current.times.clear();
+        }
+
         if !self.skip {
             self.batch.push(mem::take(&mut self.current));
 
@@ -259,13 +501,125 @@ fn java_hash_code(s: &str) -> i32 {
 struct Args {
     #[clap(long, default_value = "http://localhost:9004")]
     endpoint: String,
+    /// Write NDJSON files sharded by month to this directory, instead of
+    /// submitting to the endpoint. Games are appended to existing files, so
+    /// that inputs of the same month can be split across runs. Games that
+    /// a file already contains are skipped, so that runs can be repeated.
+    #[clap(long)]
+    output: Option<PathBuf>,
     #[clap(long, default_value = "200")]
     batch_size: usize,
+    /// Only accept games of the players listed in this file, one name per
+    /// line.
+    #[clap(long)]
+    only_players: Option<PathBuf>,
+    /// Minimum rating of both players.
+    #[clap(long, default_value = "1501")]
+    min_rating: u16,
+    /// Maximum rating difference between the players.
+    #[clap(long, default_value = "149")]
+    max_rating_diff: u16,
+    /// Accept all games of these speeds (comma separated), instead of
+    /// sampling standard games by speed and rating.
+    #[clap(long, use_delimiter = true)]
+    speeds: Vec<Speed>,
+    /// Also accept games of bots, marking the bot players, so that they can
+    /// be excluded at query time.
+    #[clap(long)]
+    include_bots: bool,
+    /// Append games that are skipped because of malformed headers to this
+    /// file, instead of printing them.
+    #[clap(long)]
+    rejects: Option<PathBuf>,
     pgns: Vec<PathBuf>,
 }
 
+struct Output {
+    dir: PathBuf,
+    files: HashMap<String, MonthFile>,
+}
+
+struct MonthFile {
+    writer: BufWriter<File>,
+    /// Ids of the games in the file, including those of earlier runs.
+    ids: HashSet<String>,
+}
+
+impl MonthFile {
+    fn open(path: &Path) -> io::Result<MonthFile> {
+        #[derive(Deserialize)]
+        struct Written {
+            id: Option<String>,
+        }
+
+        let mut ids = HashSet::new();
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let written: Written = serde_json::from_str(&line?)?;
+                    ids.extend(written.id);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+
+        Ok(MonthFile {
+            writer: BufWriter::new(OpenOptions::new().create(true).append(true).open(path)?),
+            ids,
+        })
+    }
+}
+
+impl Output {
+    fn new(dir: PathBuf) -> io::Result<Output> {
+        fs::create_dir_all(&dir)?;
+        Ok(Output {
+            dir,
+            files: HashMap::new(),
+        })
+    }
+
+    /// Returns false if the game was already written.
+    fn write(&mut self, game: &Game) -> io::Result<bool> {
+        // Dates look like 2015.09.01, so the first two parts are the month.
+        let month = game
+            .date
+            .as_deref()
+            .and_then(|date| date.get(..7))
+            .map_or_else(|| "unknown".to_owned(), |month| month.replace('.', "-"));
+
+        let file = match self.files.entry(month) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let path = self.dir.join(format!("{}.ndjson", entry.key()));
+                entry.insert(MonthFile::open(&path)?)
+            }
+        };
+
+        if let Some(ref id) = game.id {
+            if !file.ids.insert(id.clone()) {
+                return Ok(false);
+            }
+        }
+
+        serde_json::to_writer(&mut file.writer, game)?;
+        file.writer.write_all(b"\n")?;
+        Ok(true)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for file in self.files.values_mut() {
+            file.writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
 fn main() -> Result<(), io::Error> {
     let args = Args::parse();
+    let filter = Arc::new(Filter::from_args(&args)?);
+    let mut rejects = Rejects::new(args.rejects.as_deref())?;
 
     let (tx, rx) = crossbeam::channel::bounded::<Batch>(50);
 
@@ -278,17 +632,42 @@ fn main() -> Result<(), io::Error> {
             .build()
             .expect("client");
 
+        let mut output = args.output.map(|dir| Output::new(dir).expect("output dir"));
+
         while let Ok(batch) = rx.recv() {
-            let res = client
-                .put(format!("{}/import/lichess", args.endpoint))
-                .json(&batch.games)
-                .send()
-                .expect("send batch");
+            let status = match output {
+                Some(ref mut output) => {
+                    let mut written = 0;
+                    for game in &batch.games {
+                        if output.write(game).expect("write game") {
+                            written += 1;
+                        }
+                    }
+                    format!(
+                        "written {} games, skipped {} duplicates",
+                        written,
+                        batch.games.len() - written
+                    )
+                }
+                None => {
+                    let res = client
+                        .put(format!("{}/import/lichess", args.endpoint))
+                        .json(&batch.games)
+                        .send()
+                        .expect("send batch");
+
+                    format!(
+                        "{} - {}",
+                        res.status(),
+                        res.text().expect("decode response")
+                    )
+                }
+            };
 
             spinner_idx += Wrapping(1);
 
             println!(
-                "{} {:?}: {}: {} - {}",
+                "{} {:?}: {}: {}",
                 spinner[spinner_idx.0 % spinner.len()],
                 batch.filename,
                 batch
@@ -296,10 +675,13 @@ fn main() -> Result<(), io::Error> {
                     .last()
                     .and_then(|g| g.date.as_ref())
                     .unwrap_or(&String::new()),
-                res.status(),
-                res.text().expect("decode response")
+                status,
             );
         }
+
+        if let Some(ref mut output) = output {
+            output.flush().expect("flush output");
+        }
     });
 
     for arg in args.pgns {
@@ -315,11 +697,28 @@ fn main() -> Result<(), io::Error> {
 
         let mut reader = BufferedReader::new(uncompressed);
 
-        let mut importer = Importer::new(tx.clone(), arg, args.batch_size);
+        let rejected_before = rejects.count;
+        let mut importer = Importer::new(
+            tx.clone(),
+            arg.clone(),
+            args.batch_size,
+            Arc::clone(&filter),
+            &mut rejects,
+        );
         reader.read_all(&mut importer)?;
         importer.send();
+
+        if rejects.count > rejected_before {
+            println!(
+                "Skipped {} games with malformed headers in {:?}",
+                rejects.count - rejected_before,
+                arg
+            );
+        }
     }
 
+    rejects.flush()?;
+
     drop(tx);
     bg.join().expect("bg join");
     Ok(())
@@ -327,7 +726,7 @@ fn main() -> Result<(), io::Error> {
 
 #[cfg(test)]
 mod tests {
-    use super::java_hash_code;
+    use super::*;
 
     #[test]
     fn test_java_hash_code() {
@@ -336,4 +735,49 @@ mod tests {
         assert_eq!(java_hash_code("VFa7wmDN"), 90055046);
         assert_eq!(java_hash_code("rvSvQdIe"), 950841078);
     }
+
+    #[test]
+    fn test_clock_seconds() {
+        assert_eq!(clock_seconds(b" [%clk 0:03:00] "), Some(180));
+        assert_eq!(clock_seconds(b"[%eval 0.17] [%clk 1:02:03.4]"), Some(3723));
+        assert_eq!(clock_seconds(b" book move "), None);
+    }
+
+    #[test]
+    fn test_reject_malformed_headers() {
+        let pgn = b"[Site \"https://lichess.org/aaaaaaaa\"]
+[WhiteElo \"1900\"]
+[BlackElo \"?1900\"]
+[TimeControl \"180+0\"]
+[Result \"1-0\"]
+
+1. e4 1-0
+
+[Site \"https://lichess.org/bbbbbbbb\"]
+[WhiteElo \"1900\"]
+[BlackElo \"1900\"]
+[TimeControl \"180+0\"]
+[Result \"1-0\"]
+
+1. d4 1-0
+";
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let filter = Arc::new(Filter {
+            only_players: None,
+            min_rating: 0,
+            max_rating_diff: u16::MAX,
+            speeds: vec![Speed::Blitz],
+        });
+        let mut rejects = Rejects::new(None).unwrap();
+        let mut importer = Importer::new(tx, PathBuf::from("test.pgn"), 10, filter, &mut rejects);
+        BufferedReader::new_cursor(&pgn[..])
+            .read_all(&mut importer)
+            .unwrap();
+        importer.send();
+
+        let batch = rx.recv().unwrap();
+        assert_eq!(batch.games.len(), 1);
+        assert_eq!(batch.games[0].id.as_deref(), Some("bbbbbbbb"));
+        assert_eq!(rejects.count, 1);
+    }
 }
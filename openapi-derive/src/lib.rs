@@ -0,0 +1,420 @@
+//! Derives `ApiSchema` from the serde attributes of a type, so that the
+//! OpenAPI document describes exactly what is serialized or deserialized.
+//! Doc comments of types, fields and variants become descriptions.
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{
+    meta::ParseNestedMeta, parse_macro_input, token, Attribute, Data, DataEnum, DeriveInput, Expr,
+    ExprLit, Fields, GenericArgument, Lit, LitStr, Meta, PathArguments, Token, Type,
+};
+
+#[proc_macro_derive(ApiSchema, attributes(serde))]
+pub fn derive_api_schema(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// The serde attributes that change the shape of the serialized data.
+#[derive(Default)]
+struct SerdeAttrs {
+    rename: Option<String>,
+    rename_all: Option<String>,
+    aliases: Vec<String>,
+    tag: Option<String>,
+    with: Option<Type>,
+    flatten: bool,
+    skip: bool,
+    default: bool,
+    skip_serializing_if: bool,
+}
+
+impl SerdeAttrs {
+    fn parse(attrs: &[Attribute]) -> syn::Result<SerdeAttrs> {
+        let mut serde = SerdeAttrs::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+            attr.parse_nested_meta(|meta| {
+                let key = meta
+                    .path
+                    .get_ident()
+                    .map(ToString::to_string)
+                    .unwrap_or_default();
+                match key.as_str() {
+                    "rename" => serde.rename = Some(string_value(&meta)?),
+                    "rename_all" => serde.rename_all = Some(string_value(&meta)?),
+                    "alias" => serde.aliases.push(string_value(&meta)?),
+                    "tag" => serde.tag = Some(string_value(&meta)?),
+                    "with" => serde.with = Some(meta.value()?.parse::<LitStr>()?.parse()?),
+                    "flatten" => serde.flatten = true,
+                    "skip" | "skip_serializing" => serde.skip = true,
+                    "default" => {
+                        serde.default = true;
+                        skip_value(&meta)?;
+                    }
+                    "skip_serializing_if" => {
+                        serde.skip_serializing_if = true;
+                        skip_value(&meta)?;
+                    }
+                    "untagged" => {
+                        return Err(meta.error("ApiSchema does not support untagged enums"))
+                    }
+                    _ => skip_value(&meta)?,
+                }
+                Ok(())
+            })?;
+        }
+        Ok(serde)
+    }
+}
+
+fn string_value(meta: &ParseNestedMeta<'_>) -> syn::Result<String> {
+    Ok(meta.value()?.parse::<LitStr>()?.value())
+}
+
+fn skip_value(meta: &ParseNestedMeta<'_>) -> syn::Result<()> {
+    if meta.input.peek(Token![=]) {
+        meta.value()?.parse::<Expr>()?;
+    } else if meta.input.peek(token::Paren) {
+        meta.parse_nested_meta(|meta| skip_value(&meta))?;
+    }
+    Ok(())
+}
+
+/// Joins the lines of doc comments into paragraphs.
+fn docs(attrs: &[Attribute]) -> Option<String> {
+    let mut paragraphs = vec![String::new()];
+    for attr in attrs {
+        if let Meta::NameValue(meta) = &attr.meta {
+            if let (
+                true,
+                Expr::Lit(ExprLit {
+                    lit: Lit::Str(doc), ..
+                }),
+            ) = (meta.path.is_ident("doc"), &meta.value)
+            {
+                let doc = doc.value();
+                let line = doc.trim();
+                let paragraph = paragraphs.last_mut().expect("paragraph");
+                if line.is_empty() {
+                    if !paragraph.is_empty() {
+                        paragraphs.push(String::new());
+                    }
+                } else {
+                    if !paragraph.is_empty() {
+                        paragraph.push(' ');
+                    }
+                    paragraph.push_str(line);
+                }
+            }
+        }
+    }
+    let docs = paragraphs
+        .into_iter()
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    (!docs.is_empty()).then(|| docs)
+}
+
+fn option(value: Option<String>) -> TokenStream {
+    match value {
+        Some(value) => quote!(::std::option::Option::Some(#value)),
+        None => quote!(::std::option::Option::None),
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream> {
+    if let Some(param) = input.generics.type_params().next() {
+        return Err(syn::Error::new_spanned(
+            param,
+            "ApiSchema cannot be derived for generic types",
+        ));
+    }
+
+    let serde = SerdeAttrs::parse(&input.attrs)?;
+    let ident = &input.ident;
+    let name = ident.to_string();
+    let description = docs(&input.attrs);
+
+    let body = match &input.data {
+        Data::Struct(data) => object(&data.fields, &serde, description, None)?,
+        Data::Enum(data) => enumeration(data, &serde, description)?,
+        Data::Union(_) => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "ApiSchema cannot be derived for unions",
+            ))
+        }
+    };
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics crate::api::ApiSchema for #ident #ty_generics #where_clause {
+            fn schema(generator: &mut crate::api::SchemaGenerator) -> ::serde_json::Value {
+                generator.component(#name, <Self as crate::api::ApiSchema>::inline)
+            }
+
+            fn inline(generator: &mut crate::api::SchemaGenerator) -> ::serde_json::Value {
+                #body
+            }
+        }
+    })
+}
+
+/// Object with the given fields, and optionally a tag that identifies the
+/// variant of an internally tagged enum.
+fn object(
+    fields: &Fields,
+    container: &SerdeAttrs,
+    description: Option<String>,
+    tag: Option<(&str, &str)>,
+) -> syn::Result<TokenStream> {
+    let fields = match fields {
+        Fields::Named(fields) => &fields.named,
+        Fields::Unit => return Ok(tagged_object(description, tag, TokenStream::new())),
+        Fields::Unnamed(fields) => {
+            return Err(syn::Error::new_spanned(
+                fields,
+                "ApiSchema needs named fields",
+            ))
+        }
+    };
+
+    let mut statements = TokenStream::new();
+    for field in fields {
+        let serde = SerdeAttrs::parse(&field.attrs)?;
+        if serde.skip {
+            continue;
+        }
+
+        let ty = &field.ty;
+        if serde.flatten {
+            let optional = is_option(ty);
+            statements.extend(quote! {
+                object.flatten(<#ty as crate::api::ApiSchema>::inline(generator), #optional);
+            });
+            continue;
+        }
+
+        let ident = field.ident.as_ref().expect("named field").to_string();
+        let ident = ident.trim_start_matches("r#");
+        let name = serde.rename.clone().unwrap_or_else(|| {
+            container
+                .rename_all
+                .as_deref()
+                .map_or_else(|| ident.to_owned(), |rule| rename_field(rule, ident))
+        });
+        let schema = schema_expr(ty, serde.with.as_ref().and_then(serde_as));
+        let description = option(docs(&field.attrs));
+        let required =
+            !(is_option(ty) || serde.default || serde.skip_serializing_if || container.default);
+        statements.extend(quote! {
+            object.property(#name, #schema, #description, #required);
+        });
+    }
+
+    Ok(tagged_object(description, tag, statements))
+}
+
+fn tagged_object(
+    description: Option<String>,
+    tag: Option<(&str, &str)>,
+    statements: TokenStream,
+) -> TokenStream {
+    let description = option(description);
+    let tag = tag.map(|(tag, name)| quote!(object.tag(#tag, #name);));
+    quote! {{
+        let mut object = crate::api::ObjectSchema::new(#description);
+        #tag
+        #statements
+        object.into_value()
+    }}
+}
+
+fn enumeration(
+    data: &DataEnum,
+    container: &SerdeAttrs,
+    description: Option<String>,
+) -> syn::Result<TokenStream> {
+    let mut values = Vec::new();
+    let mut variants = Vec::new();
+    let mut listing = Vec::new();
+
+    for variant in &data.variants {
+        let serde = SerdeAttrs::parse(&variant.attrs)?;
+        if serde.skip {
+            continue;
+        }
+
+        let ident = variant.ident.to_string();
+        let name = serde.rename.clone().unwrap_or_else(|| {
+            container
+                .rename_all
+                .as_deref()
+                .map_or_else(|| ident.clone(), |rule| rename_variant(rule, &ident))
+        });
+        let doc = docs(&variant.attrs);
+
+        match &container.tag {
+            None => {
+                if !matches!(variant.fields, Fields::Unit) {
+                    return Err(syn::Error::new_spanned(
+                        variant,
+                        "ApiSchema needs unit variants or an internally tagged enum",
+                    ));
+                }
+                if let Some(doc) = doc {
+                    listing.push(format!("- `{}`: {}", name, doc));
+                }
+                values.push(name);
+                values.extend(serde.aliases);
+            }
+            Some(tag) => variants.push(match &variant.fields {
+                Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                    let ty = &fields.unnamed[0].ty;
+                    tagged_object(
+                        doc,
+                        Some((tag, &name)),
+                        quote! {
+                            object.flatten(<#ty as crate::api::ApiSchema>::inline(generator), false);
+                        },
+                    )
+                }
+                fields => object(fields, &serde, doc, Some((tag, &name)))?,
+            }),
+        }
+    }
+
+    if container.tag.is_some() {
+        let description = option(description);
+        return Ok(quote! {
+            crate::api::SchemaGenerator::one_of(::std::vec![#(#variants),*], #description)
+        });
+    }
+
+    let description = option(match (description, listing.is_empty()) {
+        (description, true) => description,
+        (None, false) => Some(listing.join("\n")),
+        (Some(description), false) => Some(format!("{}\n\n{}", description, listing.join("\n"))),
+    });
+    Ok(quote! {
+        crate::api::SchemaGenerator::string_enum(&[#(#values),*], #description)
+    })
+}
+
+/// Schema of a field, following the conversions of `serde_with` that change
+/// how the value is represented.
+fn schema_expr(ty: &Type, as_ty: Option<&Type>) -> TokenStream {
+    let field = quote!(<#ty as crate::api::ApiSchema>::schema(generator));
+    let (name, args) = match as_ty.and_then(last_segment) {
+        Some(segment) => segment,
+        None => return field,
+    };
+    match (name.as_str(), args.as_slice()) {
+        ("Option" | "Vec", [as_inner]) => match last_segment(ty) {
+            Some((ty_name, ty_args)) if ty_name == name && ty_args.len() == 1 => {
+                let inner = schema_expr(ty_args[0], Some(as_inner));
+                if name == "Option" {
+                    inner
+                } else {
+                    quote!(crate::api::SchemaGenerator::array(#inner))
+                }
+            }
+            _ => field,
+        },
+        ("TryFromInto" | "FromInto", [into]) => {
+            quote!(<#into as crate::api::ApiSchema>::schema(generator))
+        }
+        ("StringWithSeparator", [separator, item]) => match last_segment(separator) {
+            Some((separator, _)) if separator == "CommaSeparator" => quote! {
+                crate::api::SchemaGenerator::comma_separated(
+                    <#item as crate::api::ApiSchema>::schema(generator)
+                )
+            },
+            _ => quote!(<::std::string::String as crate::api::ApiSchema>::schema(
+                generator
+            )),
+        },
+        _ => field,
+    }
+}
+
+/// The type that `#[serde_as(as = "...")]` was expanded from, given the
+/// `with` attribute that it was expanded to.
+fn serde_as(with: &Type) -> Option<&Type> {
+    match last_segment(with)? {
+        (name, args) if name == "As" && args.len() == 1 => Some(args[0]),
+        _ => None,
+    }
+}
+
+fn last_segment(ty: &Type) -> Option<(String, Vec<&Type>)> {
+    let segment = match ty {
+        Type::Path(ty) => ty.path.segments.last()?,
+        _ => return None,
+    };
+    let args = match &segment.arguments {
+        PathArguments::AngleBracketed(args) => args
+            .args
+            .iter()
+            .filter_map(|arg| match arg {
+                GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    Some((segment.ident.to_string(), args))
+}
+
+fn is_option(ty: &Type) -> bool {
+    matches!(last_segment(ty), Some((name, _)) if name == "Option")
+}
+
+/// Applies `rename_all` to a field name in snake case, like serde.
+fn rename_field(rule: &str, field: &str) -> String {
+    match rule {
+        "UPPERCASE" | "SCREAMING_SNAKE_CASE" => field.to_ascii_uppercase(),
+        "PascalCase" | "camelCase" => {
+            let mut renamed = String::new();
+            let mut capitalize = rule == "PascalCase";
+            for c in field.chars() {
+                if c == '_' {
+                    capitalize = true;
+                } else if capitalize {
+                    renamed.push(c.to_ascii_uppercase());
+                    capitalize = false;
+                } else {
+                    renamed.push(c);
+                }
+            }
+            renamed
+        }
+        "kebab-case" => field.replace('_', "-"),
+        "SCREAMING-KEBAB-CASE" => field.replace('_', "-").to_ascii_uppercase(),
+        _ => field.to_owned(),
+    }
+}
+
+/// Applies `rename_all` to a variant name in pascal case, like serde.
+fn rename_variant(rule: &str, variant: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in variant.char_indices() {
+        if i > 0 && c.is_ascii_uppercase() {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    match rule {
+        "lowercase" => variant.to_ascii_lowercase(),
+        "UPPERCASE" => variant.to_ascii_uppercase(),
+        "camelCase" => variant[..1].to_ascii_lowercase() + &variant[1..],
+        "snake_case" => snake,
+        "SCREAMING_SNAKE_CASE" => snake.to_ascii_uppercase(),
+        "kebab-case" => snake.replace('_', "-"),
+        "SCREAMING-KEBAB-CASE" => snake.replace('_', "-").to_ascii_uppercase(),
+        _ => variant.to_owned(),
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    mem,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use axum::http::{header, HeaderMap};
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use tokio::{task::JoinHandle, time};
+
+use crate::{
+    api::{AccountResponse, ApiKeyListing, Error, UsageMonth},
+    db::Database,
+    model::{ApiKey, ApiKeyId, Month, TenantId},
+};
+
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// New or replaced API key, as submitted by an administrator.
+#[derive(Deserialize, Debug)]
+pub struct ApiKeyGrant {
+    pub token: String,
+    #[serde(flatten)]
+    pub key: ApiKey,
+}
+
+/// Admitted requests are merged into the persistent counters in bulk, at
+/// this interval.
+const USAGE_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// API keys and their usage. Keys are kept in memory, and requests of the
+/// current month are counted in memory as well, so that quotas can be
+/// checked without reading from the database. Admitted requests are
+/// periodically merged into the persistent counters.
+pub struct Accounts {
+    db: Arc<Database>,
+    keys: RwLock<FxHashMap<ApiKeyId, ApiKey>>,
+    usage: Mutex<Usage>,
+}
+
+#[derive(Default)]
+struct Usage {
+    /// Requests of the month, including those that are not yet flushed.
+    current: FxHashMap<ApiKeyId, (Month, u64)>,
+    /// Requests that are not yet merged into the persistent counters.
+    pending: FxHashMap<(ApiKeyId, Month), u64>,
+}
+
+impl Accounts {
+    pub fn load(db: Arc<Database>) -> Accounts {
+        let mut keys = FxHashMap::default();
+        db.accounts()
+            .scan_api_keys(|id, key| {
+                keys.insert(id, key);
+            })
+            .expect("scan api keys");
+        log::info!("loaded {} api keys", keys.len());
+        Accounts {
+            db,
+            keys: RwLock::new(keys),
+            usage: Mutex::new(Usage::default()),
+        }
+    }
+
+    pub fn authenticate(&self, token: &str) -> Option<ApiKeyId> {
+        let id = ApiKeyId::from_token(token);
+        self.keys
+            .read()
+            .expect("api keys")
+            .contains_key(&id)
+            .then(|| id)
+    }
+
+    /// Counts a request of the key. Returns false, without counting, if the
+    /// monthly quota is exhausted.
+    pub fn consume(&self, id: ApiKeyId) -> bool {
+        let key = match self.keys.read().expect("api keys").get(&id) {
+            Some(key) => key.clone(),
+            None => return false,
+        };
+        let month = Month::current();
+
+        let mut usage = self.usage.lock().expect("api usage");
+        let (counted_month, requests) = usage.current.entry(id).or_insert((Month::default(), 0));
+        if *counted_month != month {
+            // First request of the month since starting.
+            *counted_month = month;
+            *requests = self.db.accounts().usage(id, month).expect("get api usage");
+        }
+        if !key.allows(*requests) {
+            return false;
+        }
+        *requests += 1;
+        if self.db.is_writable() {
+            *usage.pending.entry((id, month)).or_insert(0) += 1;
+        }
+        true
+    }
+
+    /// Merges the pending requests into the persistent counters.
+    pub fn flush(&self) {
+        let pending = mem::take(&mut self.usage.lock().expect("api usage").pending);
+        for ((id, month), requests) in pending {
+            if let Err(err) = self.db.accounts().add_usage(id, month, requests) {
+                log::error!(
+                    "failed to count {} requests of api key {}: {}",
+                    requests,
+                    id,
+                    err
+                );
+            }
+        }
+    }
+
+    pub fn spawn_flushing(self: Arc<Accounts>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = time::interval(USAGE_FLUSH_INTERVAL);
+            loop {
+                interval.tick().await;
+                let accounts = Arc::clone(&self);
+                tokio::task::spawn_blocking(move || accounts.flush())
+                    .await
+                    .expect("blocking flush api usage");
+            }
+        })
+    }
+
+    /// Requests of the key in the given month, including those that are not
+    /// yet flushed.
+    fn requests(&self, id: ApiKeyId, month: Month) -> u64 {
+        let counted = self
+            .usage
+            .lock()
+            .expect("api usage")
+            .current
+            .get(&id)
+            .copied();
+        match counted {
+            Some((counted_month, requests)) if counted_month == month => requests,
+            _ => self.db.accounts().usage(id, month).expect("get api usage"),
+        }
+    }
+
+    /// Private explorer of the key sent as bearer token, and the maximum
+    /// number of games that it may hold.
+    pub fn tenant(&self, headers: &HeaderMap) -> Result<(TenantId, Option<u64>), Error> {
+        let id = ApiKeyId::from_token(bearer_token(headers).ok_or(Error::UnknownApiKey)?);
+        let keys = self.keys.read().expect("api keys");
+        let key = keys.get(&id).ok_or(Error::UnknownApiKey)?;
+        let tenant = key.tenant.clone().ok_or(Error::NoTenant)?;
+        Ok((tenant, key.max_tenant_games))
+    }
+
+    pub fn account(&self, token: &str) -> Result<AccountResponse, Error> {
+        let id = ApiKeyId::from_token(token);
+        let key = self
+            .keys
+            .read()
+            .expect("api keys")
+            .get(&id)
+            .cloned()
+            .ok_or(Error::UnknownApiKey)?;
+        let month = Month::current();
+        let mut usage = self
+            .db
+            .accounts()
+            .usage_history(id)
+            .expect("get api usage history");
+        let requests = self.requests(id, month);
+        match usage.last_mut() {
+            Some((m, current)) if *m == month => *current = requests,
+            _ if requests > 0 => usage.push((month, requests)),
+            _ => (),
+        }
+        Ok(AccountResponse {
+            name: key.name,
+            monthly_quota: key.monthly_quota,
+            remaining: key
+                .monthly_quota
+                .map(|quota| quota.saturating_sub(requests)),
+            tenant: key.tenant,
+            usage: usage
+                .into_iter()
+                .map(|(month, requests)| UsageMonth { month, requests })
+                .collect(),
+        })
+    }
+
+    pub fn grant(&self, grant: ApiKeyGrant) -> Result<(), Error> {
+        if !self.db.is_writable() {
+            return Err(Error::ReadOnly);
+        }
+        let id = ApiKeyId::from_token(&grant.token);
+        self.db
+            .accounts()
+            .put_api_key(id, &grant.key)
+            .expect("put api key");
+        self.keys.write().expect("api keys").insert(id, grant.key);
+        Ok(())
+    }
+
+    /// Revokes all keys with the given name. Returns false if there were
+    /// none.
+    pub fn revoke(&self, name: &str) -> Result<bool, Error> {
+        if !self.db.is_writable() {
+            return Err(Error::ReadOnly);
+        }
+        let mut keys = self.keys.write().expect("api keys");
+        let ids: Vec<ApiKeyId> = keys
+            .iter()
+            .filter(|(_, key)| key.name == name)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &ids {
+            self.db
+                .accounts()
+                .delete_api_key(*id)
+                .expect("delete api key");
+            keys.remove(id);
+        }
+        Ok(!ids.is_empty())
+    }
+
+    pub fn list(&self) -> Vec<ApiKeyListing> {
+        let month = Month::current();
+        let keys: Vec<(ApiKeyId, ApiKey)> = self
+            .keys
+            .read()
+            .expect("api keys")
+            .iter()
+            .map(|(id, key)| (*id, key.clone()))
+            .collect();
+        let mut listings: Vec<ApiKeyListing> = keys
+            .into_iter()
+            .map(|(id, key)| ApiKeyListing {
+                id: id.to_string(),
+                name: key.name,
+                monthly_quota: key.monthly_quota,
+                requests: self.requests(id, month),
+            })
+            .collect();
+        listings.sort_by(|a, b| a.name.cmp(&b.name));
+        listings
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::{self, HttpBody},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use tokio::{sync::mpsc, task};
+
+/// Number of chunks that may be produced ahead of the client.
+const CHUNK_BUFFER: usize = 16;
+
+/// Response body that is produced by a blocking task, one chunk at a time.
+/// The producer is paused while the client is not reading, and can stop
+/// early once the client has gone away, so that a slow client does not pin
+/// the whole response in memory.
+pub struct Chunked {
+    content_type: &'static str,
+    receiver: mpsc::Receiver<Bytes>,
+}
+
+impl Chunked {
+    pub fn spawn_blocking<F>(content_type: &'static str, produce: F) -> Chunked
+    where
+        F: FnOnce(ChunkSender) + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(CHUNK_BUFFER);
+        task::spawn_blocking(move || produce(ChunkSender { sender }));
+        Chunked {
+            content_type,
+            receiver,
+        }
+    }
+}
+
+pub struct ChunkSender {
+    sender: mpsc::Sender<Bytes>,
+}
+
+impl ChunkSender {
+    /// Blocks until there is room for the chunk. Returns `false` if the
+    /// client disconnected, in which case the producer should stop.
+    #[must_use]
+    pub fn send<B: Into<Bytes>>(&self, chunk: B) -> bool {
+        self.sender.blocking_send(chunk.into()).is_ok()
+    }
+}
+
+impl IntoResponse for Chunked {
+    fn into_response(self) -> Response {
+        Response::builder()
+            .header("X-Accel-Buffering", "no")
+            .header(axum::http::header::CONTENT_TYPE, self.content_type)
+            .body(body::boxed(ChunkedBody {
+                receiver: self.receiver,
+            }))
+            .unwrap()
+    }
+}
+
+struct ChunkedBody {
+    receiver: mpsc::Receiver<Bytes>,
+}
+
+impl HttpBody for ChunkedBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        self.get_mut()
+            .receiver
+            .poll_recv(cx)
+            .map(|chunk| chunk.map(Ok))
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(None))
+    }
+}
@@ -0,0 +1,19 @@
+use axum::{
+    body,
+    response::{IntoResponse, Response},
+};
+
+/// Minimal operator dashboard. It only calls the existing administrative
+/// and monitoring endpoints, relative to its own location.
+const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+pub struct Dashboard;
+
+impl IntoResponse for Dashboard {
+    fn into_response(self) -> Response {
+        Response::builder()
+            .header(axum::http::header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(body::boxed(body::Full::from(DASHBOARD_HTML)))
+            .unwrap()
+    }
+}
@@ -1,8 +1,18 @@
 use axum::{http::StatusCode, response::Response};
-use shakmaty::{san::SanError, uci::IllegalUciError, variant::VariantPosition, PositionError};
+use shakmaty::{
+    fen::ParseFenError,
+    san::SanError,
+    uci::{IllegalUciError, ParseUciError},
+    variant::VariantPosition,
+    PositionError,
+};
 use thiserror::Error;
 
-use crate::model::GameId;
+use crate::{
+    delta::DeltaError,
+    importer::ImplausibleRating,
+    model::{GameId, Month},
+};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -12,14 +22,65 @@ pub enum Error {
     IllegalUciError(#[from] IllegalUciError),
     #[error("bad request: {0}")]
     SanError(#[from] SanError),
+    #[error("bad request: {0}")]
+    ParseFenError(#[from] ParseFenError),
+    #[error("bad request: {0}")]
+    ParseUciError(#[from] ParseUciError),
     #[error("duplicate game {0}")]
     DuplicateGame(GameId),
     #[error("rejected import of {0}")]
     RejectedImport(GameId),
+    #[error("rejected import of {0}: {1}")]
+    ImplausibleRating(GameId, ImplausibleRating),
+    #[error("unknown game {0}")]
+    UnknownGame(GameId),
+    #[error("database is read-only")]
+    ReadOnly,
+    #[error("unknown api key")]
+    UnknownApiKey,
+    #[error("api key has no private explorer")]
+    NoTenant,
+    #[error("rejected import of {0}: private explorer is limited to {1} games")]
+    TenantGamesExhausted(GameId, u64),
+    #[error("bad request: batch of {0} positions exceeds limit of {1}")]
+    BatchTooLarge(usize, usize),
+    #[error("bad request: pgn with {0} games exceeds limit of {1}")]
+    PgnTooLarge(usize, usize),
+    #[error("bad request: at most {0} subscriptions per connection")]
+    TooManySubscriptions(usize),
+    #[error("bad request: player subscriptions need player and color")]
+    MissingPlayer,
+    #[error("deadline exceeded")]
+    DeadlineExceeded,
+    #[error("no backup directory configured")]
+    BackupNotConfigured,
+    #[error("backup failed: {0}")]
+    BackupFailed(rocksdb::Error),
+    #[error("no snapshots configured")]
+    SnapshotsNotConfigured,
+    #[error("no snapshot as of {0}")]
+    NoSnapshot(Month),
+    #[error("opening snapshot failed: {0}")]
+    SnapshotFailed(rocksdb::Error),
+    #[error("no delta directory configured")]
+    DeltaNotConfigured,
+    #[error("delta export failed: {0}")]
+    DeltaFailed(DeltaError),
 }
 
 impl axum::response::IntoResponse for Error {
     fn into_response(self) -> Response {
-        (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+        let status = match self {
+            Error::ReadOnly => StatusCode::SERVICE_UNAVAILABLE,
+            Error::UnknownApiKey => StatusCode::UNAUTHORIZED,
+            Error::NoTenant => StatusCode::FORBIDDEN,
+            Error::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            Error::NoSnapshot(_) => StatusCode::NOT_FOUND,
+            Error::BackupFailed(_) | Error::DeltaFailed(_) | Error::SnapshotFailed(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            _ => StatusCode::BAD_REQUEST,
+        };
+        (status, self.to_string()).into_response()
     }
 }
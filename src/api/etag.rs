@@ -0,0 +1,134 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    convert::Infallible,
+    fmt,
+    hash::{Hash, Hasher as _},
+};
+
+use axum::{
+    async_trait, body,
+    extract::{FromRequest, RequestParts},
+    http::{
+        header::{ETAG, IF_NONE_MATCH},
+        HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+
+use crate::api::ResponseFormat;
+
+/// Weak entity tag of a response. Responses are only equivalent, not
+/// byte-for-byte identical, because example games may be random.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct EntityTag(u64);
+
+impl fmt::Display for EntityTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "W/\"{:016x}\"", self.0)
+    }
+}
+
+/// The request as far as it determines the response, and the entity tags
+/// of responses the client has already cached.
+pub struct Revalidation {
+    path_and_query: String,
+    if_none_match: Option<String>,
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Revalidation {
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Revalidation, Infallible> {
+        Ok(Revalidation {
+            path_and_query: req
+                .uri()
+                .path_and_query()
+                .map_or_else(String::new, |path_and_query| path_and_query.to_string()),
+            if_none_match: req
+                .headers()
+                .and_then(|headers| headers.get(IF_NONE_MATCH))
+                .and_then(|value| value.to_str().ok())
+                .map(ToOwned::to_owned),
+        })
+    }
+}
+
+impl Revalidation {
+    /// Tag for the response to this request, given the generation of the
+    /// data it is computed from.
+    pub fn tag(&self, generation: u64, format: ResponseFormat) -> EntityTag {
+        let mut hasher = DefaultHasher::new();
+        self.path_and_query.hash(&mut hasher);
+        format.hash(&mut hasher);
+        generation.hash(&mut hasher);
+        EntityTag(hasher.finish())
+    }
+
+    /// Whether the client already has the response with the given tag, using
+    /// weak comparison.
+    pub fn is_fresh(&self, tag: EntityTag) -> bool {
+        let tag = tag.to_string();
+        self.if_none_match
+            .as_deref()
+            .map_or(false, |if_none_match| {
+                if_none_match.split(',').map(str::trim).any(|candidate| {
+                    candidate == "*"
+                        || candidate.trim_start_matches("W/") == tag.trim_start_matches("W/")
+                })
+            })
+    }
+}
+
+/// A response to a conditional request.
+pub enum Conditional<T> {
+    NotModified(EntityTag),
+    Modified(EntityTag, T),
+}
+
+impl<T: IntoResponse> IntoResponse for Conditional<T> {
+    fn into_response(self) -> Response {
+        let (tag, mut res) = match self {
+            Conditional::NotModified(tag) => (
+                tag,
+                Response::builder()
+                    .status(StatusCode::NOT_MODIFIED)
+                    .body(body::boxed(body::Empty::new()))
+                    .unwrap(),
+            ),
+            Conditional::Modified(tag, body) => (tag, body.into_response()),
+        };
+        res.headers_mut().insert(
+            ETAG,
+            HeaderValue::from_str(&tag.to_string()).expect("entity tag header"),
+        );
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revalidation(if_none_match: Option<&str>) -> Revalidation {
+        Revalidation {
+            path_and_query: "/lichess?fen=startpos".to_owned(),
+            if_none_match: if_none_match.map(ToOwned::to_owned),
+        }
+    }
+
+    #[test]
+    fn test_is_fresh() {
+        let tag = revalidation(None).tag(1, ResponseFormat::Json);
+        assert_ne!(tag, revalidation(None).tag(2, ResponseFormat::Json));
+        assert_ne!(tag, revalidation(None).tag(1, ResponseFormat::NdJson));
+        assert_ne!(tag, revalidation(None).tag(1, ResponseFormat::Csv));
+
+        assert!(!revalidation(None).is_fresh(tag));
+        assert!(revalidation(Some(&tag.to_string())).is_fresh(tag));
+        assert!(revalidation(Some(&format!("\"abc\", {}", tag))).is_fresh(tag));
+        assert!(revalidation(Some(tag.to_string().trim_start_matches("W/"))).is_fresh(tag));
+        assert!(revalidation(Some("*")).is_fresh(tag));
+        assert!(!revalidation(Some("W/\"0000000000000000\"")).is_fresh(tag));
+    }
+}
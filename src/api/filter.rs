@@ -0,0 +1,174 @@
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, CommaSeparator, DisplayFromStr, StringWithSeparator};
+
+use crate::{
+    api::ApiSchema,
+    model::{ClockRange, Mode, Month, Speed, SpeedGroup, Week},
+};
+
+/// Selection of speeds, shared by the lichess and player filters. Speeds
+/// and speed groups select the union of their speeds. Without either, all
+/// speeds are selected.
+#[serde_as]
+#[derive(Deserialize, ApiSchema, Debug, Default, Clone)]
+pub struct SpeedFilter {
+    /// Comma separated speeds.
+    #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, Speed>>")]
+    #[serde(default)]
+    pub speeds: Option<Vec<Speed>>,
+    /// Comma separated speed groups, adding to the selected speeds.
+    #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, SpeedGroup>>")]
+    #[serde(default, rename = "speedGroups")]
+    pub speed_groups: Option<Vec<SpeedGroup>>,
+}
+
+impl SpeedFilter {
+    pub fn contains(&self, speed: Speed) -> bool {
+        match (&self.speeds, &self.speed_groups) {
+            (None, None) => true,
+            (speeds, speed_groups) => {
+                speeds
+                    .as_ref()
+                    .map_or(false, |speeds| speeds.contains(&speed))
+                    || speed_groups.as_ref().map_or(false, |groups| {
+                        groups.iter().any(|group| group.speeds().contains(&speed))
+                    })
+            }
+        }
+    }
+}
+
+/// A filter as it was interpreted, echoed in responses so that clients can
+/// verify their parameters. Speed groups are expanded into speeds, and
+/// ratings are given as the lower bounds of the rating groups that are
+/// actually selected.
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CanonicalFilter {
+    pub speeds: Vec<Speed>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modes: Option<Vec<Mode>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ratings: Option<Vec<u16>>,
+    #[serde_as(as = "DisplayFromStr")]
+    pub since: Month,
+    #[serde_as(as = "DisplayFromStr")]
+    pub until: Month,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since_week: Option<Week>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_games: Option<u64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub exclude_bots: bool,
+}
+
+impl CanonicalFilter {
+    pub fn new(since: Month, until: Month, speeds: impl Fn(Speed) -> bool) -> CanonicalFilter {
+        CanonicalFilter {
+            speeds: Speed::ALL
+                .into_iter()
+                .filter(|speed| speeds(*speed))
+                .collect(),
+            modes: None,
+            ratings: None,
+            since,
+            until,
+            since_week: None,
+            tc: None,
+            min_games: None,
+            exclude_bots: false,
+        }
+    }
+
+    pub fn with_tc(mut self, tc: Option<ClockRange>) -> CanonicalFilter {
+        self.tc = tc.map(|tc| format!("{}-{}", tc.min, tc.max));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+
+    use super::*;
+    use crate::{
+        api::{LichessQueryFilter, PlayerQueryFilter},
+        model::RatingGroup,
+    };
+
+    impl Arbitrary for SpeedFilter {
+        fn arbitrary(g: &mut Gen) -> SpeedFilter {
+            let speeds = Vec::<u8>::arbitrary(g)
+                .into_iter()
+                .map(|i| Speed::ALL[usize::from(i) % Speed::ALL.len()])
+                .collect();
+            let speed_groups = Vec::<u8>::arbitrary(g)
+                .into_iter()
+                .map(|i| SpeedGroup::ALL[usize::from(i) % SpeedGroup::ALL.len()])
+                .collect();
+            SpeedFilter {
+                speeds: bool::arbitrary(g).then(|| speeds),
+                speed_groups: bool::arbitrary(g).then(|| speed_groups),
+            }
+        }
+    }
+
+    fn lichess_filter(speed: SpeedFilter, ratings: Option<Vec<u16>>) -> LichessQueryFilter {
+        LichessQueryFilter {
+            speed,
+            ratings: ratings.map(|ratings| {
+                ratings
+                    .into_iter()
+                    .map(|rating| rating.to_string().parse::<RatingGroup>().unwrap())
+                    .collect()
+            }),
+            ..LichessQueryFilter::default()
+        }
+    }
+
+    quickcheck! {
+        fn test_canonical_lichess_filter_is_fixed_point(
+            speed: SpeedFilter,
+            ratings: Option<Vec<u16>>
+        ) -> bool {
+            let canonical = lichess_filter(speed, ratings).canonical();
+            let reparsed = lichess_filter(
+                SpeedFilter {
+                    speeds: Some(canonical.speeds.clone()),
+                    speed_groups: None,
+                },
+                canonical.ratings.clone(),
+            )
+            .canonical();
+            reparsed == canonical
+        }
+
+        fn test_canonical_player_speeds(speed: SpeedFilter) -> bool {
+            let filter = PlayerQueryFilter {
+                modes: None,
+                speed: speed.clone(),
+                since: Month::default(),
+                until: Month::max_value(),
+                opponent_ratings: false,
+                min_games: None,
+            };
+            let canonical = filter.canonical();
+            Speed::ALL
+                .into_iter()
+                .all(|s| speed.contains(s) == canonical.speeds.contains(&s))
+        }
+    }
+
+    #[test]
+    fn test_canonical_ratings() {
+        let filter = lichess_filter(SpeedFilter::default(), Some(vec![1600, 2500]));
+        assert_eq!(
+            filter.canonical().ratings,
+            Some(vec![0, 1600, 2500, 2800, 3200])
+        );
+    }
+}
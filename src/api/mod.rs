@@ -1,14 +1,40 @@
+mod chunked;
+mod dashboard;
 mod error;
+mod etag;
+mod filter;
 mod nd_json;
+mod openapi;
+mod position_cache;
+mod prepared_json;
 mod query;
 mod response;
 mod variant;
 
+pub use chunked::{ChunkSender, Chunked};
+pub use dashboard::Dashboard;
 pub use error::Error;
-pub use nd_json::NdJson;
+pub use etag::{Conditional, Revalidation};
+pub use filter::{CanonicalFilter, SpeedFilter};
+pub use nd_json::{NdJson, ResponseFormat};
+pub use openapi::{ApiSchema, ObjectSchema, OpenApi, SchemaGenerator};
+pub use position_cache::{PositionCache, PositionCacheStats};
+pub use prepared_json::PreparedJson;
 pub use query::{
-    LichessQuery, LichessQueryFilter, Limits, MastersQuery, PlayPosition, PlayerQuery,
-    PlayerQueryFilter,
+    BestCriterion, DeltaQuery, EcoRange, ExampleGame, FeedQuery, FeedSource, GraphFormat,
+    GraphQuery, LichessBestQuery, LichessDepthProfileQuery, LichessGamesQuery, LichessQuery,
+    LichessQueryFilter, LichessTrendQuery, Limits, Mask, MastersQuery, MoveOrdersQuery, MoveSort,
+    NextMoves, ParentsQuery, Play, PlayLine, PlayPosition, PlayerColor, PlayerFindQuery,
+    PlayerQuery, PlayerQueryFilter, PrepQuery, SampleQuery, SamplingQuery, TheoryFormat,
+    TheoryQuery,
+};
+pub use response::{
+    AccountResponse, ApiKeyListing, BestMove, BookExit, BookExitPly, Contributor, DepthProfile,
+    DepthProfilePly, ExplorerBody, ExplorerGame, ExplorerGameWithUci, ExplorerLine, ExplorerMove,
+    ExplorerResponse, GamesPage, MetaResponse, MoveOrder, NearDuplicate, OpponentRatingStats,
+    Parent, PendingErratum, Percentages, PgnImportReport, PgnRejection, PrepMove, PrepResponse,
+    RatingDistributionMeta, RatingGroupMeta, SampleReport, SampledEntry, SampledGroup,
+    SamplingBucket, SamplingReport, ShadowComparison, SpecialMoves, SpeedGroupMeta, SpeedStats,
+    TrendMonth, TrendMove, TrendResponse, UsageMonth, VariantFirstMove, VariantMeta,
 };
-pub use response::{ExplorerGame, ExplorerGameWithUci, ExplorerMove, ExplorerResponse};
 pub use variant::LilaVariant;
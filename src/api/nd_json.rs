@@ -1,12 +1,15 @@
 use std::{
+    convert::Infallible,
     pin::Pin,
     task::{Context, Poll},
     time::Duration,
 };
 
 use axum::{
+    async_trait,
     body::{self, HttpBody},
-    http::HeaderMap,
+    extract::{FromRequest, RequestParts},
+    http::{header::ACCEPT, HeaderMap},
     response::{IntoResponse, Response},
 };
 use bytes::Bytes;
@@ -21,6 +24,49 @@ use tokio::{
 
 pub struct NdJson<S>(pub S);
 
+/// Response format negotiated via the `Accept` header. Clients that accept
+/// `application/x-ndjson` get a stream of lines instead of one object.
+/// Spreadsheet users can ask for a table of moves with `format=csv` or
+/// `format=tsv` in the query string.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ResponseFormat {
+    Json,
+    NdJson,
+    Csv,
+    Tsv,
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for ResponseFormat {
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<ResponseFormat, Infallible> {
+        let table = req.uri().query().and_then(|query| {
+            query.split('&').find_map(|pair| match pair {
+                "format=csv" => Some(ResponseFormat::Csv),
+                "format=tsv" => Some(ResponseFormat::Tsv),
+                _ => None,
+            })
+        });
+        if let Some(table) = table {
+            return Ok(table);
+        }
+
+        Ok(
+            if req
+                .headers()
+                .and_then(|headers| headers.get(ACCEPT))
+                .and_then(|value| value.to_str().ok())
+                .map_or(false, |value| value.contains("application/x-ndjson"))
+            {
+                ResponseFormat::NdJson
+            } else {
+                ResponseFormat::Json
+            },
+        )
+    }
+}
+
 impl<S, T> IntoResponse for NdJson<S>
 where
     S: Stream<Item = T> + Send + 'static,
@@ -0,0 +1,838 @@
+use std::collections::{BTreeMap, HashMap};
+
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+};
+pub use openapi_derive::ApiSchema;
+use serde_json::{json, Map, Value};
+use shakmaty::{
+    fen::{Epd, Fen},
+    san::{San, SanPlus},
+    uci::Uci,
+    ByColor, Color,
+};
+
+use crate::{
+    api::{
+        AccountResponse, BestMove, Contributor, DepthProfile, ExplorerGame, ExplorerLine,
+        ExplorerResponse, FeedQuery, GamesPage, GraphQuery, LichessBestQuery,
+        LichessDepthProfileQuery, LichessGamesQuery, LichessQuery, LichessTrendQuery, MastersQuery,
+        MetaResponse, MoveOrder, MoveOrdersQuery, Parent, ParentsQuery, PgnImportReport,
+        PlayerFindQuery, PlayerQuery, PrepQuery, PrepResponse, TheoryQuery, TrendResponse,
+        VariantMeta,
+    },
+    graph::{GraphEdge, GraphNode},
+    subscriptions::{ClientMessage, ServerMessage},
+    theory::TheoryTable,
+};
+
+/// Schemas of a type in the OpenAPI document, usually derived from its
+/// serde attributes and doc comments.
+pub trait ApiSchema {
+    /// Schema where the type is used, usually a reference to the component
+    /// of the type.
+    fn schema(generator: &mut SchemaGenerator) -> Value;
+
+    /// Schema of the type itself, to flatten its properties into another
+    /// object or into query parameters.
+    fn inline(generator: &mut SchemaGenerator) -> Value {
+        Self::schema(generator)
+    }
+}
+
+/// Collects the components that schemas refer to.
+#[derive(Default)]
+pub struct SchemaGenerator {
+    components: BTreeMap<&'static str, Value>,
+}
+
+/// Marks strings that hold a comma separated list, so that query parameters
+/// can be described as arrays.
+const COMMA_SEPARATED: &str = "x-comma-separated";
+
+impl SchemaGenerator {
+    /// Registers the component once, and refers to it.
+    pub fn component(
+        &mut self,
+        name: &'static str,
+        inline: fn(&mut SchemaGenerator) -> Value,
+    ) -> Value {
+        if !self.components.contains_key(name) {
+            // Placeholder for recursive types.
+            self.components.insert(name, Value::Null);
+            let schema = inline(self);
+            self.components.insert(name, schema);
+        }
+        json!({ "$ref": format!("#/components/schemas/{}", name) })
+    }
+
+    pub fn array(items: Value) -> Value {
+        json!({ "type": "array", "items": items })
+    }
+
+    pub fn comma_separated(items: Value) -> Value {
+        json!({ "type": "string", COMMA_SEPARATED: items })
+    }
+
+    pub fn string_enum(values: &[&str], description: Option<&str>) -> Value {
+        with_description(json!({ "type": "string", "enum": values }), description)
+    }
+
+    pub fn one_of(variants: Vec<Value>, description: Option<&str>) -> Value {
+        with_description(json!({ "oneOf": variants }), description)
+    }
+}
+
+fn with_description(mut schema: Value, description: Option<&str>) -> Value {
+    match description {
+        Some(description) if schema.get("$ref").is_some() => {
+            json!({ "allOf": [schema], "description": description })
+        }
+        Some(description) => {
+            schema["description"] = description.into();
+            schema
+        }
+        None => schema,
+    }
+}
+
+/// Schema of an object, built up field by field.
+pub struct ObjectSchema {
+    description: Option<String>,
+    properties: Map<String, Value>,
+    required: Vec<Value>,
+}
+
+impl ObjectSchema {
+    pub fn new(description: Option<&str>) -> ObjectSchema {
+        ObjectSchema {
+            description: description.map(ToOwned::to_owned),
+            properties: Map::new(),
+            required: Vec::new(),
+        }
+    }
+
+    pub fn property(
+        &mut self,
+        name: &str,
+        schema: Value,
+        description: Option<&str>,
+        required: bool,
+    ) {
+        self.properties
+            .insert(name.to_owned(), with_description(schema, description));
+        if required {
+            self.required.push(name.into());
+        }
+    }
+
+    /// Adds the tag that identifies a variant of an internally tagged enum.
+    pub fn tag(&mut self, tag: &str, name: &str) {
+        self.property(tag, json!({ "type": "string", "enum": [name] }), None, true);
+    }
+
+    /// Merges the properties of an inline object schema. Properties of an
+    /// optional object are never required.
+    pub fn flatten(&mut self, mut schema: Value, optional: bool) {
+        if let Value::Object(properties) = schema["properties"].take() {
+            self.properties.extend(properties);
+        }
+        if let (Value::Array(required), false) = (schema["required"].take(), optional) {
+            self.required.extend(required);
+        }
+    }
+
+    pub fn into_value(self) -> Value {
+        let mut schema = json!({ "type": "object", "properties": self.properties });
+        if !self.required.is_empty() {
+            schema["required"] = self.required.into();
+        }
+        with_description(schema, self.description.as_deref())
+    }
+}
+
+macro_rules! impl_api_schema {
+    ($($ty:ty => $schema:tt,)*) => {
+        $(impl ApiSchema for $ty {
+            fn schema(_: &mut SchemaGenerator) -> Value {
+                json!($schema)
+            }
+        })*
+    };
+}
+
+impl_api_schema! {
+    bool => { "type": "boolean" },
+    u8 => { "type": "integer", "minimum": 0, "maximum": 255 },
+    u16 => { "type": "integer", "minimum": 0, "maximum": 65535 },
+    u32 => { "type": "integer", "minimum": 0 },
+    u64 => { "type": "integer", "minimum": 0 },
+    usize => { "type": "integer", "minimum": 0 },
+    i32 => { "type": "integer" },
+    i64 => { "type": "integer" },
+    f64 => { "type": "number" },
+    str => { "type": "string" },
+    String => { "type": "string" },
+    char => { "type": "string", "minLength": 1, "maxLength": 1 },
+    Uci => { "type": "string", "description": "Move in UCI notation", "example": "e2e4" },
+    SanPlus => { "type": "string", "description": "Move in SAN notation", "example": "e4" },
+    San => { "type": "string", "description": "Move in SAN notation", "example": "e4" },
+    Epd => { "type": "string", "description": "Position in FEN notation, without move counters" },
+    Fen => { "type": "string", "description": "Position in FEN notation" },
+    Color => { "type": "string", "enum": ["white", "black"] },
+}
+
+impl<T: ApiSchema + ?Sized> ApiSchema for &T {
+    fn schema(generator: &mut SchemaGenerator) -> Value {
+        T::schema(generator)
+    }
+
+    fn inline(generator: &mut SchemaGenerator) -> Value {
+        T::inline(generator)
+    }
+}
+
+impl<T: ApiSchema> ApiSchema for Option<T> {
+    fn schema(generator: &mut SchemaGenerator) -> Value {
+        T::schema(generator)
+    }
+
+    fn inline(generator: &mut SchemaGenerator) -> Value {
+        T::inline(generator)
+    }
+}
+
+impl<T: ApiSchema> ApiSchema for Vec<T> {
+    fn schema(generator: &mut SchemaGenerator) -> Value {
+        SchemaGenerator::array(T::schema(generator))
+    }
+}
+
+impl<T: ApiSchema> ApiSchema for [T] {
+    fn schema(generator: &mut SchemaGenerator) -> Value {
+        SchemaGenerator::array(T::schema(generator))
+    }
+}
+
+impl<K, V: ApiSchema> ApiSchema for BTreeMap<K, V> {
+    fn schema(generator: &mut SchemaGenerator) -> Value {
+        json!({ "type": "object", "additionalProperties": V::schema(generator) })
+    }
+}
+
+impl<K, V: ApiSchema, S> ApiSchema for HashMap<K, V, S> {
+    fn schema(generator: &mut SchemaGenerator) -> Value {
+        json!({ "type": "object", "additionalProperties": V::schema(generator) })
+    }
+}
+
+impl<T: ApiSchema> ApiSchema for ByColor<T> {
+    fn schema(generator: &mut SchemaGenerator) -> Value {
+        let mut object = ObjectSchema::new(None);
+        object.property("white", T::schema(generator), None, true);
+        object.property("black", T::schema(generator), None, true);
+        object.into_value()
+    }
+}
+
+/// An operation of the API, built up from the types of its handler.
+struct Operation(Map<String, Value>);
+
+impl Operation {
+    fn new(summary: &str) -> Operation {
+        let mut operation = Map::new();
+        operation.insert("summary".to_owned(), summary.into());
+        operation.insert("parameters".to_owned(), json!([]));
+        operation.insert("responses".to_owned(), json!({}));
+        Operation(operation)
+    }
+
+    fn description(mut self, description: &str) -> Operation {
+        self.0.insert("description".to_owned(), description.into());
+        self
+    }
+
+    fn param(mut self, param: Value) -> Operation {
+        if let Some(Value::Array(params)) = self.0.get_mut("parameters") {
+            params.push(param);
+        }
+        self
+    }
+
+    fn path_param(self, name: &str, description: &str) -> Operation {
+        self.param(json!({
+            "name": name,
+            "in": "path",
+            "required": true,
+            "description": description,
+            "schema": { "type": "string" },
+        }))
+    }
+
+    /// Describes each property of the query type as a query parameter.
+    fn query<Q: ApiSchema>(mut self, generator: &mut SchemaGenerator) -> Operation {
+        let mut query = Q::inline(generator);
+        let required = query["required"].take();
+        let required = required.as_array().map(Vec::as_slice).unwrap_or_default();
+        if let Value::Object(properties) = query["properties"].take() {
+            for (name, mut schema) in properties {
+                let mut param = json!({
+                    "name": name,
+                    "in": "query",
+                    "required": required.contains(&Value::from(name.as_str())),
+                });
+                if let Some(description) =
+                    schema.as_object_mut().and_then(|s| s.remove("description"))
+                {
+                    param["description"] = description;
+                }
+                if let Some(Value::Array(mut all_of)) =
+                    schema.as_object_mut().and_then(|s| s.remove("allOf"))
+                {
+                    schema = all_of.remove(0);
+                }
+                if let Some(items) = schema
+                    .as_object_mut()
+                    .and_then(|s| s.remove(COMMA_SEPARATED))
+                {
+                    schema = SchemaGenerator::array(items);
+                    param["style"] = "form".into();
+                    param["explode"] = false.into();
+                }
+                param["schema"] = schema;
+                self = self.param(param);
+            }
+        }
+        self
+    }
+
+    /// Requests can ask for work on their behalf to be abandoned.
+    fn timeout(self) -> Operation {
+        self.param(json!({ "$ref": "#/components/parameters/timeout" }))
+            .reference("504", "GatewayTimeout")
+    }
+
+    fn body(mut self, content_type: &str, schema: Value, description: &str) -> Operation {
+        self.0.insert(
+            "requestBody".to_owned(),
+            json!({
+                "required": true,
+                "description": description,
+                "content": { content_type: { "schema": schema } },
+            }),
+        );
+        self
+    }
+
+    fn response(mut self, status: &str, description: &str, content: &[(&str, Value)]) -> Operation {
+        let mut response = json!({ "description": description });
+        if !content.is_empty() {
+            response["content"] = content
+                .iter()
+                .map(|(content_type, schema)| {
+                    ((*content_type).to_owned(), json!({ "schema": schema }))
+                })
+                .collect::<Map<_, _>>()
+                .into();
+        }
+        self.0["responses"][status] = response;
+        self
+    }
+
+    fn json(self, description: &str, schema: Value) -> Operation {
+        self.response("200", description, &[("application/json", schema)])
+            .reference("400", "BadRequest")
+    }
+
+    fn reference(mut self, status: &str, response: &str) -> Operation {
+        self.0["responses"][status] =
+            json!({ "$ref": format!("#/components/responses/{}", response) });
+        self
+    }
+
+    /// Moves and games of a position in any of the negotiated formats.
+    fn explorer(self, generator: &mut SchemaGenerator) -> Operation {
+        self.param(json!({ "$ref": "#/components/parameters/format" }))
+            .response(
+                "200",
+                "Moves and games of the position. Clients that send `Accept: application/x-ndjson` get a summary line, followed by one line per move, recent game and top game.",
+                &[
+                    ("application/json", ExplorerResponse::schema(generator)),
+                    ("application/x-ndjson", ExplorerLine::schema(generator)),
+                    ("text/csv", json!({ "$ref": "#/components/schemas/MoveTable" })),
+                    ("text/tab-separated-values", json!({ "$ref": "#/components/schemas/MoveTable" })),
+                ],
+            )
+            .reference("304", "NotModified")
+            .reference("400", "BadRequest")
+            .reference("404", "NoSnapshot")
+    }
+
+    fn pgn(self, description: &str) -> Operation {
+        self.response(
+            "200",
+            description,
+            &[("application/x-chess-pgn", json!({ "type": "string" }))],
+        )
+    }
+
+    fn tenant(self) -> Operation {
+        self.reference("401", "Unauthorized")
+            .reference("403", "NoTenant")
+    }
+}
+
+/// Description of the public API, generated from the query and response
+/// types of the routes in `main.rs`.
+pub struct OpenApi;
+
+impl OpenApi {
+    pub fn document() -> Value {
+        let gen = &mut SchemaGenerator::default();
+        let mut paths: BTreeMap<&str, Map<String, Value>> = BTreeMap::new();
+        let mut route = |method: &str, path: &'static str, operation: Operation| {
+            paths
+                .entry(path)
+                .or_default()
+                .insert(method.to_owned(), operation.0.into());
+        };
+
+        route(
+            "get",
+            "/openapi.json",
+            Operation::new("This document").response(
+                "200",
+                "OpenAPI document",
+                &[("application/json", json!({ "type": "object" }))],
+            ),
+        );
+        route(
+            "get",
+            "/meta",
+            Operation::new("Database options and background work").response(
+                "200",
+                "Effective options, compaction lag and the distribution of imported games",
+                &[("application/json", MetaResponse::schema(gen))],
+            ),
+        );
+        route(
+            "get",
+            "/meta/variants",
+            Operation::new("Coverage of the lichess database per variant").response(
+                "200",
+                "Summary per variant",
+                &[("application/json", Vec::<VariantMeta>::schema(gen))],
+            ),
+        );
+
+        let masters = Operation::new("Masters database")
+            .query::<MastersQuery>(gen)
+            .timeout()
+            .explorer(gen);
+        route("get", "/masters", masters);
+        route(
+            "post",
+            "/masters/batch",
+            Operation::new("Masters database, many positions")
+                .description("Looks up at most 64 positions in one request.")
+                .body(
+                    "application/json",
+                    Vec::<MastersQuery>::schema(gen),
+                    "Query parameters of `GET /masters` for each position, with values as strings",
+                )
+                .timeout()
+                .json(
+                    "Moves and games of each position, in the order of the request",
+                    Vec::<ExplorerResponse>::schema(gen),
+                ),
+        );
+        route(
+            "get",
+            "/masters/pgn",
+            Operation::new("PGN of the top masters games of a position")
+                .query::<MastersQuery>(gen)
+                .timeout()
+                .pgn("The games, one after another")
+                .reference("400", "BadRequest"),
+        );
+        route(
+            "get",
+            "/masters/pgn/{id}",
+            Operation::new("PGN of a masters game")
+                .path_param("id", "Game id")
+                .pgn("The game")
+                .response("404", "Game not found", &[]),
+        );
+
+        route(
+            "get",
+            "/lichess",
+            Operation::new("Lichess database")
+                .query::<LichessQuery>(gen)
+                .timeout()
+                .explorer(gen),
+        );
+        route(
+            "post",
+            "/lichess/batch",
+            Operation::new("Lichess database, many positions")
+                .description("Looks up at most 64 positions in one request.")
+                .body(
+                    "application/json",
+                    Vec::<LichessQuery>::schema(gen),
+                    "Query parameters of `GET /lichess` for each position, with values as strings",
+                )
+                .timeout()
+                .json(
+                    "Moves and games of each position, in the order of the request",
+                    Vec::<ExplorerResponse>::schema(gen),
+                ),
+        );
+        route("get", "/lichess/pgn", Operation::new("PGN of the top and recent lichess games of a position")
+            .description("Games contain only the moves up to and including the move played from the position, and link to the full game.")
+            .query::<LichessQuery>(gen)
+            .timeout()
+            .pgn("The games, one after another")
+            .reference("400", "BadRequest"));
+        route(
+            "get",
+            "/lichess/best",
+            Operation::new("Single best move of a position in the lichess database")
+                .description(
+                    "Cheaper than a full explorer query, for clients that probe many positions.",
+                )
+                .query::<LichessBestQuery>(gen)
+                .timeout()
+                .json(
+                    "The selected move, or null if no move qualifies",
+                    BestMove::schema(gen),
+                ),
+        );
+        route(
+            "get",
+            "/lichess/trend",
+            Operation::new("Monthly results of a position or move in the lichess database")
+                .description(
+                    "Popularity of a position over time, for analysis of opening fashions.",
+                )
+                .query::<LichessTrendQuery>(gen)
+                .timeout()
+                .json("Results per month", TrendResponse::schema(gen)),
+        );
+        route("get", "/lichess/parents", Operation::new("Positions that lead into a position in the lichess database")
+            .description("Lists the positions and moves from which games reached the position, for example to discover transpositions. Only covers the first 40 plies of games that start from the initial position, imported since the parent index was introduced. Parents are recovered by replaying their most common move order, so parents that were never reached from the initial position are omitted.")
+            .query::<ParentsQuery>(gen)
+            .json("Parent positions by number of games, most games first", Vec::<Parent>::schema(gen)));
+        route("get", "/lichess/move-orders", Operation::new("Move orders that reached a position in the lichess database")
+            .description("Lists the most common sequences of moves from the initial position by which games reached the position, for move-order research. Only covers the first 40 plies of games that start from the initial position, imported since move orders are tracked. The 128 most common sequences are tracked per position, and the number of games of rarely played sequences may be overestimated.")
+            .query::<MoveOrdersQuery>(gen)
+            .json("Move orders by number of games, most games first", Vec::<MoveOrder>::schema(gen)));
+        route("get", "/lichess/games", Operation::new("All listed games of a position in the lichess database")
+            .description("Each month keeps a few example games for every speed and rating group. This lists all of them, most recent first, for example to fetch more games than the explorer responses include.")
+            .query::<LichessGamesQuery>(gen)
+            .timeout()
+            .json("Page of games", GamesPage::schema(gen)));
+        route(
+            "get",
+            "/lichess/depth-profile",
+            Operation::new("Results of each position along a line in the lichess database")
+                .query::<LichessDepthProfileQuery>(gen)
+                .timeout()
+                .json(
+                    "Results by ply, starting with the root position",
+                    DepthProfile::schema(gen),
+                ),
+        );
+
+        let player = Operation::new("Games of a lichess player")
+            .description("Starts indexing the player, if required, and streams updated results while indexing.")
+            .query::<PlayerQuery>(gen)
+            .response("200", "Stream of results", &[("application/x-ndjson", ExplorerResponse::schema(gen))])
+            .reference("400", "BadRequest");
+        route("get", "/player", player);
+        route(
+            "get",
+            "/player/prep",
+            Operation::new("Opening preparation against a lichess player")
+                .query::<PrepQuery>(gen)
+                .json("Tree of likely continuations", PrepResponse::schema(gen)),
+        );
+        route(
+            "get",
+            "/player/find",
+            Operation::new("Indexed players that frequently reach a position")
+                .description(
+                    "Only includes games indexed since the contributor index was introduced.",
+                )
+                .query::<PlayerFindQuery>(gen)
+                .json(
+                    "Players by number of games, most games first",
+                    Vec::<Contributor>::schema(gen),
+                ),
+        );
+
+        route("get", "/account", Operation::new("Usage of the API key sent as bearer token")
+            .description("Requests with an API key are limited by its monthly quota instead of the anonymous rate limits. Once the quota is exhausted, requests are rejected with status 429. Requests to this endpoint are not counted.")
+            .response("200", "Quota and requests by month", &[("application/json", AccountResponse::schema(gen))])
+            .reference("401", "Unauthorized"));
+        route("get", "/tenant/masters", Operation::new("Private explorer of the tenant of the API key")
+            .description("Positions and games of each tenant are kept apart from the public databases and from other tenants. Snapshots are not available.")
+            .query::<MastersQuery>(gen)
+            .timeout()
+            .json("Moves and games of the position", ExplorerResponse::schema(gen))
+            .tenant());
+        route(
+            "get",
+            "/tenant/pgn/{id}",
+            Operation::new("PGN of a game in the private explorer of the tenant of the API key")
+                .path_param("id", "Game id")
+                .pgn("The game")
+                .response("400", "Game not found", &[])
+                .tenant(),
+        );
+        route("put", "/tenant/import/pgn", Operation::new("Import games into the private explorer of the tenant of the API key")
+            .description("Games are identified by their `LichessId` header, or else by an id derived from the game, so that the same game is not imported twice. Once the explorer holds the maximum number of games of the key, further games are rejected.")
+            .body("application/x-chess-pgn", json!({ "type": "string" }), "Games, one after another")
+            .json("Imported and rejected games", PgnImportReport::schema(gen))
+            .tenant());
+
+        let mut graph = ObjectSchema::new(Some("Graph with merged transpositions"));
+        graph.property("nodes", Vec::<GraphNode>::schema(gen), None, true);
+        graph.property("edges", Vec::<GraphEdge>::schema(gen), None, true);
+        route(
+            "get",
+            "/export/graph",
+            Operation::new("Opening graph of the lichess database")
+                .query::<GraphQuery>(gen)
+                .response(
+                    "200",
+                    "Graph with merged transpositions",
+                    &[
+                        ("text/vnd.graphviz", json!({ "type": "string" })),
+                        ("application/json", graph.into_value()),
+                    ],
+                )
+                .reference("400", "BadRequest"),
+        );
+        route(
+            "get",
+            "/export/theory",
+            Operation::new(
+                "Table of the popular continuations of a position in the lichess database",
+            )
+            .query::<TheoryQuery>(gen)
+            .response(
+                "200",
+                "Nested lines, or a markdown table with one row per line",
+                &[
+                    ("application/json", TheoryTable::schema(gen)),
+                    ("text/markdown", json!({ "type": "string" })),
+                ],
+            )
+            .reference("400", "BadRequest"),
+        );
+        route(
+            "get",
+            "/feed/recent",
+            Operation::new("Recently imported notable games")
+                .query::<FeedQuery>(gen)
+                .response(
+                    "200",
+                    "Stream of games, most recent first",
+                    &[("application/x-ndjson", ExplorerGame::schema(gen))],
+                ),
+        );
+
+        let mut websocket = ObjectSchema::new(None);
+        websocket.property(
+            "client",
+            ClientMessage::schema(gen),
+            Some("Messages that the client sends"),
+            true,
+        );
+        websocket.property(
+            "server",
+            ServerMessage::schema(gen),
+            Some("Messages that the server sends"),
+            true,
+        );
+        route("get", "/subscribe", Operation::new("Updates of positions as games are imported")
+            .description("Upgrades to a WebSocket. Clients subscribe to positions, and receive an update for each game that is imported into one of them. Messages are JSON text frames.")
+            .response("101", "Switching to the WebSocket protocol", &[("application/json", websocket.into_value())]));
+
+        #[cfg(feature = "graphql")]
+        route("post", "/graphql", Operation::new("GraphQL queries of positions")
+            .description("Looks up many positions of both databases in one request, selecting only the needed fields.")
+            .body("application/json", json!({
+                "type": "object",
+                "required": ["query"],
+                "properties": {
+                    "query": { "type": "string" },
+                    "variables": { "type": "object" },
+                    "operationName": { "type": "string" },
+                },
+            }), "GraphQL request")
+            .response("200", "GraphQL response", &[("application/json", json!({
+                "type": "object",
+                "properties": {
+                    "data": { "type": "object" },
+                    "errors": { "type": "array", "items": { "type": "object" } },
+                },
+            }))]));
+
+        for (alias, path) in [
+            ("/master", "/masters"),
+            ("/master/pgn/{id}", "/masters/pgn/{id}"),
+            ("/personal", "/player"),
+        ] {
+            let mut operation = paths[path].clone();
+            for operation in operation.values_mut() {
+                operation["deprecated"] = true.into();
+                operation["description"] =
+                    format!("Alias of `{}`, kept for compatibility.", path).into();
+            }
+            paths.insert(alias, operation);
+        }
+
+        let mut schemas: Map<String, Value> = gen
+            .components
+            .iter()
+            .map(|(name, schema)| ((*name).to_owned(), schema.clone()))
+            .collect();
+        schemas.insert("MoveTable".to_owned(), json!({
+            "type": "string",
+            "description": "Header row `uci,white,draws,black,averageRating,games`, followed by one row per move. Fields other than `uci` are empty if masked.",
+            "example": "uci,white,draws,black,averageRating,games\ne2e4,1207,1080,966,2407,3253\n",
+        }));
+
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": env!("CARGO_PKG_NAME"),
+                "description": env!("CARGO_PKG_DESCRIPTION"),
+                "license": { "name": env!("CARGO_PKG_LICENSE") },
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "paths": paths,
+            "components": {
+                "schemas": schemas,
+                "parameters": {
+                    "timeout": {
+                        "name": "X-Timeout-Ms",
+                        "in": "header",
+                        "description": "Give up on the request after this many milliseconds, responding with 504",
+                        "schema": { "type": "integer", "minimum": 0 },
+                    },
+                    "format": {
+                        "name": "format",
+                        "in": "query",
+                        "description": "Respond with a table of moves instead of JSON",
+                        "schema": { "type": "string", "enum": ["csv", "tsv"] },
+                    },
+                },
+                "responses": {
+                    "NotModified": {
+                        "description": "The response would be unchanged, because the `If-None-Match` header includes its `ETag`. Tags change whenever data of the position is written, and occasionally otherwise.",
+                        "headers": { "ETag": { "schema": { "type": "string" } } },
+                    },
+                    "BadRequest": {
+                        "description": "Invalid position or parameters",
+                        "content": { "text/plain": { "schema": { "type": "string" } } },
+                    },
+                    "NoSnapshot": {
+                        "description": "There is no snapshot for `asOf`",
+                        "content": { "text/plain": { "schema": { "type": "string" } } },
+                    },
+                    "GatewayTimeout": {
+                        "description": "The request took longer than `X-Timeout-Ms`",
+                        "content": { "text/plain": { "schema": { "type": "string" } } },
+                    },
+                    "Unauthorized": {
+                        "description": "Missing or unknown API key",
+                    },
+                    "NoTenant": {
+                        "description": "API key has no private explorer",
+                    },
+                },
+            },
+        })
+    }
+}
+
+impl IntoResponse for OpenApi {
+    fn into_response(self) -> Response {
+        Json(OpenApi::document()).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_openapi_routes() {
+        let doc = OpenApi::document();
+        let paths = doc["paths"].as_object().expect("paths");
+
+        let routes = include_str!("../main.rs")
+            .split(".route(\"")
+            .skip(1)
+            .filter_map(|route| route.split('"').next())
+            .filter(|path| {
+                !["/monitor/", "/admin", "/import/", "/compact"]
+                    .iter()
+                    .any(|internal| path.starts_with(internal))
+            })
+            .filter(|path| cfg!(feature = "graphql") || *path != "/graphql");
+
+        for route in routes {
+            let path = route
+                .split('/')
+                .map(|segment| match segment.strip_prefix(':') {
+                    Some(param) => format!("{{{}}}", param),
+                    None => segment.to_owned(),
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+            assert!(paths.contains_key(&path), "{} is documented", path);
+        }
+    }
+
+    #[test]
+    fn test_openapi_refs() {
+        let doc = OpenApi::document();
+        let schemas = doc["components"]["schemas"].as_object().expect("schemas");
+        let json = doc.to_string();
+        for reference in json.split("\"#/components/schemas/").skip(1) {
+            let name = reference.split('"').next().expect("name");
+            assert!(schemas.contains_key(name), "{} is defined", name);
+        }
+    }
+
+    #[test]
+    fn test_openapi_query_params() {
+        let doc = OpenApi::document();
+        let params = doc["paths"]["/lichess"]["get"]["parameters"]
+            .as_array()
+            .expect("parameters");
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|param| param["name"] == name)
+                .unwrap_or_else(|| panic!("{} is documented", name))
+        };
+        assert_eq!(param("speeds")["schema"]["type"], "array");
+        assert_eq!(param("speeds")["explode"], false);
+        assert_eq!(param("sinceWeek")["in"], "query");
+        assert_eq!(param("play")["required"], false);
+        assert!(param("tc")["description"].is_string());
+
+        let player = doc["paths"]["/player"]["get"]["parameters"]
+            .as_array()
+            .expect("parameters");
+        assert!(player
+            .iter()
+            .any(|param| param["name"] == "player" && param["required"] == true));
+    }
+}
@@ -0,0 +1,100 @@
+use std::{
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use shakmaty::variant::Variant;
+
+use crate::{
+    api::{Error, Play, PlayPosition},
+    opening::Openings,
+};
+
+/// Number of positions per generation. Up to twice as many are kept.
+const GENERATION_SIZE: usize = 10_000;
+
+type RawPlay = (Variant, Option<String>, String);
+
+#[derive(Default)]
+struct Generations {
+    current: FxHashMap<RawPlay, PlayPosition<'static>>,
+    previous: FxHashMap<RawPlay, PlayPosition<'static>>,
+}
+
+impl Generations {
+    fn insert(&mut self, key: RawPlay, position: PlayPosition<'static>) {
+        if self.current.len() >= GENERATION_SIZE {
+            self.previous = mem::take(&mut self.current);
+        }
+        self.current.insert(key, position);
+    }
+}
+
+/// Positions reached by recently requested (variant, fen, play) query
+/// parameters, so that hot positions are not parsed, validated and hashed
+/// again for every request. Entries that are not used for an entire
+/// generation are dropped.
+///
+/// Guarded by a mutex rather than a read-write lock, because hashed
+/// positions are not `Sync`. It is only held for lookups and inserts.
+#[derive(Default)]
+pub struct PositionCache {
+    generations: Mutex<Generations>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionCacheStats {
+    hits: u64,
+    misses: u64,
+    size: usize,
+}
+
+impl PositionCache {
+    pub fn position(
+        &self,
+        play: Play,
+        openings: &'static Openings,
+    ) -> Result<PlayPosition<'static>, Error> {
+        let key = (play.variant.into(), play.fen.clone(), play.play.clone());
+
+        {
+            let mut generations = self.generations.lock().expect("position cache");
+            if let Some(cached) = generations.current.get(&key) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(cached.clone());
+            }
+            if let Some(position) = generations.previous.remove(&key) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                generations.insert(key, position.clone());
+                return Ok(position);
+            }
+        }
+
+        // Compute without holding the lock. Concurrent misses of the same
+        // key may compute it more than once, but yield the same position.
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let position = play.position(openings)?;
+        self.generations
+            .lock()
+            .expect("position cache")
+            .insert(key, position.clone());
+        Ok(position)
+    }
+
+    pub fn stats(&self) -> PositionCacheStats {
+        let generations = self.generations.lock().expect("position cache");
+        PositionCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: generations.current.len() + generations.previous.len(),
+        }
+    }
+}
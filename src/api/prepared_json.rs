@@ -0,0 +1,38 @@
+use axum::{
+    body,
+    response::{IntoResponse, Response},
+    Json,
+};
+use bytes::Bytes;
+use serde::Serialize;
+
+/// A JSON response that may already have been serialized, for example on
+/// the blocking thread pool.
+pub enum PreparedJson<T> {
+    Deferred(T),
+    Serialized(Bytes),
+}
+
+impl<T: Serialize> PreparedJson<T> {
+    pub fn new(value: T, serialize_now: bool) -> PreparedJson<T> {
+        if serialize_now {
+            PreparedJson::Serialized(Bytes::from(
+                serde_json::to_vec(&value).expect("serialize response"),
+            ))
+        } else {
+            PreparedJson::Deferred(value)
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for PreparedJson<T> {
+    fn into_response(self) -> Response {
+        match self {
+            PreparedJson::Deferred(value) => Json(value).into_response(),
+            PreparedJson::Serialized(buf) => Response::builder()
+                .header(axum::http::header::CONTENT_TYPE, "application/json")
+                .body(body::boxed(body::Full::from(buf)))
+                .unwrap(),
+        }
+    }
+}
@@ -1,38 +1,61 @@
-use std::cmp::{max, min};
+use std::{
+    cmp::{max, min},
+    str::FromStr,
+};
 
 use serde::Deserialize;
+use serde_json::{json, Value};
 use serde_with::{serde_as, CommaSeparator, DisplayFromStr, StringWithSeparator, TryFromInto};
 use shakmaty::{
     fen::Fen,
+    san::SanPlus,
     uci::Uci,
     variant::{Variant, VariantPosition},
     zobrist::Zobrist,
-    CastlingMode, Color, PositionError,
+    ByColor, CastlingMode, Color, Position as _, PositionError,
 };
+use thiserror::Error as ThisError;
 
 use crate::{
-    api::{Error, LilaVariant},
-    model::{Mode, Month, RatingGroup, Speed, UserName, Year},
+    api::{ApiSchema, CanonicalFilter, Error, LilaVariant, SchemaGenerator, SpeedFilter},
+    model::{Clock, ClockRange, GamePlayer, Mode, Month, RatingGroup, Speed, UserName, Week, Year},
     opening::{Opening, Openings},
 };
 
 #[serde_as]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, ApiSchema, Debug)]
 pub struct MastersQuery {
     #[serde(flatten)]
     pub play: Play,
+    /// Include only games from this year.
     #[serde_as(as = "TryFromInto<u16>")]
     #[serde(default)]
     pub since: Year,
+    /// Include only games until this year.
     #[serde_as(as = "TryFromInto<u16>")]
     #[serde(default = "Year::max_value")]
     pub until: Year,
+    /// Exclude historical games where the rating of a player was estimated.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default, rename = "excludeEstimated")]
+    pub exclude_estimated: bool,
+    /// Answer from the last snapshot taken before the end of this month, to
+    /// compare with the data then. Responds with 404 if there is no such
+    /// snapshot.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, rename = "asOf")]
+    pub as_of: Option<Month>,
+    /// Include only moves into lines classified with an ECO code in this
+    /// range. The totals are restricted accordingly.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub eco: Option<EcoRange>,
     #[serde(flatten)]
     pub limits: Limits,
 }
 
 #[serde_as]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, ApiSchema, Debug)]
 pub struct LichessQuery {
     #[serde(flatten)]
     pub play: Play,
@@ -40,30 +63,343 @@ pub struct LichessQuery {
     pub limits: Limits,
     #[serde(flatten)]
     pub filter: LichessQueryFilter,
+    /// Include results of the position per month.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub history: bool,
+    /// Answer from the last snapshot taken before the end of this month, to
+    /// compare with the data then. Responds with 404 if there is no such
+    /// snapshot.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, rename = "asOf")]
+    pub as_of: Option<Month>,
+}
+
+#[serde_as]
+#[derive(Deserialize, ApiSchema, Debug)]
+pub struct LichessGamesQuery {
+    #[serde(flatten)]
+    pub play: Play,
+    #[serde(flatten)]
+    pub filter: LichessQueryFilter,
+    /// Page of up to 50 games, starting with the most recent games.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub page: usize,
+}
+
+#[derive(Deserialize, ApiSchema, Debug)]
+pub struct LichessDepthProfileQuery {
+    #[serde(flatten)]
+    pub play: Play,
+    #[serde(flatten)]
+    pub filter: LichessQueryFilter,
+}
+
+#[derive(Deserialize, ApiSchema, Debug)]
+pub struct LichessBestQuery {
+    #[serde(flatten)]
+    pub play: Play,
+    #[serde(flatten)]
+    pub filter: LichessQueryFilter,
+    #[serde(default)]
+    pub criterion: BestCriterion,
+}
+
+/// How to select the single best move.
+#[derive(Deserialize, ApiSchema, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum BestCriterion {
+    /// Best score for the side to move.
+    Score,
+    /// Most played.
+    Popularity,
+}
+
+impl Default for BestCriterion {
+    fn default() -> BestCriterion {
+        BestCriterion::Score
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, ApiSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LichessTrendQuery {
+    #[serde(flatten)]
+    pub play: Play,
+    /// Trend of this move, instead of the position itself.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub uci: Option<Uci>,
+    /// Also list the trend of each candidate move, most played first.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub by_move: bool,
+    #[serde(flatten)]
+    pub filter: LichessQueryFilter,
+}
+
+#[serde_as]
+#[derive(Deserialize, ApiSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphQuery {
+    #[serde(flatten)]
+    pub play: Play,
+    #[serde(flatten)]
+    pub filter: LichessQueryFilter,
+    /// Number of plies to expand. Defaults to 4, at most 8.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "GraphQuery::default_depth")]
+    pub depth: u32,
+    /// Expand only moves played in at least this many games. Defaults to
+    /// 1000.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "GraphQuery::default_min_games")]
+    pub min_games: u64,
+    #[serde(default)]
+    pub format: GraphFormat,
+}
+
+impl GraphQuery {
+    fn default_depth() -> u32 {
+        4
+    }
+
+    fn default_min_games() -> u64 {
+        1000
+    }
+}
+
+/// Format of the exported graph.
+#[derive(Deserialize, ApiSchema, Debug, Copy, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum GraphFormat {
+    /// Graphviz source.
+    Dot,
+    /// Lists of nodes and edges.
+    Json,
+}
+
+impl Default for GraphFormat {
+    fn default() -> GraphFormat {
+        GraphFormat::Dot
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, ApiSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TheoryQuery {
+    #[serde(flatten)]
+    pub play: Play,
+    #[serde(flatten)]
+    pub filter: LichessQueryFilter,
+    /// Number of plies to expand. Defaults to 10, at most 20.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "TheoryQuery::default_depth")]
+    pub depth: u32,
+    /// Include only moves chosen in at least this fraction of the games in
+    /// the position. Defaults to 0.05.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "TheoryQuery::default_min_share")]
+    pub min_share: f64,
+    #[serde(default)]
+    pub format: TheoryFormat,
+}
+
+impl TheoryQuery {
+    fn default_depth() -> u32 {
+        10
+    }
+
+    fn default_min_share() -> f64 {
+        0.05
+    }
+}
+
+/// Format of the exported theory table.
+#[derive(Deserialize, ApiSchema, Debug, Copy, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum TheoryFormat {
+    Json,
+    /// Table of lines for documentation.
+    Markdown,
+}
+
+impl Default for TheoryFormat {
+    fn default() -> TheoryFormat {
+        TheoryFormat::Json
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, ApiSchema, Debug)]
+pub struct FeedQuery {
+    #[serde(default)]
+    pub source: FeedSource,
+    /// Number of most recently imported games to list. Defaults to 100.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "FeedQuery::default_limit")]
+    pub limit: usize,
+}
+
+impl FeedQuery {
+    fn default_limit() -> usize {
+        100
+    }
 }
 
 #[serde_as]
 #[derive(Deserialize, Debug)]
-pub struct LichessQueryFilter {
-    #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, Speed>>")]
+pub struct DeltaQuery {
+    #[serde_as(as = "DisplayFromStr")]
+    pub month: Month,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct SampleQuery {
     #[serde(default)]
-    pub speeds: Option<Vec<Speed>>,
+    pub source: FeedSource,
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "SampleQuery::default_n")]
+    pub n: usize,
+}
+
+impl SampleQuery {
+    fn default_n() -> usize {
+        1000
+    }
+}
+
+/// Database to list games from.
+#[derive(Deserialize, ApiSchema, Debug, Copy, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum FeedSource {
+    Masters,
+    Lichess,
+}
+
+impl Default for FeedSource {
+    fn default() -> FeedSource {
+        FeedSource::Lichess
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, ApiSchema, Debug)]
+pub struct LichessQueryFilter {
+    #[serde(flatten)]
+    pub speed: SpeedFilter,
+    /// Comma separated rating groups, by their lower bound.
     #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, RatingGroup>>")]
     #[serde(default)]
     pub ratings: Option<Vec<RatingGroup>>,
+    /// Include only games from this month.
     #[serde_as(as = "DisplayFromStr")]
     #[serde(default)]
     pub since: Month,
+    /// Include only games until this month.
     #[serde_as(as = "DisplayFromStr")]
     #[serde(default = "Month::max_value")]
     pub until: Month,
+    /// Use recent weekly data instead, starting with the week (Monday to
+    /// Sunday) that contains this date. Only the last 8 weeks of imported
+    /// games are available.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, rename = "sinceWeek")]
+    pub since_week: Option<Week>,
+    /// Range of clocks, ordered by estimated duration (initial + 40 *
+    /// increment). Games without a known clock are only counted if the
+    /// range covers their whole speed.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub tc: Option<ClockRange>,
+    /// Omit moves with fewer games. The totals of the position still
+    /// include them.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, rename = "minGames")]
+    pub min_games: Option<u64>,
+    /// Leave out games in which at least one player is a bot, from both
+    /// statistics and listed games.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default, rename = "excludeBots")]
+    pub exclude_bots: bool,
+    /// Include results by speed for each move.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default, rename = "groupBySpeed")]
+    pub group_by_speed: bool,
+    #[serde(default, rename = "exampleGame")]
+    pub example_game: ExampleGame,
+    /// Seed for random example games, to reproduce the same selection.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+/// Game to show for each move.
+#[derive(Deserialize, ApiSchema, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ExampleGame {
+    /// Only if the move was played in a single game.
+    Single,
+    /// A uniformly random game in which the move was played.
+    Random,
+}
+
+impl Default for ExampleGame {
+    fn default() -> ExampleGame {
+        ExampleGame::Single
+    }
+}
+
+impl Default for LichessQueryFilter {
+    fn default() -> LichessQueryFilter {
+        LichessQueryFilter {
+            speed: SpeedFilter::default(),
+            ratings: None,
+            since: Month::default(),
+            until: Month::max_value(),
+            since_week: None,
+            tc: None,
+            min_games: None,
+            exclude_bots: false,
+            group_by_speed: false,
+            example_game: ExampleGame::Single,
+            seed: None,
+        }
+    }
 }
 
 impl LichessQueryFilter {
+    pub fn is_all_time(&self) -> bool {
+        self.since == Month::default()
+            && self.until == Month::max_value()
+            && self.since_week.is_none()
+    }
+
     pub fn contains_speed(&self, speed: Speed) -> bool {
-        self.speeds
-            .as_ref()
-            .map_or(true, |speeds| speeds.contains(&speed))
+        self.speed.contains(speed) && self.tc.map_or(true, |tc| tc.overlaps_speed(speed))
+    }
+
+    /// Time control filter for individual games, consistent with the speeds
+    /// that statistics are filtered by. Games without a clock are
+    /// correspondence games.
+    pub fn contains_clock(&self, clock: Option<Clock>) -> bool {
+        match (self.tc, clock) {
+            (Some(tc), Some(clock)) => tc.contains(clock),
+            (Some(tc), None) => tc.overlaps_speed(Speed::Correspondence),
+            (None, _) => true,
+        }
+    }
+
+    pub fn has_min_games(&self, games: u64) -> bool {
+        self.min_games.map_or(true, |min_games| games >= min_games)
+    }
+
+    pub fn contains_players(&self, players: &ByColor<GamePlayer>) -> bool {
+        !self.exclude_bots || !(players.white.bot || players.black.bot)
     }
 
     pub fn contains_rating_group(&self, rating_group: RatingGroup) -> bool {
@@ -85,91 +421,621 @@ impl LichessQueryFilter {
         }
         top_group
     }
+
+    /// The filter as it is actually applied, with speeds narrowed down by
+    /// the time control range, and ratings by the rating groups that can be
+    /// distinguished.
+    pub fn canonical(&self) -> CanonicalFilter {
+        CanonicalFilter {
+            ratings: self.ratings.as_ref().map(|_| {
+                RatingGroup::ALL
+                    .into_iter()
+                    .filter(|group| self.contains_rating_group(*group))
+                    .map(RatingGroup::lower_bound)
+                    .collect()
+            }),
+            since_week: self.since_week,
+            min_games: self.min_games,
+            exclude_bots: self.exclude_bots,
+            ..CanonicalFilter::new(self.since, self.until, |speed| self.contains_speed(speed))
+        }
+        .with_tc(self.tc)
+    }
 }
 
 #[serde_as]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, ApiSchema, Debug)]
 pub struct PlayerQuery {
     #[serde(flatten)]
     pub play: Play,
+    /// Username of the player.
     #[serde_as(as = "DisplayFromStr")]
     pub player: UserName,
+    /// With `both`, the results of both colors are combined from the point
+    /// of view of the player: `white` counts wins of the player and `black`
+    /// counts losses.
     #[serde_as(as = "DisplayFromStr")]
-    pub color: Color,
+    pub color: PlayerColor,
     #[serde(flatten)]
     pub filter: PlayerQueryFilter,
     #[serde(flatten)]
     pub limits: Limits,
+    /// Include where the games of the player leave the lichess mainline.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default, rename = "bookExit")]
+    pub book_exit: bool,
+}
+
+#[derive(ThisError, Debug)]
+#[error("invalid color, expected white, black or both")]
+pub struct InvalidPlayerColor;
+
+/// Color of the player, or both colors with the results from the point of
+/// view of the player.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PlayerColor {
+    Single(Color),
+    Both,
+}
+
+impl FromStr for PlayerColor {
+    type Err = InvalidPlayerColor;
+
+    fn from_str(s: &str) -> Result<PlayerColor, InvalidPlayerColor> {
+        match s {
+            "both" => Ok(PlayerColor::Both),
+            _ => s
+                .parse()
+                .map(PlayerColor::Single)
+                .map_err(|_| InvalidPlayerColor),
+        }
+    }
+}
+
+impl ApiSchema for PlayerColor {
+    fn schema(_: &mut SchemaGenerator) -> Value {
+        json!({ "type": "string", "enum": ["white", "black", "both"] })
+    }
+}
+
+#[derive(ThisError, Debug)]
+#[error("invalid eco range, expected code like B90 or range like B90-B99")]
+pub struct InvalidEcoRange;
+
+/// Inclusive range of ECO codes.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EcoRange {
+    from: String,
+    to: String,
+}
+
+impl EcoRange {
+    pub fn contains(&self, eco: &str) -> bool {
+        self.from.as_str() <= eco && eco <= self.to.as_str()
+    }
+}
+
+fn parse_eco(s: &str) -> Result<String, InvalidEcoRange> {
+    match s.as_bytes() {
+        [b'A'..=b'E', b'0'..=b'9', b'0'..=b'9'] => Ok(s.to_owned()),
+        _ => Err(InvalidEcoRange),
+    }
+}
+
+impl FromStr for EcoRange {
+    type Err = InvalidEcoRange;
+
+    fn from_str(s: &str) -> Result<EcoRange, InvalidEcoRange> {
+        let s = s.to_ascii_uppercase();
+        let (from, to) = s.split_once('-').unwrap_or((&s, &s));
+        let range = EcoRange {
+            from: parse_eco(from)?,
+            to: parse_eco(to)?,
+        };
+        if range.from <= range.to {
+            Ok(range)
+        } else {
+            Err(InvalidEcoRange)
+        }
+    }
+}
+
+impl ApiSchema for EcoRange {
+    fn schema(_: &mut SchemaGenerator) -> Value {
+        json!({ "type": "string", "pattern": "^[A-Ea-e][0-9]{2}(-[A-Ea-e][0-9]{2})?$", "example": "B90-B99" })
+    }
 }
 
 #[serde_as]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, ApiSchema, Debug)]
+pub struct PlayerFindQuery {
+    #[serde(flatten)]
+    pub play: Play,
+    /// Include only players with at least this many games in the position.
+    /// Defaults to 5.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "PlayerFindQuery::default_min_games", rename = "minGames")]
+    pub min_games: u64,
+}
+
+impl PlayerFindQuery {
+    fn default_min_games() -> u64 {
+        5
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, ApiSchema, Debug)]
+pub struct ParentsQuery {
+    #[serde(flatten)]
+    pub play: Play,
+    /// Include only parents that reached the position in at least this
+    /// many games. Defaults to 1.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "ParentsQuery::default_min_games", rename = "minGames")]
+    pub min_games: u64,
+}
+
+impl ParentsQuery {
+    fn default_min_games() -> u64 {
+        1
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, ApiSchema, Debug)]
+pub struct MoveOrdersQuery {
+    #[serde(flatten)]
+    pub play: Play,
+    /// Include only move orders played in at least this many games.
+    /// Defaults to 1.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "MoveOrdersQuery::default_min_games", rename = "minGames")]
+    pub min_games: u64,
+}
+
+impl MoveOrdersQuery {
+    fn default_min_games() -> u64 {
+        1
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, ApiSchema, Debug)]
+pub struct PrepQuery {
+    #[serde(flatten)]
+    pub play: Play,
+    /// Username of the player to prepare against.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(alias = "name")]
+    pub player: UserName,
+    /// Color of the player.
+    #[serde_as(as = "DisplayFromStr")]
+    pub color: Color,
+    #[serde(flatten)]
+    pub filter: PlayerQueryFilter,
+    /// Number of plies to expand. Defaults to 8, at most 16.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "PrepQuery::default_depth")]
+    pub depth: u32,
+}
+
+impl PrepQuery {
+    fn default_depth() -> u32 {
+        8
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, ApiSchema, Debug)]
 pub struct PlayerQueryFilter {
+    /// Comma separated modes.
     #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, Mode>>")]
     #[serde(default)]
     pub modes: Option<Vec<Mode>>,
-    #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, Speed>>")]
-    #[serde(default)]
-    pub speeds: Option<Vec<Speed>>,
+    #[serde(flatten)]
+    pub speed: SpeedFilter,
+    /// Include only games from this month.
     #[serde_as(as = "DisplayFromStr")]
     #[serde(default)]
     pub since: Month,
+    /// Include only games until this month.
     #[serde_as(as = "DisplayFromStr")]
     #[serde(default = "Month::max_value")]
     pub until: Month,
+    /// Include results by opponent rating group for each move.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default, rename = "opponentRatings")]
+    pub opponent_ratings: bool,
+    /// Omit moves with fewer games. The totals of the position still
+    /// include them.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, rename = "minGames")]
+    pub min_games: Option<u64>,
+}
+
+impl PlayerQueryFilter {
+    pub fn has_min_games(&self, games: u64) -> bool {
+        self.min_games.map_or(true, |min_games| games >= min_games)
+    }
+
+    pub fn contains_speed(&self, speed: Speed) -> bool {
+        self.speed.contains(speed)
+    }
+
+    pub fn canonical(&self) -> CanonicalFilter {
+        CanonicalFilter {
+            modes: self.modes.clone(),
+            min_games: self.min_games,
+            ..CanonicalFilter::new(self.since, self.until, |speed| self.contains_speed(speed))
+        }
+    }
 }
 
 #[serde_as]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, ApiSchema, Debug, Clone)]
 pub struct Play {
     #[serde(default)]
     pub variant: LilaVariant,
-    #[serde_as(as = "Option<DisplayFromStr>")]
+    /// Root position in FEN notation, defaults to the initial position of
+    /// the variant.
+    // Kept raw, so that it can be used to look up cached positions.
     #[serde(default)]
-    pub fen: Option<Fen>,
-    #[serde_as(as = "StringWithSeparator<CommaSeparator, Uci>")]
+    pub fen: Option<String>,
+    /// Comma separated moves in UCI or SAN notation, played from the root
+    /// position.
     #[serde(default)]
-    pub play: Vec<Uci>,
+    pub play: String,
 }
 
+#[derive(Clone)]
 pub struct PlayPosition<'a> {
     pub variant: Variant,
     pub pos: Zobrist<VariantPosition, u128>,
     pub opening: Option<&'a Opening>,
 }
 
+/// Root position and every position reached along the played moves.
+pub struct PlayLine {
+    pub variant: Variant,
+    /// Zobrist hashes by ply, with the move that led to the position.
+    pub positions: Vec<(Option<Uci>, u128)>,
+}
+
 impl Play {
+    pub fn is_chess960(&self) -> bool {
+        matches!(self.variant, LilaVariant::Chess960)
+    }
+
     pub fn position(self, openings: &Openings) -> Result<PlayPosition<'_>, Error> {
         let variant = Variant::from(self.variant);
-        let mut pos = Zobrist::new(match self.fen {
-            Some(fen) => {
-                VariantPosition::from_setup(variant, fen.into_setup(), CastlingMode::Chess960)
-                    .or_else(PositionError::ignore_invalid_castling_rights)
-                    .or_else(PositionError::ignore_invalid_ep_square)
-                    .or_else(PositionError::ignore_impossible_material)?
-            }
-            None => VariantPosition::new(variant),
-        });
-        let opening = openings.classify_and_play(&mut pos, self.play)?;
+        let root = root_position(variant, self.fen.as_deref())?;
+        let play = self.moves(&root)?;
+        let mut pos = Zobrist::new(root);
+        let opening = openings.classify_and_play(&mut pos, play)?;
         Ok(PlayPosition {
             variant,
             pos,
             opening,
         })
     }
+
+    /// Root position, and the played moves in UCI.
+    pub fn root_and_moves(self) -> Result<(VariantPosition, Vec<Uci>), Error> {
+        let root = root_position(Variant::from(self.variant), self.fen.as_deref())?;
+        let play = self.moves(&root)?;
+        Ok((root, play))
+    }
+
+    pub fn line(self) -> Result<PlayLine, Error> {
+        let variant = Variant::from(self.variant);
+        let root = root_position(variant, self.fen.as_deref())?;
+        let play = self.moves(&root)?;
+        let mut pos: Zobrist<_, u128> = Zobrist::new(root);
+        let mut positions = Vec::with_capacity(play.len() + 1);
+        positions.push((None, pos.zobrist_hash()));
+        for uci in play {
+            let m = uci.to_move(&pos)?;
+            pos.play_unchecked(&m);
+            positions.push((Some(uci), pos.zobrist_hash()));
+        }
+        Ok(PlayLine { variant, positions })
+    }
+
+    /// Validates the played moves from the root position. Moves given in
+    /// SAN are converted to UCI.
+    fn moves(&self, root: &VariantPosition) -> Result<Vec<Uci>, Error> {
+        let castling_mode = CastlingMode::from_chess960(self.is_chess960());
+        let mut pos = root.clone();
+        let mut moves = Vec::new();
+        for token in self.play.split(',').filter(|token| !token.is_empty()) {
+            let (uci, m) = match token.parse::<Uci>() {
+                Ok(uci) => {
+                    let m = uci.to_move(&pos)?;
+                    (uci, m)
+                }
+                Err(err) => {
+                    let san = token.parse::<SanPlus>().map_err(|_| err)?.san;
+                    let m = san.to_move(&pos)?;
+                    (m.to_uci(castling_mode), m)
+                }
+            };
+            pos.play_unchecked(&m);
+            moves.push(uci);
+        }
+        Ok(moves)
+    }
+}
+
+fn root_position(variant: Variant, fen: Option<&str>) -> Result<VariantPosition, Error> {
+    Ok(match fen.map(|fen| fen.parse::<Fen>()).transpose()? {
+        Some(fen) => VariantPosition::from_setup(variant, fen.into_setup(), CastlingMode::Chess960)
+            .or_else(PositionError::ignore_invalid_castling_rights)
+            .or_else(PositionError::ignore_invalid_ep_square)
+            .or_else(PositionError::ignore_impossible_material)?,
+        None => VariantPosition::new(variant),
+    })
 }
 
 #[serde_as]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, ApiSchema, Debug, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Limits {
-    #[serde_as(as = "DisplayFromStr")]
-    #[serde(default = "usize::max_value")]
-    pub top_games: usize,
-    #[serde_as(as = "DisplayFromStr")]
-    #[serde(default = "usize::max_value")]
-    pub recent_games: usize,
+    /// Number of games with the highest rated players to display.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub top_games: Option<usize>,
+    /// Number of recent games to display.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub recent_games: Option<usize>,
+    /// Number of most common moves to display.
     #[serde_as(as = "Option<DisplayFromStr>")]
     #[serde(default)]
     pub moves: Option<usize>,
+    /// Page of moves to display, with pages of `moves` moves each. Totals
+    /// always cover all moves.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub moves_page: Option<usize>,
+    /// Number of sorted moves to skip, instead of selecting a page.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub moves_offset: Option<usize>,
+    /// Alias of `moves`, for use with `movesOffset`.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub moves_limit: Option<usize>,
+    /// Also list legal moves that were never played, with empty stats.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub include_unplayed: bool,
+    #[serde(default)]
+    pub mask: Option<Mask>,
+    #[serde(default)]
+    pub sort: MoveSort,
+}
+
+/// Parts of the response to withhold, for training clients that let users
+/// guess before revealing the data.
+#[derive(Deserialize, ApiSchema, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Mask {
+    /// Keep only the aggregate totals.
+    Stats,
+    /// Keep only the list of moves, without per-move stats.
+    Moves,
+}
+
+/// Order of moves. Moves that compare equal remain sorted by popularity.
+#[derive(Deserialize, ApiSchema, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum MoveSort {
+    /// Most played moves first.
+    Games,
+    /// Best score for the side to move first.
+    Winrate,
+    /// Highest average rating first.
+    AverageRating,
+    /// Highest performance rating for the side to move first.
+    Performance,
+    /// Most recently played moves first.
+    Recent,
+}
+
+impl Default for MoveSort {
+    fn default() -> MoveSort {
+        MoveSort::Games
+    }
+}
+
+/// Where the moves that follow a selected page start.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct NextMoves {
+    /// Number of the next page, unless moves were selected by offset.
+    pub page: Option<usize>,
+    pub offset: usize,
+}
+
+impl Limits {
+    /// Keeps only the requested page or range of the sorted moves. Returns
+    /// where the next moves start, if there are more moves.
+    pub fn page_moves<T>(&self, moves: &mut Vec<T>, default_moves: usize) -> Option<NextMoves> {
+        let page_size = self.moves_limit.or(self.moves).unwrap_or(default_moves);
+        let page = self.moves_page.unwrap_or(0);
+        let offset = self
+            .moves_offset
+            .unwrap_or_else(|| page.saturating_mul(page_size));
+        moves.drain(..min(offset, moves.len()));
+        let more = page_size > 0 && moves.len() > page_size;
+        moves.truncate(page_size);
+        more.then(|| NextMoves {
+            page: self.moves_offset.is_none().then(|| page + 1),
+            offset: offset.saturating_add(page_size),
+        })
+    }
+}
+
+#[derive(ThisError, Debug)]
+#[error("invalid sampling rate, expected speed:percent or speed/rating:percent")]
+pub struct InvalidSamplingRate;
+
+/// Percentage of games to accept, either for all games of a speed, or only
+/// for a rating group of that speed.
+#[derive(Debug, Copy, Clone)]
+pub struct SamplingRate {
+    pub speed: Speed,
+    pub rating_group: Option<RatingGroup>,
+    pub percent: u8,
+}
+
+impl FromStr for SamplingRate {
+    type Err = InvalidSamplingRate;
+
+    fn from_str(s: &str) -> Result<SamplingRate, InvalidSamplingRate> {
+        let (bucket, percent) = s.split_once(':').ok_or(InvalidSamplingRate)?;
+        let (speed, rating_group) = match bucket.split_once('/') {
+            Some((speed, rating)) => (
+                speed,
+                Some(rating.parse().map_err(|_| InvalidSamplingRate)?),
+            ),
+            None => (bucket, None),
+        };
+        Ok(SamplingRate {
+            speed: speed.parse().map_err(|_| InvalidSamplingRate)?,
+            rating_group,
+            percent: percent
+                .parse()
+                .ok()
+                .filter(|percent| *percent <= 100)
+                .ok_or(InvalidSamplingRate)?,
+        })
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct SamplingQuery {
+    #[serde_as(as = "DisplayFromStr")]
+    pub month: Month,
+    /// Candidate acceptance rates. Games without a matching rate are
+    /// rejected.
+    #[serde_as(as = "StringWithSeparator<CommaSeparator, SamplingRate>")]
+    #[serde(default)]
+    pub rates: Vec<SamplingRate>,
+    /// Acceptance rates that were used when importing the month. Games
+    /// without a matching rate are assumed to have been imported completely.
+    #[serde_as(as = "StringWithSeparator<CommaSeparator, SamplingRate>")]
+    #[serde(default)]
+    pub imported: Vec<SamplingRate>,
+}
+
+impl SamplingQuery {
+    pub fn rate(&self, speed: Speed, rating_group: RatingGroup) -> u8 {
+        SamplingQuery::lookup(&self.rates, speed, rating_group).unwrap_or(0)
+    }
+
+    pub fn imported_rate(&self, speed: Speed, rating_group: RatingGroup) -> u8 {
+        SamplingQuery::lookup(&self.imported, speed, rating_group).unwrap_or(100)
+    }
+
+    /// The rate for the specific rating group takes precedence over the
+    /// rate for the entire speed.
+    fn lookup(rates: &[SamplingRate], speed: Speed, rating_group: RatingGroup) -> Option<u8> {
+        let mut found = None;
+        for rate in rates.iter().filter(|rate| rate.speed == speed) {
+            match rate.rating_group {
+                Some(group) if group == rating_group => return Some(rate.percent),
+                Some(_) => (),
+                None => found = Some(rate.percent),
+            }
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(moves: &str) -> Play {
+        Play {
+            variant: LilaVariant::Standard,
+            fen: None,
+            play: moves.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_play_san() {
+        let uci = play("e2e4,c7c5,g1f3").line().unwrap();
+        let san = play("e4,c5,Nf3").line().unwrap();
+        assert_eq!(uci.positions, san.positions);
+
+        let mixed = play("e2e4,c5,Nf3").line().unwrap();
+        assert_eq!(uci.positions, mixed.positions);
+
+        assert!(play("e4,e4").line().is_err());
+        assert!(play("e4,xyz").line().is_err());
+    }
+
+    #[test]
+    fn test_eco_range() {
+        let range: EcoRange = "b90-B99".parse().unwrap();
+        assert!(range.contains("B90"));
+        assert!(range.contains("B94"));
+        assert!(range.contains("B99"));
+        assert!(!range.contains("B89"));
+        assert!(!range.contains("C00"));
+
+        let single: EcoRange = "C42".parse().unwrap();
+        assert!(single.contains("C42"));
+        assert!(!single.contains("C43"));
+
+        assert!("B99-B90".parse::<EcoRange>().is_err());
+        assert!("F00".parse::<EcoRange>().is_err());
+        assert!("B9".parse::<EcoRange>().is_err());
+    }
+
+    #[test]
+    fn test_page_moves() {
+        let limits = Limits {
+            moves: Some(4),
+            moves_page: Some(1),
+            ..Limits::default()
+        };
+        let mut moves: Vec<u32> = (0..10).collect();
+        assert_eq!(
+            limits.page_moves(&mut moves, 12),
+            Some(NextMoves {
+                page: Some(2),
+                offset: 8
+            })
+        );
+        assert_eq!(moves, [4, 5, 6, 7]);
+
+        let limits = Limits {
+            moves_offset: Some(3),
+            moves_limit: Some(5),
+            ..Limits::default()
+        };
+        let mut moves: Vec<u32> = (0..10).collect();
+        assert_eq!(
+            limits.page_moves(&mut moves, 12),
+            Some(NextMoves {
+                page: None,
+                offset: 8
+            })
+        );
+        assert_eq!(moves, [3, 4, 5, 6, 7]);
+
+        let limits = Limits {
+            moves_offset: Some(8),
+            moves_limit: Some(5),
+            ..Limits::default()
+        };
+        let mut moves: Vec<u32> = (0..10).collect();
+        assert_eq!(limits.page_moves(&mut moves, 12), None);
+        assert_eq!(moves, [8, 9]);
+    }
 }
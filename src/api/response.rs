@@ -1,29 +1,283 @@
+use std::{collections::BTreeMap, io};
+
+use axum::{
+    body,
+    response::{IntoResponse, Response},
+};
+use futures_util::stream;
 use serde::Serialize;
-use serde_with::{serde_as, DisplayFromStr, TryFromInto};
-use shakmaty::{san::SanPlus, uci::Uci, ByColor, Color};
+use serde_with::{serde_as, DisplayFromStr, SpaceSeparator, StringWithSeparator, TryFromInto};
+use shakmaty::{fen::Epd, san::SanPlus, uci::Uci, ByColor, Color, Move};
 
 use crate::{
-    model::{GameId, GamePlayer, LichessGame, MastersGame, Mode, Month, Speed, Stats, Year},
+    api::{ApiSchema, CanonicalFilter, Mask, NdJson, PreparedJson, ResponseFormat},
+    db::{CompactionLag, DbTuning},
+    model::{
+        Annotations, Clock, ContributorEntry, Erratum, GameId, GamePlayer, LichessGame,
+        MastersGame, Mode, Month, Speed, SpeedGroup, Stats, TenantId, Year,
+    },
     opening::Opening,
     util::ByColorDef,
 };
 
 #[serde_as]
-#[derive(Serialize, Debug)]
+#[derive(Serialize, ApiSchema, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ExplorerResponse {
     #[serde(flatten)]
     pub total: Stats,
+    pub distinct_moves: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branching_percentile: Option<u8>,
+    /// Average number of plies played from the position, only for the
+    /// lichess database.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg_remaining_plies: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub special_moves: Option<SpecialMoves>,
     pub moves: Vec<ExplorerMove>,
+    /// Set if there are more moves than fit on the requested page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_moves_page: Option<usize>,
+    /// Set if there are more moves than fit on the requested page or range.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_moves_offset: Option<usize>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub recent_games: Option<Vec<ExplorerGameWithUci>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub top_games: Option<Vec<ExplorerGameWithUci>>,
     pub opening: Option<&'static Opening>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub book_exit: Option<BookExit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history: Option<Vec<TrendMonth>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<CanonicalFilter>,
 }
 
+/// Serialized responses larger than this are worth serializing on the
+/// blocking thread pool. Reached by default responses for popular positions.
+const HEAVY_JSON_BYTES: usize = 2048;
+
+impl ExplorerResponse {
+    /// Rough estimate of the size of the response serialized as JSON, in
+    /// bytes.
+    fn estimated_json_size(&self) -> usize {
+        const SUMMARY: usize = 200;
+        const MOVE: usize = 200;
+        const BREAKDOWN: usize = 50;
+        const GAME: usize = 150;
+        const MONTH: usize = 50;
+
+        SUMMARY
+            + self
+                .moves
+                .iter()
+                .map(|m| {
+                    MOVE + m.game.as_ref().map_or(0, |_| GAME)
+                        + BREAKDOWN
+                            * (m.opponent_ratings.as_ref().map_or(0, Vec::len)
+                                + m.speeds.as_ref().map_or(0, Vec::len))
+                })
+                .sum::<usize>()
+            + GAME
+                * (self.recent_games.as_ref().map_or(0, Vec::len)
+                    + self.top_games.as_ref().map_or(0, Vec::len))
+            + MONTH * self.history.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Whether serialization is expensive enough to be worth moving to the
+    /// blocking thread pool.
+    pub fn is_heavy(&self) -> bool {
+        self.estimated_json_size() > HEAVY_JSON_BYTES
+    }
+
+    /// Withholds parts of the response. Games and the book exit would give
+    /// away the moves, so they are always dropped.
+    pub fn mask(&mut self, mask: Mask) {
+        self.recent_games = None;
+        self.top_games = None;
+        self.book_exit = None;
+        self.special_moves = None;
+        match mask {
+            Mask::Stats => {
+                self.moves.clear();
+                self.next_moves_page = None;
+                self.next_moves_offset = None;
+            }
+            Mask::Moves => {
+                self.history = None;
+                for m in &mut self.moves {
+                    m.stats = None;
+                    m.average_rating = None;
+                    m.average_opponent_rating = None;
+                    m.avg_seconds = None;
+                    m.repeated = None;
+                    m.upset_rate = None;
+                    m.avg_remaining_plies = None;
+                    m.annotations = None;
+                    m.opponent_ratings = None;
+                    m.speeds = None;
+                    m.performance = None;
+                    m.performance_delta = None;
+                    m.percentages = None;
+                    m.game = None;
+                }
+                // Do not reveal popularity by the order of moves.
+                self.moves.sort_by_cached_key(|m| m.san.to_string());
+            }
+        }
+    }
+
+    /// Splits the response into a summary line, followed by one line for
+    /// each move, recent game and top game.
+    pub fn into_lines(self) -> Vec<ExplorerLine> {
+        let recent_games = self.recent_games.unwrap_or_default();
+        let top_games = self.top_games.unwrap_or_default();
+        let mut lines =
+            Vec::with_capacity(1 + self.moves.len() + recent_games.len() + top_games.len());
+        lines.push(ExplorerLine::Summary(ExplorerSummary {
+            total: self.total,
+            distinct_moves: self.distinct_moves,
+            branching_percentile: self.branching_percentile,
+            avg_remaining_plies: self.avg_remaining_plies,
+            special_moves: self.special_moves,
+            next_moves_page: self.next_moves_page,
+            next_moves_offset: self.next_moves_offset,
+            opening: self.opening,
+            book_exit: self.book_exit,
+            history: self.history,
+            filter: self.filter,
+        }));
+        lines.extend(self.moves.into_iter().map(ExplorerLine::Move));
+        lines.extend(recent_games.into_iter().map(ExplorerLine::RecentGame));
+        lines.extend(top_games.into_iter().map(ExplorerLine::TopGame));
+        lines
+    }
+
+    /// Writes one row for each move, with a header row.
+    pub fn write_table(&self, delimiter: u8) -> Result<Vec<u8>, csv::Error> {
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(delimiter)
+            .from_writer(Vec::new());
+        for m in &self.moves {
+            writer.serialize(MoveRow {
+                uci: &m.uci,
+                white: m.stats.as_ref().map(|stats| stats.white),
+                draws: m.stats.as_ref().map(|stats| stats.draws),
+                black: m.stats.as_ref().map(|stats| stats.black),
+                average_rating: m.average_rating,
+                games: m.stats.as_ref().map(Stats::total),
+            })?;
+        }
+        if self.moves.is_empty() {
+            // Without rows there is nothing to derive the header from.
+            writer.write_record(MoveRow::HEADER)?;
+        }
+        writer
+            .into_inner()
+            .map_err(|err| io::Error::from(err.error().kind()).into())
+    }
+}
+
+/// Row of the table of moves. Fields are empty if the response is masked.
 #[serde_as]
-#[derive(Serialize, Debug)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MoveRow<'a> {
+    #[serde_as(as = "DisplayFromStr")]
+    uci: &'a Uci,
+    white: Option<u64>,
+    draws: Option<u64>,
+    black: Option<u64>,
+    average_rating: Option<u64>,
+    games: Option<u64>,
+}
+
+impl MoveRow<'_> {
+    const HEADER: [&'static str; 6] = ["uci", "white", "draws", "black", "averageRating", "games"];
+}
+
+/// An explorer response in the format requested by the client.
+pub enum ExplorerBody {
+    Json(PreparedJson<ExplorerResponse>),
+    NdJson(Vec<ExplorerLine>),
+    Table(ResponseFormat, Vec<u8>),
+}
+
+impl ExplorerBody {
+    pub fn new(res: ExplorerResponse, format: ResponseFormat) -> ExplorerBody {
+        match format {
+            ResponseFormat::Json => {
+                let heavy = res.is_heavy();
+                ExplorerBody::Json(PreparedJson::new(res, heavy))
+            }
+            ResponseFormat::NdJson => ExplorerBody::NdJson(res.into_lines()),
+            ResponseFormat::Csv => {
+                ExplorerBody::Table(format, res.write_table(b',').expect("write csv"))
+            }
+            ResponseFormat::Tsv => {
+                ExplorerBody::Table(format, res.write_table(b'\t').expect("write tsv"))
+            }
+        }
+    }
+}
+
+impl IntoResponse for ExplorerBody {
+    fn into_response(self) -> Response {
+        match self {
+            ExplorerBody::Json(json) => json.into_response(),
+            ExplorerBody::NdJson(lines) => NdJson(stream::iter(lines)).into_response(),
+            ExplorerBody::Table(format, table) => {
+                let content_type = match format {
+                    ResponseFormat::Tsv => "text/tab-separated-values; charset=utf-8",
+                    _ => "text/csv; charset=utf-8",
+                };
+                Response::builder()
+                    .header(axum::http::header::CONTENT_TYPE, content_type)
+                    .body(body::boxed(body::Full::from(table)))
+                    .unwrap()
+            }
+        }
+    }
+}
+
+#[derive(Serialize, ApiSchema, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ExplorerLine {
+    Summary(ExplorerSummary),
+    Move(ExplorerMove),
+    RecentGame(ExplorerGameWithUci),
+    TopGame(ExplorerGameWithUci),
+}
+
+#[derive(Serialize, ApiSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ExplorerSummary {
+    #[serde(flatten)]
+    pub total: Stats,
+    pub distinct_moves: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branching_percentile: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg_remaining_plies: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub special_moves: Option<SpecialMoves>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_moves_page: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_moves_offset: Option<usize>,
+    pub opening: Option<&'static Opening>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub book_exit: Option<BookExit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history: Option<Vec<TrendMonth>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<CanonicalFilter>,
+}
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ExplorerMove {
     #[serde_as(as = "DisplayFromStr")]
@@ -34,13 +288,197 @@ pub struct ExplorerMove {
     pub average_rating: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub average_opponent_rating: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg_seconds: Option<u64>,
+    /// Omitted if the response is masked.
     #[serde(flatten)]
-    pub stats: Stats,
+    pub stats: Option<Stats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeated: Option<Stats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upset_rate: Option<f64>,
+    /// Average number of plies played after the move.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg_remaining_plies: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<Annotations>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub opponent_ratings: Option<Vec<OpponentRatingStats>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speeds: Option<Vec<SpeedStats>>,
+    /// Performance rating of the side to move against the average opponent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub performance: Option<i64>,
+    /// Actual minus expected score of the side to move, given the ratings
+    /// of both players.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub performance_delta: Option<f64>,
+    #[serde(flatten)]
+    pub percentages: Option<Percentages>,
     pub game: Option<ExplorerGame>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub played: Option<bool>,
+}
+
+/// Results in percent, rounded to one decimal.
+#[derive(Serialize, ApiSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Percentages {
+    pub white_percent: f64,
+    pub draw_percent: f64,
+    pub black_percent: f64,
+}
+
+impl Percentages {
+    pub fn from_stats(stats: &Stats) -> Option<Percentages> {
+        let total = stats.total();
+        let percent = |games: u64| (games as f64 * 1000.0 / total as f64).round() / 10.0;
+        (total > 0).then(|| Percentages {
+            white_percent: percent(stats.white),
+            draw_percent: percent(stats.draws),
+            black_percent: percent(stats.black),
+        })
+    }
 }
 
+/// Number of games in which each kind of special move was chosen. Kinds
+/// that are not legal in the position are left out.
+#[derive(Serialize, ApiSchema, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SpecialMoves {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub castle_short: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub castle_long: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub en_passant: Option<u64>,
+    /// By the piece promoted to.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub promotions: BTreeMap<char, u64>,
+}
+
+impl SpecialMoves {
+    /// Counts games of the move, if it is a special move. Count 0 games
+    /// to mark the kind of move as legal.
+    pub fn add(&mut self, m: &Move, games: u64) {
+        let counter = match *m {
+            Move::Castle { king, rook } if king.file() < rook.file() => {
+                self.castle_short.get_or_insert(0)
+            }
+            Move::Castle { .. } => self.castle_long.get_or_insert(0),
+            Move::EnPassant { .. } => self.en_passant.get_or_insert(0),
+            Move::Normal {
+                promotion: Some(role),
+                ..
+            } => self.promotions.entry(role.char()).or_insert(0),
+            _ => return,
+        };
+        *counter += games;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.castle_short.is_none()
+            && self.castle_long.is_none()
+            && self.en_passant.is_none()
+            && self.promotions.is_empty()
+    }
+}
+
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct OpponentRatingStats {
+    pub rating: u16,
+    #[serde(flatten)]
+    pub stats: Stats,
+}
+
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct SpeedStats {
+    pub speed: Speed,
+    #[serde(flatten)]
+    pub stats: Stats,
+}
+
+/// Estimated number of games that a sampling configuration would accept in
+/// a month, based on the games from the initial position that are stored.
 #[serde_as]
 #[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SamplingReport {
+    #[serde_as(as = "DisplayFromStr")]
+    pub month: Month,
+    pub stored: u64,
+    pub estimated: u64,
+    pub buckets: Vec<SamplingBucket>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SamplingBucket {
+    pub speed: Speed,
+    pub rating: u16,
+    pub stored: u64,
+    /// Unknown if no games of the bucket were imported.
+    pub estimated: Option<u64>,
+}
+
+/// Prepared lichess responses from the production and the shadow format,
+/// for the same query.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ShadowComparison {
+    /// Whether shadow writes are currently enabled. Otherwise differences
+    /// are expected.
+    pub shadow_writes: bool,
+    pub identical: bool,
+    pub production: Stats,
+    pub production_moves: usize,
+    pub shadow: Stats,
+    pub shadow_moves: usize,
+}
+
+/// Entries at random keys, to look into distributions without a full scan.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SampleReport {
+    pub avg_size: usize,
+    pub entries: Vec<SampledEntry>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SampledEntry {
+    /// Hex encoded.
+    pub key: String,
+    /// Month or year of the entry.
+    pub period: Option<String>,
+    /// Serialized size in bytes.
+    pub size: usize,
+    pub moves: usize,
+    pub games: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<SampledGroup>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SampledGroup {
+    pub speed: Speed,
+    pub rating: u16,
+    pub games: u64,
+}
+
+/// Page of the games that are listed for a position.
+#[derive(Serialize, ApiSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GamesPage {
+    pub games: Vec<ExplorerGameWithUci>,
+    /// Set if there are more games on the next page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_page: Option<usize>,
+}
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
 pub struct ExplorerGameWithUci {
     #[serde_as(as = "DisplayFromStr")]
     pub uci: Uci,
@@ -49,7 +487,7 @@ pub struct ExplorerGameWithUci {
 }
 
 #[serde_as]
-#[derive(Serialize, Debug)]
+#[derive(Serialize, ApiSchema, Debug)]
 pub struct ExplorerGame {
     #[serde_as(as = "DisplayFromStr")]
     pub id: GameId,
@@ -59,6 +497,9 @@ pub struct ExplorerGame {
     pub speed: Option<Speed>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mode: Option<Mode>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock: Option<Clock>,
     #[serde(flatten, with = "ByColorDef")]
     pub players: ByColor<GamePlayer>,
     #[serde_as(as = "TryFromInto<u16>")]
@@ -74,6 +515,7 @@ impl ExplorerGame {
             winner: info.outcome.winner(),
             speed: Some(info.speed),
             mode: Some(info.mode),
+            clock: info.clock,
             players: info.players,
             year: info.month.year(),
             month: Some(info.month),
@@ -86,9 +528,415 @@ impl ExplorerGame {
             winner: info.winner,
             speed: None,
             mode: None,
+            clock: None,
             players: info.players,
             year: info.date.year(),
             month: info.date.month(),
         }
     }
 }
+
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct PrepResponse {
+    #[serde(flatten)]
+    pub total: Stats,
+    pub moves: Vec<PrepMove>,
+    pub opening: Option<&'static Opening>,
+}
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct PrepMove {
+    #[serde_as(as = "DisplayFromStr")]
+    pub uci: Uci,
+    #[serde_as(as = "DisplayFromStr")]
+    pub san: SanPlus,
+    #[serde(flatten)]
+    pub stats: Stats,
+    /// Percentage of games in the parent position that continued with this
+    /// move.
+    pub probability: u8,
+    /// Percentage score of the player after this move.
+    pub score: u8,
+    /// Whether this is a frequent choice of the player that scores poorly.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub weak: bool,
+    pub moves: Vec<PrepMove>,
+}
+
+/// Where the games of a player leave the lichess mainline, i.e. the most
+/// popular move of the lichess database, starting from the requested
+/// position.
+#[derive(Serialize, ApiSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BookExit {
+    /// Average number of plies played on the mainline before leaving it.
+    pub average_ply: Option<f64>,
+    pub exits: Vec<BookExitPly>,
+    /// Games that were still on the mainline at the maximum depth.
+    pub in_book: Stats,
+}
+
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct BookExitPly {
+    /// Number of plies played on the mainline before leaving it.
+    pub ply: u32,
+    #[serde(flatten)]
+    pub stats: Stats,
+    /// Percentage score of the player in games that left the mainline here.
+    pub score: u8,
+}
+
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct DepthProfile {
+    pub plies: Vec<DepthProfilePly>,
+}
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct DepthProfilePly {
+    pub ply: u32,
+    /// Move that led to the position, unless it is the root position.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uci: Option<Uci>,
+    #[serde(flatten)]
+    pub stats: Stats,
+    /// Percentage score of white, if there are games.
+    pub score: Option<u8>,
+}
+
+/// The single move selected by `/lichess/best`.
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct BestMove {
+    #[serde_as(as = "DisplayFromStr")]
+    pub uci: Uci,
+    #[serde_as(as = "DisplayFromStr")]
+    pub san: SanPlus,
+    #[serde(flatten)]
+    pub stats: Stats,
+}
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct TrendResponse {
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uci: Option<Uci>,
+    pub history: Vec<TrendMonth>,
+    /// Most played moves first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moves: Option<Vec<TrendMove>>,
+}
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct TrendMove {
+    #[serde_as(as = "DisplayFromStr")]
+    pub uci: Uci,
+    #[serde_as(as = "DisplayFromStr")]
+    pub san: SanPlus,
+    pub history: Vec<TrendMonth>,
+}
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct TrendMonth {
+    #[serde_as(as = "DisplayFromStr")]
+    pub month: Month,
+    #[serde(flatten)]
+    pub stats: Stats,
+}
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VariantMeta {
+    pub variant: &'static str,
+    pub games: u64,
+    pub first_moves: Vec<VariantFirstMove>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub since: Option<Month>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub until: Option<Month>,
+}
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct VariantFirstMove {
+    #[serde_as(as = "DisplayFromStr")]
+    pub uci: Uci,
+    pub games: u64,
+}
+
+/// Position that leads into the requested position, and the move that was
+/// played from there.
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct Parent {
+    #[serde_as(as = "DisplayFromStr")]
+    pub fen: Epd,
+    #[serde_as(as = "DisplayFromStr")]
+    pub uci: Uci,
+    #[serde_as(as = "DisplayFromStr")]
+    pub san: SanPlus,
+    pub games: u64,
+    pub opening: Option<&'static Opening>,
+}
+
+/// Sequence of moves from the initial position that reached the queried
+/// position.
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct MoveOrder {
+    #[serde_as(as = "StringWithSeparator<SpaceSeparator, Uci>")]
+    pub uci: Vec<Uci>,
+    /// With move numbers, like in PGN.
+    pub san: String,
+    pub games: u64,
+}
+
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct Contributor {
+    pub id: String,
+    #[serde(flatten)]
+    pub games: ContributorEntry,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+pub struct PendingErratum {
+    #[serde_as(as = "DisplayFromStr")]
+    pub id: GameId,
+    #[serde(flatten)]
+    pub erratum: Erratum,
+}
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug, Default)]
+pub struct PgnImportReport {
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub imported: Vec<GameId>,
+    pub rejected: Vec<PgnRejection>,
+}
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct PgnRejection {
+    /// Position of the game in the submitted file, starting at 0.
+    pub index: usize,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<GameId>,
+    pub error: String,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug, Clone)]
+pub struct NearDuplicate {
+    #[serde_as(as = "DisplayFromStr")]
+    pub id: GameId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub candidate: GameId,
+    pub similarity: f64,
+}
+
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct MetaResponse {
+    pub db: DbTuning,
+    #[serde(rename = "compactionLag")]
+    pub compaction_lag: Vec<CompactionLag>,
+    #[serde(rename = "speedGroups")]
+    pub speed_groups: Vec<SpeedGroupMeta>,
+    #[serde(rename = "ratingDistribution")]
+    pub rating_distribution: Vec<RatingDistributionMeta>,
+}
+
+/// Average ratings of the lichess games of a speed that were imported since
+/// startup.
+#[derive(Serialize, ApiSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RatingDistributionMeta {
+    pub speed: Speed,
+    pub games: u64,
+    pub bucket_width: u16,
+    /// Games by average rating, starting at 0. The last bucket also
+    /// includes all higher ratings.
+    pub buckets: Vec<u64>,
+    pub rating_groups: Vec<RatingGroupMeta>,
+}
+
+#[derive(Serialize, ApiSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RatingGroupMeta {
+    pub lower_bound: u16,
+    pub games: u64,
+}
+
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct SpeedGroupMeta {
+    pub name: SpeedGroup,
+    pub speeds: &'static [Speed],
+}
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountResponse {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub monthly_quota: Option<u64>,
+    /// Requests left in the current month.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<u64>,
+    /// Private explorer that the key gives access to.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<TenantId>,
+    pub usage: Vec<UsageMonth>,
+}
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct UsageMonth {
+    #[serde_as(as = "DisplayFromStr")]
+    pub month: Month,
+    pub requests: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyListing {
+    /// Prefix of the digest of the token.
+    pub id: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub monthly_quota: Option<u64>,
+    /// Requests in the current month.
+    pub requests: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn explorer_move(uci: &str, breakdowns: usize) -> ExplorerMove {
+        let stats = Stats {
+            white: 123_456,
+            draws: 98_765,
+            black: 87_654,
+            ..Stats::default()
+        };
+        ExplorerMove {
+            uci: uci.parse().unwrap(),
+            san: "e4".parse().unwrap(),
+            average_rating: Some(2012),
+            average_opponent_rating: Some(2008),
+            avg_seconds: Some(4),
+            percentages: Percentages::from_stats(&stats),
+            stats: Some(stats.clone()),
+            repeated: None,
+            upset_rate: Some(0.25),
+            avg_remaining_plies: Some(72),
+            annotations: None,
+            opponent_ratings: Some(
+                (0..breakdowns)
+                    .map(|i| OpponentRatingStats {
+                        rating: 1000 + 200 * i as u16,
+                        stats: stats.clone(),
+                    })
+                    .collect(),
+            ),
+            speeds: None,
+            performance: Some(2034),
+            performance_delta: Some(0.012),
+            game: None,
+            played: None,
+        }
+    }
+
+    fn explorer_game() -> ExplorerGameWithUci {
+        let player = |name: &str| GamePlayer {
+            name: name.to_owned(),
+            rating: 2345,
+            provisional: false,
+            estimated_rating: None,
+            estimated: false,
+            bot: false,
+        };
+        ExplorerGameWithUci {
+            uci: "e2e4".parse().unwrap(),
+            row: ExplorerGame {
+                id: "Zomg6a1B".parse().unwrap(),
+                winner: Some(Color::White),
+                speed: Some(Speed::Blitz),
+                mode: Some(Mode::Rated),
+                clock: None,
+                players: ByColor {
+                    white: player("WhitePlayer"),
+                    black: player("BlackPlayer"),
+                },
+                year: Year::try_from(2022).unwrap(),
+                month: Some("2022-03".parse().unwrap()),
+            },
+        }
+    }
+
+    fn explorer_response(moves: usize, breakdowns: usize, games: usize) -> ExplorerResponse {
+        ExplorerResponse {
+            total: Stats::default(),
+            distinct_moves: moves,
+            branching_percentile: Some(50),
+            avg_remaining_plies: Some(80),
+            special_moves: None,
+            moves: (0..moves)
+                .map(|_| explorer_move("e2e4", breakdowns))
+                .collect(),
+            next_moves_page: None,
+            next_moves_offset: None,
+            recent_games: Some((0..games).map(|_| explorer_game()).collect()),
+            top_games: Some((0..games).map(|_| explorer_game()).collect()),
+            opening: None,
+            book_exit: None,
+            history: None,
+            filter: None,
+        }
+    }
+
+    #[test]
+    fn test_estimated_json_size() {
+        for res in [
+            explorer_response(2, 0, 0),
+            explorer_response(12, 0, 4),
+            explorer_response(12, 6, 4),
+        ] {
+            let estimate = res.estimated_json_size();
+            let actual = serde_json::to_vec(&res).unwrap().len();
+            assert!(
+                actual / 2 <= estimate && estimate <= actual * 2,
+                "estimate {} vs. actual {}",
+                estimate,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_heavy() {
+        let light = explorer_response(2, 0, 0);
+        assert!(!light.is_heavy());
+        assert!(matches!(
+            ExplorerBody::new(light, ResponseFormat::Json),
+            ExplorerBody::Json(PreparedJson::Deferred(_))
+        ));
+
+        // Default number of moves and games.
+        let heavy = explorer_response(12, 0, 4);
+        assert!(heavy.is_heavy());
+        assert!(matches!(
+            ExplorerBody::new(heavy, ResponseFormat::Json),
+            ExplorerBody::Json(PreparedJson::Serialized(_))
+        ));
+    }
+}
@@ -1,7 +1,9 @@
 use serde::Deserialize;
 use shakmaty::variant::Variant;
 
-#[derive(Debug, Deserialize, Copy, Clone)]
+use crate::api::ApiSchema;
+
+#[derive(Debug, Deserialize, ApiSchema, Copy, Clone)]
 pub enum LilaVariant {
     #[serde(alias = "antichess")]
     Antichess,
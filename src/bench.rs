@@ -0,0 +1,253 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Cursor},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use shakmaty::{uci::Uci, Color, Outcome, Square};
+
+use crate::{
+    api::{LichessQueryFilter, Limits},
+    db::merge_lichess_values,
+    model::{GameId, LichessEntry, MoveDetails, Speed},
+};
+
+/// Minimum time spent on each measurement, to smooth out noise.
+const MIN_DURATION: Duration = Duration::from_millis(500);
+
+/// Relative slowdown against the baseline that is reported as a regression.
+const REGRESSION_THRESHOLD: f64 = 0.1;
+
+/// Synthetic workloads, modeled after the shapes of entries that dominate
+/// production load.
+const WORKLOADS: [Workload; 3] = [
+    // Popular opening position: few moves with very many games.
+    Workload {
+        name: "hot",
+        games: 20_000,
+        moves: 12,
+    },
+    // Position with a wide list of moves, for example in a middlegame.
+    Workload {
+        name: "wide",
+        games: 5_000,
+        moves: 200,
+    },
+    // Typical position deep in the tree.
+    Workload {
+        name: "sparse",
+        games: 50,
+        moves: 8,
+    },
+];
+
+/// Number of single game operands merged at once, like a merge of a busy
+/// key during compaction.
+const MERGE_OPERANDS: usize = 100;
+
+struct Workload {
+    name: &'static str,
+    games: usize,
+    moves: u32,
+}
+
+/// Small deterministic generator, so that workloads are identical across
+/// runs and comparable with the baseline.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// Skewed towards small values, like the popularity of moves.
+    fn skewed_below(&mut self, n: u64) -> u64 {
+        let a = self.below(n);
+        let b = self.below(n);
+        a * b / n
+    }
+}
+
+fn single_game(rng: &mut XorShift, workload: &Workload) -> Vec<u8> {
+    let m = rng.skewed_below(u64::from(workload.moves)) as u32;
+    let uci = Uci::Normal {
+        from: Square::new(m % 64),
+        to: Square::new((m / 64 + m * 7 + 1) % 64),
+        promotion: None,
+    };
+    let speed = match rng.below(100) {
+        0..=29 => Speed::Bullet,
+        30..=69 => Speed::Blitz,
+        70..=89 => Speed::Rapid,
+        90..=95 => Speed::Classical,
+        96..=98 => Speed::UltraBullet,
+        _ => Speed::Correspondence,
+    };
+    let mut rating = || (800 + rng.below(600) + rng.below(600) + rng.below(600)) as u16;
+    let (mover_rating, opponent_rating) = (rating(), rating());
+    let outcome = match rng.below(20) {
+        0..=8 => Outcome::Decisive {
+            winner: Color::White,
+        },
+        9..=17 => Outcome::Decisive {
+            winner: Color::Black,
+        },
+        _ => Outcome::Draw,
+    };
+    let id = (rng.next() % 62u64.pow(8)).to_le_bytes();
+    let game_id = GameId::read(&mut Cursor::new(&id[..GameId::LICHESS_SIZE])).expect("game id");
+    let entry = LichessEntry::new_single(
+        uci,
+        speed,
+        game_id,
+        outcome,
+        mover_rating,
+        opponent_rating,
+        MoveDetails {
+            seconds: Some(rng.below(30) as u32),
+            repeats: rng.below(10) == 0,
+            upset: Some(rng.below(3) == 0),
+            remaining_plies: Some(rng.below(80) as u32),
+            bot: rng.below(20) == 0,
+            rated: true,
+            clock: None,
+        },
+    );
+    let mut buf = Vec::with_capacity(LichessEntry::SIZE_HINT);
+    entry.write(&mut buf).expect("write single entry");
+    buf
+}
+
+/// Measures how many times per second `f` can run.
+fn throughput(mut f: impl FnMut() -> Duration) -> f64 {
+    let mut iterations = 0u64;
+    let mut elapsed = Duration::ZERO;
+    while elapsed < MIN_DURATION {
+        elapsed += f();
+        iterations += 1;
+    }
+    iterations as f64 / elapsed.as_secs_f64()
+}
+
+fn timed(f: impl FnOnce()) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+fn run_workload(workload: &Workload, results: &mut BTreeMap<String, f64>) {
+    let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+    let singles: Vec<Vec<u8>> = (0..workload.games)
+        .map(|_| single_game(&mut rng, workload))
+        .collect();
+    let value = merge_lichess_values(singles.iter().map(Vec::as_slice));
+
+    let read = || {
+        let mut entry = LichessEntry::default();
+        entry
+            .extend_from_reader(&mut Cursor::new(&value[..]))
+            .expect("read entry");
+        entry
+    };
+
+    results.insert(
+        format!("{}/read", workload.name),
+        throughput(|| timed(|| drop(read()))),
+    );
+
+    let filter = LichessQueryFilter::default();
+    let limits = Limits::default();
+    results.insert(
+        format!("{}/prepare", workload.name),
+        throughput(|| {
+            let entry = read();
+            timed(|| drop(entry.prepare(&filter, &limits)))
+        }),
+    );
+
+    let entry = read();
+    results.insert(
+        format!("{}/serialize", workload.name),
+        throughput(|| {
+            timed(|| {
+                let mut buf = Vec::with_capacity(value.len());
+                entry.write(&mut buf).expect("write entry");
+            })
+        }),
+    );
+
+    let mut operands = singles.iter().cycle();
+    results.insert(
+        format!("{}/merge", workload.name),
+        throughput(|| {
+            let batch: Vec<&[u8]> = operands
+                .by_ref()
+                .take(MERGE_OPERANDS)
+                .map(Vec::as_slice)
+                .collect();
+            timed(|| {
+                drop(merge_lichess_values(
+                    Some(&value[..]).into_iter().chain(batch.into_iter()),
+                ))
+            })
+        }),
+    );
+}
+
+/// Runs all workloads and prints throughput, compared to the baseline if
+/// one is given. With `save`, the results become the new baseline.
+pub fn run(baseline: Option<&Path>, save: bool) -> io::Result<()> {
+    let mut results = BTreeMap::new();
+    for workload in &WORKLOADS {
+        log::info!("running {} workload ...", workload.name);
+        run_workload(workload, &mut results);
+    }
+
+    let previous: Option<BTreeMap<String, f64>> = match baseline {
+        Some(path) if path.exists() => Some(serde_json::from_reader(File::open(path)?)?),
+        _ => None,
+    };
+
+    let mut regressions = 0;
+    for (name, per_sec) in &results {
+        match previous.as_ref().and_then(|previous| previous.get(name)) {
+            Some(before) => {
+                let change = per_sec / before - 1.0;
+                let regressed = change < -REGRESSION_THRESHOLD;
+                if regressed {
+                    regressions += 1;
+                }
+                println!(
+                    "{:<20} {:>12.1}/s {:>12.1}/s {:>+7.1}%{}",
+                    name,
+                    per_sec,
+                    before,
+                    change * 100.0,
+                    if regressed { "  REGRESSION" } else { "" }
+                );
+            }
+            None => println!("{:<20} {:>12.1}/s", name, per_sec),
+        }
+    }
+    if regressions > 0 {
+        log::warn!(
+            "{} measurements regressed against the baseline",
+            regressions
+        );
+    }
+
+    if let (Some(path), true) = (baseline, save) {
+        serde_json::to_writer_pretty(File::create(path)?, &results)?;
+        log::info!("saved baseline to {:?}", path);
+    }
+    Ok(())
+}
@@ -0,0 +1,211 @@
+use std::{
+    cmp::Reverse,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use rustc_hash::FxHashSet;
+use shakmaty::{
+    uci::Uci,
+    variant::{Variant, VariantPosition},
+    zobrist::Zobrist,
+    Position as _,
+};
+use tokio::{task, task::JoinHandle, time};
+
+use crate::{
+    api::{LichessQueryFilter, Limits},
+    db::Database,
+    model::{KeyBuilder, Month, Year},
+};
+
+const MAX_DEPTH: usize = 50; // plies
+const MAX_DISTINCT_MOVES: usize = 64;
+
+/// Number of the most played positions that are sampled at each depth.
+const POSITIONS_PER_DEPTH: usize = 500;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Distribution of the number of distinct continuations of stored positions,
+/// bucketed by depth. Used to rank a position against others at a similar
+/// depth. The distributions are sampled from the most played lines of each
+/// database and refreshed periodically, so serving a position does not
+/// change its rank.
+#[derive(Default)]
+pub struct BranchingFactors {
+    masters: RwLock<DepthHistogram>,
+    lichess: RwLock<DepthHistogram>,
+}
+
+impl BranchingFactors {
+    pub fn masters_percentile(&self, ply: u32, distinct_moves: usize) -> Option<u8> {
+        let guard = self.masters.read().expect("branching factors");
+        guard.percentile(ply, distinct_moves)
+    }
+
+    pub fn lichess_percentile(&self, ply: u32, distinct_moves: usize) -> Option<u8> {
+        let guard = self.lichess.read().expect("branching factors");
+        guard.percentile(ply, distinct_moves)
+    }
+
+    /// Samples both distributions from the standard chess positions of the
+    /// public databases.
+    pub fn refresh(&self, db: &Database) {
+        let masters_db = db.masters();
+        let masters = DepthHistogram::sample(|pos| {
+            let key = KeyBuilder::masters().with_zobrist(Variant::Chess, pos.zobrist_hash());
+            masters_db
+                .read(key, Year::default(), Year::max_value())
+                .expect("read masters")
+                .prepare()
+                .moves
+                .into_iter()
+                .map(|p| (p.uci, p.stats.total()))
+                .collect()
+        });
+        *self.masters.write().expect("branching factors") = masters;
+
+        let lichess_db = db.lichess();
+        let filter = LichessQueryFilter::default();
+        let lichess = DepthHistogram::sample(|pos| {
+            let key = KeyBuilder::lichess().with_zobrist(Variant::Chess, pos.zobrist_hash());
+            lichess_db
+                .read_lichess(&key, Month::default(), Month::max_value())
+                .expect("read lichess")
+                .prepare(&filter, &Limits::default())
+                .moves
+                .into_iter()
+                .map(|p| (p.uci, p.stats.total()))
+                .collect()
+        });
+        *self.lichess.write().expect("branching factors") = lichess;
+    }
+
+    pub fn spawn_refresh(&'static self, db: Arc<Database>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = time::interval(REFRESH_INTERVAL);
+            loop {
+                interval.tick().await;
+                let db = Arc::clone(&db);
+                task::spawn_blocking(move || self.refresh(&db))
+                    .await
+                    .expect("refresh branching factors");
+                log::info!("refreshed branching factors");
+            }
+        })
+    }
+}
+
+pub struct DepthHistogram {
+    by_depth: Vec<[u64; MAX_DISTINCT_MOVES + 1]>,
+}
+
+impl Default for DepthHistogram {
+    fn default() -> DepthHistogram {
+        DepthHistogram {
+            by_depth: vec![[0; MAX_DISTINCT_MOVES + 1]; MAX_DEPTH + 1],
+        }
+    }
+}
+
+impl DepthHistogram {
+    /// Walks the stored positions breadth-first from the initial position,
+    /// expanding the most played positions at each depth. `moves` reads the
+    /// moves of a position and the number of games they were played in.
+    fn sample<F>(mut moves: F) -> DepthHistogram
+    where
+        F: FnMut(&Zobrist<VariantPosition, u128>) -> Vec<(Uci, u64)>,
+    {
+        let mut histogram = DepthHistogram::default();
+        let mut frontier = vec![Zobrist::new(VariantPosition::new(Variant::Chess))];
+
+        for depth in 0..=MAX_DEPTH {
+            let mut children = Vec::new();
+            for pos in &frontier {
+                let moves = moves(pos);
+                if moves.is_empty() {
+                    continue;
+                }
+                histogram.record(depth as u32, moves.len());
+                for (uci, games) in moves {
+                    if let Ok(m) = uci.to_move(pos) {
+                        let mut child = pos.clone();
+                        child.play_unchecked(&m);
+                        children.push((games, child));
+                    }
+                }
+            }
+
+            children.sort_by_key(|(games, _)| Reverse(*games));
+            let mut seen: FxHashSet<u128> = FxHashSet::default();
+            frontier = children
+                .into_iter()
+                .map(|(_, child)| child)
+                .filter(|child| seen.insert(child.zobrist_hash()))
+                .take(POSITIONS_PER_DEPTH)
+                .collect();
+        }
+
+        histogram
+    }
+
+    fn record(&mut self, ply: u32, distinct_moves: usize) {
+        let depth = (ply as usize).min(MAX_DEPTH);
+        let bucket = distinct_moves.min(MAX_DISTINCT_MOVES);
+        self.by_depth[depth][bucket] += 1;
+    }
+
+    /// Percentage of sampled positions at the same depth that have at most
+    /// as many distinct moves, if any positions were sampled at that depth.
+    pub fn percentile(&self, ply: u32, distinct_moves: usize) -> Option<u8> {
+        let histogram = &self.by_depth[(ply as usize).min(MAX_DEPTH)];
+        let bucket = distinct_moves.min(MAX_DISTINCT_MOVES);
+
+        let total: u64 = histogram.iter().sum();
+        let at_most: u64 = histogram[..=bucket].iter().sum();
+        (total > 0).then(|| (at_most * 100 / total) as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let mut histogram = DepthHistogram::default();
+        assert_eq!(histogram.percentile(2, 10), None);
+        histogram.record(2, 10);
+        histogram.record(2, 20);
+        histogram.record(2, 5);
+        histogram.record(2, 10);
+        assert_eq!(histogram.percentile(2, 10), Some(75));
+        assert_eq!(histogram.percentile(2, 10), Some(75), "pure reads");
+        assert_eq!(histogram.percentile(2, 4), Some(0));
+        assert_eq!(histogram.percentile(2, 20), Some(100));
+        assert_eq!(histogram.percentile(3, 1), None, "independent depth");
+    }
+
+    #[test]
+    fn test_sample() {
+        // Stored lines: 1. e4 e5 and 1. d4 e5, with 1. e4 more popular.
+        let histogram = DepthHistogram::sample(|pos| {
+            let moves: &[(&str, u64)] = match pos.as_inner().fullmoves().get() * 2
+                - u32::from(pos.as_inner().turn().is_white())
+            {
+                1 => &[("e2e4", 10), ("d2d4", 5)],
+                2 => &[("e7e5", 10)],
+                _ => &[],
+            };
+            moves
+                .iter()
+                .map(|(uci, games)| (uci.parse().unwrap(), *games))
+                .collect()
+        });
+        assert_eq!(histogram.percentile(0, 2), Some(100));
+        assert_eq!(histogram.percentile(0, 1), Some(0));
+        assert_eq!(histogram.percentile(1, 1), Some(100));
+        assert_eq!(histogram.percentile(2, 0), None);
+    }
+}
@@ -1,18 +1,388 @@
-use std::{io::Cursor, path::Path};
+use std::{
+    cmp::max,
+    collections::{hash_map::RandomState, BTreeMap},
+    fs::File,
+    hash::{BuildHasher as _, Hash as _, Hasher as _},
+    io::{self, Cursor},
+    mem,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::SystemTime,
+};
 
+use byteorder::{ByteOrder as _, LittleEndian};
+use clap::{ArgEnum, Parser};
 use rocksdb::{
-    BlockBasedOptions, Cache, ColumnFamily, ColumnFamilyDescriptor, DBCompressionType,
-    MergeOperands, Options, ReadOptions, SliceTransform, WriteBatch, DB,
+    checkpoint::Checkpoint, BlockBasedOptions, Cache, ColumnFamily, ColumnFamilyDescriptor,
+    DBCompactionStyle, DBCompressionType, MergeOperands, Options, ReadOptions, SliceTransform,
+    WriteBatch, DB,
 };
+use rustc_hash::{FxHashSet, FxHasher};
+use serde::{Deserialize, Serialize};
+use shakmaty::{uci::Uci, Color};
 
-use crate::model::{
-    GameId, Key, KeyPrefix, LichessEntry, LichessGame, MastersEntry, MastersGame, Month,
-    PlayerEntry, PlayerStatus, UserId, Year,
+use crate::{
+    api::{ApiSchema, LichessQueryFilter, Limits},
+    deadline::Deadline,
+    hot::{HotChanges, HotOpt, HotPositions},
+    model::{
+        read_uint, ApiKey, ApiKeyId, ContributorEntry, Erratum, GameId, Key, KeyPrefix,
+        LichessEntry, LichessGame, LichessSource, MastersEntry, MastersGame, Month, MoveOrderEntry,
+        MoveOrderStep, ParentEntry, PathHash, PlayerEntry, PlayerStatus, PreparedResponse,
+        TenantId, TimeBucket, TrackedImport, UserId, UserName, Week, Year,
+    },
 };
 
+/// Bound for following the steps of a move order, well above the number of
+/// plies that are indexed.
+const MAX_MOVE_ORDER_STEPS: usize = 64;
+
+#[derive(Parser, Clone, Debug)]
+pub struct DbOpt {
+    /// Named set of RocksDB options. Individual options can be overridden,
+    /// here or in the config file. Defaults to serving.
+    #[clap(long = "db-preset", arg_enum)]
+    preset: Option<DbPreset>,
+    /// JSON file with RocksDB options, including overrides for individual
+    /// column families. Options given on the command line take precedence
+    /// over the global options of the file.
+    #[clap(long = "db-config", parse(try_from_str = DbConfig::load))]
+    config: Option<DbConfig>,
+    /// Size of the shared block cache in MiB.
+    #[clap(long = "db-cache-mib")]
+    cache_mib: Option<usize>,
+    /// Size of the write buffer per column family in MiB.
+    #[clap(long = "db-write-buffer-mib")]
+    write_buffer_mib: Option<usize>,
+    #[clap(long = "db-compaction-style", arg_enum)]
+    compaction_style: Option<CompactionStyle>,
+    /// Bits per key for filters.
+    #[clap(long = "db-bloom-bits")]
+    bloom_bits: Option<f64>,
+    #[clap(long = "db-background-jobs")]
+    background_jobs: Option<i32>,
+    /// Open the database read-only, for example to serve queries from a
+    /// snapshot of the data directory of another instance.
+    #[clap(long = "db-read-only")]
+    read_only: bool,
+    /// Directory for backups, which are created as RocksDB checkpoints on
+    /// request.
+    #[clap(long = "db-backup-dir")]
+    backup_dir: Option<PathBuf>,
+    /// Directory for monthly delta files, which are exported on request.
+    #[clap(long = "db-delta-dir")]
+    delta_dir: Option<PathBuf>,
+    /// Also write monthly lichess entries in the experimental shadow format,
+    /// so that it can be compared with the production format.
+    #[clap(long = "db-shadow-writes")]
+    shadow_writes: bool,
+    /// Drop all monthly lichess entries from this source when opening the
+    /// database, for example after a botched import. Can be repeated.
+    /// Games of the source can then be imported again. Weekly and shadow
+    /// entries are not kept per source, so they are dropped entirely.
+    #[clap(long = "db-drop-lichess-source")]
+    drop_lichess_sources: Vec<LichessSource>,
+    /// Delay imports while the estimated pending compaction bytes of any
+    /// column family exceed this many GiB.
+    #[clap(long = "db-import-max-pending-compaction-gib")]
+    import_max_pending_compaction_gib: Option<u64>,
+    /// Delay imports while any column family has more level 0 files.
+    #[clap(long = "db-import-max-l0-files")]
+    import_max_l0_files: Option<u64>,
+}
+
+#[derive(ArgEnum, Copy, Clone, Debug, Serialize, Deserialize, ApiSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum DbPreset {
+    BulkImport,
+    Serving,
+    LowMemory,
+}
+
+#[derive(ArgEnum, Copy, Clone, Debug, Serialize, Deserialize, ApiSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompactionStyle {
+    Level,
+    Universal,
+}
+
+impl From<CompactionStyle> for DBCompactionStyle {
+    fn from(style: CompactionStyle) -> DBCompactionStyle {
+        match style {
+            CompactionStyle::Level => DBCompactionStyle::Level,
+            CompactionStyle::Universal => DBCompactionStyle::Universal,
+        }
+    }
+}
+
+/// Contents of the file given with `--db-config`, for example:
+///
+/// ```json
+/// {
+///   "preset": "serving",
+///   "cacheMib": 8192,
+///   "columnFamilies": {
+///     "lichess": { "writeBufferMib": 128, "bloomBits": 10 },
+///     "lichess_game": { "compactionStyle": "universal" }
+///   }
+/// }
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DbConfig {
+    preset: Option<DbPreset>,
+    cache_mib: Option<usize>,
+    background_jobs: Option<i32>,
+    write_buffer_mib: Option<usize>,
+    compaction_style: Option<CompactionStyle>,
+    bloom_bits: Option<f64>,
+    #[serde(default)]
+    column_families: BTreeMap<String, CfConfig>,
+}
+
+/// Options that can be set for each column family.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct CfConfig {
+    write_buffer_mib: Option<usize>,
+    compaction_style: Option<CompactionStyle>,
+    bloom_bits: Option<f64>,
+}
+
+impl DbConfig {
+    fn load(path: &str) -> io::Result<DbConfig> {
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+}
+
+/// Effective RocksDB options, after applying overrides to the preset.
+#[derive(Clone, Debug, Serialize, ApiSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DbTuning {
+    pub preset: DbPreset,
+    pub cache_mib: usize,
+    pub background_jobs: i32,
+    /// Options of column families that are not overridden.
+    #[serde(flatten)]
+    pub defaults: CfTuning,
+    /// Options of all column families, filled in when the database is
+    /// opened.
+    pub column_families: BTreeMap<String, CfTuning>,
+}
+
+/// Effective options of a column family.
+#[derive(Copy, Clone, Debug, Serialize, ApiSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CfTuning {
+    pub write_buffer_mib: usize,
+    pub compaction_style: CompactionStyle,
+    pub bloom_bits: f64,
+}
+
+impl DbPreset {
+    fn tuning(self) -> DbTuning {
+        match self {
+            // Target memory usage is 16 GiB. Leave the majority for operating
+            // system page cache.
+            DbPreset::Serving => DbTuning {
+                preset: self,
+                cache_mib: 4 * 1024,
+                background_jobs: 4,
+                defaults: CfTuning {
+                    write_buffer_mib: 64,
+                    compaction_style: CompactionStyle::Level,
+                    bloom_bits: 8.0,
+                },
+                column_families: BTreeMap::new(),
+            },
+            // Favor write throughput. Reads are rare during imports.
+            DbPreset::BulkImport => DbTuning {
+                preset: self,
+                cache_mib: 1024,
+                background_jobs: 8,
+                defaults: CfTuning {
+                    write_buffer_mib: 256,
+                    compaction_style: CompactionStyle::Level,
+                    bloom_bits: 8.0,
+                },
+                column_families: BTreeMap::new(),
+            },
+            DbPreset::LowMemory => DbTuning {
+                preset: self,
+                cache_mib: 256,
+                background_jobs: 2,
+                defaults: CfTuning {
+                    write_buffer_mib: 16,
+                    compaction_style: CompactionStyle::Level,
+                    bloom_bits: 6.0,
+                },
+                column_families: BTreeMap::new(),
+            },
+        }
+    }
+}
+
+impl DbOpt {
+    pub fn backup_dir(&self) -> Option<&Path> {
+        self.backup_dir.as_deref()
+    }
+
+    /// Options for opening a checkpoint next to the main database: read-only,
+    /// with a small cache, and without any maintenance on open.
+    pub fn snapshot(&self) -> DbOpt {
+        DbOpt {
+            preset: Some(DbPreset::LowMemory),
+            config: None,
+            cache_mib: None,
+            write_buffer_mib: None,
+            read_only: true,
+            backup_dir: None,
+            delta_dir: None,
+            shadow_writes: false,
+            drop_lichess_sources: Vec::new(),
+            import_max_pending_compaction_gib: None,
+            import_max_l0_files: None,
+            ..self.clone()
+        }
+    }
+
+    /// Effective options, with the column families still to be filled in
+    /// by [`DbTuning::column()`].
+    pub fn tuning(&self) -> DbTuning {
+        let file = self.config.clone().unwrap_or_default();
+        let preset = self.preset.or(file.preset).unwrap_or(DbPreset::Serving);
+        let defaults = preset.tuning();
+        DbTuning {
+            preset,
+            cache_mib: self
+                .cache_mib
+                .or(file.cache_mib)
+                .unwrap_or(defaults.cache_mib),
+            background_jobs: self
+                .background_jobs
+                .or(file.background_jobs)
+                .unwrap_or(defaults.background_jobs),
+            defaults: CfTuning {
+                write_buffer_mib: self
+                    .write_buffer_mib
+                    .or(file.write_buffer_mib)
+                    .unwrap_or(defaults.defaults.write_buffer_mib),
+                compaction_style: self
+                    .compaction_style
+                    .or(file.compaction_style)
+                    .unwrap_or(defaults.defaults.compaction_style),
+                bloom_bits: self
+                    .bloom_bits
+                    .or(file.bloom_bits)
+                    .unwrap_or(defaults.defaults.bloom_bits),
+            },
+            column_families: BTreeMap::new(),
+        }
+    }
+
+    fn cf_config(&self, name: &str) -> Option<&CfConfig> {
+        self.config.as_ref()?.column_families.get(name)
+    }
+}
+
+impl CfConfig {
+    fn apply(&self, tuning: CfTuning) -> CfTuning {
+        CfTuning {
+            write_buffer_mib: self.write_buffer_mib.unwrap_or(tuning.write_buffer_mib),
+            compaction_style: self.compaction_style.unwrap_or(tuning.compaction_style),
+            bloom_bits: self.bloom_bits.unwrap_or(tuning.bloom_bits),
+        }
+    }
+}
+
+impl DbTuning {
+    /// Applies the overrides of the column family, and records the result.
+    fn column(&mut self, opt: &DbOpt, name: &str) -> CfTuning {
+        let tuning = match opt.cf_config(name) {
+            Some(config) => config.apply(self.defaults),
+            None => self.defaults,
+        };
+        self.column_families.insert(name.to_owned(), tuning);
+        tuning
+    }
+}
+
+/// Background work of a column family that has not caught up with writes.
+/// RocksDB does not count unmerged operands, so level 0 files and pending
+/// compaction bytes stand in for them.
+#[derive(Serialize, ApiSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CompactionLag {
+    pub cf: String,
+    pub pending_compaction_bytes: u64,
+    pub l0_files: u64,
+    pub immutable_memtables: u64,
+}
+
 #[derive(Debug)]
 pub struct Database {
     pub inner: DB,
+    pub tuning: DbTuning,
+    cf_names: Vec<String>,
+    import_max_pending_compaction_bytes: Option<u64>,
+    import_max_l0_files: Option<u64>,
+    opened_read_only: bool,
+    writes_paused: AtomicBool,
+    masters_generations: Generations,
+    lichess_generations: Generations,
+    hot_positions: HotPositions,
+    backup_dir: Option<PathBuf>,
+    delta_dir: Option<PathBuf>,
+    pub shadow_writes: bool,
+}
+
+/// Sequence numbers of the latest writes to the entries of positions,
+/// spread over a fixed number of slots by key prefix. Positions that share a
+/// slot are invalidated together. Derived from persistent sequence numbers,
+/// so values are not reused after a restart.
+#[derive(Debug)]
+struct Generations {
+    /// Latest write that may affect any position.
+    all: AtomicU64,
+    by_prefix: Box<[AtomicU64]>,
+}
+
+impl Generations {
+    const SLOTS: usize = 1 << 16;
+
+    fn new(generation: u64) -> Generations {
+        Generations {
+            all: AtomicU64::new(generation),
+            by_prefix: (0..Generations::SLOTS)
+                .map(|_| AtomicU64::new(generation))
+                .collect(),
+        }
+    }
+
+    fn slot(&self, key: &KeyPrefix) -> &AtomicU64 {
+        let mut hasher = FxHasher::default();
+        key.hash(&mut hasher);
+        &self.by_prefix[hasher.finish() as usize % Generations::SLOTS]
+    }
+
+    fn get(&self, key: &KeyPrefix) -> u64 {
+        max(
+            self.all.load(Ordering::Relaxed),
+            self.slot(key).load(Ordering::Relaxed),
+        )
+    }
+
+    fn bump(&self, inner: &DB, keys: &[KeyPrefix]) {
+        let generation = inner.latest_sequence_number();
+        for key in keys {
+            self.slot(key).fetch_max(generation, Ordering::Relaxed);
+        }
+    }
+
+    fn bump_all(&self, inner: &DB) {
+        self.all
+            .fetch_max(inner.latest_sequence_number(), Ordering::Relaxed);
+    }
 }
 
 type MergeFn = fn(key: &[u8], existing: Option<&[u8]>, operands: &MergeOperands) -> Option<Vec<u8>>;
@@ -22,10 +392,15 @@ struct Column<'a> {
     prefix: Option<usize>,
     merge: Option<(&'a str, MergeFn)>,
     cache: &'a Cache,
+    tuning: CfTuning,
 }
 
 impl Column<'_> {
     fn descriptor(self) -> ColumnFamilyDescriptor {
+        ColumnFamilyDescriptor::new(self.name, self.options())
+    }
+
+    fn options(&self) -> Options {
         // Mostly using modern defaults from
         // https://github.com/facebook/rocksdb/wiki/Setup-Options-and-Basic-Tuning.
         let mut table_opts = BlockBasedOptions::default();
@@ -33,7 +408,7 @@ impl Column<'_> {
         table_opts.set_block_size(16 * 1024);
         table_opts.set_cache_index_and_filter_blocks(true);
         table_opts.set_pin_l0_filter_and_index_blocks_in_cache(true);
-        table_opts.set_hybrid_ribbon_filter(8.0, 1);
+        table_opts.set_hybrid_ribbon_filter(self.tuning.bloom_bits, 1);
         table_opts.set_whole_key_filtering(self.prefix.is_none()); // Only prefix seeks for positions
         table_opts.set_format_version(5);
 
@@ -43,6 +418,8 @@ impl Column<'_> {
         cf_opts.set_bottommost_compression_type(DBCompressionType::Zstd);
         cf_opts.set_level_compaction_dynamic_level_bytes(false); // Infinitely growing database
         cf_opts.set_optimize_filters_for_hits(true); // 90% filter size reduction
+        cf_opts.set_write_buffer_size(self.tuning.write_buffer_mib * 1024 * 1024);
+        cf_opts.set_compaction_style(self.tuning.compaction_style.into());
 
         cf_opts.set_prefix_extractor(match self.prefix {
             Some(prefix) => SliceTransform::create_fixed_prefix(prefix),
@@ -53,77 +430,311 @@ impl Column<'_> {
             cf_opts.set_merge_operator_associative(name, merge_fn);
         }
 
-        ColumnFamilyDescriptor::new(self.name, cf_opts)
+        cf_opts
+    }
+}
+
+/// Column family with the monthly lichess entries of the source. Dumps
+/// keep the original name, which predates the separation.
+pub fn lichess_cf_name(source: LichessSource) -> &'static str {
+    match source {
+        LichessSource::Dump => "lichess",
+        LichessSource::Live => "lichess_live",
+        LichessSource::Manual => "lichess_manual",
+    }
+}
+
+/// Marks the games of the given sources as not imported, and stops tracking
+/// them, so that they can be imported again once their entries are dropped.
+fn forget_lichess_sources(inner: &DB, sources: &[LichessSource]) -> Result<(), rocksdb::Error> {
+    const BATCH_SIZE: usize = 10_000;
+
+    let cf_game = inner.cf_handle("lichess_game").expect("cf lichess_game");
+    let mut batch = WriteBatch::default();
+    let mut forgotten = 0;
+    let mut iter = inner.raw_iterator_cf(cf_game);
+    iter.seek_to_first();
+    while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+        let mut info = LichessGame::read(&mut Cursor::new(value)).expect("deserialize game info");
+        if info.indexed_lichess && sources.contains(&info.source) {
+            // Put rather than merge, because merges keep the index status.
+            info.indexed_lichess = false;
+            let mut cursor = Cursor::new(Vec::with_capacity(LichessGame::SIZE_HINT));
+            info.write(&mut cursor).expect("write lichess game");
+            batch.put_cf(cf_game, key, cursor.into_inner());
+            forgotten += 1;
+        }
+        if batch.len() >= BATCH_SIZE {
+            inner.write(mem::take(&mut batch))?;
+        }
+        iter.next();
+    }
+    iter.status()?;
+
+    let cf_tracked = inner
+        .cf_handle("lichess_tracked")
+        .expect("cf lichess_tracked");
+    let mut iter = inner.raw_iterator_cf(cf_tracked);
+    iter.seek_to_first();
+    while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+        let tracked =
+            TrackedImport::read(&mut Cursor::new(value)).expect("deserialize tracked import");
+        if sources.contains(&tracked.source) {
+            batch.delete_cf(cf_tracked, key);
+        }
+        if batch.len() >= BATCH_SIZE {
+            inner.write(mem::take(&mut batch))?;
+        }
+        iter.next();
     }
+    iter.status()?;
+
+    inner.write(batch)?;
+    log::warn!(
+        "{} games of dropped lichess sources can be imported again",
+        forgotten
+    );
+    Ok(())
 }
 
 impl Database {
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Database, rocksdb::Error> {
+    pub fn open<P: AsRef<Path>>(path: P, opt: &DbOpt) -> Result<Database, rocksdb::Error> {
+        let mut tuning = opt.tuning();
+
         let mut db_opts = Options::default();
         db_opts.create_if_missing(true);
         db_opts.create_missing_column_families(true);
-        db_opts.set_max_background_jobs(4);
+        db_opts.set_max_background_jobs(tuning.background_jobs);
         db_opts.set_bytes_per_sync(1024 * 1024);
 
-        // Target memory usage is 16 GiB. Leave the majority for operating
-        // system page cache.
-        let cache = Cache::new_lru_cache(4 * 1024 * 1024 * 1024)?;
-
-        let inner = DB::open_cf_descriptors(
-            &db_opts,
-            path,
-            vec![
-                // Masters database
-                Column {
-                    name: "masters",
-                    prefix: Some(KeyPrefix::SIZE),
-                    merge: Some(("masters_merge", masters_merge)),
-                    cache: &cache,
-                }
-                .descriptor(),
-                Column {
-                    name: "masters_game",
-                    prefix: None,
-                    merge: None,
-                    cache: &cache,
-                }
-                .descriptor(),
-                // Lichess database
-                Column {
-                    name: "lichess",
-                    prefix: Some(KeyPrefix::SIZE),
-                    merge: Some(("lichess_merge", lichess_merge)),
-                    cache: &cache,
-                }
-                .descriptor(),
-                Column {
-                    name: "lichess_game",
-                    prefix: None,
-                    merge: Some(("lichess_game_merge", lichess_game_merge)),
-                    cache: &cache,
-                }
-                .descriptor(),
-                // Player database (also shares lichess_game)
-                Column {
-                    name: "player",
-                    prefix: Some(KeyPrefix::SIZE),
-                    merge: Some(("player_merge", player_merge)),
-                    cache: &cache,
+        let cache = Cache::new_lru_cache(tuning.cache_mib * 1024 * 1024)?;
+
+        let lichess_column = |source| Column {
+            name: lichess_cf_name(source),
+            prefix: Some(KeyPrefix::SIZE),
+            merge: Some(("lichess_merge", lichess_merge)),
+            cache: &cache,
+            tuning: tuning.defaults,
+        };
+
+        let mut columns = vec![
+            // Masters database
+            Column {
+                name: "masters",
+                prefix: Some(KeyPrefix::SIZE),
+                merge: Some(("masters_merge", masters_merge)),
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            Column {
+                name: "masters_game",
+                prefix: None,
+                merge: None,
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            Column {
+                name: "masters_errata",
+                prefix: None,
+                merge: None,
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            Column {
+                name: "masters_position_game",
+                prefix: Some(KeyPrefix::SIZE),
+                merge: None,
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            // Games of private explorers (entries share masters)
+            Column {
+                name: "tenant_game",
+                prefix: None,
+                merge: None,
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            // Lichess database (monthly entries by source added below)
+            Column {
+                name: "lichess_week",
+                prefix: Some(KeyPrefix::SIZE),
+                merge: Some(("lichess_merge", lichess_merge)),
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            Column {
+                name: "lichess_game",
+                prefix: None,
+                merge: Some(("lichess_game_merge", lichess_game_merge)),
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            Column {
+                name: "lichess_shadow",
+                prefix: Some(KeyPrefix::SIZE),
+                merge: Some(("lichess_shadow_merge", lichess_shadow_merge)),
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            Column {
+                name: "lichess_tracked",
+                prefix: None,
+                merge: None,
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            Column {
+                name: "lichess_parent",
+                prefix: Some(KeyPrefix::SIZE),
+                merge: Some(("lichess_parent_merge", lichess_parent_merge)),
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            Column {
+                name: "lichess_move_order",
+                prefix: Some(KeyPrefix::SIZE),
+                merge: Some(("lichess_move_order_merge", lichess_move_order_merge)),
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            Column {
+                name: "lichess_move_order_step",
+                prefix: None,
+                merge: None,
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            // Player database (also shares lichess_game)
+            Column {
+                name: "player",
+                prefix: Some(KeyPrefix::SIZE),
+                merge: Some(("player_merge", player_merge)),
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            Column {
+                name: "player_status",
+                prefix: None,
+                merge: None,
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            Column {
+                name: "player_contributor",
+                prefix: Some(KeyPrefix::SIZE),
+                merge: Some(("player_contributor_merge", player_contributor_merge)),
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            // Accounts
+            Column {
+                name: "api_key",
+                prefix: None,
+                merge: None,
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+            Column {
+                name: "api_usage",
+                prefix: Some(ApiKeyId::SIZE),
+                merge: Some(("api_usage_merge", api_usage_merge)),
+                cache: &cache,
+                tuning: tuning.defaults,
+            },
+        ];
+
+        columns.extend(LichessSource::ALL.into_iter().map(lichess_column));
+
+        for column in &mut columns {
+            column.tuning = tuning.column(opt, column.name);
+        }
+        if let Some(config) = &opt.config {
+            for name in config.column_families.keys() {
+                if !tuning.column_families.contains_key(name) {
+                    log::warn!("ignoring options for unknown column family {}", name);
                 }
-                .descriptor(),
-                Column {
-                    name: "player_status",
-                    prefix: None,
-                    merge: None,
-                    cache: &cache,
+            }
+        }
+        log::info!("opening database with {:?}", tuning);
+
+        // Column families that are recreated empty, along with dropped
+        // sources.
+        let mut reset: Vec<(String, Options)> = Vec::new();
+        if !opt.read_only && !opt.drop_lichess_sources.is_empty() {
+            for column in &columns {
+                if ["lichess_week", "lichess_shadow"].contains(&column.name)
+                    || opt
+                        .drop_lichess_sources
+                        .iter()
+                        .any(|source| lichess_cf_name(*source) == column.name)
+                {
+                    reset.push((column.name.to_owned(), column.options()));
                 }
-                .descriptor(),
-            ],
-        )?;
+            }
+        }
+
+        let cf_names: Vec<String> = columns
+            .iter()
+            .map(|column| column.name.to_owned())
+            .collect();
+        let column_families: Vec<ColumnFamilyDescriptor> =
+            columns.into_iter().map(Column::descriptor).collect();
+
+        let mut inner = if opt.read_only {
+            DB::open_cf_descriptors_read_only(&db_opts, path, column_families, false)?
+        } else {
+            DB::open_cf_descriptors(&db_opts, path, column_families)?
+        };
 
-        log::info!("database opened");
+        if !reset.is_empty() {
+            log::warn!("dropping lichess sources {:?}", opt.drop_lichess_sources);
+            forget_lichess_sources(&inner, &opt.drop_lichess_sources)?;
+            for (name, options) in reset {
+                log::warn!("recreating column family {}", name);
+                inner.drop_cf(&name)?;
+                inner.create_cf(&name, &options)?;
+            }
+        }
+
+        log::info!("database opened (read-only: {})", opt.read_only);
+
+        let generation = inner.latest_sequence_number();
+
+        Ok(Database {
+            inner,
+            tuning,
+            cf_names,
+            import_max_pending_compaction_bytes: opt
+                .import_max_pending_compaction_gib
+                .map(|gib| gib * 1024 * 1024 * 1024),
+            import_max_l0_files: opt.import_max_l0_files,
+            opened_read_only: opt.read_only,
+            writes_paused: AtomicBool::new(false),
+            masters_generations: Generations::new(generation),
+            lichess_generations: Generations::new(generation),
+            hot_positions: HotPositions::default(),
+            backup_dir: opt.backup_dir.clone(),
+            delta_dir: opt.delta_dir.clone(),
+            shadow_writes: opt.shadow_writes,
+        })
+    }
+
+    /// Whether the database was opened read-only. Writes can then not be
+    /// resumed at runtime.
+    pub fn opened_read_only(&self) -> bool {
+        self.opened_read_only
+    }
+
+    pub fn is_writable(&self) -> bool {
+        !self.opened_read_only && !self.writes_paused.load(Ordering::Relaxed)
+    }
 
-        Ok(Database { inner })
+    /// Rejects (or accepts again) all writes. Returns false if writes can
+    /// not be resumed, because the database was opened read-only.
+    pub fn pause_writes(&self, paused: bool) -> bool {
+        self.writes_paused.store(paused, Ordering::Relaxed);
+        paused || !self.opened_read_only
     }
 
     pub fn compact(&self) {
@@ -131,45 +742,183 @@ impl Database {
         self.masters().compact();
     }
 
+    pub fn compaction_lag(&self) -> Vec<CompactionLag> {
+        self.cf_names
+            .iter()
+            .filter_map(|name| {
+                let cf = self.inner.cf_handle(name)?;
+                let prop = |prop: &str| {
+                    self.inner
+                        .property_int_value_cf(cf, prop)
+                        .expect("property value")
+                        .unwrap_or(0)
+                };
+                Some(CompactionLag {
+                    cf: name.clone(),
+                    pending_compaction_bytes: prop("rocksdb.estimate-pending-compaction-bytes"),
+                    l0_files: prop("rocksdb.num-files-at-level0"),
+                    immutable_memtables: prop("rocksdb.num-immutable-mem-table"),
+                })
+            })
+            .collect()
+    }
+
+    /// Whether imports should be delayed until compactions catch up, to
+    /// keep read latency predictable.
+    pub fn is_lagging(&self) -> bool {
+        if self.import_max_pending_compaction_bytes.is_none() && self.import_max_l0_files.is_none()
+        {
+            return false;
+        }
+        self.compaction_lag().into_iter().any(|lag| {
+            self.import_max_pending_compaction_bytes
+                .map_or(false, |max| lag.pending_compaction_bytes > max)
+                || self
+                    .import_max_l0_files
+                    .map_or(false, |max| lag.l0_files > max)
+        })
+    }
+
+    /// Creates a consistent checkpoint in a new subdirectory of the backup
+    /// directory, hard linking files where possible. Returns `None` if there
+    /// is no backup directory.
+    pub fn backup(&self) -> Result<Option<PathBuf>, rocksdb::Error> {
+        let dir = match self.backup_dir {
+            Some(ref dir) => dir,
+            None => return Ok(None),
+        };
+        let path = dir.join(format!(
+            "checkpoint-{}",
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs())
+        ));
+        Checkpoint::new(&self.inner)?.create_checkpoint(&path)?;
+        log::info!("created backup at {}", path.display());
+        Ok(Some(path))
+    }
+
+    /// Visits the entries at or after `n` random keys of the named column
+    /// family.
+    pub fn sample_cf<F: FnMut(&[u8], &[u8])>(
+        &self,
+        name: &str,
+        n: usize,
+        f: F,
+    ) -> Result<(), rocksdb::Error> {
+        sample_column(&self.inner, self.inner.cf_handle(name).expect("cf"), n, f)
+    }
+
+    pub fn delta_dir(&self) -> Option<&Path> {
+        self.delta_dir.as_deref()
+    }
+
     pub fn masters(&self) -> MastersDatabase<'_> {
         MastersDatabase {
             inner: &self.inner,
+            generations: &self.masters_generations,
             cf_masters: self.inner.cf_handle("masters").expect("cf masters"),
             cf_masters_game: self
                 .inner
                 .cf_handle("masters_game")
                 .expect("cf masters_game"),
+            cf_masters_errata: self
+                .inner
+                .cf_handle("masters_errata")
+                .expect("cf masters_errata"),
+            cf_masters_position_game: self
+                .inner
+                .cf_handle("masters_position_game")
+                .expect("cf masters_position_game"),
+            cf_tenant_game: self.inner.cf_handle("tenant_game").expect("cf tenant_game"),
         }
     }
 
     pub fn lichess(&self) -> LichessDatabase<'_> {
         LichessDatabase {
             inner: &self.inner,
-            cf_lichess: self.inner.cf_handle("lichess").expect("cf lichess"),
+            generations: &self.lichess_generations,
+            hot_positions: &self.hot_positions,
+            cf_lichess: LichessSource::ALL.map(|source| {
+                self.inner
+                    .cf_handle(lichess_cf_name(source))
+                    .expect("cf lichess source")
+            }),
+            cf_lichess_week: self
+                .inner
+                .cf_handle("lichess_week")
+                .expect("cf lichess_week"),
             cf_lichess_game: self
                 .inner
                 .cf_handle("lichess_game")
                 .expect("cf lichess_game"),
+            cf_lichess_tracked: self
+                .inner
+                .cf_handle("lichess_tracked")
+                .expect("cf lichess_tracked"),
+            cf_lichess_shadow: self
+                .inner
+                .cf_handle("lichess_shadow")
+                .expect("cf lichess_shadow"),
+            cf_lichess_parent: self
+                .inner
+                .cf_handle("lichess_parent")
+                .expect("cf lichess_parent"),
+            cf_lichess_move_order: self
+                .inner
+                .cf_handle("lichess_move_order")
+                .expect("cf lichess_move_order"),
+            cf_lichess_move_order_step: self
+                .inner
+                .cf_handle("lichess_move_order_step")
+                .expect("cf lichess_move_order_step"),
+            shadow_writes: self.shadow_writes,
 
             cf_player: self.inner.cf_handle("player").expect("cf player"),
             cf_player_status: self
                 .inner
                 .cf_handle("player_status")
                 .expect("cf player_status"),
+            cf_player_contributor: self
+                .inner
+                .cf_handle("player_contributor")
+                .expect("cf player_contributor"),
+        }
+    }
+
+    pub fn accounts(&self) -> AccountsDatabase<'_> {
+        AccountsDatabase {
+            inner: &self.inner,
+            cf_api_key: self.inner.cf_handle("api_key").expect("cf api_key"),
+            cf_api_usage: self.inner.cf_handle("api_usage").expect("cf api_usage"),
         }
     }
 }
 
 pub struct MastersDatabase<'a> {
     inner: &'a DB,
+    generations: &'a Generations,
     cf_masters: &'a ColumnFamily,
     cf_masters_game: &'a ColumnFamily,
+    cf_masters_errata: &'a ColumnFamily,
+    /// Games by the positions they reached, regardless of the year.
+    cf_masters_position_game: &'a ColumnFamily,
+    cf_tenant_game: &'a ColumnFamily,
 }
 
 impl MastersDatabase<'_> {
+    /// Changes whenever masters data of the position is written, and
+    /// possibly when that of other positions is written.
+    pub fn generation(&self, key: &KeyPrefix) -> u64 {
+        self.generations.get(key)
+    }
+
     pub fn compact(&self) {
         compact_column(self.inner, self.cf_masters);
         compact_column(self.inner, self.cf_masters_game);
+        compact_column(self.inner, self.cf_masters_errata);
+        compact_column(self.inner, self.cf_masters_position_game);
+        compact_column(self.inner, self.cf_tenant_game);
     }
 
     pub fn has_game(&self, id: GameId) -> Result<bool, rocksdb::Error> {
@@ -204,6 +953,127 @@ impl MastersDatabase<'_> {
             .collect()
     }
 
+    pub fn has_tenant_game(&self, tenant: &TenantId, id: GameId) -> Result<bool, rocksdb::Error> {
+        self.inner
+            .get_pinned_cf(self.cf_tenant_game, tenant.game_key(id))
+            .map(|maybe_entry| maybe_entry.is_some())
+    }
+
+    pub fn tenant_game(
+        &self,
+        tenant: &TenantId,
+        id: GameId,
+    ) -> Result<Option<MastersGame>, rocksdb::Error> {
+        Ok(self
+            .inner
+            .get_pinned_cf(self.cf_tenant_game, tenant.game_key(id))?
+            .map(|buf| serde_json::from_slice(&buf).expect("deserialize tenant game")))
+    }
+
+    pub fn tenant_games<I: IntoIterator<Item = GameId>>(
+        &self,
+        tenant: &TenantId,
+        ids: I,
+    ) -> Result<Vec<Option<MastersGame>>, rocksdb::Error> {
+        self.inner
+            .multi_get_cf(
+                ids.into_iter()
+                    .map(|id| (self.cf_tenant_game, tenant.game_key(id))),
+            )
+            .into_iter()
+            .map(|maybe_buf_or_err| {
+                maybe_buf_or_err.map(|maybe_buf| {
+                    maybe_buf
+                        .map(|buf| serde_json::from_slice(&buf).expect("deserialize tenant game"))
+                })
+            })
+            .collect()
+    }
+
+    /// Number of games stored for the tenant. Scans all of them, so it
+    /// should be called at most once per import.
+    pub fn count_tenant_games(&self, tenant: &TenantId) -> Result<u64, rocksdb::Error> {
+        let digest = tenant.digest();
+        let mut iter = self.inner.raw_iterator_cf(self.cf_tenant_game);
+        iter.seek(digest);
+
+        let mut games = 0;
+        while let Some(key) = iter.key() {
+            if !key.starts_with(&digest) {
+                break;
+            }
+            games += 1;
+            iter.next();
+        }
+
+        iter.status().map(|_| games)
+    }
+
+    pub fn scan_games<F: FnMut(GameId, MastersGame)>(
+        &self,
+        mut f: F,
+    ) -> Result<(), rocksdb::Error> {
+        let mut iter = self.inner.raw_iterator_cf(self.cf_masters_game);
+        iter.seek_to_first();
+
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            let id = GameId::read(&mut Cursor::new(key)).expect("deserialize masters game id");
+            f(
+                id,
+                serde_json::from_slice(value).expect("deserialize masters game"),
+            );
+            iter.next();
+        }
+
+        iter.status()
+    }
+
+    /// Reads the ids of all games that reached the position. Games that
+    /// were imported before the index was introduced are missing, until
+    /// [`MastersBatch::put_position_game()`] was called for them.
+    pub fn position_games(&self, key: &KeyPrefix) -> Result<Vec<GameId>, rocksdb::Error> {
+        let mut games = Vec::new();
+
+        let mut opt = ReadOptions::default();
+        opt.set_prefix_same_as_start(true);
+        opt.set_iterate_lower_bound(key.as_bytes());
+
+        let mut iter = self
+            .inner
+            .raw_iterator_cf_opt(self.cf_masters_position_game, opt);
+        iter.seek_to_first();
+
+        while let Some(game) = iter.key() {
+            games.push(
+                GameId::read(&mut Cursor::new(&game[KeyPrefix::SIZE..]))
+                    .expect("deserialize position game id"),
+            );
+            iter.next();
+        }
+
+        iter.status().map(|_| games)
+    }
+
+    pub fn scan_errata<F: FnMut(GameId, Erratum)>(&self, mut f: F) -> Result<(), rocksdb::Error> {
+        let mut iter = self.inner.raw_iterator_cf(self.cf_masters_errata);
+        iter.seek_to_first();
+
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            let id = GameId::read(&mut Cursor::new(key)).expect("deserialize erratum game id");
+            f(
+                id,
+                serde_json::from_slice(value).expect("deserialize erratum"),
+            );
+            iter.next();
+        }
+
+        iter.status()
+    }
+
+    pub fn sample<F: FnMut(&[u8], &[u8])>(&self, n: usize, f: F) -> Result<(), rocksdb::Error> {
+        sample_column(self.inner, self.cf_masters, n, f)
+    }
+
     pub fn has(&self, key: Key) -> Result<bool, rocksdb::Error> {
         self.inner
             .get_pinned_cf(self.cf_masters, key.into_bytes())
@@ -215,6 +1085,18 @@ impl MastersDatabase<'_> {
         key: KeyPrefix,
         since: Year,
         until: Year,
+    ) -> Result<MastersEntry, rocksdb::Error> {
+        self.read_within(key, since, until, &Deadline::never())
+    }
+
+    /// Like [`MastersDatabase::read()`], but stops early once the deadline
+    /// is over. The entry is then incomplete and should be discarded.
+    pub fn read_within(
+        &self,
+        key: KeyPrefix,
+        since: Year,
+        until: Year,
+        deadline: &Deadline,
     ) -> Result<MastersEntry, rocksdb::Error> {
         let mut entry = MastersEntry::default();
 
@@ -227,6 +1109,9 @@ impl MastersDatabase<'_> {
         iter.seek_to_first();
 
         while let Some(value) = iter.value() {
+            if deadline.is_over() {
+                break;
+            }
             let mut cursor = Cursor::new(value);
             entry
                 .extend_from_reader(&mut cursor)
@@ -237,10 +1122,26 @@ impl MastersDatabase<'_> {
         iter.status().map(|_| entry)
     }
 
+    /// Reads the entry of a single year, without merging adjacent years.
+    pub fn get(&self, key: Key) -> Result<MastersEntry, rocksdb::Error> {
+        let mut entry = MastersEntry::default();
+        if let Some(value) = self
+            .inner
+            .get_pinned_cf(self.cf_masters, key.into_bytes())?
+        {
+            entry
+                .extend_from_reader(&mut Cursor::new(&value[..]))
+                .expect("deserialize masters entry");
+        }
+        Ok(entry)
+    }
+
     pub fn batch(&self) -> MastersBatch<'_> {
         MastersBatch {
             db: self,
             batch: WriteBatch::default(),
+            touched: Vec::new(),
+            touches_all: false,
         }
     }
 }
@@ -248,16 +1149,38 @@ impl MastersDatabase<'_> {
 pub struct MastersBatch<'a> {
     db: &'a MastersDatabase<'a>,
     batch: WriteBatch,
+    /// Positions with written entries.
+    touched: Vec<KeyPrefix>,
+    /// Whether errata were written, which apply to entries when reading.
+    touches_all: bool,
 }
 
 impl MastersBatch<'_> {
     pub fn merge(&mut self, key: Key, entry: MastersEntry) {
         let mut cursor = Cursor::new(Vec::with_capacity(MastersEntry::SIZE_HINT));
         entry.write(&mut cursor).expect("serialize masters entry");
+        self.touched.push(key.prefix());
         self.batch
             .merge_cf(self.db.cf_masters, key.into_bytes(), cursor.into_inner());
     }
 
+    pub fn put(&mut self, key: Key, entry: &MastersEntry) {
+        let mut cursor = Cursor::new(Vec::with_capacity(MastersEntry::SIZE_HINT));
+        entry.write(&mut cursor).expect("serialize masters entry");
+        self.touched.push(key.prefix());
+        self.batch
+            .put_cf(self.db.cf_masters, key.into_bytes(), cursor.into_inner());
+    }
+
+    pub fn delete_prefix(&mut self, key: &KeyPrefix) {
+        self.touched.push(key.clone());
+        self.batch.delete_range_cf(
+            self.db.cf_masters,
+            key.with_year(Year::default()).into_bytes(),
+            key.with_year(Year::max_value()).into_bytes(),
+        );
+    }
+
     pub fn put_game(&mut self, id: GameId, game: &MastersGame) {
         self.batch.put_cf(
             self.db.cf_masters_game,
@@ -266,26 +1189,137 @@ impl MastersBatch<'_> {
         );
     }
 
+    pub fn put_position_game(&mut self, key: &KeyPrefix, id: GameId) {
+        self.batch
+            .put_cf(self.db.cf_masters_position_game, key.with_game(id), []);
+    }
+
+    pub fn put_tenant_game(&mut self, tenant: &TenantId, id: GameId, game: &MastersGame) {
+        self.batch.put_cf(
+            self.db.cf_tenant_game,
+            tenant.game_key(id),
+            serde_json::to_vec(game).expect("serialize tenant game"),
+        );
+    }
+
+    pub fn put_erratum(&mut self, id: GameId, erratum: &Erratum) {
+        self.touches_all = true;
+        self.batch.put_cf(
+            self.db.cf_masters_errata,
+            id.to_bytes(),
+            serde_json::to_vec(erratum).expect("serialize erratum"),
+        );
+    }
+
+    pub fn delete_erratum(&mut self, id: GameId) {
+        self.touches_all = true;
+        self.batch
+            .delete_cf(self.db.cf_masters_errata, id.to_bytes());
+    }
+
     pub fn commit(self) -> Result<(), rocksdb::Error> {
-        self.db.inner.write(self.batch)
+        self.db.inner.write(self.batch)?;
+        if self.touches_all {
+            self.db.generations.bump_all(self.db.inner);
+        } else {
+            self.db.generations.bump(self.db.inner, &self.touched);
+        }
+        Ok(())
     }
 }
 
 pub struct LichessDatabase<'a> {
     inner: &'a DB,
-    cf_lichess: &'a ColumnFamily,
+    generations: &'a Generations,
+    hot_positions: &'a HotPositions,
+    /// Monthly entries, separately for each source in the order of
+    /// `LichessSource::ALL`. Merged when reading.
+    cf_lichess: [&'a ColumnFamily; 3],
+    /// Like lichess, but by week instead of month. Only maintained for
+    /// games imported live.
+    cf_lichess_week: &'a ColumnFamily,
     cf_lichess_game: &'a ColumnFamily,
+    /// Recently imported games, until they can no longer be aborted.
+    cf_lichess_tracked: &'a ColumnFamily,
+    /// Like lichess, but in the experimental shadow format. Only maintained
+    /// while shadow writes are enabled.
+    cf_lichess_shadow: &'a ColumnFamily,
+    shadow_writes: bool,
+    /// Positions that lead into each position.
+    cf_lichess_parent: &'a ColumnFamily,
+    /// The most common move orders from the initial position that reached
+    /// each position, by digest.
+    cf_lichess_move_order: &'a ColumnFamily,
+    /// Last move of each move order, by digest.
+    cf_lichess_move_order_step: &'a ColumnFamily,
 
     cf_player: &'a ColumnFamily,
     cf_player_status: &'a ColumnFamily,
+    cf_player_contributor: &'a ColumnFamily,
 }
 
 impl LichessDatabase<'_> {
+    /// Changes whenever lichess data of the position is written, and
+    /// possibly when that of other positions is written.
+    pub fn generation(&self, key: &KeyPrefix) -> u64 {
+        self.generations.get(key)
+    }
+
+    /// Selects and loads the hot positions. Until then, and always for
+    /// snapshots, no positions are hot.
+    pub fn load_hot_positions(&self, opt: HotOpt) {
+        self.hot_positions.load(opt, self);
+    }
+
+    /// Prepares the response of a hot position from memory. Returns `None`
+    /// if the position is not hot, or if the filter selects a date range.
+    pub fn prepare_hot(
+        &self,
+        key: &KeyPrefix,
+        filter: &LichessQueryFilter,
+        limits: &Limits,
+    ) -> Option<PreparedResponse> {
+        if !filter.is_all_time() {
+            return None;
+        }
+        self.hot_positions
+            .prepare(key, filter, limits, || self.read_hot(key))
+    }
+
+    /// Calls `f` with the entry of a hot position from memory. Returns
+    /// `None` if the position is not hot, or if the filter selects a date
+    /// range.
+    pub fn with_hot<T, F: FnOnce(&LichessEntry) -> T>(
+        &self,
+        key: &KeyPrefix,
+        filter: &LichessQueryFilter,
+        f: F,
+    ) -> Option<T> {
+        if !filter.is_all_time() {
+            return None;
+        }
+        self.hot_positions.with_entry(key, || self.read_hot(key), f)
+    }
+
+    fn read_hot(&self, key: &KeyPrefix) -> LichessEntry {
+        self.read_lichess(key, Month::default(), Month::max_value())
+            .expect("get hot lichess entry")
+    }
+
     pub fn compact(&self) {
-        compact_column(self.inner, self.cf_lichess);
+        for cf in self.cf_lichess {
+            compact_column(self.inner, cf);
+        }
+        compact_column(self.inner, self.cf_lichess_week);
         compact_column(self.inner, self.cf_lichess_game);
+        compact_column(self.inner, self.cf_lichess_tracked);
+        compact_column(self.inner, self.cf_lichess_shadow);
+        compact_column(self.inner, self.cf_lichess_parent);
+        compact_column(self.inner, self.cf_lichess_move_order);
+        compact_column(self.inner, self.cf_lichess_move_order_step);
         compact_column(self.inner, self.cf_player);
         compact_column(self.inner, self.cf_player_status);
+        compact_column(self.inner, self.cf_player_contributor);
     }
 
     pub fn game(&self, id: GameId) -> Result<Option<LichessGame>, rocksdb::Error> {
@@ -319,9 +1353,279 @@ impl LichessDatabase<'_> {
             .collect()
     }
 
-    pub fn read_lichess(
+    fn cf_lichess(&self, source: LichessSource) -> &ColumnFamily {
+        self.cf_lichess[usize::from(source.to_u8())]
+    }
+
+    /// Samples only the entries from dumps, which are the vast majority.
+    pub fn sample_lichess<F: FnMut(&[u8], &[u8])>(
         &self,
-        key: &KeyPrefix,
+        n: usize,
+        f: F,
+    ) -> Result<(), rocksdb::Error> {
+        sample_column(self.inner, self.cf_lichess(LichessSource::Dump), n, f)
+    }
+
+    pub fn get_lichess(
+        &self,
+        source: LichessSource,
+        key: Key,
+    ) -> Result<LichessEntry, rocksdb::Error> {
+        self.get_lichess_bucket(self.cf_lichess(source), key)
+    }
+
+    pub fn get_lichess_shadow(&self, key: Key) -> Result<LichessEntry, rocksdb::Error> {
+        let mut entry = LichessEntry::default();
+        if let Some(buf) = self
+            .inner
+            .get_pinned_cf(self.cf_lichess_shadow, key.into_bytes())?
+        {
+            entry
+                .extend_from_shadow_reader(&mut Cursor::new(buf))
+                .expect("deserialize shadow lichess entry");
+        }
+        Ok(entry)
+    }
+
+    pub fn get_lichess_week(&self, key: Key) -> Result<LichessEntry, rocksdb::Error> {
+        self.get_lichess_bucket(self.cf_lichess_week, key)
+    }
+
+    fn get_lichess_bucket(
+        &self,
+        cf: &ColumnFamily,
+        key: Key,
+    ) -> Result<LichessEntry, rocksdb::Error> {
+        let mut entry = LichessEntry::default();
+        if let Some(buf) = self.inner.get_pinned_cf(cf, key.into_bytes())? {
+            entry
+                .extend_from_reader(&mut Cursor::new(buf))
+                .expect("deserialize lichess entry");
+        }
+        Ok(entry)
+    }
+
+    pub fn tracked(&self, id: GameId) -> Result<Option<TrackedImport>, rocksdb::Error> {
+        Ok(self
+            .inner
+            .get_pinned_cf(self.cf_lichess_tracked, id.to_bytes())?
+            .map(|buf| {
+                let mut cursor = Cursor::new(buf);
+                TrackedImport::read(&mut cursor).expect("deserialize tracked import")
+            }))
+    }
+
+    /// Calls `f` with the serialized monthly entries of all sources in the
+    /// given month. Keys start with hashes, so this has to scan the entire
+    /// column families.
+    pub fn scan_lichess_month<F: FnMut(LichessSource, Key, &[u8])>(
+        &self,
+        month: Month,
+        mut f: F,
+    ) -> Result<(), rocksdb::Error> {
+        for source in LichessSource::ALL {
+            let mut opt = ReadOptions::default();
+            opt.set_total_order_seek(true);
+            let mut iter = self.inner.raw_iterator_cf_opt(self.cf_lichess(source), opt);
+            iter.seek_to_first();
+
+            while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+                let key = Key::from_bytes(key).expect("lichess key size");
+                if key.month().map_or(false, |m| m == month) {
+                    f(source, key, value);
+                }
+                iter.next();
+            }
+
+            iter.status()?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the weekly entries of all weeks before `since`, which are no
+    /// longer written or served. Keys start with hashes, so this has to scan
+    /// the entire column family, but it only holds recent weeks. Returns the
+    /// number of positions with deleted entries.
+    pub fn prune_weeks(&self, since: Week) -> Result<usize, rocksdb::Error> {
+        let mut batch = self.batch();
+        let mut last_prefix = None;
+
+        let mut iter = self.inner.raw_iterator_cf(self.cf_lichess_week);
+        iter.seek_to_first();
+
+        while let Some(key) = iter.key() {
+            let key = Key::from_bytes(key).expect("lichess week key");
+            let prefix = key.prefix();
+            // Weeks of a position are in order, so only the first matters.
+            if last_prefix.as_ref() != Some(&prefix) {
+                if key.bucket::<Week>().map_or(true, |week| week < since) {
+                    batch.delete_weeks(&prefix, since);
+                }
+                last_prefix = Some(prefix);
+            }
+            iter.next();
+        }
+        iter.status()?;
+
+        let num_positions = batch.touched.len();
+        batch.commit()?;
+        Ok(num_positions)
+    }
+
+    /// Calls `f` with the id and import time of each tracked game.
+    pub fn scan_tracked<F: FnMut(GameId, u64)>(&self, mut f: F) -> Result<(), rocksdb::Error> {
+        let mut iter = self.inner.raw_iterator_cf(self.cf_lichess_tracked);
+        iter.seek_to_first();
+
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            let id = GameId::read(&mut Cursor::new(key)).expect("deserialize tracked game id");
+            f(
+                id,
+                read_uint(&mut Cursor::new(value)).expect("deserialize tracked import time"),
+            );
+            iter.next();
+        }
+
+        iter.status()
+    }
+
+    /// Finds the lichess, weekly, shadow and player entries that reference
+    /// any of the given games, without blocking writes. Keys start with
+    /// hashes, so this has to scan the entire column families.
+    pub fn find_game_references(
+        &self,
+        ids: &FxHashSet<GameId>,
+    ) -> Result<GameReferences<'_>, rocksdb::Error> {
+        let mut columns: Vec<(&ColumnFamily, EntryKind)> = self
+            .cf_lichess
+            .iter()
+            .map(|cf| (*cf, EntryKind::Lichess))
+            .collect();
+        columns.push((self.cf_lichess_week, EntryKind::Lichess));
+        columns.push((self.cf_lichess_shadow, EntryKind::LichessShadow));
+        columns.push((self.cf_player, EntryKind::Player));
+
+        let mut entries = Vec::new();
+        for (cf, kind) in columns {
+            let mut opt = ReadOptions::default();
+            opt.set_total_order_seek(true);
+            let mut iter = self.inner.raw_iterator_cf_opt(cf, opt);
+            iter.seek_to_first();
+
+            while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+                if kind.prune(value, ids).is_some() {
+                    entries.push((cf, kind, key.to_vec()));
+                }
+                iter.next();
+            }
+
+            iter.status()?;
+        }
+
+        Ok(GameReferences { entries })
+    }
+
+    /// Rewrites the entries found by `find_game_references()` without the
+    /// references, and deletes the infos of the games. Entries are read
+    /// again, so that writes since the scan are kept. Returns the number of
+    /// rewritten entries.
+    pub fn prune_game_references(
+        &self,
+        references: GameReferences<'_>,
+        ids: &FxHashSet<GameId>,
+    ) -> Result<usize, rocksdb::Error> {
+        let mut batch = WriteBatch::default();
+        let mut num_entries = 0;
+        for (cf, kind, key) in references.entries {
+            if let Some(value) = self.inner.get_pinned_cf(cf, &key)? {
+                if let Some(pruned) = kind.prune(&value, ids) {
+                    batch.put_cf(cf, key, pruned);
+                    num_entries += 1;
+                }
+            }
+        }
+        for id in ids {
+            batch.delete_cf(self.cf_lichess_game, id.to_bytes());
+        }
+        self.inner.write(batch)?;
+        self.hot_positions.prune_games(ids);
+        self.generations.bump_all(self.inner);
+        Ok(num_entries)
+    }
+
+    pub fn read_lichess(
+        &self,
+        key: &KeyPrefix,
+        since: Month,
+        until: Month,
+    ) -> Result<LichessEntry, rocksdb::Error> {
+        self.read_lichess_within(key, since, until, &Deadline::never())
+    }
+
+    /// Like [`LichessDatabase::read_lichess()`], but stops early once the
+    /// deadline is over. The entry is then incomplete and should be
+    /// discarded.
+    pub fn read_lichess_within(
+        &self,
+        key: &KeyPrefix,
+        since: Month,
+        until: Month,
+        deadline: &Deadline,
+    ) -> Result<LichessEntry, rocksdb::Error> {
+        self.read_lichess_buckets(&self.cf_lichess, key, since, until, deadline)
+    }
+
+    pub fn read_lichess_weeks(
+        &self,
+        key: &KeyPrefix,
+        since: Week,
+        until: Week,
+        deadline: &Deadline,
+    ) -> Result<LichessEntry, rocksdb::Error> {
+        self.read_lichess_buckets(&[self.cf_lichess_week], key, since, until, deadline)
+    }
+
+    fn read_lichess_buckets<T: TimeBucket>(
+        &self,
+        cfs: &[&ColumnFamily],
+        key: &KeyPrefix,
+        since: T,
+        until: T,
+        deadline: &Deadline,
+    ) -> Result<LichessEntry, rocksdb::Error> {
+        let mut entry = LichessEntry::default();
+
+        for cf in cfs {
+            let mut opt = ReadOptions::default();
+            opt.set_prefix_same_as_start(true);
+            opt.set_iterate_lower_bound(key.with_bucket(since).into_bytes());
+            opt.set_iterate_upper_bound(key.with_bucket(until.add_saturating(1)).into_bytes());
+
+            let mut iter = self.inner.raw_iterator_cf_opt(*cf, opt);
+            iter.seek_to_first();
+
+            while let Some(value) = iter.value() {
+                if deadline.is_over() {
+                    break;
+                }
+                let mut cursor = Cursor::new(value);
+                entry
+                    .extend_from_reader(&mut cursor)
+                    .expect("deserialize lichess entry");
+                iter.next();
+            }
+
+            iter.status()?;
+        }
+
+        Ok(entry)
+    }
+
+    /// Like `read_lichess()`, but from the shadow format.
+    pub fn read_lichess_shadow(
+        &self,
+        key: &KeyPrefix,
         since: Month,
         until: Month,
     ) -> Result<LichessEntry, rocksdb::Error> {
@@ -332,20 +1636,58 @@ impl LichessDatabase<'_> {
         opt.set_iterate_lower_bound(key.with_month(since).into_bytes());
         opt.set_iterate_upper_bound(key.with_month(until.add_months_saturating(1)).into_bytes());
 
-        let mut iter = self.inner.raw_iterator_cf_opt(self.cf_lichess, opt);
+        let mut iter = self.inner.raw_iterator_cf_opt(self.cf_lichess_shadow, opt);
         iter.seek_to_first();
 
         while let Some(value) = iter.value() {
-            let mut cursor = Cursor::new(value);
             entry
-                .extend_from_reader(&mut cursor)
-                .expect("deserialize lichess entry");
+                .extend_from_shadow_reader(&mut Cursor::new(value))
+                .expect("deserialize shadow lichess entry");
             iter.next();
         }
 
         iter.status().map(|_| entry)
     }
 
+    pub fn read_lichess_by_month(
+        &self,
+        key: &KeyPrefix,
+        since: Month,
+        until: Month,
+    ) -> Result<Vec<(Month, LichessEntry)>, rocksdb::Error> {
+        let mut entries: BTreeMap<Month, LichessEntry> = BTreeMap::new();
+
+        for cf in self.cf_lichess {
+            let mut opt = ReadOptions::default();
+            opt.set_prefix_same_as_start(true);
+            opt.set_iterate_lower_bound(key.with_month(since).into_bytes());
+            opt.set_iterate_upper_bound(
+                key.with_month(until.add_months_saturating(1)).into_bytes(),
+            );
+
+            let mut iter = self.inner.raw_iterator_cf_opt(cf, opt);
+            iter.seek_to_first();
+
+            while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+                let month = Key::from_bytes(key)
+                    .expect("lichess key size")
+                    .month()
+                    .expect("lichess key month");
+                let mut cursor = Cursor::new(value);
+                entries
+                    .entry(month)
+                    .or_default()
+                    .extend_from_reader(&mut cursor)
+                    .expect("deserialize lichess entry");
+                iter.next();
+            }
+
+            iter.status()?;
+        }
+
+        Ok(entries.into_iter().collect())
+    }
+
     pub fn read_player(
         &self,
         key: &KeyPrefix,
@@ -373,6 +1715,163 @@ impl LichessDatabase<'_> {
         iter.status().map(|_| entry)
     }
 
+    /// Reads the entries of both colors of a player, merged month by month.
+    /// The results of black are mirrored, so that `white` counts the wins of
+    /// the player.
+    pub fn read_player_both(
+        &self,
+        white: &KeyPrefix,
+        black: &KeyPrefix,
+        since: Month,
+        until: Month,
+    ) -> Result<PlayerEntry, rocksdb::Error> {
+        let mut months: BTreeMap<Month, PlayerEntry> = BTreeMap::new();
+
+        for (color, key) in [(Color::White, white), (Color::Black, black)] {
+            let mut opt = ReadOptions::default();
+            opt.set_prefix_same_as_start(true);
+            opt.set_iterate_lower_bound(key.with_month(since).into_bytes());
+            opt.set_iterate_upper_bound(
+                key.with_month(until.add_months_saturating(1)).into_bytes(),
+            );
+
+            let mut iter = self.inner.raw_iterator_cf_opt(self.cf_player, opt);
+            iter.seek_to_first();
+
+            while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+                let month = Key::from_bytes(key)
+                    .expect("player key size")
+                    .month()
+                    .expect("player key month");
+                let mut entry = PlayerEntry::default();
+                entry
+                    .extend_from_reader(&mut Cursor::new(value))
+                    .expect("deserialize player entry");
+                if color == Color::Black {
+                    entry.mirror();
+                }
+                months.entry(month).or_default().append(entry);
+                iter.next();
+            }
+
+            iter.status()?;
+        }
+
+        let mut entry = PlayerEntry::default();
+        for (_, month) in months {
+            entry.append(month);
+        }
+        Ok(entry)
+    }
+
+    /// Reads the indexed players that reached the position, scanning at most
+    /// `max_players` of them.
+    pub fn read_contributors(
+        &self,
+        key: &KeyPrefix,
+        max_players: usize,
+    ) -> Result<Vec<(UserId, ContributorEntry)>, rocksdb::Error> {
+        let mut contributors = Vec::new();
+
+        let mut opt = ReadOptions::default();
+        opt.set_prefix_same_as_start(true);
+        opt.set_iterate_lower_bound(key.as_bytes());
+
+        let mut iter = self
+            .inner
+            .raw_iterator_cf_opt(self.cf_player_contributor, opt);
+        iter.seek_to_first();
+
+        while let (Some(user), Some(value)) = (iter.key(), iter.value()) {
+            if contributors.len() >= max_players {
+                break;
+            }
+            match UserName::from_bytes(&user[KeyPrefix::SIZE..]) {
+                Ok(user) => contributors.push((
+                    UserId::from(user),
+                    ContributorEntry::read(&mut Cursor::new(value))
+                        .expect("deserialize contributor entry"),
+                )),
+                Err(_) => log::error!("invalid user in contributor key"),
+            }
+            iter.next();
+        }
+
+        iter.status().map(|_| contributors)
+    }
+
+    /// Reads the positions that lead into the position.
+    pub fn read_parents(&self, key: &KeyPrefix) -> Result<ParentEntry, rocksdb::Error> {
+        Ok(self
+            .inner
+            .get_pinned_cf(self.cf_lichess_parent, key.as_bytes())?
+            .map_or_else(ParentEntry::default, |buf| {
+                ParentEntry::read(&mut Cursor::new(buf)).expect("deserialize parent entry")
+            }))
+    }
+
+    /// Reads the most common move orders that reached the position.
+    pub fn read_move_orders(&self, key: &KeyPrefix) -> Result<MoveOrderEntry, rocksdb::Error> {
+        Ok(self
+            .inner
+            .get_pinned_cf(self.cf_lichess_move_order, key.as_bytes())?
+            .map_or_else(MoveOrderEntry::default, |buf| {
+                MoveOrderEntry::read(&mut Cursor::new(buf)).expect("deserialize move order entry")
+            }))
+    }
+
+    /// Recovers the moves of each move order by following its steps back to
+    /// the initial position, all move orders at once. Move orders with
+    /// missing steps are not recovered.
+    pub fn read_move_order_paths(
+        &self,
+        paths: &[PathHash],
+    ) -> Result<Vec<Option<Vec<Uci>>>, rocksdb::Error> {
+        let mut moves: Vec<Option<Vec<Uci>>> = vec![Some(Vec::new()); paths.len()];
+        let mut pending: Vec<(usize, PathHash)> = paths
+            .iter()
+            .enumerate()
+            .filter(|(_, path)| !path.is_empty())
+            .map(|(i, path)| (i, *path))
+            .collect();
+
+        for _ in 0..MAX_MOVE_ORDER_STEPS {
+            if pending.is_empty() {
+                break;
+            }
+            let steps = self.inner.multi_get_cf(
+                pending
+                    .iter()
+                    .map(|(_, path)| (self.cf_lichess_move_order_step, path.as_bytes())),
+            );
+            let mut next = Vec::with_capacity(pending.len());
+            for ((i, _), step) in pending.into_iter().zip(steps) {
+                match step? {
+                    Some(buf) => {
+                        let step = MoveOrderStep::read(&mut Cursor::new(buf))
+                            .expect("deserialize move order step");
+                        if let Some(ref mut moves) = moves[i] {
+                            moves.push(step.uci);
+                        }
+                        if !step.prev.is_empty() {
+                            next.push((i, step.prev));
+                        }
+                    }
+                    None => moves[i] = None,
+                }
+            }
+            pending = next;
+        }
+        for (i, _) in pending {
+            moves[i] = None;
+        }
+
+        for moves in moves.iter_mut().flatten() {
+            moves.reverse();
+        }
+        Ok(moves)
+    }
+
     pub fn player_status(&self, id: &UserId) -> Result<Option<PlayerStatus>, rocksdb::Error> {
         Ok(self
             .inner
@@ -401,21 +1900,187 @@ impl LichessDatabase<'_> {
         LichessBatch {
             inner: self,
             batch: WriteBatch::default(),
+            touched: Vec::new(),
+            hot: HotChanges::default(),
         }
     }
 }
 
+/// Entries that reference games to be pruned.
+pub struct GameReferences<'a> {
+    entries: Vec<(&'a ColumnFamily, EntryKind, Vec<u8>)>,
+}
+
+impl GameReferences<'_> {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[derive(Copy, Clone)]
+enum EntryKind {
+    Lichess,
+    LichessShadow,
+    Player,
+}
+
+impl EntryKind {
+    /// Returns the serialized entry without references to the given games,
+    /// or `None` if there are no such references.
+    fn prune(self, value: &[u8], ids: &FxHashSet<GameId>) -> Option<Vec<u8>> {
+        let mut buf = Vec::with_capacity(value.len());
+        match self {
+            EntryKind::Lichess => {
+                let mut entry = LichessEntry::default();
+                entry
+                    .extend_from_reader(&mut Cursor::new(value))
+                    .expect("deserialize lichess entry");
+                if !entry.prune_games(ids) {
+                    return None;
+                }
+                entry.write(&mut buf).expect("serialize lichess entry");
+            }
+            EntryKind::LichessShadow => {
+                let mut entry = LichessEntry::default();
+                entry
+                    .extend_from_shadow_reader(&mut Cursor::new(value))
+                    .expect("deserialize shadow lichess entry");
+                if !entry.prune_games(ids) {
+                    return None;
+                }
+                entry
+                    .write_shadow(&mut buf)
+                    .expect("serialize shadow lichess entry");
+            }
+            EntryKind::Player => {
+                let mut entry = PlayerEntry::default();
+                entry
+                    .extend_from_reader(&mut Cursor::new(value))
+                    .expect("deserialize player entry");
+                if !entry.prune_games(ids) {
+                    return None;
+                }
+                entry.write(&mut buf).expect("serialize player entry");
+            }
+        }
+        Some(buf)
+    }
+}
+
 pub struct LichessBatch<'a> {
     inner: &'a LichessDatabase<'a>,
     batch: WriteBatch,
+    /// Positions with written lichess entries.
+    touched: Vec<KeyPrefix>,
+    /// Changes to monthly entries of hot positions.
+    hot: HotChanges,
 }
 
 impl LichessBatch<'_> {
-    pub fn merge_lichess(&mut self, key: Key, entry: LichessEntry) {
+    pub fn merge_lichess(&mut self, source: LichessSource, key: Key, entry: &LichessEntry) {
+        self.touched.push(key.prefix());
+        if self.inner.shadow_writes {
+            let mut cursor = Cursor::new(Vec::with_capacity(LichessEntry::SIZE_HINT));
+            entry
+                .write_shadow(&mut cursor)
+                .expect("serialize shadow lichess entry");
+            self.batch.merge_cf(
+                self.inner.cf_lichess_shadow,
+                key.clone().into_bytes(),
+                cursor.into_inner(),
+            );
+        }
+
         let mut cursor = Cursor::new(Vec::with_capacity(LichessEntry::SIZE_HINT));
         entry.write(&mut cursor).expect("serialize lichess entry");
+        self.hot
+            .merge(self.inner.hot_positions, key.prefix(), cursor.get_ref());
+        self.batch.merge_cf(
+            self.inner.cf_lichess(source),
+            key.into_bytes(),
+            cursor.into_inner(),
+        );
+    }
+
+    pub fn merge_lichess_week(&mut self, key: Key, entry: &LichessEntry) {
+        self.touched.push(key.prefix());
+        let mut cursor = Cursor::new(Vec::with_capacity(LichessEntry::SIZE_HINT));
+        entry.write(&mut cursor).expect("serialize lichess entry");
+        self.batch.merge_cf(
+            self.inner.cf_lichess_week,
+            key.into_bytes(),
+            cursor.into_inner(),
+        );
+    }
+
+    pub fn put_lichess(&mut self, source: LichessSource, key: Key, entry: &LichessEntry) {
+        self.touched.push(key.prefix());
+        self.hot.replace(self.inner.hot_positions, key.prefix());
+        let mut cursor = Cursor::new(Vec::new());
+        entry.write(&mut cursor).expect("serialize lichess entry");
+        self.batch.put_cf(
+            self.inner.cf_lichess(source),
+            key.into_bytes(),
+            cursor.into_inner(),
+        );
+    }
+
+    /// Replaces the shadow entry, which merges all sources. Ignored unless
+    /// shadow writes are enabled.
+    pub fn put_lichess_shadow(&mut self, key: Key, entry: &LichessEntry) {
+        if self.inner.shadow_writes {
+            let mut cursor = Cursor::new(Vec::new());
+            entry
+                .write_shadow(&mut cursor)
+                .expect("serialize shadow lichess entry");
+            self.batch.put_cf(
+                self.inner.cf_lichess_shadow,
+                key.into_bytes(),
+                cursor.into_inner(),
+            );
+        }
+    }
+
+    pub fn put_lichess_week(&mut self, key: Key, entry: &LichessEntry) {
+        self.touched.push(key.prefix());
+        let mut cursor = Cursor::new(Vec::new());
+        entry.write(&mut cursor).expect("serialize lichess entry");
+        self.batch.put_cf(
+            self.inner.cf_lichess_week,
+            key.into_bytes(),
+            cursor.into_inner(),
+        );
+    }
+
+    /// Deletes the weekly entries of the position before `until`.
+    pub fn delete_weeks(&mut self, key: &KeyPrefix, until: Week) {
+        self.touched.push(key.clone());
+        self.batch.delete_range_cf(
+            self.inner.cf_lichess_week,
+            key.with_bucket(Week::default()).into_bytes(),
+            key.with_bucket(until).into_bytes(),
+        );
+    }
+
+    pub fn put_tracked(&mut self, id: GameId, tracked: &TrackedImport) {
+        let mut cursor = Cursor::new(Vec::new());
+        tracked
+            .write(&mut cursor)
+            .expect("serialize tracked import");
+        self.batch.put_cf(
+            self.inner.cf_lichess_tracked,
+            id.to_bytes(),
+            cursor.into_inner(),
+        );
+    }
+
+    pub fn delete_tracked(&mut self, id: GameId) {
         self.batch
-            .merge_cf(self.inner.cf_lichess, key.into_bytes(), cursor.into_inner());
+            .delete_cf(self.inner.cf_lichess_tracked, id.to_bytes());
     }
 
     pub fn merge_game(&mut self, id: GameId, info: LichessGame) {
@@ -435,8 +2100,51 @@ impl LichessBatch<'_> {
             .merge_cf(self.inner.cf_player, key.into_bytes(), cursor.into_inner());
     }
 
+    pub fn merge_contributor(&mut self, key: &KeyPrefix, user: &UserId, entry: ContributorEntry) {
+        let mut cursor = Cursor::new(Vec::with_capacity(ContributorEntry::SIZE_HINT));
+        entry
+            .write(&mut cursor)
+            .expect("serialize contributor entry");
+        self.batch.merge_cf(
+            self.inner.cf_player_contributor,
+            key.with_user(user),
+            cursor.into_inner(),
+        );
+    }
+
+    pub fn merge_parent(&mut self, key: &KeyPrefix, entry: &ParentEntry) {
+        let mut buf = Vec::new();
+        entry.write(&mut buf).expect("serialize parent entry");
+        self.batch
+            .merge_cf(self.inner.cf_lichess_parent, key.as_bytes(), buf);
+    }
+
+    pub fn merge_move_order(&mut self, key: &KeyPrefix, entry: &MoveOrderEntry) {
+        let mut buf = Vec::new();
+        entry.write(&mut buf).expect("serialize move order entry");
+        self.batch
+            .merge_cf(self.inner.cf_lichess_move_order, key.as_bytes(), buf);
+    }
+
+    pub fn put_move_order_step(&mut self, path: &PathHash, step: &MoveOrderStep) {
+        let mut buf = Vec::new();
+        step.write(&mut buf).expect("serialize move order step");
+        self.batch
+            .put_cf(self.inner.cf_lichess_move_order_step, path.as_bytes(), buf);
+    }
+
     pub fn commit(self) -> Result<(), rocksdb::Error> {
-        self.inner.inner.write(self.batch)
+        let LichessBatch {
+            inner,
+            batch,
+            touched,
+            hot,
+        } = self;
+        inner
+            .hot_positions
+            .commit(hot, || inner.inner.write(batch))?;
+        inner.generations.bump(inner.inner, &touched);
+        Ok(())
     }
 }
 
@@ -445,9 +2153,20 @@ fn lichess_merge(
     existing: Option<&[u8]>,
     operands: &MergeOperands,
 ) -> Option<Vec<u8>> {
+    Some(merge_lichess_values(
+        existing.into_iter().chain(operands.into_iter()),
+    ))
+}
+
+/// Merges serialized lichess entries, starting with the existing value if
+/// any, as done by the merge operator. Games that are merged more than once,
+/// for example by concurrent imports that both passed the game info check,
+/// are skipped if they are still listed or found in the bloom filter of the
+/// entry.
+pub fn merge_lichess_values<'a>(values: impl Iterator<Item = &'a [u8]>) -> Vec<u8> {
     let mut entry = LichessEntry::default();
     let mut size_hint = 0;
-    for op in existing.into_iter().chain(operands.into_iter()) {
+    for op in values {
         let mut cursor = Cursor::new(op);
         entry
             .extend_from_reader(&mut cursor)
@@ -456,6 +2175,27 @@ fn lichess_merge(
     }
     let mut cursor = Cursor::new(Vec::with_capacity(size_hint));
     entry.write(&mut cursor).expect("write lichess entry");
+    cursor.into_inner()
+}
+
+fn lichess_shadow_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    // Same as lichess_merge(), including skipping duplicate games.
+    let mut entry = LichessEntry::default();
+    let mut size_hint = 0;
+    for op in existing.into_iter().chain(operands.into_iter()) {
+        entry
+            .extend_from_shadow_reader(&mut Cursor::new(op))
+            .expect("deserialize for shadow lichess merge");
+        size_hint += op.len();
+    }
+    let mut cursor = Cursor::new(Vec::with_capacity(size_hint));
+    entry
+        .write_shadow(&mut cursor)
+        .expect("write shadow lichess entry");
     Some(cursor.into_inner())
 }
 
@@ -471,9 +2211,15 @@ fn lichess_game_merge(
         let mut cursor = Cursor::new(op);
         let mut new_info = LichessGame::read(&mut cursor).expect("read for lichess game merge");
         if let Some(old_info) = info {
+            if old_info.indexed_lichess && !new_info.indexed_lichess {
+                new_info.source = old_info.source;
+            }
             new_info.indexed_player.white |= old_info.indexed_player.white;
             new_info.indexed_player.black |= old_info.indexed_player.black;
             new_info.indexed_lichess |= old_info.indexed_lichess;
+            new_info.players.white.bot |= old_info.players.white.bot;
+            new_info.players.black.bot |= old_info.players.black.bot;
+            new_info.clock = new_info.clock.or(old_info.clock);
         }
         info = Some(new_info);
         size_hint = op.len();
@@ -500,6 +2246,58 @@ fn player_merge(_key: &[u8], existing: Option<&[u8]>, operands: &MergeOperands)
     Some(cursor.into_inner())
 }
 
+fn player_contributor_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    let mut entry = ContributorEntry::default();
+    for op in existing.into_iter().chain(operands.into_iter()) {
+        let mut cursor = Cursor::new(op);
+        entry += ContributorEntry::read(&mut cursor).expect("deserialize for contributor merge");
+    }
+    let mut cursor = Cursor::new(Vec::with_capacity(ContributorEntry::SIZE_HINT));
+    entry.write(&mut cursor).expect("write contributor entry");
+    Some(cursor.into_inner())
+}
+
+fn lichess_parent_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    let mut entry: Option<ParentEntry> = None;
+    for op in existing.into_iter().chain(operands.into_iter()) {
+        let rhs = ParentEntry::read(&mut Cursor::new(op)).expect("deserialize for parent merge");
+        match entry {
+            Some(ref mut entry) => *entry += rhs,
+            None => entry = Some(rhs),
+        }
+    }
+    let mut buf = Vec::new();
+    entry?.write(&mut buf).expect("write parent entry");
+    Some(buf)
+}
+
+fn lichess_move_order_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    let mut entry: Option<MoveOrderEntry> = None;
+    for op in existing.into_iter().chain(operands.into_iter()) {
+        let rhs =
+            MoveOrderEntry::read(&mut Cursor::new(op)).expect("deserialize for move order merge");
+        match entry {
+            Some(ref mut entry) => *entry += rhs,
+            None => entry = Some(rhs),
+        }
+    }
+    let mut buf = Vec::new();
+    entry?.write(&mut buf).expect("write move order entry");
+    Some(buf)
+}
+
 fn masters_merge(
     _key: &[u8],
     existing: Option<&[u8]>,
@@ -519,6 +2317,132 @@ fn masters_merge(
     Some(cursor.into_inner())
 }
 
+pub struct AccountsDatabase<'a> {
+    inner: &'a DB,
+    cf_api_key: &'a ColumnFamily,
+    cf_api_usage: &'a ColumnFamily,
+}
+
+impl AccountsDatabase<'_> {
+    pub fn scan_api_keys<F: FnMut(ApiKeyId, ApiKey)>(
+        &self,
+        mut f: F,
+    ) -> Result<(), rocksdb::Error> {
+        let mut iter = self.inner.raw_iterator_cf(self.cf_api_key);
+        iter.seek_to_first();
+
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            f(
+                ApiKeyId::from_bytes(key),
+                serde_json::from_slice(value).expect("deserialize api key"),
+            );
+            iter.next();
+        }
+
+        iter.status()
+    }
+
+    pub fn put_api_key(&self, id: ApiKeyId, key: &ApiKey) -> Result<(), rocksdb::Error> {
+        self.inner.put_cf(
+            self.cf_api_key,
+            id.as_bytes(),
+            serde_json::to_vec(key).expect("serialize api key"),
+        )
+    }
+
+    /// Deletes the key, but keeps its usage for auditing.
+    pub fn delete_api_key(&self, id: ApiKeyId) -> Result<(), rocksdb::Error> {
+        self.inner.delete_cf(self.cf_api_key, id.as_bytes())
+    }
+
+    pub fn add_usage(
+        &self,
+        id: ApiKeyId,
+        month: Month,
+        requests: u64,
+    ) -> Result<(), rocksdb::Error> {
+        self.inner.merge_cf(
+            self.cf_api_usage,
+            id.usage_key(month),
+            requests.to_le_bytes(),
+        )
+    }
+
+    pub fn usage(&self, id: ApiKeyId, month: Month) -> Result<u64, rocksdb::Error> {
+        Ok(self
+            .inner
+            .get_pinned_cf(self.cf_api_usage, id.usage_key(month))?
+            .map_or(0, |buf| LittleEndian::read_u64(&buf)))
+    }
+
+    /// Requests by month, oldest first.
+    pub fn usage_history(&self, id: ApiKeyId) -> Result<Vec<(Month, u64)>, rocksdb::Error> {
+        let mut history = Vec::new();
+        let mut iter = self.inner.raw_iterator_cf(self.cf_api_usage);
+        iter.seek(id.as_bytes());
+
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            if !key.starts_with(id.as_bytes()) {
+                break;
+            }
+            let month = ApiKeyId::usage_month(key).expect("deserialize usage month");
+            history.push((month, LittleEndian::read_u64(value)));
+            iter.next();
+        }
+
+        iter.status().map(|_| history)
+    }
+}
+
+fn api_usage_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    let requests: u64 = existing
+        .into_iter()
+        .chain(operands.into_iter())
+        .map(LittleEndian::read_u64)
+        .sum();
+    Some(requests.to_le_bytes().to_vec())
+}
+
 fn compact_column(db: &DB, cf: &ColumnFamily) {
     db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
 }
+
+/// Visits the entries at or after `n` random keys. Keys start with hashes,
+/// so this is close to a uniform sample, except that entries after larger
+/// gaps are somewhat more likely.
+fn sample_column<F: FnMut(&[u8], &[u8])>(
+    db: &DB,
+    cf: &ColumnFamily,
+    n: usize,
+    mut f: F,
+) -> Result<(), rocksdb::Error> {
+    let mut opt = ReadOptions::default();
+    opt.set_total_order_seek(true);
+    let mut iter = db.raw_iterator_cf_opt(cf, opt);
+
+    let random = RandomState::new();
+    for i in 0..n {
+        let mut target = [0; 16];
+        for (half, chunk) in target.chunks_mut(8).enumerate() {
+            let mut hasher = random.build_hasher();
+            hasher.write_usize(i);
+            hasher.write_usize(half);
+            chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+
+        iter.seek(&target[..Key::SIZE]);
+        if !iter.valid() {
+            iter.seek_to_first();
+        }
+        match (iter.key(), iter.value()) {
+            (Some(key), Some(value)) => f(key, value),
+            _ => break, // Empty
+        }
+    }
+
+    iter.status()
+}
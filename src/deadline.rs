@@ -0,0 +1,88 @@
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRequest, RequestParts},
+};
+
+use crate::api::Error;
+
+/// Requests can ask for work on their behalf to be abandoned after the given
+/// number of milliseconds.
+const TIMEOUT_HEADER: &str = "x-timeout-ms";
+
+/// Cooperative cancellation of blocking work done on behalf of a request.
+/// Work is cancelled once the deadline requested by the client has passed,
+/// or when the client is gone, so that the handler future was dropped.
+#[derive(Clone, Debug, Default)]
+pub struct Deadline {
+    at: Option<Instant>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Deadline {
+    pub fn never() -> Deadline {
+        Deadline::default()
+    }
+
+    pub fn after(timeout: Duration) -> Deadline {
+        Deadline {
+            at: Instant::now().checked_add(timeout),
+            cancelled: Arc::default(),
+        }
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.at.map_or(false, |at| at <= Instant::now())
+    }
+
+    pub fn check(&self) -> Result<(), Error> {
+        if self.is_over() {
+            Err(Error::DeadlineExceeded)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Cancels the work when dropped. Keep it alive in the handler future,
+    /// across the await point of the blocking task.
+    pub fn cancel_on_drop(&self) -> CancelOnDrop {
+        CancelOnDrop {
+            cancelled: Arc::clone(&self.cancelled),
+        }
+    }
+}
+
+#[must_use]
+pub struct CancelOnDrop {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Deadline {
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Deadline, Infallible> {
+        Ok(req
+            .headers()
+            .and_then(|headers| headers.get(TIMEOUT_HEADER))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .map_or_else(Deadline::never, |millis| {
+                Deadline::after(Duration::from_millis(millis))
+            }))
+    }
+}
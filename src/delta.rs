@@ -0,0 +1,224 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Cursor, Read, Write},
+    mem,
+    path::PathBuf,
+};
+
+use thiserror::Error;
+
+use crate::{
+    db::Database,
+    model::{read_uint, write_uint, Key, LichessEntry, LichessSource, Month},
+};
+
+const MAGIC: &[u8; 8] = b"lxdelta1";
+
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+#[derive(Error, Debug)]
+pub enum DeltaError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("rocksdb error: {0}")]
+    RocksDb(#[from] rocksdb::Error),
+}
+
+/// Writes a delta file: a header with the month, followed by the serialized
+/// monthly lichess entries of that month, each with its source and key.
+pub struct DeltaWriter<W> {
+    writer: W,
+    records: u64,
+}
+
+impl<W: Write> DeltaWriter<W> {
+    pub fn new(mut writer: W, month: Month) -> io::Result<DeltaWriter<W>> {
+        writer.write_all(MAGIC)?;
+        write_uint(&mut writer, u64::from(u16::from(month)))?;
+        Ok(DeltaWriter { writer, records: 0 })
+    }
+
+    pub fn write_record(
+        &mut self,
+        source: LichessSource,
+        key: Key,
+        value: &[u8],
+    ) -> io::Result<()> {
+        self.writer.write_all(&[source.to_u8()])?;
+        self.writer.write_all(&key.into_bytes())?;
+        write_uint(&mut self.writer, value.len() as u64)?;
+        self.writer.write_all(value)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Flushes the writer and returns the number of records written.
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.writer.flush()?;
+        Ok(self.records)
+    }
+}
+
+pub struct DeltaReader<R> {
+    reader: R,
+    month: Month,
+}
+
+impl<R: Read> DeltaReader<R> {
+    pub fn new(mut reader: R) -> io::Result<DeltaReader<R>> {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a delta file",
+            ));
+        }
+        let month = u16::try_from(read_uint(&mut reader)?)
+            .ok()
+            .and_then(|n| Month::try_from(n).ok())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+        Ok(DeltaReader { reader, month })
+    }
+
+    pub fn month(&self) -> Month {
+        self.month
+    }
+
+    /// Reads the next record, or `None` at the end of the file.
+    pub fn read_record(&mut self) -> io::Result<Option<(LichessSource, Key, LichessEntry)>> {
+        let mut source = [0];
+        match self.reader.read_exact(&mut source) {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let source = LichessSource::from_u8(source[0])
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+        let mut key = [0; Key::SIZE];
+        self.reader.read_exact(&mut key)?;
+        let mut buf = vec![0; read_uint(&mut self.reader)? as usize];
+        self.reader.read_exact(&mut buf)?;
+        let mut entry = LichessEntry::default();
+        entry.extend_from_reader(&mut Cursor::new(buf))?;
+        Ok(Some((
+            source,
+            Key::from_bytes(&key).expect("lichess key size"),
+            entry,
+        )))
+    }
+}
+
+/// Writes all monthly lichess entries of the given month to a new delta
+/// file in the delta directory. Returns `None` if there is no delta
+/// directory.
+pub fn export(db: &Database, month: Month) -> Result<Option<PathBuf>, DeltaError> {
+    let dir = match db.delta_dir() {
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
+    let path = dir.join(format!("delta-{}.bin", month));
+    let tmp_path = dir.join(format!("delta-{}.bin.tmp", month));
+
+    let mut writer = DeltaWriter::new(BufWriter::new(File::create(&tmp_path)?), month)?;
+    let mut res = Ok(());
+    db.lichess()
+        .scan_lichess_month(month, |source, key, value| {
+            if res.is_ok() {
+                res = writer.write_record(source, key, value);
+            }
+        })?;
+    res?;
+    let records = writer.finish()?;
+
+    // Only complete files ever appear under the final name.
+    fs::rename(&tmp_path, &path)?;
+    log::info!(
+        "exported {} entries of {} to {}",
+        records,
+        month,
+        path.display()
+    );
+    Ok(Some(path))
+}
+
+/// Merges all entries of a delta file into the database. Returns the month
+/// and the number of imported entries.
+pub fn import<R: Read>(db: &Database, reader: R) -> Result<(Month, u64), DeltaError> {
+    let mut reader = DeltaReader::new(reader)?;
+    let lichess_db = db.lichess();
+    let mut batch = lichess_db.batch();
+    let mut batch_size = 0;
+    let mut records = 0;
+
+    while let Some((source, key, entry)) = reader.read_record()? {
+        batch.merge_lichess(source, key, &entry);
+        records += 1;
+        batch_size += 1;
+        if batch_size >= IMPORT_BATCH_SIZE {
+            mem::replace(&mut batch, lichess_db.batch()).commit()?;
+            batch_size = 0;
+        }
+    }
+
+    batch.commit()?;
+    Ok((reader.month(), records))
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::{uci::Uci, variant::Variant, Outcome, Square};
+
+    use super::*;
+    use crate::model::{KeyBuilder, MoveDetails, Speed};
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let month = "2022-03".parse::<Month>().unwrap();
+        let key = KeyBuilder::lichess()
+            .with_zobrist(Variant::Chess, 0xd1d0_6239_bbdc_01c7)
+            .with_month(month);
+        let entry = LichessEntry::new_single(
+            Uci::Normal {
+                from: Square::E2,
+                to: Square::E4,
+                promotion: None,
+            },
+            Speed::Blitz,
+            "aaaaaaaa".parse().unwrap(),
+            Outcome::Draw,
+            1700,
+            1800,
+            MoveDetails::default(),
+        );
+        let mut value = Cursor::new(Vec::new());
+        entry.write(&mut value).unwrap();
+
+        let mut writer = DeltaWriter::new(Vec::new(), month).unwrap();
+        writer
+            .write_record(LichessSource::Live, key.clone(), value.get_ref())
+            .unwrap();
+        writer
+            .write_record(LichessSource::Dump, key.clone(), value.get_ref())
+            .unwrap();
+        assert_eq!(writer.records, 2);
+        let buf = writer.writer;
+
+        let mut reader = DeltaReader::new(Cursor::new(buf)).unwrap();
+        assert_eq!(reader.month(), month);
+        let (source, read_key, read_entry) = reader.read_record().unwrap().unwrap();
+        assert_eq!(source, LichessSource::Live);
+        assert_eq!(read_key, key);
+        let mut read_value = Cursor::new(Vec::new());
+        read_entry.write(&mut read_value).unwrap();
+        assert_eq!(read_value.get_ref(), value.get_ref());
+        let (source, _, _) = reader.read_record().unwrap().unwrap();
+        assert_eq!(source, LichessSource::Dump);
+        assert!(reader.read_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_delta_rejects_other_files() {
+        assert!(DeltaReader::new(Cursor::new(b"{\"id\":\"ABCDEFGH\"}\n".to_vec())).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use rustc_hash::FxHashSet;
+use shakmaty::{variant::VariantPosition, zobrist::Zobrist, ByColor, Color, Position as _};
+
+use crate::{
+    api::{Error, NearDuplicate},
+    model::{GameId, MastersGame, Year},
+};
+
+/// Games that are at least this similar to a stored game are rejected.
+pub const DUPLICATE_SIMILARITY: f64 = 0.9;
+
+/// Games that are at least this similar to a stored game are imported, but
+/// queued for review.
+pub const NEAR_DUPLICATE_SIMILARITY: f64 = 0.6;
+
+/// Number of trailing positions whose stored entries provide the candidate
+/// games. More than one, so that records of the same game that are a few
+/// moves longer or shorter are still found.
+const CANDIDATE_PLIES: usize = 4;
+
+/// Every n-th position is looked up in the other game.
+const SAMPLE_EVERY: usize = 4;
+
+const MAX_REVIEW_QUEUE: usize = 1000;
+
+/// What is compared to tell whether two masters games are the same.
+pub struct Fingerprint {
+    surnames: ByColor<String>,
+    date: String,
+    year: Year,
+    winner: Option<Color>,
+    /// Zobrist hashes of all positions, starting with the initial position.
+    hashes: Vec<u128>,
+    positions: FxHashSet<u128>,
+}
+
+impl Fingerprint {
+    pub fn new(game: &MastersGame) -> Result<Fingerprint, Error> {
+        let mut pos: Zobrist<VariantPosition, u128> = Zobrist::new(game.initial_position()?);
+        let mut hashes = Vec::with_capacity(game.moves.len() + 1);
+        hashes.push(pos.zobrist_hash());
+        for uci in &game.moves {
+            let m = uci.to_move(&pos)?;
+            pos.play_unchecked(&m);
+            hashes.push(pos.zobrist_hash());
+        }
+        Ok(Fingerprint {
+            surnames: ByColor {
+                white: surname(&game.players.white.name),
+                black: surname(&game.players.black.name),
+            },
+            date: game.date.to_string(),
+            year: game.date.year(),
+            winner: game.winner,
+            positions: hashes.iter().copied().collect(),
+            hashes,
+        })
+    }
+
+    /// Hashes of the last positions before a move. Stored games that played
+    /// a move from any of them are candidates.
+    pub fn candidate_hashes(&self) -> &[u128] {
+        let before_moves = &self.hashes[..self.hashes.len() - 1];
+        &before_moves[before_moves.len().saturating_sub(CANDIDATE_PLIES)..]
+    }
+
+    /// Weighted similarity from 0 to 1. Positions are compared as sets, so
+    /// that transposed move orders still match.
+    pub fn similarity(&self, other: &Fingerprint) -> f64 {
+        let players = [Color::White, Color::Black]
+            .into_iter()
+            .filter(|color| self.surnames.get(*color) == other.surnames.get(*color))
+            .count() as f64
+            / 2.0;
+        let date = if self.date == other.date {
+            1.0
+        } else if self.year == other.year {
+            0.5
+        } else {
+            0.0
+        };
+        let result = if self.winner == other.winner {
+            1.0
+        } else {
+            0.0
+        };
+        let moves = match (self.hashes.len() as isize - other.hashes.len() as isize).abs() {
+            0 => 1.0,
+            1..=4 => 0.5,
+            _ => 0.0,
+        };
+        let positions = (self.sampled_overlap(other) + other.sampled_overlap(self)) / 2.0;
+
+        0.3 * players + 0.1 * date + 0.1 * result + 0.1 * moves + 0.4 * positions
+    }
+
+    fn sampled_overlap(&self, other: &Fingerprint) -> f64 {
+        let samples: Vec<u128> = self
+            .hashes
+            .iter()
+            .step_by(SAMPLE_EVERY)
+            .chain(self.hashes.last())
+            .copied()
+            .collect();
+        samples
+            .iter()
+            .filter(|hash| other.positions.contains(hash))
+            .count() as f64
+            / samples.len() as f64
+    }
+}
+
+/// Compares only surnames, which are spelled most consistently across
+/// sources.
+fn surname(name: &str) -> String {
+    name.split(',')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+}
+
+/// Imported masters games that are similar to a stored game, but not
+/// similar enough to be rejected outright.
+#[derive(Default)]
+pub struct DuplicateReview {
+    queue: Mutex<VecDeque<NearDuplicate>>,
+}
+
+impl DuplicateReview {
+    pub fn push(&self, near: NearDuplicate) {
+        let mut queue = self.queue.lock().expect("duplicate review");
+        if queue.len() >= MAX_REVIEW_QUEUE {
+            queue.pop_front();
+        }
+        queue.push_back(near);
+    }
+
+    pub fn pending(&self) -> Vec<NearDuplicate> {
+        self.queue
+            .lock()
+            .expect("duplicate review")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Removes all reviews of the game. Returns false if there were none.
+    pub fn dismiss(&self, id: GameId) -> bool {
+        let mut queue = self.queue.lock().expect("duplicate review");
+        let before = queue.len();
+        queue.retain(|near| near.id != id);
+        queue.len() < before
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game(white: &str, date: &str, moves: &str) -> MastersGame {
+        serde_json::from_value(serde_json::json!({
+            "event": "Test",
+            "site": "Test",
+            "date": date,
+            "round": "1",
+            "white": { "name": white, "rating": 2700 },
+            "black": { "name": "Nakamura, Hikaru", "rating": 2700 },
+            "winner": "white",
+            "moves": moves,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_similarity() {
+        let a = Fingerprint::new(&game(
+            "Carlsen, Magnus",
+            "2022.10.30",
+            "e2e4 e7e5 g1f3 b8c6 f1b5 a7a6",
+        ))
+        .unwrap();
+
+        // Same game with a differently spelled name.
+        let b = Fingerprint::new(&game(
+            "Carlsen, M.",
+            "2022.10.30",
+            "e2e4 e7e5 g1f3 b8c6 f1b5 a7a6",
+        ))
+        .unwrap();
+        assert!(a.similarity(&b) >= DUPLICATE_SIMILARITY);
+
+        // Same game with a transposed move order.
+        let c = Fingerprint::new(&game(
+            "Carlsen, Magnus",
+            "2022.10.30",
+            "g1f3 b8c6 e2e4 e7e5 f1b5 a7a6",
+        ))
+        .unwrap();
+        assert!(a.similarity(&c) >= DUPLICATE_SIMILARITY);
+
+        // Same moves by other players.
+        let d = Fingerprint::new(&game(
+            "Caruana, Fabiano",
+            "2019.05.01",
+            "e2e4 e7e5 g1f3 b8c6 f1b5 a7a6",
+        ))
+        .unwrap();
+        let similarity = a.similarity(&d);
+        assert!(similarity < DUPLICATE_SIMILARITY);
+        assert!(similarity >= NEAR_DUPLICATE_SIMILARITY);
+    }
+}
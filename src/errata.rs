@@ -0,0 +1,163 @@
+use std::sync::RwLock;
+
+use rustc_hash::FxHashMap;
+use shakmaty::{uci::Uci, ByColor, Color, Outcome};
+
+use crate::{
+    db::MastersDatabase,
+    importer::masters_without_loops,
+    model::{Erratum, GameId, KeyPrefix, MastersEntry, MastersGame, Year},
+};
+
+/// Corrections of masters games that have been submitted, but not yet folded
+/// into the stored entries. They are applied as an overlay when reading.
+#[derive(Default)]
+pub struct Errata {
+    inner: RwLock<ErrataInner>,
+}
+
+#[derive(Default)]
+struct ErrataInner {
+    pending: FxHashMap<GameId, Erratum>,
+    by_prefix: FxHashMap<KeyPrefix, Vec<Correction>>,
+}
+
+#[derive(Clone)]
+struct Contribution {
+    outcome: Outcome,
+    ratings: ByColor<u16>,
+    estimated: bool,
+}
+
+impl Contribution {
+    fn new(game: &MastersGame) -> Contribution {
+        Contribution {
+            outcome: Outcome::from_winner(game.winner),
+            ratings: ByColor {
+                white: game.players.white.rating,
+                black: game.players.black.rating,
+            },
+            estimated: game.has_estimated_rating(),
+        }
+    }
+
+    fn entry(&self, uci: Uci, turn: Color, id: GameId) -> MastersEntry {
+        MastersEntry::new_single(
+            uci,
+            id,
+            self.outcome,
+            *self.ratings.get(turn),
+            *self.ratings.get(!turn),
+            None,
+            self.estimated,
+        )
+    }
+}
+
+struct Correction {
+    id: GameId,
+    year: Year,
+    uci: Uci,
+    turn: Color,
+    before: Contribution,
+    after: Contribution,
+}
+
+impl Errata {
+    pub fn load(masters_db: &MastersDatabase<'_>) -> Errata {
+        let errata = Errata::default();
+        masters_db
+            .scan_errata(
+                |id, erratum| match masters_db.game(id).expect("get masters game") {
+                    Some(game) => errata.insert(id, &game, erratum),
+                    None => log::error!("erratum for unknown masters game {}", id),
+                },
+            )
+            .expect("scan masters errata");
+        errata
+    }
+
+    /// Registers a correction of the given stored game, replacing any
+    /// previous correction of the same game.
+    pub fn insert(&self, id: GameId, game: &MastersGame, erratum: Erratum) {
+        let mut corrected = game.clone();
+        erratum.apply(&mut corrected);
+        let before = Contribution::new(game);
+        let after = Contribution::new(&corrected);
+
+        let mut inner = self.inner.write().expect("errata");
+        inner.remove(id);
+        match masters_without_loops(game) {
+            Ok((without_loops, _)) => {
+                for (key, (uci, turn, _)) in without_loops {
+                    inner
+                        .by_prefix
+                        .entry(key.prefix())
+                        .or_default()
+                        .push(Correction {
+                            id,
+                            year: game.date.year(),
+                            uci,
+                            turn,
+                            before: before.clone(),
+                            after: after.clone(),
+                        });
+                }
+            }
+            Err(err) => log::error!("masters game {} not replayable: {}", id, err),
+        }
+        inner.pending.insert(id, erratum);
+    }
+
+    pub fn remove(&self, id: GameId) {
+        self.inner.write().expect("errata").remove(id);
+    }
+
+    pub fn pending(&self) -> Vec<(GameId, Erratum)> {
+        let inner = self.inner.read().expect("errata");
+        inner
+            .pending
+            .iter()
+            .map(|(id, erratum)| (*id, erratum.clone()))
+            .collect()
+    }
+
+    pub fn correct_entry(
+        &self,
+        key: &KeyPrefix,
+        since: Year,
+        until: Year,
+        entry: &mut MastersEntry,
+    ) {
+        let inner = self.inner.read().expect("errata");
+        for correction in inner.by_prefix.get(key).into_iter().flatten() {
+            if since <= correction.year && correction.year <= until {
+                entry.correct(
+                    correction
+                        .before
+                        .entry(correction.uci.clone(), correction.turn, correction.id),
+                    correction
+                        .after
+                        .entry(correction.uci.clone(), correction.turn, correction.id),
+                );
+            }
+        }
+    }
+
+    pub fn correct_game(&self, id: GameId, game: &mut MastersGame) {
+        if let Some(erratum) = self.inner.read().expect("errata").pending.get(&id) {
+            erratum.apply(game);
+        }
+    }
+}
+
+impl ErrataInner {
+    fn remove(&mut self, id: GameId) {
+        if self.pending.remove(&id).is_some() {
+            self.by_prefix.retain(|_, corrections| {
+                corrections.retain(|correction| correction.id != id);
+                !corrections.is_empty()
+            });
+        }
+    }
+}
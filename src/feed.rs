@@ -0,0 +1,48 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use rustc_hash::FxHashSet;
+use shakmaty::Outcome;
+
+use crate::{api::FeedSource, model::GameId};
+
+const CAPACITY: usize = 256;
+
+/// Ring buffers of the most recently imported notable games.
+#[derive(Default)]
+pub struct RecentGames {
+    masters: Mutex<VecDeque<GameId>>,
+    lichess: Mutex<VecDeque<GameId>>,
+}
+
+impl RecentGames {
+    /// Decisive games between strong players.
+    pub fn is_notable(outcome: Outcome, white_rating: u16, black_rating: u16) -> bool {
+        outcome.winner().is_some() && white_rating / 2 + black_rating / 2 >= 2500
+    }
+
+    fn buffer(&self, source: FeedSource) -> &Mutex<VecDeque<GameId>> {
+        match source {
+            FeedSource::Masters => &self.masters,
+            FeedSource::Lichess => &self.lichess,
+        }
+    }
+
+    pub fn push(&self, source: FeedSource, id: GameId) {
+        let mut guard = self.buffer(source).lock().expect("recent games");
+        if guard.len() >= CAPACITY {
+            guard.pop_back();
+        }
+        guard.push_front(id);
+    }
+
+    pub fn remove(&self, source: FeedSource, ids: &FxHashSet<GameId>) {
+        let mut guard = self.buffer(source).lock().expect("recent games");
+        guard.retain(|id| !ids.contains(id));
+    }
+
+    /// Most recent games first.
+    pub fn latest(&self, source: FeedSource, limit: usize) -> Vec<GameId> {
+        let guard = self.buffer(source).lock().expect("recent games");
+        guard.iter().copied().take(limit).collect()
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::hash_map::Entry;
+
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use shakmaty::{
+    fen::Fen,
+    san::SanPlus,
+    uci::Uci,
+    variant::{Variant, VariantPosition},
+    zobrist::Zobrist,
+    EnPassantMode, Position as _,
+};
+
+use crate::{
+    api::{ApiSchema, ChunkSender, GraphFormat, LichessQueryFilter, Limits},
+    db::LichessDatabase,
+    model::KeyBuilder,
+};
+
+const MAX_NODES: usize = 1000;
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct GraphNode {
+    id: usize,
+    #[serde_as(as = "DisplayFromStr")]
+    fen: Fen,
+    games: u64,
+}
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+pub struct GraphEdge {
+    from: usize,
+    to: usize,
+    #[serde_as(as = "DisplayFromStr")]
+    uci: Uci,
+    #[serde_as(as = "DisplayFromStr")]
+    san: SanPlus,
+    games: u64,
+}
+
+/// Part of the opening tree rooted at a position. Transpositions are merged
+/// into a single node, so this is a directed acyclic graph.
+pub struct OpeningGraph;
+
+impl OpeningGraph {
+    pub fn content_type(format: GraphFormat) -> &'static str {
+        match format {
+            GraphFormat::Dot => "text/vnd.graphviz",
+            GraphFormat::Json => "application/json",
+        }
+    }
+
+    /// Expands all moves that were played in at least `min_games` games, up
+    /// to the given depth. Nodes are written as soon as they are complete,
+    /// followed by all edges. Stops early if the client disconnects.
+    #[allow(clippy::too_many_arguments)]
+    pub fn export(
+        lichess_db: &LichessDatabase,
+        variant: Variant,
+        root: Zobrist<VariantPosition, u128>,
+        filter: &LichessQueryFilter,
+        depth: u32,
+        min_games: u64,
+        format: GraphFormat,
+        sender: ChunkSender,
+    ) {
+        let mut writer = GraphWriter {
+            format,
+            sender,
+            nodes: 0,
+            edges: 0,
+        };
+        if !writer.begin() {
+            return;
+        }
+
+        let read = |pos: &Zobrist<VariantPosition, u128>| {
+            lichess_db
+                .read_lichess(
+                    &KeyBuilder::lichess().with_zobrist(variant, pos.zobrist_hash()),
+                    filter.since,
+                    filter.until,
+                )
+                .expect("get lichess")
+                .prepare(filter, &Limits::default())
+        };
+
+        let mut ids: FxHashMap<u128, usize> = FxHashMap::default();
+        let mut edges = Vec::new();
+
+        ids.insert(root.zobrist_hash(), 0);
+        let mut layer = vec![(
+            GraphNode {
+                id: 0,
+                fen: Fen::from_position(root.as_inner().clone(), EnPassantMode::Legal),
+                games: 0,
+            },
+            root,
+        )];
+
+        for _ in 0..depth {
+            let mut next_layer = Vec::new();
+
+            for (mut node, pos) in layer {
+                let prepared = read(&pos);
+                node.games = prepared.total.total();
+
+                for p in prepared.moves {
+                    let games = p.stats.total();
+                    if games < min_games {
+                        continue;
+                    }
+                    let m = match p.uci.to_move(&pos) {
+                        Ok(m) => m,
+                        Err(_) => continue,
+                    };
+                    let san = SanPlus::from_move(pos.clone(), &m);
+                    let mut child = pos.clone();
+                    child.play_unchecked(&m);
+
+                    let num_nodes = ids.len();
+                    let child_id = match ids.entry(child.zobrist_hash()) {
+                        Entry::Occupied(entry) => *entry.get(),
+                        Entry::Vacant(entry) => {
+                            if num_nodes >= MAX_NODES {
+                                continue;
+                            }
+                            entry.insert(num_nodes);
+                            next_layer.push((
+                                GraphNode {
+                                    id: num_nodes,
+                                    fen: Fen::from_position(
+                                        child.as_inner().clone(),
+                                        EnPassantMode::Legal,
+                                    ),
+                                    games: 0,
+                                },
+                                child,
+                            ));
+                            num_nodes
+                        }
+                    };
+
+                    edges.push(GraphEdge {
+                        from: node.id,
+                        to: child_id,
+                        uci: p.uci,
+                        san,
+                        games,
+                    });
+                }
+
+                if !writer.node(&node) {
+                    return;
+                }
+            }
+
+            layer = next_layer;
+        }
+
+        // Leaves are not expanded, but their totals still come from their
+        // own entries, so that transpositions count all games.
+        for (mut node, pos) in layer {
+            node.games = read(&pos).total.total();
+            if !writer.node(&node) {
+                return;
+            }
+        }
+
+        for edge in &edges {
+            if !writer.edge(edge) {
+                return;
+            }
+        }
+
+        writer.end();
+    }
+}
+
+struct GraphWriter {
+    format: GraphFormat,
+    sender: ChunkSender,
+    nodes: usize,
+    edges: usize,
+}
+
+impl GraphWriter {
+    fn begin(&mut self) -> bool {
+        self.sender.send(match self.format {
+            GraphFormat::Dot => "digraph opening {\n",
+            GraphFormat::Json => "{\"nodes\":[",
+        })
+    }
+
+    fn node(&mut self, node: &GraphNode) -> bool {
+        let chunk = match self.format {
+            GraphFormat::Dot => format!(
+                "  n{} [label=\"{}\", tooltip=\"{}\"];\n",
+                node.id, node.games, node.fen
+            ),
+            GraphFormat::Json => format!(
+                "{}{}",
+                if self.nodes > 0 { "," } else { "" },
+                serde_json::to_string(node).expect("serialize node")
+            ),
+        };
+        self.nodes += 1;
+        self.sender.send(chunk)
+    }
+
+    fn edge(&mut self, edge: &GraphEdge) -> bool {
+        let chunk = match self.format {
+            GraphFormat::Dot => format!(
+                "  n{} -> n{} [label=\"{}\"];\n",
+                edge.from, edge.to, edge.san
+            ),
+            GraphFormat::Json => format!(
+                "{}{}",
+                if self.edges > 0 { "," } else { "],\"edges\":[" },
+                serde_json::to_string(edge).expect("serialize edge")
+            ),
+        };
+        self.edges += 1;
+        self.sender.send(chunk)
+    }
+
+    fn end(&mut self) -> bool {
+        self.sender.send(match self.format {
+            GraphFormat::Dot => "}\n",
+            GraphFormat::Json if self.edges > 0 => "]}",
+            GraphFormat::Json => "],\"edges\":[]}",
+        })
+    }
+}
@@ -0,0 +1,286 @@
+use std::sync::Arc;
+
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, Object, Request, Response, Schema, SimpleObject,
+};
+use axum::{extract::Extension, Json};
+use serde::de::DeserializeOwned;
+use tokio::task;
+
+use crate::{
+    api::{
+        Error, ExplorerGame, ExplorerGameWithUci, ExplorerMove, ExplorerResponse, LichessQuery,
+        Limits, MastersQuery, PlayerQuery, PositionCache,
+    },
+    branching::BranchingFactors,
+    db::Database,
+    deadline::Deadline,
+    errata::Errata,
+    lichess_response, masters_response,
+    model::GamePlayer,
+    opening::Openings,
+    snapshots::Snapshots,
+    PlayerStreamState,
+};
+
+pub type ExplorerSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+pub fn schema() -> ExplorerSchema {
+    Schema::new(QueryRoot, EmptyMutation, EmptySubscription)
+}
+
+/// Shared state that resolvers need, attached to each request.
+#[derive(Clone)]
+struct Data {
+    openings: &'static Openings,
+    positions: Arc<PositionCache>,
+    branching_factors: &'static BranchingFactors,
+    db: Arc<Database>,
+    snapshots: Arc<Snapshots>,
+    errata: Arc<Errata>,
+    deadline: Deadline,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn handler(
+    Extension(schema): Extension<ExplorerSchema>,
+    Extension(openings): Extension<&'static Openings>,
+    Extension(positions): Extension<Arc<PositionCache>>,
+    Extension(branching_factors): Extension<&'static BranchingFactors>,
+    Extension(db): Extension<Arc<Database>>,
+    Extension(snapshots): Extension<Arc<Snapshots>>,
+    Extension(errata): Extension<Arc<Errata>>,
+    deadline: Deadline,
+    Json(req): Json<Request>,
+) -> Json<Response> {
+    let _cancel = deadline.cancel_on_drop();
+
+    Json(
+        schema
+            .execute(req.data(Data {
+                openings,
+                positions,
+                branching_factors,
+                db,
+                snapshots,
+                errata,
+                deadline,
+            }))
+            .await,
+    )
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Masters database. Takes the parameters of `/masters`, as a URL
+    /// encoded query string like `fen=...&since=1990`.
+    async fn masters(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] params: String,
+    ) -> async_graphql::Result<Explorer> {
+        let mut query: MastersQuery = parse_params(&params)?;
+        skip_unselected_games(ctx, &mut query.limits);
+        let data = ctx.data_unchecked::<Data>().clone();
+        let res = task::spawn_blocking(move || {
+            masters_response(
+                data.openings,
+                &data.positions,
+                data.branching_factors,
+                &data.snapshots.get(&data.db, query.as_of)?,
+                &data.errata,
+                &data.deadline,
+                query,
+                None,
+            )
+        })
+        .await
+        .expect("blocking graphql masters")?;
+        Ok(Explorer::from(res))
+    }
+
+    /// Lichess database. Takes the parameters of `/lichess`, as a URL
+    /// encoded query string.
+    async fn lichess(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] params: String,
+    ) -> async_graphql::Result<Explorer> {
+        let mut query: LichessQuery = parse_params(&params)?;
+        skip_unselected_games(ctx, &mut query.limits);
+        let data = ctx.data_unchecked::<Data>().clone();
+        let res = task::spawn_blocking(move || {
+            lichess_response(
+                data.openings,
+                &data.positions,
+                data.branching_factors,
+                &data.snapshots.get(&data.db, query.as_of)?,
+                &data.deadline,
+                query,
+            )
+        })
+        .await
+        .expect("blocking graphql lichess")?;
+        Ok(Explorer::from(res))
+    }
+
+    /// Games of a player, as far as they are already indexed. Unlike
+    /// `/player`, this does not start indexing new games. Takes the
+    /// parameters of `/player`, as a URL encoded query string.
+    async fn player(&self, ctx: &Context<'_>, params: String) -> async_graphql::Result<Explorer> {
+        let mut query: PlayerQuery = parse_params(&params)?;
+        skip_unselected_games(ctx, &mut query.limits);
+        let data = ctx.data_unchecked::<Data>().clone();
+        let res = task::spawn_blocking(move || {
+            Ok::<_, Error>(
+                PlayerStreamState::new(data.openings, &data.positions, data.db, None, query)?
+                    .response(),
+            )
+        })
+        .await
+        .expect("blocking graphql player")?;
+        Ok(Explorer::from(res))
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: &str) -> async_graphql::Result<T> {
+    serde_urlencoded::from_str(params).map_err(|err| format!("bad request: {}", err).into())
+}
+
+/// Looking up games is the most expensive part of a response, so do not
+/// bother if they are not selected.
+fn skip_unselected_games(ctx: &Context<'_>, limits: &mut Limits) {
+    let selection = ctx.look_ahead();
+    if !selection.field("topGames").exists() {
+        limits.top_games = Some(0);
+    }
+    if !selection.field("recentGames").exists() {
+        limits.recent_games = Some(0);
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Explorer {
+    white: u64,
+    draws: u64,
+    black: u64,
+    distinct_moves: usize,
+    avg_remaining_plies: Option<u64>,
+    opening: Option<Opening>,
+    moves: Vec<Move>,
+    recent_games: Vec<Game>,
+    top_games: Vec<Game>,
+}
+
+impl From<ExplorerResponse> for Explorer {
+    fn from(res: ExplorerResponse) -> Explorer {
+        Explorer {
+            white: res.total.white,
+            draws: res.total.draws,
+            black: res.total.black,
+            distinct_moves: res.distinct_moves,
+            avg_remaining_plies: res.avg_remaining_plies,
+            opening: res.opening.map(|opening| Opening {
+                eco: opening.eco().to_owned(),
+                name: opening.name().to_owned(),
+            }),
+            moves: res.moves.into_iter().map(Move::from).collect(),
+            recent_games: res
+                .recent_games
+                .unwrap_or_default()
+                .into_iter()
+                .map(Game::from)
+                .collect(),
+            top_games: res
+                .top_games
+                .unwrap_or_default()
+                .into_iter()
+                .map(Game::from)
+                .collect(),
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Opening {
+    eco: String,
+    name: String,
+}
+
+#[derive(SimpleObject)]
+pub struct Move {
+    uci: String,
+    san: String,
+    white: Option<u64>,
+    draws: Option<u64>,
+    black: Option<u64>,
+    average_rating: Option<u64>,
+    performance: Option<i64>,
+    performance_delta: Option<f64>,
+    game: Option<Game>,
+}
+
+impl From<ExplorerMove> for Move {
+    fn from(m: ExplorerMove) -> Move {
+        Move {
+            uci: m.uci.to_string(),
+            san: m.san.to_string(),
+            white: m.stats.as_ref().map(|stats| stats.white),
+            draws: m.stats.as_ref().map(|stats| stats.draws),
+            black: m.stats.as_ref().map(|stats| stats.black),
+            average_rating: m.average_rating,
+            performance: m.performance,
+            performance_delta: m.performance_delta,
+            game: m.game.map(|game| Game::new(None, game)),
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Game {
+    /// Move played in the game, for top and recent games.
+    uci: Option<String>,
+    id: String,
+    winner: Option<String>,
+    white: Player,
+    black: Player,
+    year: u16,
+    month: Option<String>,
+}
+
+impl Game {
+    fn new(uci: Option<String>, game: ExplorerGame) -> Game {
+        Game {
+            uci,
+            id: game.id.to_string(),
+            winner: game.winner.map(|color| color.to_string()),
+            white: Player::from(game.players.white),
+            black: Player::from(game.players.black),
+            year: u16::from(game.year),
+            month: game.month.map(|month| month.to_string()),
+        }
+    }
+}
+
+impl From<ExplorerGameWithUci> for Game {
+    fn from(game: ExplorerGameWithUci) -> Game {
+        Game::new(Some(game.uci.to_string()), game.row)
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Player {
+    name: String,
+    rating: u16,
+}
+
+impl From<GamePlayer> for Player {
+    fn from(player: GamePlayer) -> Player {
+        Player {
+            name: player.name,
+            rating: player.rating,
+        }
+    }
+}
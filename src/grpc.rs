@@ -0,0 +1,372 @@
+use std::{net::SocketAddr, pin::Pin, sync::Arc};
+
+use futures_util::stream::{Stream, StreamExt as _};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use tokio::task::{self, JoinHandle};
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+
+use crate::{
+    api::{
+        Error, ExplorerGame, ExplorerGameWithUci, ExplorerMove, ExplorerResponse, LichessQuery,
+        MastersQuery, PlayerQuery, PositionCache,
+    },
+    branching::BranchingFactors,
+    db::Database,
+    deadline::Deadline,
+    errata::Errata,
+    importer::{LichessGameImport, LichessImporter, MastersImporter},
+    indexer::IndexerStub,
+    lichess_response, masters_response,
+    model::{GamePlayer, LichessSource, MastersGameWithId, Stats, UserId},
+    opening::Openings,
+    player_stream,
+    snapshots::Snapshots,
+    PlayerStreamState,
+};
+
+mod proto {
+    tonic::include_proto!("explorer");
+}
+
+use proto::explorer_server::{Explorer, ExplorerServer};
+
+pub struct ExplorerService {
+    pub openings: &'static Openings,
+    pub positions: Arc<PositionCache>,
+    pub branching_factors: &'static BranchingFactors,
+    pub db: Arc<Database>,
+    pub snapshots: Arc<Snapshots>,
+    pub errata: Arc<Errata>,
+    pub indexer: IndexerStub,
+    pub masters_importer: MastersImporter,
+    pub lichess_importer: LichessImporter,
+}
+
+impl ExplorerService {
+    pub fn spawn(self, bind: SocketAddr) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            Server::builder()
+                .add_service(ExplorerServer::new(self))
+                .serve(bind)
+                .await
+                .expect("bind grpc");
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Explorer for ExplorerService {
+    async fn masters(
+        &self,
+        request: Request<proto::Query>,
+    ) -> Result<Response<proto::ExplorerResponse>, Status> {
+        let query: MastersQuery = parse_params(&request.get_ref().params)?;
+        let openings = self.openings;
+        let positions = Arc::clone(&self.positions);
+        let branching_factors = self.branching_factors;
+        let db = Arc::clone(&self.db);
+        let snapshots = Arc::clone(&self.snapshots);
+        let errata = Arc::clone(&self.errata);
+        let deadline = Deadline::never();
+        let _cancel = deadline.cancel_on_drop();
+        let res = task::spawn_blocking(move || {
+            masters_response(
+                openings,
+                &positions,
+                branching_factors,
+                &snapshots.get(&db, query.as_of)?,
+                &errata,
+                &deadline,
+                query,
+                None,
+            )
+        })
+        .await
+        .expect("blocking grpc masters")?;
+        Ok(Response::new(res.into()))
+    }
+
+    async fn lichess(
+        &self,
+        request: Request<proto::Query>,
+    ) -> Result<Response<proto::ExplorerResponse>, Status> {
+        let query: LichessQuery = parse_params(&request.get_ref().params)?;
+        let openings = self.openings;
+        let positions = Arc::clone(&self.positions);
+        let branching_factors = self.branching_factors;
+        let db = Arc::clone(&self.db);
+        let snapshots = Arc::clone(&self.snapshots);
+        let deadline = Deadline::never();
+        let _cancel = deadline.cancel_on_drop();
+        let res = task::spawn_blocking(move || {
+            lichess_response(
+                openings,
+                &positions,
+                branching_factors,
+                &snapshots.get(&db, query.as_of)?,
+                &deadline,
+                query,
+            )
+        })
+        .await
+        .expect("blocking grpc lichess")?;
+        Ok(Response::new(res.into()))
+    }
+
+    type PlayerStream =
+        Pin<Box<dyn Stream<Item = Result<proto::ExplorerResponse, Status>> + Send + 'static>>;
+
+    async fn player(
+        &self,
+        request: Request<proto::Query>,
+    ) -> Result<Response<Self::PlayerStream>, Status> {
+        let query: PlayerQuery = parse_params(&request.get_ref().params)?;
+        let indexing = self
+            .indexer
+            .index_player(&UserId::from(query.player.clone()))
+            .await;
+        let state = PlayerStreamState::new(
+            self.openings,
+            &self.positions,
+            Arc::clone(&self.db),
+            indexing,
+            query,
+        )?;
+        let stream: Self::PlayerStream = Box::pin(player_stream(state).map(|res| Ok(res.into())));
+        Ok(Response::new(stream))
+    }
+
+    async fn import_masters(
+        &self,
+        request: Request<Streaming<proto::MastersGame>>,
+    ) -> Result<Response<proto::ImportReport>, Status> {
+        let mut games = request.into_inner();
+        let mut report = proto::ImportReport::default();
+        let mut index = 0;
+        while let Some(game) = games.message().await? {
+            let id = game.id.clone();
+            let res = match serde_json::from_value::<MastersGameWithId>(game.into_json()) {
+                Ok(game) => self
+                    .masters_importer
+                    .import(game)
+                    .await
+                    .map_err(|err| err.to_string()),
+                Err(err) => Err(format!("bad request: {}", err)),
+            };
+            report.record(index, id, res);
+            index += 1;
+        }
+        Ok(Response::new(report))
+    }
+
+    async fn import_lichess(
+        &self,
+        request: Request<Streaming<proto::LichessGame>>,
+    ) -> Result<Response<proto::ImportReport>, Status> {
+        let mut games = request.into_inner();
+        let mut report = proto::ImportReport::default();
+        let mut index = 0;
+        while let Some(game) = games.message().await? {
+            let id = game.id.clone();
+            let source = match game.source.as_str() {
+                "" => Ok(LichessSource::default()),
+                source => source.parse::<LichessSource>(),
+            };
+            let res = match (
+                source,
+                serde_json::from_value::<LichessGameImport>(game.into_json()),
+            ) {
+                (Ok(source), Ok(game)) => self
+                    .lichess_importer
+                    .import(game, source)
+                    .await
+                    .map_err(|err| err.to_string()),
+                (Err(err), _) => Err(format!("bad request: {}", err)),
+                (_, Err(err)) => Err(format!("bad request: {}", err)),
+            };
+            report.record(index, id, res);
+            index += 1;
+        }
+        Ok(Response::new(report))
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: &str) -> Result<T, Status> {
+    serde_urlencoded::from_str(params)
+        .map_err(|err| Status::invalid_argument(format!("bad request: {}", err)))
+}
+
+impl From<Error> for Status {
+    fn from(err: Error) -> Status {
+        let message = err.to_string();
+        match err {
+            Error::ReadOnly => Status::unavailable(message),
+            Error::UnknownApiKey => Status::unauthenticated(message),
+            Error::DeadlineExceeded => Status::deadline_exceeded(message),
+            Error::NoSnapshot(_) => Status::not_found(message),
+            Error::BackupFailed(_) | Error::DeltaFailed(_) | Error::SnapshotFailed(_) => {
+                Status::internal(message)
+            }
+            _ => Status::invalid_argument(message),
+        }
+    }
+}
+
+impl proto::ImportReport {
+    fn record(&mut self, index: u64, id: String, res: Result<(), String>) {
+        match res {
+            Ok(()) => self.imported.push(id),
+            Err(error) => self
+                .rejected
+                .push(proto::ImportRejection { index, id, error }),
+        }
+    }
+}
+
+// Imported games are converted to the structure of the JSON import, so that
+// they are validated exactly the same way.
+
+fn non_empty(s: String) -> Option<String> {
+    Some(s).filter(|s| !s.is_empty())
+}
+
+fn join<T: ToString>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl proto::MastersGame {
+    fn into_json(self) -> Value {
+        let player = |player: Option<proto::Player>| {
+            let player = player.unwrap_or_default();
+            json!({ "name": player.name, "rating": player.rating })
+        };
+        json!({
+            "id": self.id,
+            "event": self.event,
+            "site": self.site,
+            "date": self.date,
+            "round": self.round,
+            "white": player(self.white),
+            "black": player(self.black),
+            "winner": non_empty(self.winner),
+            "moves": join(&self.moves),
+            "nags": Some(join(&self.nags)).filter(|_| !self.nags.is_empty()),
+            "fen": non_empty(self.fen),
+        })
+    }
+}
+
+impl proto::LichessGame {
+    fn into_json(self) -> Value {
+        let player = |player: Option<proto::LichessPlayer>| {
+            let player = player.unwrap_or_default();
+            json!({
+                "name": player.name,
+                "rating": player.rating,
+                "provisional": player.provisional,
+                "bot": player.bot,
+            })
+        };
+        json!({
+            "id": self.id,
+            "variant": non_empty(self.variant),
+            "speed": non_empty(self.speed),
+            "clock": non_empty(self.clock),
+            "fen": non_empty(self.fen),
+            "date": self.date,
+            "white": player(self.white),
+            "black": player(self.black),
+            "winner": non_empty(self.winner),
+            "moves": join(&self.moves),
+            "times": Some(join(&self.times)).filter(|_| !self.times.is_empty()),
+        })
+    }
+}
+
+impl From<Stats> for proto::Stats {
+    fn from(stats: Stats) -> proto::Stats {
+        proto::Stats {
+            white: stats.white,
+            draws: stats.draws,
+            black: stats.black,
+        }
+    }
+}
+
+impl From<ExplorerResponse> for proto::ExplorerResponse {
+    fn from(res: ExplorerResponse) -> proto::ExplorerResponse {
+        proto::ExplorerResponse {
+            total: Some(res.total.into()),
+            distinct_moves: res.distinct_moves as u64,
+            avg_remaining_plies: res.avg_remaining_plies.unwrap_or(0),
+            opening: res.opening.map(|opening| proto::Opening {
+                eco: opening.eco().to_owned(),
+                name: opening.name().to_owned(),
+            }),
+            moves: res.moves.into_iter().map(proto::Move::from).collect(),
+            recent_games: res
+                .recent_games
+                .unwrap_or_default()
+                .into_iter()
+                .map(proto::Game::from)
+                .collect(),
+            top_games: res
+                .top_games
+                .unwrap_or_default()
+                .into_iter()
+                .map(proto::Game::from)
+                .collect(),
+        }
+    }
+}
+
+impl From<ExplorerMove> for proto::Move {
+    fn from(m: ExplorerMove) -> proto::Move {
+        proto::Move {
+            uci: m.uci.to_string(),
+            san: m.san.to_string(),
+            stats: m.stats.map(proto::Stats::from),
+            average_rating: m.average_rating.unwrap_or(0),
+            performance: m.performance.unwrap_or(0),
+            game: m.game.map(|game| proto::Game::new(String::new(), game)),
+        }
+    }
+}
+
+impl proto::Game {
+    fn new(uci: String, game: ExplorerGame) -> proto::Game {
+        proto::Game {
+            uci,
+            id: game.id.to_string(),
+            winner: game
+                .winner
+                .map_or_else(String::new, |color| color.to_string()),
+            white: Some(game.players.white.into()),
+            black: Some(game.players.black.into()),
+            year: u32::from(u16::from(game.year)),
+            month: game
+                .month
+                .map_or_else(String::new, |month| month.to_string()),
+        }
+    }
+}
+
+impl From<ExplorerGameWithUci> for proto::Game {
+    fn from(game: ExplorerGameWithUci) -> proto::Game {
+        proto::Game::new(game.uci.to_string(), game.row)
+    }
+}
+
+impl From<GamePlayer> for proto::Player {
+    fn from(player: GamePlayer) -> proto::Player {
+        proto::Player {
+            name: player.name,
+            rating: u32::from(player.rating),
+        }
+    }
+}
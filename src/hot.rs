@@ -0,0 +1,259 @@
+use std::{collections::BinaryHeap, fmt, io::Cursor, sync::RwLock};
+
+use clap::Parser;
+use rustc_hash::{FxHashMap, FxHashSet};
+use shakmaty::{
+    variant::{Variant, VariantPosition},
+    zobrist::Zobrist,
+    Position as _,
+};
+
+use crate::{
+    api::{ExampleGame, LichessQueryFilter, Limits},
+    db::LichessDatabase,
+    model::{GameId, KeyBuilder, KeyPrefix, LichessEntry, Month, PreparedResponse},
+};
+
+#[derive(Parser, Clone)]
+pub struct HotOpt {
+    /// Number of the most played lichess positions, starting with the
+    /// initial position, that are kept in memory.
+    #[clap(long = "hot-positions", default_value = "100")]
+    hot_positions: usize,
+}
+
+/// Lichess entries of the most frequently requested positions, merged over
+/// all months and kept up to date by the write batches that change them, so
+/// that requests without a date range can be answered without touching the
+/// database. Owned by the database, so that snapshots have none.
+#[derive(Default)]
+pub struct HotPositions {
+    entries: RwLock<FxHashMap<KeyPrefix, HotEntry>>,
+}
+
+struct HotEntry {
+    /// `None` after the entry was replaced in the database, until it is
+    /// read again.
+    entry: Option<LichessEntry>,
+    /// Response for the default filter, prepared at most once per change.
+    prepared: Option<PreparedResponse>,
+}
+
+/// Changes of a write batch to the entries that hot positions are merged
+/// from.
+#[derive(Default)]
+pub struct HotChanges {
+    merged: Vec<(KeyPrefix, Vec<u8>)>,
+    replaced: Vec<KeyPrefix>,
+}
+
+impl HotChanges {
+    /// Records a serialized entry that is merged into a monthly entry of
+    /// the position.
+    pub fn merge(&mut self, hot: &HotPositions, key: KeyPrefix, buf: &[u8]) {
+        if hot.contains(&key) {
+            self.merged.push((key, buf.to_vec()));
+        }
+    }
+
+    /// Records that a monthly entry of the position is replaced.
+    pub fn replace(&mut self, hot: &HotPositions, key: KeyPrefix) {
+        if hot.contains(&key) {
+            self.replaced.push(key);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.merged.is_empty() && self.replaced.is_empty()
+    }
+}
+
+impl HotPositions {
+    /// Selects and loads the hot positions, by repeatedly expanding the
+    /// position that was reached in the most games.
+    pub fn load(&self, opt: HotOpt, lichess_db: &LichessDatabase) {
+        let mut entries = FxHashMap::default();
+        let mut positions = Vec::new();
+        let mut queue = BinaryHeap::new();
+
+        positions.push(Zobrist::<_, u128>::new(VariantPosition::new(
+            Variant::Chess,
+        )));
+        queue.push((u64::MAX, 0));
+
+        let filter = LichessQueryFilter::default();
+        let limits = Limits::default();
+
+        while let Some((_, idx)) = queue.pop() {
+            if entries.len() >= opt.hot_positions {
+                break;
+            }
+
+            let pos: Zobrist<VariantPosition, u128> = positions[idx].clone();
+            let key = KeyBuilder::lichess().with_zobrist(Variant::Chess, pos.zobrist_hash());
+            if entries.contains_key(&key) {
+                continue; // Transposition
+            }
+
+            let entry = lichess_db
+                .read_lichess(&key, Month::default(), Month::max_value())
+                .expect("get hot lichess entry");
+            let prepared = entry.clone().prepare(&filter, &limits);
+
+            for p in &prepared.moves {
+                if let Ok(m) = p.uci.to_move(&pos) {
+                    let mut child = pos.clone();
+                    child.play_unchecked(&m);
+                    queue.push((p.stats.total(), positions.len()));
+                    positions.push(child);
+                }
+            }
+
+            entries.insert(
+                key,
+                HotEntry {
+                    entry: Some(entry),
+                    prepared: Some(prepared),
+                },
+            );
+        }
+
+        log::info!("loaded {} hot positions", entries.len());
+
+        *self.entries.write().expect("hot positions") = entries;
+    }
+
+    fn contains(&self, key: &KeyPrefix) -> bool {
+        self.entries
+            .read()
+            .expect("hot positions")
+            .contains_key(key)
+    }
+
+    /// Prepares the response of a hot position, merged over all months.
+    /// Responses for the default filter are only prepared once per change.
+    /// Returns `None` if the position is not hot.
+    pub fn prepare<F: FnOnce() -> LichessEntry>(
+        &self,
+        key: &KeyPrefix,
+        filter: &LichessQueryFilter,
+        limits: &Limits,
+        read: F,
+    ) -> Option<PreparedResponse> {
+        let default = is_default(filter, limits);
+
+        {
+            let guard = self.entries.read().expect("hot positions");
+            let hot = guard.get(key)?;
+            match (&hot.entry, &hot.prepared) {
+                (Some(_), Some(prepared)) if default => return Some(prepared.clone()),
+                (Some(entry), _) if !default => return Some(entry.clone().prepare(filter, limits)),
+                _ => (),
+            }
+        }
+
+        let mut guard = self.entries.write().expect("hot positions");
+        let hot = guard.get_mut(key)?;
+        let entry = hot.entry.get_or_insert_with(read);
+        Some(if default {
+            hot.prepared
+                .get_or_insert_with(|| entry.clone().prepare(filter, limits))
+                .clone()
+        } else {
+            entry.clone().prepare(filter, limits)
+        })
+    }
+
+    /// Calls `f` with the entry of a hot position, merged over all months.
+    /// Returns `None` if the position is not hot.
+    pub fn with_entry<T, R, F>(&self, key: &KeyPrefix, read: R, f: F) -> Option<T>
+    where
+        R: FnOnce() -> LichessEntry,
+        F: FnOnce(&LichessEntry) -> T,
+    {
+        {
+            let guard = self.entries.read().expect("hot positions");
+            if let Some(entry) = &guard.get(key)?.entry {
+                return Some(f(entry));
+            }
+        }
+
+        let mut guard = self.entries.write().expect("hot positions");
+        let hot = guard.get_mut(key)?;
+        Some(f(hot.entry.get_or_insert_with(read)))
+    }
+
+    /// Writes a batch and applies its changes. Readers wait until both are
+    /// done, so that a replaced entry that is read again concurrently
+    /// neither misses nor repeats a merge.
+    pub fn commit<E, F: FnOnce() -> Result<(), E>>(
+        &self,
+        changes: HotChanges,
+        write: F,
+    ) -> Result<(), E> {
+        if changes.is_empty() {
+            return write();
+        }
+
+        let mut guard = self.entries.write().expect("hot positions");
+        write()?;
+
+        for (key, buf) in changes.merged {
+            if let Some(hot) = guard.get_mut(&key) {
+                if let Some(entry) = &mut hot.entry {
+                    entry
+                        .extend_from_reader(&mut Cursor::new(buf))
+                        .expect("deserialize lichess entry");
+                    // Also drops games that are no longer among the top and
+                    // recent games, so that the entry does not grow without
+                    // bound.
+                    entry.truncate_games();
+                }
+                hot.prepared = None;
+            }
+        }
+
+        for key in changes.replaced {
+            if let Some(hot) = guard.get_mut(&key) {
+                hot.entry = None;
+                hot.prepared = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes references to the given games from all hot positions, after
+    /// they were removed from the database.
+    pub fn prune_games(&self, ids: &FxHashSet<GameId>) {
+        let mut guard = self.entries.write().expect("hot positions");
+        for hot in guard.values_mut() {
+            if let Some(entry) = &mut hot.entry {
+                if entry.prune_games(ids) {
+                    hot.prepared = None;
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for HotPositions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HotPositions")
+            .field(
+                "positions",
+                &self.entries.read().expect("hot positions").len(),
+            )
+            .finish()
+    }
+}
+
+/// Whether the response can be shared with all requests that do not select
+/// anything specific.
+fn is_default(filter: &LichessQueryFilter, limits: &Limits) -> bool {
+    filter.canonical() == LichessQueryFilter::default().canonical()
+        && !filter.group_by_speed
+        && filter.example_game == ExampleGame::Single
+        && limits.top_games.is_none()
+        && limits.recent_games.is_none()
+}
@@ -1,7 +1,15 @@
-use std::sync::Arc;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
 
-use rustc_hash::FxHashMap;
-use serde::Deserialize;
+use clap::Parser;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr, SpaceSeparator, StringWithSeparator};
 use shakmaty::{
     fen::Fen,
@@ -9,37 +17,291 @@ use shakmaty::{
     uci::Uci,
     variant::{Variant, VariantPosition},
     zobrist::Zobrist,
-    ByColor, CastlingMode, Chess, Color, Outcome, Position,
+    ByColor, CastlingMode, Color, Outcome, Position,
+};
+use tokio::{
+    sync::Mutex,
+    task::{self, JoinHandle},
+    time,
 };
-use tokio::sync::Mutex;
 
 use crate::{
-    api::{Error, LilaVariant},
+    api::{Error, FeedSource, LilaVariant, NearDuplicate},
     db::Database,
+    duplicates::{DuplicateReview, Fingerprint, DUPLICATE_SIMILARITY, NEAR_DUPLICATE_SIMILARITY},
+    errata::Errata,
+    feed::RecentGames,
     model::{
-        GameId, GamePlayer, Key, KeyBuilder, LaxDate, LichessEntry, LichessGame, MastersEntry,
-        MastersGameWithId, Mode, Speed, Year,
+        Clock, Erratum, GameId, GamePlayer, Key, KeyBuilder, KeyPrefix, LaxDate, LichessEntry,
+        LichessGame, LichessSource, MastersEntry, MastersGame, MastersGameWithId, Mode,
+        MoveDetails, MoveOrderEntry, MoveOrderStep, ParentEntry, PathHash, Speed, TenantId,
+        TrackedImport, Week, Year,
     },
+    publisher::{ImportEvent, ImportSource, Publisher},
+    rating_distribution::RatingDistribution,
+    subscriptions::{PositionUpdates, Subscriptions},
     util::ByColorDef,
+    variants::VariantStats,
 };
 
 const MAX_PLIES: usize = 40;
 
+/// Games are also indexed by week, if they are at most this many weeks old.
+/// Older weekly entries are pruned periodically.
+const MAX_WEEKS_RECENT: u16 = 8;
+
+/// Imported lichess games are tracked for this long, in case they turn out
+/// to have been aborted.
+const MAX_TRACKING_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+const ERRATA_FOLD_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// References to retracted or erased games are pruned in bulk, because each
+/// pruning scans all lichess and player entries.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const MAX_POLICY_CHANGES: usize = 100;
+
+const MIN_RATING: u16 = 400;
+const MAX_RATING: u16 = 3500;
+
+#[derive(Parser, Clone)]
+pub struct ImportOpt {
+    /// Clamp implausible ratings of imported games into the plausible range
+    /// and accept games with provisional ratings, instead of rejecting them.
+    #[clap(long = "import-clamp-ratings")]
+    clamp_ratings: bool,
+}
+
+#[derive(thiserror::Error, Debug, Copy, Clone)]
+pub enum ImplausibleRating {
+    #[error("rating below {}", MIN_RATING)]
+    TooLow,
+    #[error("rating above {}", MAX_RATING)]
+    TooHigh,
+    #[error("provisional rating")]
+    Provisional,
+}
+
+/// Sanity checks for the ratings of imported games, so that garbage does
+/// not skew average ratings.
+#[derive(Default)]
+pub struct RatingChecks {
+    clamp: bool,
+    too_low: AtomicU64,
+    too_high: AtomicU64,
+    provisional: AtomicU64,
+    clamped: AtomicU64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RatingCounters {
+    too_low: u64,
+    too_high: u64,
+    provisional: u64,
+    clamped: u64,
+}
+
+impl RatingChecks {
+    pub fn new(opt: ImportOpt) -> RatingChecks {
+        RatingChecks {
+            clamp: opt.clamp_ratings,
+            ..Default::default()
+        }
+    }
+
+    /// Checks both players, so that the counters include implausible ratings
+    /// of either player, even if the game is rejected.
+    pub fn check(&self, players: &mut ByColor<GamePlayer>) -> Result<(), ImplausibleRating> {
+        let mut rejected = None;
+        for player in [&mut players.white, &mut players.black] {
+            let reason = if player.rating < MIN_RATING {
+                self.too_low.fetch_add(1, Ordering::Relaxed);
+                ImplausibleRating::TooLow
+            } else if player.rating > MAX_RATING {
+                self.too_high.fetch_add(1, Ordering::Relaxed);
+                ImplausibleRating::TooHigh
+            } else if player.provisional {
+                self.provisional.fetch_add(1, Ordering::Relaxed);
+                ImplausibleRating::Provisional
+            } else {
+                continue;
+            };
+
+            if !self.clamp {
+                rejected = rejected.or(Some(reason));
+                continue;
+            }
+
+            let clamped = player.rating.clamp(MIN_RATING, MAX_RATING);
+            if clamped != player.rating {
+                player.rating = clamped;
+                self.clamped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        rejected.map_or(Ok(()), Err)
+    }
+
+    pub fn counters(&self) -> RatingCounters {
+        RatingCounters {
+            too_low: self.too_low.load(Ordering::Relaxed),
+            too_high: self.too_high.load(Ordering::Relaxed),
+            provisional: self.provisional.load(Ordering::Relaxed),
+            clamped: self.clamped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Which imported lichess games are added to the database.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AcceptancePolicy {
+    pub speeds: Vec<Speed>,
+    /// Minimum average rating of both players.
+    #[serde(default)]
+    pub min_rating: u16,
+}
+
+impl Default for AcceptancePolicy {
+    fn default() -> AcceptancePolicy {
+        AcceptancePolicy {
+            speeds: vec![
+                Speed::Blitz,
+                Speed::Rapid,
+                Speed::Classical,
+                Speed::Correspondence,
+            ],
+            min_rating: 0,
+        }
+    }
+}
+
+impl AcceptancePolicy {
+    fn accepts(&self, speed: Speed, players: &ByColor<GamePlayer>) -> bool {
+        self.speeds.contains(&speed)
+            && players.white.rating / 2 + players.black.rating / 2 >= self.min_rating
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PolicyChange {
+    /// Seconds since the unix epoch.
+    at: u64,
+    from: AcceptancePolicy,
+    to: AcceptancePolicy,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AcceptanceStatus {
+    policy: AcceptancePolicy,
+    changes: Vec<PolicyChange>,
+}
+
+/// Acceptance policy that can be replaced at runtime, with a record of the
+/// most recent changes.
+#[derive(Default)]
+pub struct Acceptance {
+    policy: RwLock<AcceptancePolicy>,
+    changes: RwLock<VecDeque<PolicyChange>>,
+}
+
+impl Acceptance {
+    pub fn policy(&self) -> AcceptancePolicy {
+        self.policy.read().expect("acceptance policy").clone()
+    }
+
+    pub fn set_policy(&self, policy: AcceptancePolicy) {
+        let from = {
+            let mut guard = self.policy.write().expect("acceptance policy");
+            std::mem::replace(&mut *guard, policy.clone())
+        };
+        log::warn!("import policy changed from {:?} to {:?}", from, policy);
+
+        let mut changes = self.changes.write().expect("policy changes");
+        if changes.len() >= MAX_POLICY_CHANGES {
+            changes.pop_front();
+        }
+        changes.push_back(PolicyChange {
+            at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("duration since unix epoch")
+                .as_secs(),
+            from,
+            to: policy,
+        });
+    }
+
+    pub fn status(&self) -> AcceptanceStatus {
+        AcceptanceStatus {
+            policy: self.policy(),
+            changes: self
+                .changes
+                .read()
+                .expect("policy changes")
+                .iter()
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Delays imports while compactions lag behind, so that reads keep
+/// predictable latency during heavy loads.
+async fn wait_for_compactions(db: &Database) {
+    if db.is_lagging() {
+        log::warn!("delaying import until compactions catch up");
+        while db.is_lagging() {
+            time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct MastersImporter {
     db: Arc<Database>,
+    errata: Arc<Errata>,
+    rating_checks: Arc<RatingChecks>,
+    recent_games: Arc<RecentGames>,
+    duplicate_review: Arc<DuplicateReview>,
+    publisher: Publisher,
+    subscriptions: Subscriptions,
     mutex: Arc<Mutex<()>>,
 }
 
 impl MastersImporter {
-    pub fn new(db: Arc<Database>) -> MastersImporter {
+    pub fn new(
+        db: Arc<Database>,
+        errata: Arc<Errata>,
+        rating_checks: Arc<RatingChecks>,
+        recent_games: Arc<RecentGames>,
+        duplicate_review: Arc<DuplicateReview>,
+        publisher: Publisher,
+        subscriptions: Subscriptions,
+    ) -> MastersImporter {
         MastersImporter {
             db,
+            errata,
+            rating_checks,
+            recent_games,
+            duplicate_review,
+            publisher,
+            subscriptions,
             mutex: Arc::new(Mutex::new(())),
         }
     }
 
-    pub async fn import(&self, body: MastersGameWithId) -> Result<(), Error> {
+    pub async fn import(&self, mut body: MastersGameWithId) -> Result<(), Error> {
+        if !self.db.is_writable() {
+            return Err(Error::ReadOnly);
+        }
+
+        body.game.apply_estimated_ratings();
+
+        self.rating_checks
+            .check(&mut body.game.players)
+            .map_err(|reason| Error::ImplausibleRating(body.id, reason))?;
+
         if body.game.players.white.rating / 2 + body.game.players.black.rating / 2 < 2200 {
             return Err(Error::RejectedImport(body.id));
         }
@@ -49,7 +311,9 @@ impl MastersImporter {
             return Err(Error::RejectedImport(body.id));
         }
 
-        let _guard = self.mutex.lock();
+        wait_for_compactions(&self.db).await;
+
+        let _guard = self.mutex.lock().await;
         let masters_db = self.db.masters();
         if masters_db
             .has_game(body.id)
@@ -58,29 +322,26 @@ impl MastersImporter {
             return Err(Error::DuplicateGame(body.id));
         }
 
-        let mut without_loops: FxHashMap<Key, (Uci, Color)> =
-            FxHashMap::with_capacity_and_hasher(body.game.moves.len(), Default::default());
-        let mut pos: Zobrist<Chess, u128> = Zobrist::default();
-        let mut final_key = None;
-        for uci in &body.game.moves {
-            let key = KeyBuilder::masters()
-                .with_zobrist(Variant::Chess, pos.zobrist_hash())
-                .with_year(year);
-            final_key = Some(key.clone());
-            let m = uci.to_move(&pos)?;
-            without_loops.insert(key, (Uci::from_chess960(&m), pos.turn()));
-            pos.play_unchecked(&m);
-        }
+        let (without_loops, _) = masters_without_loops(&body.game)?;
 
-        if let Some(final_key) = final_key {
-            if masters_db.has(final_key).expect("check for masters entry") {
+        let near_duplicate = self.most_similar(&body)?;
+        if let Some(near) = &near_duplicate {
+            if near.similarity >= DUPLICATE_SIMILARITY {
                 return Err(Error::DuplicateGame(body.id));
             }
         }
 
+        let num_keys = without_loops.len();
+        let mut moves = FxHashMap::default();
+        if self.subscriptions.is_watched() {
+            for (key, (uci, _, _)) in &without_loops {
+                moves.insert(key.prefix(), uci.clone());
+            }
+        }
         let mut batch = masters_db.batch();
         batch.put_game(body.id, &body.game);
-        for (key, (uci, turn)) in without_loops {
+        for (key, (uci, turn, nag)) in without_loops {
+            batch.put_position_game(&key.prefix(), body.id);
             batch.merge(
                 key,
                 MastersEntry::new_single(
@@ -89,20 +350,406 @@ impl MastersImporter {
                     Outcome::from_winner(body.game.winner),
                     body.game.players.get(turn).rating,
                     body.game.players.get(!turn).rating,
+                    nag,
+                    body.game.has_estimated_rating(),
                 ),
             );
         }
 
         batch.commit().expect("commit masters game");
+
+        if let Some(near) = near_duplicate {
+            log::warn!(
+                "masters game {} is similar to {} ({:.2}), queued for review",
+                near.id,
+                near.candidate,
+                near.similarity
+            );
+            self.duplicate_review.push(near);
+        }
+
+        if RecentGames::is_notable(
+            Outcome::from_winner(body.game.winner),
+            body.game.players.white.rating,
+            body.game.players.black.rating,
+        ) {
+            self.recent_games.push(FeedSource::Masters, body.id);
+        }
+
+        self.subscriptions.notify(PositionUpdates {
+            source: ImportSource::Masters,
+            id: body.id,
+            outcome: Outcome::from_winner(body.game.winner),
+            moves,
+        });
+
+        self.publisher.publish(ImportEvent {
+            source: ImportSource::Masters,
+            id: body.id,
+            keys: num_keys,
+            speed: None,
+            rating_bucket: ImportEvent::rating_bucket(
+                body.game.players.white.rating,
+                body.game.players.black.rating,
+            ),
+        });
+        Ok(())
+    }
+
+    /// Imports games into the private explorer of a tenant. Games are not
+    /// held to the standards of the masters database, are not published,
+    /// and do not show up in feeds. Stops accepting games once the tenant
+    /// holds `max_games`.
+    pub async fn import_tenant(
+        &self,
+        tenant: &TenantId,
+        max_games: Option<u64>,
+        games: Vec<MastersGameWithId>,
+    ) -> Vec<Result<(), Error>> {
+        if !self.db.is_writable() {
+            return games.iter().map(|_| Err(Error::ReadOnly)).collect();
+        }
+
+        wait_for_compactions(&self.db).await;
+
+        let _guard = self.mutex.lock().await;
+        let masters_db = self.db.masters();
+        let mut stored = masters_db
+            .count_tenant_games(tenant)
+            .expect("count tenant games");
+
+        games
+            .into_iter()
+            .map(|mut body| {
+                if let Some(max_games) = max_games.filter(|max_games| stored >= *max_games) {
+                    return Err(Error::TenantGamesExhausted(body.id, max_games));
+                }
+
+                body.game.apply_estimated_ratings();
+
+                if masters_db
+                    .has_tenant_game(tenant, body.id)
+                    .expect("check for tenant game")
+                {
+                    return Err(Error::DuplicateGame(body.id));
+                }
+
+                let (without_loops, _) = masters_without_loops_with(
+                    &body.game,
+                    &body.game.key_builder().with_tenant(tenant),
+                )?;
+
+                let mut batch = masters_db.batch();
+                batch.put_tenant_game(tenant, body.id, &body.game);
+                for (key, (uci, turn, nag)) in without_loops {
+                    batch.merge(
+                        key,
+                        MastersEntry::new_single(
+                            uci,
+                            body.id,
+                            Outcome::from_winner(body.game.winner),
+                            body.game.players.get(turn).rating,
+                            body.game.players.get(!turn).rating,
+                            nag,
+                            body.game.has_estimated_rating(),
+                        ),
+                    );
+                }
+                batch.commit().expect("commit tenant game");
+
+                stored += 1;
+                Ok(())
+            })
+            .collect()
+    }
+
+    /// Finds the stored game that is most similar to the imported game,
+    /// among the games that reached one of its final positions in the same
+    /// year.
+    fn most_similar(&self, body: &MastersGameWithId) -> Result<Option<NearDuplicate>, Error> {
+        let fingerprint = Fingerprint::new(&body.game)?;
+        let key_builder = body.game.key_builder();
+        let year = body.game.date.year();
+        let masters_db = self.db.masters();
+
+        let mut candidates = FxHashSet::default();
+        for hash in fingerprint.candidate_hashes() {
+            let entry = masters_db
+                .get(
+                    key_builder
+                        .with_zobrist(Variant::Chess, *hash)
+                        .with_year(year),
+                )
+                .expect("get masters entry");
+            for group in entry.groups.into_values() {
+                candidates.extend(group.games.into_iter().map(|(_, id)| id));
+            }
+        }
+        candidates.remove(&body.id);
+
+        let mut best: Option<NearDuplicate> = None;
+        let candidates: Vec<GameId> = candidates.into_iter().collect();
+        let games = masters_db
+            .games(candidates.iter().copied())
+            .expect("get masters games");
+        for (candidate, game) in candidates.into_iter().zip(games) {
+            let game = match game {
+                Some(game) => game,
+                None => continue,
+            };
+            let similarity = fingerprint.similarity(&Fingerprint::new(&game)?);
+            if similarity >= NEAR_DUPLICATE_SIMILARITY
+                && best
+                    .as_ref()
+                    .map_or(true, |best| similarity > best.similarity)
+            {
+                best = Some(NearDuplicate {
+                    id: body.id,
+                    candidate,
+                    similarity,
+                });
+            }
+        }
+        Ok(best)
+    }
+
+    /// Recomputes the masters entries of a single position from the stored
+    /// games that reached it, according to the position index, and replaces
+    /// the current value. Returns the number of games that pass through the
+    /// position.
+    pub async fn rebuild(&self, key: KeyPrefix) -> Result<u64, Error> {
+        if !self.db.is_writable() {
+            return Err(Error::ReadOnly);
+        }
+
+        let db = Arc::clone(&self.db);
+        let mutex = Arc::clone(&self.mutex);
+        task::spawn_blocking(move || {
+            let _guard = mutex.blocking_lock();
+            let masters_db = db.masters();
+
+            let ids = masters_db
+                .position_games(&key)
+                .expect("read masters position games");
+            let games = masters_db
+                .games(ids.iter().copied())
+                .expect("get masters games");
+
+            let mut entries = Vec::new();
+            for (id, game) in ids.into_iter().zip(games) {
+                let game = match game {
+                    Some(game) => game,
+                    None => {
+                        log::error!("indexed masters game {} not found", id);
+                        continue;
+                    }
+                };
+                let key = key.with_year(game.date.year());
+                match masters_without_loops(&game) {
+                    Ok((mut without_loops, _)) => {
+                        if let Some((uci, turn, nag)) = without_loops.remove(&key) {
+                            entries.push((
+                                key,
+                                MastersEntry::new_single(
+                                    uci,
+                                    id,
+                                    Outcome::from_winner(game.winner),
+                                    game.players.get(turn).rating,
+                                    game.players.get(!turn).rating,
+                                    nag,
+                                    game.has_estimated_rating(),
+                                ),
+                            ));
+                        }
+                    }
+                    Err(err) => log::error!("masters game {} not replayable: {}", id, err),
+                }
+            }
+
+            let num_games = entries.len() as u64;
+            let mut batch = masters_db.batch();
+            batch.delete_prefix(&key);
+            for (key, entry) in entries {
+                batch.merge(key, entry);
+            }
+            batch.commit().expect("commit rebuilt masters entry");
+            Ok(num_games)
+        })
+        .await
+        .expect("blocking rebuild")
+    }
+
+    /// Adds all stored games to the position index, including those that
+    /// were imported before it was maintained. Positions can only be
+    /// rebuilt from games in the index. Does not block imports, which
+    /// maintain the index themselves. Returns the number of indexed games.
+    pub async fn index_positions(&self) -> Result<u64, Error> {
+        if !self.db.is_writable() {
+            return Err(Error::ReadOnly);
+        }
+
+        let db = Arc::clone(&self.db);
+        task::spawn_blocking(move || {
+            let masters_db = db.masters();
+            let mut num_games = 0;
+            masters_db
+                .scan_games(|id, game| match masters_without_loops(&game) {
+                    Ok((without_loops, _)) => {
+                        let mut batch = masters_db.batch();
+                        for key in without_loops.keys() {
+                            batch.put_position_game(&key.prefix(), id);
+                        }
+                        batch.commit().expect("commit masters position index");
+                        num_games += 1;
+                    }
+                    Err(err) => log::error!("masters game {} not replayable: {}", id, err),
+                })
+                .expect("scan masters games");
+            Ok(num_games)
+        })
+        .await
+        .expect("blocking position index")
+    }
+
+    /// Stores a correction of a masters game. It is applied when reading,
+    /// until it is folded into the stored entries.
+    pub async fn submit_erratum(&self, id: GameId, erratum: Erratum) -> Result<(), Error> {
+        if !self.db.is_writable() {
+            return Err(Error::ReadOnly);
+        }
+
+        let _guard = self.mutex.lock().await;
+        let masters_db = self.db.masters();
+
+        let game = masters_db
+            .game(id)
+            .expect("get masters game")
+            .ok_or(Error::UnknownGame(id))?;
+        masters_without_loops(&game)?;
+
+        let mut batch = masters_db.batch();
+        batch.put_erratum(id, &erratum);
+        batch.commit().expect("commit erratum");
+
+        self.errata.insert(id, &game, erratum);
         Ok(())
     }
+
+    pub fn spawn_errata_folding(&self) -> JoinHandle<()> {
+        let importer = self.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(ERRATA_FOLD_INTERVAL);
+            loop {
+                interval.tick().await;
+                let num_games = importer.fold_errata().await;
+                if num_games > 0 {
+                    log::info!("folded errata of {} masters games", num_games);
+                }
+            }
+        })
+    }
+
+    /// Folds all pending corrections into the stored games and entries.
+    /// Returns the number of corrected games.
+    pub async fn fold_errata(&self) -> usize {
+        if !self.db.is_writable() {
+            return 0;
+        }
+
+        let _guard = self.mutex.lock().await;
+        let masters_db = self.db.masters();
+
+        let mut num_games = 0;
+        for (id, erratum) in self.errata.pending() {
+            let mut batch = masters_db.batch();
+            batch.delete_erratum(id);
+
+            match masters_db.game(id).expect("get masters game") {
+                Some(game) => match masters_without_loops(&game) {
+                    Ok((without_loops, _)) => {
+                        let mut corrected = game.clone();
+                        erratum.apply(&mut corrected);
+
+                        for (key, (uci, turn, _)) in without_loops {
+                            let mut entry = masters_db.get(key.clone()).expect("get masters entry");
+                            entry.correct(
+                                MastersEntry::new_single(
+                                    uci.clone(),
+                                    id,
+                                    Outcome::from_winner(game.winner),
+                                    game.players.get(turn).rating,
+                                    game.players.get(!turn).rating,
+                                    None,
+                                    game.has_estimated_rating(),
+                                ),
+                                MastersEntry::new_single(
+                                    uci,
+                                    id,
+                                    Outcome::from_winner(corrected.winner),
+                                    corrected.players.get(turn).rating,
+                                    corrected.players.get(!turn).rating,
+                                    None,
+                                    corrected.has_estimated_rating(),
+                                ),
+                            );
+                            batch.put(key, &entry);
+                        }
+
+                        batch.put_game(id, &corrected);
+                        num_games += 1;
+                    }
+                    Err(err) => log::error!("masters game {} not replayable: {}", id, err),
+                },
+                None => log::error!("erratum for unknown masters game {}", id),
+            }
+
+            batch.commit().expect("commit folded erratum");
+            self.errata.remove(id);
+        }
+        num_games
+    }
+}
+
+pub type MastersMove = (Uci, Color, Option<u8>);
+
+pub fn masters_without_loops(
+    game: &MastersGame,
+) -> Result<(FxHashMap<Key, MastersMove>, Option<Key>), Error> {
+    masters_without_loops_with(game, &game.key_builder())
+}
+
+/// Like [`masters_without_loops()`], but with the keys of another
+/// namespace.
+fn masters_without_loops_with(
+    game: &MastersGame,
+    key_builder: &KeyBuilder,
+) -> Result<(FxHashMap<Key, MastersMove>, Option<Key>), Error> {
+    let year = game.date.year();
+    let mut without_loops: FxHashMap<Key, MastersMove> =
+        FxHashMap::with_capacity_and_hasher(game.moves.len(), Default::default());
+    let mut pos: Zobrist<VariantPosition, u128> = Zobrist::new(game.initial_position()?);
+    let mut final_key = None;
+    for (ply, uci) in game.moves.iter().enumerate() {
+        let key = key_builder
+            .with_zobrist(Variant::Chess, pos.zobrist_hash())
+            .with_year(year);
+        final_key = Some(key.clone());
+        let m = uci.to_move(&pos)?;
+        without_loops.insert(key, (Uci::from_chess960(&m), pos.turn(), game.nag(ply)));
+        pos.play_unchecked(&m);
+    }
+    Ok((without_loops, final_key))
 }
 
 #[serde_as]
 #[derive(Deserialize)]
 pub struct LichessGameImport {
     variant: Option<LilaVariant>,
-    speed: Speed,
+    /// Derived from the clock instead, if given.
+    #[serde(default)]
+    speed: Option<Speed>,
+    #[serde(default)]
+    clock: Option<Clock>,
     #[serde_as(as = "Option<DisplayFromStr>")]
     fen: Option<Fen>,
     #[serde_as(as = "DisplayFromStr")]
@@ -115,24 +762,101 @@ pub struct LichessGameImport {
     winner: Option<Color>,
     #[serde_as(as = "StringWithSeparator<SpaceSeparator, San>")]
     moves: Vec<San>,
+    #[serde_as(as = "Option<StringWithSeparator<SpaceSeparator, u32>>")]
+    #[serde(default)]
+    times: Option<Vec<u32>>,
 }
 
 #[derive(Clone)]
 pub struct LichessImporter {
     db: Arc<Database>,
+    acceptance: Arc<Acceptance>,
+    rating_checks: Arc<RatingChecks>,
+    variant_stats: Arc<VariantStats>,
+    rating_distribution: Arc<RatingDistribution>,
+    recent_games: Arc<RecentGames>,
+    publisher: Publisher,
+    subscriptions: Subscriptions,
+    speed_mismatches: Arc<AtomicU64>,
+    /// Retracted or erased games, whose references are yet to be pruned.
+    pending_prunes: Arc<StdMutex<FxHashSet<GameId>>>,
     mutex: Arc<Mutex<()>>,
 }
 
 impl LichessImporter {
-    pub fn new(db: Arc<Database>) -> LichessImporter {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        db: Arc<Database>,
+        acceptance: Arc<Acceptance>,
+        rating_checks: Arc<RatingChecks>,
+        variant_stats: Arc<VariantStats>,
+        rating_distribution: Arc<RatingDistribution>,
+        recent_games: Arc<RecentGames>,
+        publisher: Publisher,
+        subscriptions: Subscriptions,
+    ) -> LichessImporter {
         LichessImporter {
             db,
+            acceptance,
+            rating_checks,
+            variant_stats,
+            rating_distribution,
+            recent_games,
+            publisher,
+            subscriptions,
+            speed_mismatches: Arc::new(AtomicU64::new(0)),
+            pending_prunes: Arc::default(),
             mutex: Arc::new(Mutex::new(())),
         }
     }
 
-    pub async fn import(&self, game: LichessGameImport) -> Result<(), Error> {
-        let _guard = self.mutex.lock();
+    /// Number of imported games whose declared speed did not match their
+    /// clock.
+    pub fn speed_mismatches(&self) -> u64 {
+        self.speed_mismatches.load(Ordering::Relaxed)
+    }
+
+    /// Derives the speed from the clock, using the same thresholds as
+    /// index-pgn, so that all pipelines classify speeds identically.
+    fn speed(&self, game: &LichessGameImport) -> Result<Speed, Error> {
+        match (game.clock, game.speed) {
+            (Some(clock), declared) => {
+                let speed = clock.speed();
+                if let Some(declared) = declared.filter(|declared| *declared != speed) {
+                    self.speed_mismatches.fetch_add(1, Ordering::Relaxed);
+                    log::warn!(
+                        "lichess game {} declared as {:?}, but clock {} is {:?}",
+                        game.id,
+                        declared,
+                        clock,
+                        speed
+                    );
+                }
+                Ok(speed)
+            }
+            (None, Some(speed)) => Ok(speed),
+            (None, None) => Err(Error::RejectedImport(game.id)),
+        }
+    }
+
+    pub async fn import(
+        &self,
+        mut game: LichessGameImport,
+        source: LichessSource,
+    ) -> Result<(), Error> {
+        if !self.db.is_writable() {
+            return Err(Error::ReadOnly);
+        }
+
+        self.rating_checks
+            .check(&mut game.players)
+            .map_err(|reason| Error::ImplausibleRating(game.id, reason))?;
+
+        let speed = self.speed(&game)?;
+
+        wait_for_compactions(&self.db).await;
+
+        let _guard = self.mutex.lock().await;
 
         let lichess_db = self.db.lichess();
 
@@ -145,12 +869,8 @@ impl LichessImporter {
             return Ok(());
         }
 
-        if game.speed == Speed::Bullet {
-            // log::debug!("lichess game is a fucking bullet");
-            return Ok(());
-        }
-
-        if game.speed == Speed::UltraBullet {
+        if !self.acceptance.policy().accepts(speed, &game.players) {
+            log::debug!("lichess game {} not accepted by policy", game.id);
             return Ok(());
         }
 
@@ -161,9 +881,14 @@ impl LichessImporter {
                 return Err(Error::RejectedImport(game.id));
             }
         };
+        let week = game
+            .date
+            .week()
+            .filter(|week| *week >= Week::current().sub_weeks_saturating(MAX_WEEKS_RECENT));
         let outcome = Outcome::from_winner(game.winner);
         let variant = Variant::from(game.variant.unwrap_or_default());
 
+        let from_initial = game.fen.is_none();
         let mut pos: Zobrist<_, u128> = Zobrist::new(match game.fen {
             Some(fen) => {
                 VariantPosition::from_setup(variant, fen.into_setup(), CastlingMode::Chess960)?
@@ -171,23 +896,99 @@ impl LichessImporter {
             None => VariantPosition::new(variant),
         });
 
-        let mut without_loops: FxHashMap<Key, (Uci, Color)> =
-            FxHashMap::with_capacity_and_hasher(game.moves.len(), Default::default());
+        // Seconds spent on each move, if the dump had clock comments.
+        let times = game.times.unwrap_or_default();
+
+        let mut seen: FxHashSet<u128> =
+            FxHashSet::with_capacity_and_hasher(game.moves.len() + 1, Default::default());
+        seen.insert(pos.zobrist_hash());
+
+        let first_move = game
+            .moves
+            .first()
+            .and_then(|san| san.to_move(&pos).ok())
+            .map(|m| Uci::from_chess960(&m));
+
+        // Whether the lower rated side won.
+        let upset = if game.players.white.rating == 0
+            || game.players.black.rating == 0
+            || game.players.white.rating == game.players.black.rating
+        {
+            None
+        } else if game.players.white.rating < game.players.black.rating {
+            Some(game.winner == Some(Color::White))
+        } else {
+            Some(game.winner == Some(Color::Black))
+        };
+        let bot = game.players.white.bot || game.players.black.bot;
+        let rated = game.players.white.rating != 0 && game.players.black.rating != 0;
+
+        let num_plies = game.moves.len();
+        let mut without_loops: FxHashMap<Key, (Uci, Color, MoveDetails)> =
+            FxHashMap::with_capacity_and_hasher(num_plies, Default::default());
+        let mut parents: FxHashMap<(KeyPrefix, u128), ParentEntry> =
+            FxHashMap::with_capacity_and_hasher(num_plies, Default::default());
+        // Move orders are only meaningful from the initial position, and
+        // only the first visit of each position counts. Parents are also
+        // only indexed from there, so that they can be recovered by replaying
+        // their move orders.
+        let mut path = PathHash::empty();
+        let mut steps = Vec::new();
+        let mut move_orders = Vec::new();
         for (ply, san) in game.moves.into_iter().enumerate() {
             if ply >= MAX_PLIES {
                 break;
             }
 
             let m = san.to_move(&pos)?;
+            let key = KeyBuilder::lichess()
+                .with_zobrist(variant, pos.zobrist_hash())
+                .with_month(month);
+            let turn = pos.turn();
+            let parent = pos.zobrist_hash();
+            pos.play_unchecked(&m);
+            let child = KeyBuilder::lichess().with_zobrist(variant, pos.zobrist_hash());
+            let repeats = !seen.insert(pos.zobrist_hash());
+            if from_initial {
+                let uci = Uci::from_chess960(&m);
+                let prev = path;
+                path = path.then(&uci);
+                steps.push((
+                    path,
+                    MoveOrderStep {
+                        prev,
+                        uci: uci.clone(),
+                    },
+                ));
+                if !repeats {
+                    move_orders.push((child.clone(), MoveOrderEntry::new_single(path)));
+                }
+                parents
+                    .entry((child, parent))
+                    .or_insert_with(|| ParentEntry::new_single(parent, uci));
+            }
             without_loops.insert(
-                KeyBuilder::lichess()
-                    .with_zobrist(variant, pos.zobrist_hash())
-                    .with_month(month),
-                (Uci::from_chess960(&m), pos.turn()),
+                key,
+                (
+                    Uci::from_chess960(&m),
+                    turn,
+                    MoveDetails {
+                        seconds: times.get(ply).copied(),
+                        repeats,
+                        upset,
+                        remaining_plies: Some((num_plies - ply - 1) as u32),
+                        bot,
+                        rated,
+                        clock: game.clock,
+                    },
+                ),
             );
-            pos.play_unchecked(&m);
         }
 
+        let num_keys = without_loops.len();
+        let mut entries = Vec::with_capacity(num_keys);
+        let watched = self.subscriptions.is_watched();
+        let mut moves = FxHashMap::default();
         let mut batch = lichess_db.batch();
         batch.merge_game(
             game.id,
@@ -195,27 +996,303 @@ impl LichessImporter {
                 mode: Mode::Rated,
                 indexed_player: Default::default(),
                 indexed_lichess: true,
+                source,
+                clock: game.clock,
                 outcome,
                 players: game.players.clone(),
                 month,
-                speed: game.speed,
+                speed,
             },
         );
-        for (key, (uci, turn)) in without_loops {
-            batch.merge_lichess(
-                key,
-                LichessEntry::new_single(
-                    uci,
-                    game.speed,
-                    game.id,
-                    outcome,
-                    game.players.get(turn).rating,
-                    game.players.get(!turn).rating,
-                ),
+        for ((key, _), entry) in parents {
+            batch.merge_parent(&key, &entry);
+        }
+        for (path, step) in steps {
+            batch.put_move_order_step(&path, &step);
+        }
+        for (key, entry) in move_orders {
+            batch.merge_move_order(&key, &entry);
+        }
+        for (key, (uci, turn, details)) in without_loops {
+            if watched {
+                moves.insert(key.prefix(), uci.clone());
+            }
+            let entry = LichessEntry::new_single(
+                uci,
+                speed,
+                game.id,
+                outcome,
+                game.players.get(turn).rating,
+                game.players.get(!turn).rating,
+                details,
             );
+            batch.merge_lichess(source, key.clone(), &entry);
+            if let Some(week) = week {
+                batch.merge_lichess_week(key.prefix().with_bucket(week), &entry);
+            }
+            entries.push((key, entry));
+        }
+
+        // Only games that are still recent can turn out to have been
+        // aborted, not those imported from older dumps.
+        let tracked = TrackedImport {
+            imported_at: unix_secs(),
+            week,
+            entries,
+            source,
+        };
+        if game.date.week() >= Some(Week::current().sub_weeks_saturating(1)) {
+            batch.put_tracked(game.id, &tracked);
         }
 
         batch.commit().expect("commit lichess game");
+
+        if let (true, Some(first_move)) = (from_initial, first_move) {
+            self.variant_stats.record(variant, month, first_move);
+        }
+
+        self.rating_distribution.record(
+            speed,
+            game.players.white.rating,
+            game.players.black.rating,
+        );
+
+        if RecentGames::is_notable(
+            outcome,
+            game.players.white.rating,
+            game.players.black.rating,
+        ) {
+            self.recent_games.push(FeedSource::Lichess, game.id);
+        }
+
+        self.subscriptions.notify(PositionUpdates {
+            source: ImportSource::Lichess,
+            id: game.id,
+            outcome,
+            moves,
+        });
+
+        self.publisher.publish(ImportEvent {
+            source: ImportSource::Lichess,
+            id: game.id,
+            keys: num_keys,
+            speed: Some(speed),
+            rating_bucket: ImportEvent::rating_bucket(
+                game.players.white.rating,
+                game.players.black.rating,
+            ),
+        });
         Ok(())
     }
+
+    /// Forgets tracked games that were imported too long ago to still turn
+    /// out to have been aborted. Returns the ids of the remaining ones.
+    pub async fn expire_tracked(&self) -> Vec<GameId> {
+        if !self.db.is_writable() {
+            return Vec::new();
+        }
+
+        let _guard = self.mutex.lock().await;
+        let lichess_db = self.db.lichess();
+
+        let expired_before = unix_secs().saturating_sub(MAX_TRACKING_AGE.as_secs());
+        let mut batch = lichess_db.batch();
+        let mut ids = Vec::new();
+        lichess_db
+            .scan_tracked(|id, imported_at| {
+                if imported_at < expired_before {
+                    batch.delete_tracked(id);
+                } else {
+                    ids.push(id);
+                }
+            })
+            .expect("scan tracked imports");
+        batch.commit().expect("commit expired tracked imports");
+        ids
+    }
+
+    /// Removes the contributions of a tracked game, because it turned out to
+    /// have been aborted. Returns false if the game is not tracked (anymore).
+    /// Position entries, including weekly and shadow entries, and hot
+    /// positions are corrected immediately. Player entries and the feed of
+    /// recent games are pruned later. Variant stats, the rating distribution
+    /// and the counters of rating checks keep counting the game.
+    pub async fn retract(&self, id: GameId) -> Result<bool, Error> {
+        if !self.db.is_writable() {
+            return Err(Error::ReadOnly);
+        }
+
+        let _guard = self.mutex.lock().await;
+        let lichess_db = self.db.lichess();
+
+        let tracked = match lichess_db.tracked(id).expect("get tracked import") {
+            Some(tracked) => tracked,
+            None => return Ok(false),
+        };
+
+        let mut batch = lichess_db.batch();
+        for (key, single) in &tracked.entries {
+            if let Some(week) = tracked.week {
+                let week_key = key.prefix().with_bucket(week);
+                let mut entry = lichess_db
+                    .get_lichess_week(week_key.clone())
+                    .expect("get lichess week entry");
+                entry.retract(single);
+                batch.put_lichess_week(week_key, &entry);
+            }
+
+            let mut entry = lichess_db
+                .get_lichess(tracked.source, key.clone())
+                .expect("get lichess entry");
+            entry.retract(single);
+            batch.put_lichess(tracked.source, key.clone(), &entry);
+
+            let mut shadow = lichess_db
+                .get_lichess_shadow(key.clone())
+                .expect("get shadow lichess entry");
+            shadow.retract(single);
+            batch.put_lichess_shadow(key.clone(), &shadow);
+        }
+        batch.delete_tracked(id);
+        batch.commit().expect("commit retracted lichess game");
+
+        // Player entries and in-memory summaries may still list the game.
+        self.schedule_pruning(id);
+
+        log::warn!("retracted aborted lichess game {}", id);
+        Ok(true)
+    }
+
+    /// Schedules removal of all references to the game, for example after
+    /// it was retracted or erased on lichess.
+    pub fn schedule_pruning(&self, id: GameId) {
+        self.pending_prunes
+            .lock()
+            .expect("pending prunes")
+            .insert(id);
+    }
+
+    pub fn spawn_pruning(&self) -> JoinHandle<()> {
+        let importer = self.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let num_entries = importer.prune_games().await;
+                if num_entries > 0 {
+                    log::info!("pruned game references from {} entries", num_entries);
+                }
+                let num_positions = importer.prune_weeks().await;
+                if num_positions > 0 {
+                    log::info!("pruned old weeks of {} positions", num_positions);
+                }
+            }
+        })
+    }
+
+    /// Deletes weekly entries that are older than the recent weeks that are
+    /// still written. Returns the number of positions with deleted entries.
+    pub async fn prune_weeks(&self) -> usize {
+        if !self.db.is_writable() {
+            return 0;
+        }
+
+        let db = Arc::clone(&self.db);
+        task::spawn_blocking(move || {
+            db.lichess()
+                .prune_weeks(Week::current().sub_weeks_saturating(MAX_WEEKS_RECENT))
+                .expect("prune weeks")
+        })
+        .await
+        .expect("blocking prune weeks")
+    }
+
+    /// Removes references to all games scheduled for pruning. Entries are
+    /// found without blocking imports, and then rewritten while holding the
+    /// import lock, so that no concurrent merge is lost. Player entries are
+    /// written by the indexer, which does not take the lock, so a player
+    /// indexed at the same time may need to be indexed again. Returns the
+    /// number of rewritten entries.
+    pub async fn prune_games(&self) -> usize {
+        if !self.db.is_writable() {
+            return 0;
+        }
+
+        let ids = std::mem::take(&mut *self.pending_prunes.lock().expect("pending prunes"));
+        if ids.is_empty() {
+            return 0;
+        }
+
+        self.recent_games.remove(FeedSource::Lichess, &ids);
+
+        let db = Arc::clone(&self.db);
+        let mutex = Arc::clone(&self.mutex);
+        task::spawn_blocking(move || {
+            let lichess_db = db.lichess();
+            let references = lichess_db
+                .find_game_references(&ids)
+                .expect("find game references");
+            if !references.is_empty() {
+                log::info!(
+                    "pruning {} games referenced by {} entries",
+                    ids.len(),
+                    references.len()
+                );
+            }
+            let _guard = mutex.blocking_lock();
+            lichess_db
+                .prune_game_references(references, &ids)
+                .expect("prune game references")
+        })
+        .await
+        .expect("blocking prune games")
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn players(white: u16, black: u16) -> ByColor<GamePlayer> {
+        let player = |rating| GamePlayer {
+            name: "player".to_owned(),
+            rating,
+            provisional: false,
+            estimated_rating: None,
+            estimated: false,
+            bot: false,
+        };
+        ByColor {
+            white: player(white),
+            black: player(black),
+        }
+    }
+
+    #[test]
+    fn test_rating_checks() {
+        let checks = RatingChecks::default();
+        assert!(checks.check(&mut players(1500, 1600)).is_ok());
+        assert!(matches!(
+            checks.check(&mut players(250, 4000)),
+            Err(ImplausibleRating::TooLow)
+        ));
+        let counters = checks.counters();
+        assert_eq!(counters.too_low, 1);
+        assert_eq!(counters.too_high, 1, "both players checked");
+
+        let checks = RatingChecks::new(ImportOpt {
+            clamp_ratings: true,
+        });
+        let mut clamped = players(250, 4000);
+        assert!(checks.check(&mut clamped).is_ok());
+        assert_eq!(clamped.white.rating, MIN_RATING);
+        assert_eq!(clamped.black.rating, MAX_RATING);
+        assert_eq!(checks.counters().clamped, 2);
+    }
 }
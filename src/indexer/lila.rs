@@ -1,4 +1,4 @@
-use std::io;
+use std::{io, sync::Arc};
 
 use chrono::{DateTime, Utc};
 use futures_util::stream::{Stream, StreamExt as _, TryStreamExt as _};
@@ -13,24 +13,31 @@ use tokio_util::io::StreamReader;
 
 use crate::{
     api::LilaVariant,
-    indexer::IndexerOpt,
-    model::{GameId, Speed, UserId, UserName},
+    indexer::{rate_limit::RateLimiter, IndexerOpt},
+    model::{Clock, GameId, Speed, UserId, UserName},
     util::ByColorDef,
 };
 
 pub struct Lila {
     client: reqwest::Client,
+    rate_limiter: Arc<RateLimiter>,
     opt: IndexerOpt,
 }
 
 impl Lila {
-    pub fn new(opt: IndexerOpt) -> Lila {
+    pub fn new(opt: IndexerOpt, rate_limiter: Arc<RateLimiter>) -> Lila {
         Lila {
             client: reqwest::Client::builder().build().expect("reqwest client"),
+            rate_limiter,
             opt,
         }
     }
 
+    /// Waits until the shared rate limit allows another request.
+    pub async fn acquire(&self) {
+        self.rate_limiter.acquire().await;
+    }
+
     pub async fn user_games(
         &self,
         user: &UserId,
@@ -51,26 +58,54 @@ impl Lila {
             builder = builder.bearer_auth(bearer);
         }
 
-        let stream = builder
-            .send()
-            .await
-            .and_then(|r| r.error_for_status())?
-            .bytes_stream()
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
-
-        Ok(Box::pin(
-            LinesStream::new(StreamReader::new(stream).lines()).filter_map(|line| async move {
-                match line {
-                    Ok(line) if line.is_empty() => None,
-                    Ok(line) => Some(
-                        serde_json::from_str::<Game>(&line)
-                            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
-                    ),
-                    Err(err) => Some(Err(err)),
-                }
-            }),
-        ))
+        let res = builder.send().await?;
+        self.rate_limiter.observe(res.status(), res.headers());
+        Ok(ndjson_games(res.error_for_status()?))
     }
+
+    pub async fn games_by_ids(
+        &self,
+        ids: &[GameId],
+    ) -> Result<impl Stream<Item = Result<Game, io::Error>>, reqwest::Error> {
+        // https://lichess.org/api#operation/gamesExportIds
+        let mut builder = self
+            .client
+            .post(format!("{}/api/games/export/_ids", self.opt.lila))
+            .body(
+                ids.iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            )
+            .header("Accept", "application/x-ndjson");
+
+        if let Some(ref bearer) = self.opt.bearer {
+            builder = builder.bearer_auth(bearer);
+        }
+
+        let res = builder.send().await?;
+        self.rate_limiter.observe(res.status(), res.headers());
+        Ok(ndjson_games(res.error_for_status()?))
+    }
+}
+
+fn ndjson_games(res: reqwest::Response) -> impl Stream<Item = Result<Game, io::Error>> {
+    let stream = res
+        .bytes_stream()
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+
+    Box::pin(
+        LinesStream::new(StreamReader::new(stream).lines()).filter_map(|line| async move {
+            match line {
+                Ok(line) if line.is_empty() => None,
+                Ok(line) => Some(
+                    serde_json::from_str::<Game>(&line)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+                ),
+                Err(err) => Some(Err(err)),
+            }
+        }),
+    )
 }
 
 #[serde_as]
@@ -96,6 +131,23 @@ pub struct Game {
     #[serde_as(as = "Option<DisplayFromStr>")]
     #[serde(default)]
     pub initial_fen: Option<Fen>,
+    #[serde(default)]
+    pub clock: Option<LilaClock>,
+}
+
+#[derive(Debug, Deserialize, Copy, Clone)]
+pub struct LilaClock {
+    pub initial: u32,
+    pub increment: u32,
+}
+
+impl From<LilaClock> for Clock {
+    fn from(clock: LilaClock) -> Clock {
+        Clock {
+            initial: clock.initial,
+            increment: clock.increment,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
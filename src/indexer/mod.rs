@@ -15,23 +15,35 @@ use shakmaty::{
 use tokio::{
     sync::{watch, RwLock},
     task::JoinHandle,
-    time::{sleep, timeout},
+    time::{interval, sleep, timeout},
 };
 
 use crate::{
     db::Database,
+    importer::LichessImporter,
     model::{
-        GamePlayer, IndexRun, KeyBuilder, LichessGame, Mode, Month, PlayerEntry, PlayerStatus,
-        UserId,
+        Clock, ContributorEntry, GameId, GamePlayer, IndexRun, KeyBuilder, LichessGame,
+        LichessSource, Mode, Month, PlayerEntry, PlayerStatus, UserId,
     },
+    publisher::{ImportEvent, ImportSource, Publisher},
+    subscriptions::{PositionUpdates, Subscriptions},
 };
 
 mod lila;
+mod rate_limit;
 
 use lila::{Game, Lila};
+use rate_limit::RateLimiter;
 
 const MAX_PLIES: usize = 50;
 
+/// How often recently imported lichess games are checked for having been
+/// aborted after all.
+const RETRACTION_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Maximum number of games that can be requested from lila at once.
+const MAX_IDS_PER_REQUEST: usize = 300;
+
 #[derive(Parser, Clone)]
 pub struct IndexerOpt {
     /// Base url for the indexer.
@@ -43,6 +55,10 @@ pub struct IndexerOpt {
     /// Number of parallel indexing tasks.
     #[clap(long = "indexers", default_value = "16")]
     indexers: usize,
+    /// Maximum number of requests to lila per minute, shared by all indexing
+    /// tasks. Unlimited by default.
+    #[clap(long = "lila-requests-per-minute")]
+    lila_requests_per_minute: Option<u32>,
 }
 
 #[derive(Clone)]
@@ -50,12 +66,21 @@ pub struct IndexerStub {
     db: Arc<Database>,
     indexing: Arc<RwLock<HashMap<UserId, watch::Sender<()>>>>,
     tx: async_channel::Sender<IndexerMessage>,
+    opt: IndexerOpt,
+    rate_limiter: Arc<RateLimiter>,
 }
 
 impl IndexerStub {
-    pub fn spawn(db: Arc<Database>, opt: IndexerOpt) -> (IndexerStub, Vec<JoinHandle<()>>) {
+    pub fn spawn(
+        db: Arc<Database>,
+        publisher: Publisher,
+        subscriptions: Subscriptions,
+        opt: IndexerOpt,
+    ) -> (IndexerStub, Vec<JoinHandle<()>>) {
         let indexing = Arc::new(RwLock::new(HashMap::new()));
 
+        let rate_limiter = Arc::new(RateLimiter::new(opt.lila_requests_per_minute));
+
         let (tx, rx) = async_channel::bounded(opt.indexers * 10);
         let mut join_handles = Vec::with_capacity(opt.indexers);
         for idx in 0..opt.indexers {
@@ -65,13 +90,36 @@ impl IndexerStub {
                     rx: rx.clone(),
                     indexing: Arc::clone(&indexing),
                     db: Arc::clone(&db),
-                    lila: Lila::new(opt.clone()),
+                    publisher: publisher.clone(),
+                    subscriptions: subscriptions.clone(),
+                    lila: Lila::new(opt.clone(), Arc::clone(&rate_limiter)),
                 }
                 .run(),
             ));
         }
 
-        (IndexerStub { db, indexing, tx }, join_handles)
+        (
+            IndexerStub {
+                db,
+                indexing,
+                tx,
+                opt,
+                rate_limiter,
+            },
+            join_handles,
+        )
+    }
+
+    /// Periodically retracts recently imported lichess games that turned out
+    /// to have been aborted.
+    pub fn spawn_retraction(&self, importer: LichessImporter) -> JoinHandle<()> {
+        tokio::spawn(
+            RetractionActor {
+                lila: Lila::new(self.opt.clone(), Arc::clone(&self.rate_limiter)),
+                importer,
+            }
+            .run(),
+        )
     }
 
     pub async fn num_indexing(&self) -> usize {
@@ -80,6 +128,10 @@ impl IndexerStub {
     }
 
     pub async fn index_player(&self, player: &UserId) -> Option<watch::Receiver<()>> {
+        if !self.db.is_writable() {
+            return None; // Serve what is already indexed
+        }
+
         // Optimization: First try subscribing to an existing indexing run,
         // without acquiring a write lock.
         {
@@ -134,11 +186,67 @@ impl IndexerStub {
     }
 }
 
+struct RetractionActor {
+    lila: Lila,
+    importer: LichessImporter,
+}
+
+impl RetractionActor {
+    async fn run(self) {
+        let mut interval = interval(RETRACTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            let ids = self.importer.expire_tracked().await;
+            for chunk in ids.chunks(MAX_IDS_PER_REQUEST) {
+                self.retract_aborted(chunk).await;
+            }
+        }
+    }
+
+    async fn retract_aborted(&self, ids: &[GameId]) {
+        self.lila.acquire().await;
+        let mut games = match timeout(Duration::from_secs(60), self.lila.games_by_ids(ids)).await {
+            Ok(Ok(games)) => games,
+            Ok(Err(err)) => {
+                log::error!("retraction: request failed: {}", err);
+                return;
+            }
+            Err(timed_out) => {
+                log::error!("retraction: request to lila: {}", timed_out);
+                return;
+            }
+        };
+
+        loop {
+            let game = match timeout(Duration::from_secs(60), games.next()).await {
+                Ok(Some(Ok(game))) => game,
+                Ok(Some(Err(err))) => {
+                    log::error!("retraction: {}", err);
+                    continue;
+                }
+                Ok(None) => break,
+                Err(timed_out) => {
+                    log::error!("retraction: stream from lila: {}", timed_out);
+                    return;
+                }
+            };
+
+            if game.status.is_unindexable() {
+                if let Err(err) = self.importer.retract(game.id).await {
+                    log::error!("retraction: lichess game {}: {}", game.id, err);
+                }
+            }
+        }
+    }
+}
+
 struct IndexerActor {
     idx: usize,
     indexing: Arc<RwLock<HashMap<UserId, watch::Sender<()>>>>,
     rx: async_channel::Receiver<IndexerMessage>,
     db: Arc<Database>,
+    publisher: Publisher,
+    subscriptions: Subscriptions,
     lila: Lila,
 }
 
@@ -169,6 +277,7 @@ impl IndexerActor {
             index_run,
         );
 
+        self.lila.acquire().await;
         let mut games = match timeout(
             Duration::from_secs(60),
             self.lila.user_games(player, index_run.since()),
@@ -184,6 +293,10 @@ impl IndexerActor {
                 );
                 return;
             }
+            Ok(Err(err)) if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) => {
+                log::error!("indexer {:02}: rate limited by lila", self.idx);
+                return;
+            }
             Ok(Err(err)) => {
                 log::error!("indexer {:02}: request failed: {}", self.idx, err);
                 sleep(Duration::from_secs(5)).await;
@@ -387,6 +500,13 @@ impl IndexerActor {
         // Write to database. All writes regarding this game are batched and
         // atomically committed, so the database will always be in a consistent
         // state.
+        let num_keys = table.len();
+        let rating_bucket = ImportEvent::rating_bucket(
+            game.players.white.rating.unwrap_or_default(),
+            game.players.black.rating.unwrap_or_default(),
+        );
+        let watched = self.subscriptions.is_watched();
+        let mut moves = FxHashMap::default();
         let mut batch = lichess_db.batch();
 
         batch.merge_game(
@@ -399,17 +519,30 @@ impl IndexerActor {
                 players: game.players.map(|p| GamePlayer {
                     name: p.user.map_or(String::new(), |u| u.name.to_string()),
                     rating: p.rating.unwrap_or_default(),
+                    provisional: false,
+                    estimated_rating: None,
+                    estimated: false,
+                    bot: false,
                 }),
                 indexed_player: ByColor::new_with(|c| color == c),
                 indexed_lichess: false,
+                source: LichessSource::default(),
+                clock: game.clock.map(Clock::from),
             },
         );
 
         for (zobrist, uci) in table {
+            batch.merge_contributor(
+                &KeyBuilder::lichess().with_zobrist(variant, zobrist),
+                player,
+                ContributorEntry::new_single(color),
+            );
+            let prefix = hash.get(color).with_zobrist(variant, zobrist);
+            if watched {
+                moves.insert(prefix.clone(), uci.clone());
+            }
             batch.merge_player(
-                hash.get(color)
-                    .with_zobrist(variant, zobrist)
-                    .with_month(month),
+                prefix.with_month(month),
                 PlayerEntry::new_single(
                     uci.clone(),
                     game.speed,
@@ -422,6 +555,21 @@ impl IndexerActor {
         }
 
         batch.commit().expect("atomically commit game and moves");
+
+        self.subscriptions.notify(PositionUpdates {
+            source: ImportSource::Player,
+            id: game.id,
+            outcome,
+            moves,
+        });
+
+        self.publisher.publish(ImportEvent {
+            source: ImportSource::Player,
+            id: game.id,
+            keys: num_keys,
+            speed: Some(game.speed),
+            rating_bucket,
+        });
     }
 }
 
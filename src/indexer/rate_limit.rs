@@ -0,0 +1,144 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use reqwest::header::HeaderMap;
+use tokio::time::sleep;
+
+const BURST: f64 = 4.0;
+
+/// Default pause after being rate limited, if lila does not say otherwise.
+const RATE_LIMITED_PAUSE: Duration = Duration::from_secs(60);
+
+/// Token bucket for requests to lila, shared by all indexers, so that bursts
+/// of index runs do not get the API token banned. Without a configured rate,
+/// requests are only paused when lila asks for it.
+pub struct RateLimiter {
+    per_second: Option<f64>,
+    state: Mutex<State>,
+}
+
+struct State {
+    tokens: f64,
+    updated_at: Instant,
+    paused_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: Option<u32>) -> RateLimiter {
+        RateLimiter {
+            per_second: per_minute.map(|per_minute| f64::from(per_minute.max(1)) / 60.0),
+            state: Mutex::new(State {
+                tokens: BURST,
+                updated_at: Instant::now(),
+                paused_until: None,
+            }),
+        }
+    }
+
+    /// Waits until a request may be sent.
+    pub async fn acquire(&self) {
+        while let Err(wait) = self.try_acquire(Instant::now()) {
+            sleep(wait).await;
+        }
+    }
+
+    /// Takes a token if a request may be sent at the given time, or else
+    /// returns how long to wait before trying again.
+    fn try_acquire(&self, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().expect("rate limiter");
+        if let Some(until) = state.paused_until {
+            if now < until {
+                return Err(until - now);
+            }
+            state.paused_until = None;
+        }
+        let per_second = match self.per_second {
+            Some(per_second) => per_second,
+            None => return Ok(()),
+        };
+        state.tokens = (state.tokens
+            + now
+                .saturating_duration_since(state.updated_at)
+                .as_secs_f64()
+                * per_second)
+            .min(BURST);
+        state.updated_at = now;
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - state.tokens) / per_second))
+        }
+    }
+
+    /// Pauses all requests, if the response indicates that the rate limit
+    /// was hit or is about to be hit.
+    pub fn observe(&self, status: reqwest::StatusCode, headers: &HeaderMap) {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+
+        let pause = if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            Some(header("Retry-After").map_or(RATE_LIMITED_PAUSE, Duration::from_secs))
+        } else if header("X-RateLimit-Remaining") == Some(0) {
+            header("X-RateLimit-Reset").map(Duration::from_secs)
+        } else {
+            None
+        };
+
+        if let Some(pause) = pause {
+            log::warn!("pausing requests to lila for {:?}", pause);
+            let until = Instant::now() + pause;
+            let mut state = self.state.lock().expect("rate limiter");
+            state.paused_until = Some(state.paused_until.map_or(until, |u| u.max(until)));
+            state.tokens = 0.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_and_refill() {
+        let limiter = RateLimiter::new(Some(60));
+        let start = Instant::now();
+        for _ in 0..4 {
+            assert_eq!(limiter.try_acquire(start), Ok(()));
+        }
+        let wait = limiter.try_acquire(start).unwrap_err();
+        assert!(wait <= Duration::from_secs(1));
+
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limiter.try_acquire(later), Ok(()), "refilled one token");
+        assert!(limiter.try_acquire(later).is_err());
+
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..4 {
+            assert_eq!(limiter.try_acquire(much_later), Ok(()));
+        }
+        assert!(limiter.try_acquire(much_later).is_err(), "capped at burst");
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = RateLimiter::new(None);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(limiter.try_acquire(now), Ok(()));
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert("Retry-After", "10".parse().unwrap());
+        limiter.observe(reqwest::StatusCode::TOO_MANY_REQUESTS, &headers);
+        let now = Instant::now();
+        assert!(limiter.try_acquire(now).is_err(), "paused by lila");
+        assert_eq!(limiter.try_acquire(now + Duration::from_secs(10)), Ok(()));
+    }
+}
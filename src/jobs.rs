@@ -0,0 +1,73 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    sync::Mutex,
+    time::{Instant, SystemTime},
+};
+
+use serde::Serialize;
+
+const CAPACITY: usize = 64;
+
+#[derive(Serialize, Debug, Copy, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum JobKind {
+    Compact,
+    Backup,
+    ExportDelta,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct JobRecord {
+    kind: JobKind,
+    /// Seconds since the Unix epoch.
+    started_at: u64,
+    duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Ring buffer of recently finished administrative jobs.
+#[derive(Default)]
+pub struct JobHistory {
+    jobs: Mutex<VecDeque<JobRecord>>,
+}
+
+impl JobHistory {
+    /// Runs the job and records its outcome.
+    pub fn run<T, E: Display, F: FnOnce() -> Result<T, E>>(
+        &self,
+        kind: JobKind,
+        f: F,
+    ) -> Result<T, E> {
+        let started_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let started = Instant::now();
+        let res = f();
+        let record = JobRecord {
+            kind,
+            started_at,
+            duration_ms: started.elapsed().as_millis() as u64,
+            error: res.as_ref().err().map(|err| err.to_string()),
+        };
+
+        let mut guard = self.jobs.lock().expect("job history");
+        if guard.len() >= CAPACITY {
+            guard.pop_back();
+        }
+        guard.push_front(record);
+        res
+    }
+
+    /// Most recent jobs first.
+    pub fn list(&self) -> Vec<JobRecord> {
+        self.jobs
+            .lock()
+            .expect("job history")
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
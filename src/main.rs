@@ -1,45 +1,122 @@
 #![forbid(unsafe_code)]
 
+pub mod accounts;
 pub mod api;
+pub mod bench;
+pub mod branching;
 pub mod db;
+pub mod deadline;
+pub mod delta;
+pub mod duplicates;
+pub mod errata;
+pub mod feed;
+pub mod graph;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod hot;
 pub mod importer;
 pub mod indexer;
+pub mod jobs;
 pub mod model;
 pub mod opening;
+pub mod pgn;
+pub mod proxy;
+pub mod publisher;
+pub mod rating_distribution;
+pub mod selfcheck;
+pub mod shaping;
+pub mod snapshots;
+pub mod subscriptions;
+pub mod theory;
 pub mod util;
+pub mod variants;
 
-use std::{mem, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    cmp::{min, Reverse},
+    fmt::Write as _,
+    fs::File,
+    io::{BufRead as _, BufReader, Cursor},
+    mem,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
 use axum::{
     extract::{Extension, Path, Query},
-    http::StatusCode,
-    routing::{get, post, put},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    routing::{delete, get, post, put},
     AddExtensionLayer, Json, Router,
 };
-use clap::Parser;
-use futures_util::stream::Stream;
-use serde::Deserialize;
+use bytes::Bytes;
+use clap::{Parser, Subcommand};
+use futures_util::{future, stream::Stream};
+use rustc_hash::FxHashSet;
+use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use shakmaty::{
+    fen::Epd,
     san::{San, SanPlus},
     uci::Uci,
-    variant::VariantPosition,
+    variant::{Variant, VariantPosition},
+    zobrist::Zobrist,
+    ByColor, CastlingMode, Color, EnPassantMode, Position as _,
 };
 use tikv_jemallocator::Jemalloc;
-use tokio::sync::watch;
+use tokio::{sync::watch, task};
 use tower::ServiceBuilder;
 
 use crate::{
+    accounts::{bearer_token, Accounts, ApiKeyGrant},
     api::{
-        Error, ExplorerGame, ExplorerGameWithUci, ExplorerMove, ExplorerResponse, LichessQuery,
-        Limits, MastersQuery, NdJson, PlayPosition, PlayerQuery, PlayerQueryFilter,
+        AccountResponse, ApiKeyListing, BestMove, BookExit, BookExitPly, Chunked, Conditional,
+        Contributor, Dashboard, DeltaQuery, DepthProfile, DepthProfilePly, Error, ExplorerBody,
+        ExplorerGame, ExplorerGameWithUci, ExplorerMove, ExplorerResponse, FeedQuery, FeedSource,
+        GamesPage, GraphQuery, LichessBestQuery, LichessDepthProfileQuery, LichessGamesQuery,
+        LichessQuery, LichessQueryFilter, LichessTrendQuery, Limits, MastersQuery, MetaResponse,
+        MoveOrder, MoveOrdersQuery, NdJson, NearDuplicate, OpenApi, OpponentRatingStats, Parent,
+        ParentsQuery, PendingErratum, Percentages, PgnImportReport, PgnRejection, Play, PlayLine,
+        PlayPosition, PlayerColor, PlayerFindQuery, PlayerQuery, PlayerQueryFilter, PositionCache,
+        PositionCacheStats, PrepMove, PrepQuery, PrepResponse, PreparedJson, ResponseFormat,
+        Revalidation, SampleQuery, SampleReport, SampledEntry, SampledGroup, SamplingBucket,
+        SamplingQuery, SamplingReport, ShadowComparison, SpecialMoves, SpeedGroupMeta, SpeedStats,
+        TheoryQuery, TrendMonth, TrendMove, TrendResponse, VariantMeta,
+    },
+    branching::BranchingFactors,
+    db::{Database, DbOpt, LichessDatabase},
+    deadline::Deadline,
+    duplicates::DuplicateReview,
+    errata::Errata,
+    feed::RecentGames,
+    graph::OpeningGraph,
+    hot::HotOpt,
+    importer::{
+        Acceptance, AcceptancePolicy, AcceptanceStatus, ImportOpt, LichessGameImport,
+        LichessImporter, MastersImporter, RatingChecks, RatingCounters,
     },
-    db::{Database, LichessDatabase},
-    importer::{LichessGameImport, LichessImporter, MastersImporter},
     indexer::{IndexerOpt, IndexerStub},
-    model::{GameId, KeyBuilder, KeyPrefix, MastersGame, MastersGameWithId, PreparedMove, UserId},
+    jobs::{JobHistory, JobKind, JobRecord},
+    model::{
+        Erratum, GameId, Key, KeyBuilder, KeyPrefix, LichessEntry, LichessSource, MastersEntry,
+        MastersGame, MastersGameWithId, Month, ParentMove, PathHash, PlayerEntry, PreparedMove,
+        SpeedGroup, Stats, TenantId, UserId, Week, Year,
+    },
     opening::{Opening, Openings},
+    pgn::{read_masters_pgn, LichessRoot, PgnBundle},
+    proxy::{ProxyLayer, ProxyOpt},
+    publisher::{Publisher, PublisherOpt},
+    rating_distribution::RatingDistribution,
+    selfcheck::SelfCheckOpt,
+    shaping::{Budgets, Shaper, ShapingLayer, ShapingOpt},
+    snapshots::Snapshots,
+    subscriptions::Subscriptions,
+    theory::TheoryTable,
     util::DedupStreamExt as _,
+    variants::VariantStats,
 };
 
 #[global_allocator]
@@ -57,8 +134,53 @@ struct Opt {
     /// Allow access from all origins.
     #[clap(long)]
     cors: bool,
+    /// Binding address of the gRPC service. Not started if not given.
+    #[cfg(feature = "grpc")]
+    #[clap(long)]
+    grpc_bind: Option<SocketAddr>,
+    #[clap(flatten)]
+    db_opt: DbOpt,
     #[clap(flatten)]
     indexer: IndexerOpt,
+    #[clap(flatten)]
+    publisher: PublisherOpt,
+    #[clap(flatten)]
+    hot: HotOpt,
+    #[clap(flatten)]
+    import: ImportOpt,
+    #[clap(flatten)]
+    shaping: ShapingOpt,
+    #[clap(flatten)]
+    proxy: ProxyOpt,
+    #[clap(flatten)]
+    self_check: SelfCheckOpt,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Import NDJSON files written by index-lichess --output, instead of
+    /// starting the server.
+    ImportFile {
+        files: Vec<PathBuf>,
+        /// Source to store the games under.
+        #[clap(long, default_value = "dump")]
+        source: LichessSource,
+    },
+    /// Merge monthly delta files exported by another instance, instead of
+    /// starting the server.
+    ImportDelta { files: Vec<PathBuf> },
+    /// Measure throughput of model code on synthetic entries, instead of
+    /// starting the server.
+    Bench {
+        /// JSON file with results of a previous run to compare against.
+        #[clap(long)]
+        baseline: Option<PathBuf>,
+        /// Store the results as the new baseline.
+        #[clap(long, requires = "baseline")]
+        save_baseline: bool,
+    },
 }
 
 #[tokio::main]
@@ -75,34 +197,221 @@ async fn main() {
 
     let opt = Opt::parse();
 
+    if let Some(Command::Bench {
+        baseline,
+        save_baseline,
+    }) = opt.command
+    {
+        bench::run(baseline.as_deref(), save_baseline).expect("bench");
+        return;
+    }
+
+    let (publisher, publisher_join_handle) = Publisher::spawn(opt.publisher);
+    let rating_checks = Arc::new(RatingChecks::new(opt.import));
+
+    if let Some(Command::ImportDelta { files }) = opt.command {
+        let db = Database::open(opt.db, &opt.db_opt).expect("db");
+        for path in files {
+            let (month, records) = delta::import(
+                &db,
+                BufReader::new(File::open(&path).expect("open delta file")),
+            )
+            .expect("import delta file");
+            log::info!("imported {} entries of {} from {:?}", records, month, path);
+        }
+        return;
+    }
+
+    if let Some(Command::ImportFile { files, source }) = opt.command {
+        let db = Arc::new(Database::open(opt.db, &opt.db_opt).expect("db"));
+        import_files(
+            LichessImporter::new(
+                Arc::clone(&db),
+                Arc::new(Acceptance::default()),
+                rating_checks,
+                Arc::new(VariantStats::load(&db.lichess())),
+                Arc::new(RatingDistribution::default()),
+                Arc::new(RecentGames::default()),
+                publisher,
+                Subscriptions::default(),
+            ),
+            files,
+            source,
+        )
+        .await;
+        if let Some(join_handle) = publisher_join_handle {
+            join_handle.await.expect("publisher");
+        }
+        return;
+    }
+
     let openings: &'static Openings = Box::leak(Box::new(Openings::build_table()));
-    let db = Arc::new(Database::open(opt.db).expect("db"));
-    let (indexer, join_handles) = IndexerStub::spawn(Arc::clone(&db), opt.indexer);
-    let masters_importer = MastersImporter::new(Arc::clone(&db));
-    let lichess_importer = LichessImporter::new(Arc::clone(&db));
+    let branching_factors: &'static BranchingFactors =
+        Box::leak(Box::new(BranchingFactors::default()));
+    let db = Arc::new(Database::open(opt.db, &opt.db_opt).expect("db"));
+    selfcheck::run(&db, &opt.self_check);
+    let snapshots = Arc::new(Snapshots::new(&opt.db_opt));
+    db.lichess().load_hot_positions(opt.hot);
+    let variant_stats = Arc::new(VariantStats::load(&db.lichess()));
+    let rating_distribution = Arc::new(RatingDistribution::default());
+    let recent_games = Arc::new(RecentGames::default());
+    let accounts = Arc::new(Accounts::load(Arc::clone(&db)));
+    let shaper = Arc::new(Shaper::new(opt.shaping, Arc::clone(&accounts)));
+    let (proxy_layer, access_log_join_handle) = ProxyLayer::spawn(opt.proxy, Arc::clone(&shaper));
+    let subscriptions = Subscriptions::default();
+    let (indexer, mut join_handles) = IndexerStub::spawn(
+        Arc::clone(&db),
+        publisher.clone(),
+        subscriptions.clone(),
+        opt.indexer,
+    );
+    join_handles.extend(publisher_join_handle);
+    join_handles.extend(access_log_join_handle);
+    let errata = Arc::new(Errata::load(&db.masters()));
+    let duplicate_review = Arc::new(DuplicateReview::default());
+    let masters_importer = MastersImporter::new(
+        Arc::clone(&db),
+        Arc::clone(&errata),
+        Arc::clone(&rating_checks),
+        Arc::clone(&recent_games),
+        Arc::clone(&duplicate_review),
+        publisher.clone(),
+        subscriptions.clone(),
+    );
+    let acceptance = Arc::new(Acceptance::default());
+    let lichess_importer = LichessImporter::new(
+        Arc::clone(&db),
+        Arc::clone(&acceptance),
+        Arc::clone(&rating_checks),
+        Arc::clone(&variant_stats),
+        Arc::clone(&rating_distribution),
+        Arc::clone(&recent_games),
+        publisher,
+        subscriptions.clone(),
+    );
+    masters_importer.spawn_errata_folding();
+    join_handles.push(branching_factors.spawn_refresh(Arc::clone(&db)));
+    join_handles.push(indexer.spawn_retraction(lichess_importer.clone()));
+    join_handles.push(lichess_importer.spawn_pruning());
+    join_handles.push(Arc::clone(&accounts).spawn_flushing());
+
+    let positions = Arc::new(PositionCache::default());
+
+    #[cfg(feature = "grpc")]
+    if let Some(bind) = opt.grpc_bind {
+        join_handles.push(
+            grpc::ExplorerService {
+                openings,
+                positions: Arc::clone(&positions),
+                branching_factors,
+                db: Arc::clone(&db),
+                snapshots: Arc::clone(&snapshots),
+                errata: Arc::clone(&errata),
+                indexer: indexer.clone(),
+                masters_importer: masters_importer.clone(),
+                lichess_importer: lichess_importer.clone(),
+            }
+            .spawn(bind),
+        );
+    }
 
     let app = Router::new()
         .route("/monitor/cf/:cf/:prop", get(cf_prop))
         .route("/monitor/db/:prop", get(db_prop))
         .route("/monitor/indexing", get(num_indexing))
+        .route("/monitor/import/ratings", get(import_ratings))
+        .route("/monitor/import/speeds", get(import_speed_mismatches))
+        .route("/monitor/stats/saturated", get(stats_saturated))
+        .route("/monitor/cache/positions", get(position_cache_stats))
+        .route("/openapi.json", get(openapi))
+        .route("/meta", get(meta))
+        .route("/meta/variants", get(meta_variants))
         .route("/compact", post(compact))
+        .route("/admin", get(dashboard))
+        .route("/admin/jobs", get(job_history))
+        .route("/admin/backup", post(backup))
+        .route("/admin/export/delta", post(export_delta))
+        .route("/admin/rebuild", post(rebuild))
+        .route("/admin/rebuild/index", post(index_positions))
+        .route("/admin/read-only", get(read_only).put(set_read_only))
+        .route("/admin/sampling", get(sampling_report))
+        .route("/admin/sample", get(sample))
+        .route("/admin/shadow", get(shadow_comparison))
+        .route("/admin/errata", get(pending_errata))
+        .route(
+            "/admin/import/policy",
+            get(acceptance_status).put(set_acceptance_policy),
+        )
+        .route("/admin/errata/:id", put(submit_erratum))
+        .route("/admin/lichess/games/:id", delete(erase_lichess_game))
+        .route("/admin/duplicates", get(pending_duplicates))
+        .route("/admin/duplicates/:id", delete(dismiss_duplicate))
+        .route(
+            "/admin/shaping",
+            get(shaping_budgets).put(set_shaping_budgets),
+        )
+        .route("/admin/api-keys", get(api_keys).put(grant_api_key))
+        .route("/admin/api-keys/:name", delete(revoke_api_keys))
         .route("/import/masters", put(masters_import))
+        .route("/import/masters/pgn", put(masters_import_pgn))
         .route("/import/lichess", put(lichess_import))
+        .route("/masters/pgn", get(masters_pgn_bundle))
         .route("/masters/pgn/:id", get(masters_pgn))
         .route("/masters", get(masters))
+        .route("/masters/batch", post(masters_batch))
         .route("/lichess", get(lichess))
+        .route("/lichess/batch", post(lichess_batch))
+        .route("/lichess/pgn", get(lichess_pgn_bundle))
+        .route("/lichess/best", get(lichess_best))
+        .route("/lichess/trend", get(lichess_trend))
+        .route("/lichess/parents", get(lichess_parents))
+        .route("/lichess/move-orders", get(lichess_move_orders))
+        .route("/lichess/games", get(lichess_games))
+        .route("/lichess/depth-profile", get(lichess_depth_profile))
         .route("/player", get(player))
+        .route("/player/prep", get(player_prep))
+        .route("/player/find", get(player_find))
+        .route("/account", get(account))
+        .route("/tenant/masters", get(tenant_masters))
+        .route("/tenant/pgn/:id", get(tenant_pgn))
+        .route("/tenant/import/pgn", put(tenant_import_pgn))
+        .route("/export/graph", get(export_graph))
+        .route("/export/theory", get(export_theory))
+        .route("/feed/recent", get(feed_recent))
+        .route("/subscribe", get(subscriptions::handler))
         .route("/master/pgn/:id", get(masters_pgn)) // bc
         .route("/master", get(masters)) // bc
-        .route("/personal", get(player)) // bc
-        .layer(
-            ServiceBuilder::new()
-                .layer(AddExtensionLayer::new(openings))
-                .layer(AddExtensionLayer::new(db))
-                .layer(AddExtensionLayer::new(masters_importer))
-                .layer(AddExtensionLayer::new(lichess_importer))
-                .layer(AddExtensionLayer::new(indexer)),
-        );
+        .route("/personal", get(player)); // bc
+
+    #[cfg(feature = "graphql")]
+    let app = app
+        .route("/graphql", post(graphql::handler))
+        .layer(AddExtensionLayer::new(graphql::schema()));
+
+    let app = app.layer(
+        ServiceBuilder::new()
+            .layer(proxy_layer)
+            .layer(AddExtensionLayer::new(openings))
+            .layer(AddExtensionLayer::new(positions))
+            .layer(AddExtensionLayer::new(branching_factors))
+            .layer(AddExtensionLayer::new(db))
+            .layer(AddExtensionLayer::new(snapshots))
+            .layer(AddExtensionLayer::new(rating_checks))
+            .layer(AddExtensionLayer::new(variant_stats))
+            .layer(AddExtensionLayer::new(rating_distribution))
+            .layer(AddExtensionLayer::new(recent_games))
+            .layer(AddExtensionLayer::new(errata))
+            .layer(AddExtensionLayer::new(duplicate_review))
+            .layer(AddExtensionLayer::new(acceptance))
+            .layer(AddExtensionLayer::new(masters_importer))
+            .layer(AddExtensionLayer::new(lichess_importer))
+            .layer(AddExtensionLayer::new(indexer))
+            .layer(AddExtensionLayer::new(Arc::new(JobHistory::default())))
+            .layer(AddExtensionLayer::new(accounts))
+            .layer(AddExtensionLayer::new(subscriptions))
+            .layer(AddExtensionLayer::new(Arc::clone(&shaper)))
+            .layer(ShapingLayer::new(shaper)),
+    );
 
     let app = if opt.cors {
         app.layer(
@@ -116,12 +425,47 @@ async fn main() {
     };
 
     axum::Server::bind(&opt.bind)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr, _>())
         .await
         .expect("bind");
 
     for join_handle in join_handles {
-        join_handle.await.expect("indexer");
+        join_handle.await.expect("background task");
+    }
+}
+
+async fn import_files(importer: LichessImporter, files: Vec<PathBuf>, source: LichessSource) {
+    for path in files {
+        log::info!("importing {:?} ...", path);
+        let reader = BufReader::new(File::open(&path).expect("open ndjson file"));
+        let (mut imported, mut rejected) = (0u64, 0u64);
+        for line in reader.lines() {
+            let line = line.expect("read ndjson line");
+            if line.is_empty() {
+                continue;
+            }
+            let game: LichessGameImport = match serde_json::from_str(&line) {
+                Ok(game) => game,
+                Err(err) => {
+                    log::error!("skipping invalid line in {:?}: {}", path, err);
+                    rejected += 1;
+                    continue;
+                }
+            };
+            match importer.import(game, source).await {
+                Ok(()) => imported += 1,
+                Err(err) => {
+                    log::warn!("{}", err);
+                    rejected += 1;
+                }
+            }
+        }
+        log::info!(
+            "imported {} games from {:?} ({} rejected)",
+            imported,
+            path,
+            rejected
+        );
     }
 }
 
@@ -155,12 +499,397 @@ async fn db_prop(
         .ok_or(StatusCode::NOT_FOUND)
 }
 
+async fn openapi() -> OpenApi {
+    OpenApi
+}
+
+async fn meta(
+    Extension(db): Extension<Arc<Database>>,
+    Extension(rating_distribution): Extension<Arc<RatingDistribution>>,
+) -> Json<MetaResponse> {
+    Json(MetaResponse {
+        db: db.tuning.clone(),
+        compaction_lag: db.compaction_lag(),
+        speed_groups: SpeedGroup::ALL
+            .into_iter()
+            .map(|name| SpeedGroupMeta {
+                name,
+                speeds: name.speeds(),
+            })
+            .collect(),
+        rating_distribution: rating_distribution.summarize(),
+    })
+}
+
+async fn meta_variants(
+    Extension(variant_stats): Extension<Arc<VariantStats>>,
+) -> Json<Vec<VariantMeta>> {
+    Json(variant_stats.summarize())
+}
+
 async fn num_indexing(Extension(indexer): Extension<IndexerStub>) -> String {
     indexer.num_indexing().await.to_string()
 }
 
-async fn compact(Extension(db): Extension<Arc<Database>>) {
-    db.compact();
+async fn position_cache_stats(
+    Extension(positions): Extension<Arc<PositionCache>>,
+) -> Json<PositionCacheStats> {
+    Json(positions.stats())
+}
+
+async fn import_ratings(
+    Extension(rating_checks): Extension<Arc<RatingChecks>>,
+) -> Json<RatingCounters> {
+    Json(rating_checks.counters())
+}
+
+async fn import_speed_mismatches(Extension(importer): Extension<LichessImporter>) -> String {
+    importer.speed_mismatches().to_string()
+}
+
+async fn acceptance_status(
+    Extension(acceptance): Extension<Arc<Acceptance>>,
+) -> Json<AcceptanceStatus> {
+    Json(acceptance.status())
+}
+
+async fn set_acceptance_policy(
+    Json(policy): Json<AcceptancePolicy>,
+    Extension(acceptance): Extension<Arc<Acceptance>>,
+) {
+    acceptance.set_policy(policy);
+}
+
+async fn stats_saturated() -> String {
+    model::saturated().to_string()
+}
+
+async fn shaping_budgets(Extension(shaper): Extension<Arc<Shaper>>) -> Json<Budgets> {
+    Json(shaper.budgets())
+}
+
+async fn set_shaping_budgets(
+    Json(budgets): Json<Budgets>,
+    Extension(shaper): Extension<Arc<Shaper>>,
+) {
+    shaper.set_budgets(budgets);
+}
+
+async fn api_keys(Extension(accounts): Extension<Arc<Accounts>>) -> Json<Vec<ApiKeyListing>> {
+    Json(accounts.list())
+}
+
+async fn grant_api_key(
+    Json(grant): Json<ApiKeyGrant>,
+    Extension(accounts): Extension<Arc<Accounts>>,
+) -> Result<(), Error> {
+    accounts.grant(grant)
+}
+
+async fn revoke_api_keys(
+    Path(name): Path<String>,
+    Extension(accounts): Extension<Arc<Accounts>>,
+) -> Result<(), Error> {
+    if accounts.revoke(&name)? {
+        Ok(())
+    } else {
+        Err(Error::UnknownApiKey)
+    }
+}
+
+async fn account(
+    headers: HeaderMap,
+    Extension(accounts): Extension<Arc<Accounts>>,
+) -> Result<Json<AccountResponse>, Error> {
+    let token = bearer_token(&headers).ok_or(Error::UnknownApiKey)?;
+    accounts.account(token).map(Json)
+}
+
+async fn compact(
+    Extension(db): Extension<Arc<Database>>,
+    Extension(jobs): Extension<Arc<JobHistory>>,
+) -> Result<(), Error> {
+    jobs.run(JobKind::Compact, || {
+        if !db.is_writable() {
+            return Err(Error::ReadOnly);
+        }
+        db.compact();
+        Ok(())
+    })
+}
+
+async fn backup(
+    Extension(db): Extension<Arc<Database>>,
+    Extension(jobs): Extension<Arc<JobHistory>>,
+) -> Result<String, Error> {
+    task::spawn_blocking(move || {
+        jobs.run(JobKind::Backup, || match db.backup() {
+            Ok(Some(path)) => Ok(path.display().to_string()),
+            Ok(None) => Err(Error::BackupNotConfigured),
+            Err(err) => Err(Error::BackupFailed(err)),
+        })
+    })
+    .await
+    .expect("blocking backup")
+}
+
+async fn export_delta(
+    Query(query): Query<DeltaQuery>,
+    Extension(db): Extension<Arc<Database>>,
+    Extension(jobs): Extension<Arc<JobHistory>>,
+) -> Result<String, Error> {
+    task::spawn_blocking(move || {
+        jobs.run(JobKind::ExportDelta, || {
+            match delta::export(&db, query.month) {
+                Ok(Some(path)) => Ok(path.display().to_string()),
+                Ok(None) => Err(Error::DeltaNotConfigured),
+                Err(err) => Err(Error::DeltaFailed(err)),
+            }
+        })
+    })
+    .await
+    .expect("blocking delta export")
+}
+
+async fn job_history(Extension(jobs): Extension<Arc<JobHistory>>) -> Json<Vec<JobRecord>> {
+    Json(jobs.list())
+}
+
+async fn dashboard() -> Dashboard {
+    Dashboard
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReadOnly {
+    read_only: bool,
+    #[serde(default, skip_deserializing)]
+    opened_read_only: bool,
+}
+
+async fn read_only(Extension(db): Extension<Arc<Database>>) -> Json<ReadOnly> {
+    Json(ReadOnly {
+        read_only: !db.is_writable(),
+        opened_read_only: db.opened_read_only(),
+    })
+}
+
+async fn set_read_only(
+    Json(body): Json<ReadOnly>,
+    Extension(db): Extension<Arc<Database>>,
+) -> Result<Json<ReadOnly>, Error> {
+    if !db.pause_writes(body.read_only) {
+        return Err(Error::ReadOnly);
+    }
+    log::warn!("database writes paused: {}", body.read_only);
+    Ok(read_only(Extension(db)).await)
+}
+
+async fn rebuild(
+    Extension(openings): Extension<&'static Openings>,
+    Extension(importer): Extension<MastersImporter>,
+    Query(play): Query<Play>,
+) -> Result<String, Error> {
+    // Only masters games are stored with their moves, so lichess and player
+    // entries can not be rebuilt.
+    let key_builder = masters_key_builder(&play);
+    let PlayPosition { variant, pos, .. } = play.position(openings)?;
+    let key = key_builder.with_zobrist(variant, pos.zobrist_hash());
+    Ok(importer.rebuild(key).await?.to_string())
+}
+
+async fn index_positions(Extension(importer): Extension<MastersImporter>) -> Result<String, Error> {
+    Ok(importer.index_positions().await?.to_string())
+}
+
+async fn pending_errata(Extension(errata): Extension<Arc<Errata>>) -> Json<Vec<PendingErratum>> {
+    Json(
+        errata
+            .pending()
+            .into_iter()
+            .map(|(id, erratum)| PendingErratum { id, erratum })
+            .collect(),
+    )
+}
+
+async fn submit_erratum(
+    Path(MastersGameId(id)): Path<MastersGameId>,
+    Json(erratum): Json<Erratum>,
+    Extension(importer): Extension<MastersImporter>,
+) -> Result<(), Error> {
+    importer.submit_erratum(id, erratum).await
+}
+
+/// Schedules removal of all references to a lichess game, for example after
+/// it was erased on lichess.
+async fn erase_lichess_game(
+    Path(LichessGameId(id)): Path<LichessGameId>,
+    Extension(importer): Extension<LichessImporter>,
+) {
+    importer.schedule_pruning(id);
+}
+
+async fn pending_duplicates(
+    Extension(duplicate_review): Extension<Arc<DuplicateReview>>,
+) -> Json<Vec<NearDuplicate>> {
+    Json(duplicate_review.pending())
+}
+
+async fn dismiss_duplicate(
+    Path(MastersGameId(id)): Path<MastersGameId>,
+    Extension(duplicate_review): Extension<Arc<DuplicateReview>>,
+) -> Result<(), Error> {
+    if duplicate_review.dismiss(id) {
+        Ok(())
+    } else {
+        Err(Error::UnknownGame(id))
+    }
+}
+
+async fn sampling_report(
+    Extension(db): Extension<Arc<Database>>,
+    Query(query): Query<SamplingQuery>,
+) -> Json<SamplingReport> {
+    // Games from custom positions are not counted. There are few of them.
+    let key = KeyBuilder::lichess().with_zobrist(
+        Variant::Chess,
+        Zobrist::<_, u128>::new(VariantPosition::new(Variant::Chess)).zobrist_hash(),
+    );
+    let entry = db
+        .lichess()
+        .read_lichess(&key, query.month, query.month)
+        .expect("get lichess");
+
+    let mut buckets = Vec::new();
+    for (speed, rating_group, stored) in entry.games_by_group() {
+        let imported = u64::from(query.imported_rate(speed, rating_group));
+        buckets.push(SamplingBucket {
+            speed,
+            rating: rating_group.lower_bound(),
+            stored,
+            estimated: (imported > 0)
+                .then(|| stored * u64::from(query.rate(speed, rating_group)) / imported),
+        });
+    }
+
+    Json(SamplingReport {
+        month: query.month,
+        stored: buckets.iter().map(|bucket| bucket.stored).sum(),
+        estimated: buckets.iter().filter_map(|bucket| bucket.estimated).sum(),
+        buckets,
+    })
+}
+
+const MAX_SAMPLE: usize = 100_000;
+
+async fn shadow_comparison(
+    Extension(openings): Extension<&'static Openings>,
+    Extension(positions): Extension<Arc<PositionCache>>,
+    Extension(db): Extension<Arc<Database>>,
+    Query(query): Query<LichessQuery>,
+) -> Result<Json<ShadowComparison>, Error> {
+    let PlayPosition { variant, pos, .. } = positions.position(query.play, openings)?;
+    let key = KeyBuilder::lichess().with_zobrist(variant, pos.zobrist_hash());
+
+    Ok(Json(
+        task::spawn_blocking(move || {
+            let lichess_db = db.lichess();
+            let production = lichess_db
+                .read_lichess(&key, query.filter.since, query.filter.until)
+                .expect("read lichess")
+                .prepare(&query.filter, &query.limits);
+            let shadow = lichess_db
+                .read_lichess_shadow(&key, query.filter.since, query.filter.until)
+                .expect("read shadow lichess")
+                .prepare(&query.filter, &query.limits);
+            ShadowComparison {
+                shadow_writes: db.shadow_writes,
+                identical: production == shadow,
+                production_moves: production.moves.len(),
+                production: production.total,
+                shadow_moves: shadow.moves.len(),
+                shadow: shadow.total,
+            }
+        })
+        .await
+        .expect("blocking shadow comparison"),
+    ))
+}
+
+async fn sample(
+    Extension(db): Extension<Arc<Database>>,
+    Query(query): Query<SampleQuery>,
+) -> Json<SampleReport> {
+    let n = min(query.n, MAX_SAMPLE);
+    let entries = task::spawn_blocking(move || {
+        let mut entries = Vec::with_capacity(n);
+        match query.source {
+            FeedSource::Lichess => db.lichess().sample_lichess(n, |key, value| {
+                let mut entry = LichessEntry::default();
+                entry
+                    .extend_from_reader(&mut Cursor::new(value))
+                    .expect("deserialize lichess entry");
+                let groups: Vec<SampledGroup> = entry
+                    .games_by_group()
+                    .into_iter()
+                    .map(|(speed, rating_group, games)| SampledGroup {
+                        speed,
+                        rating: rating_group.lower_bound(),
+                        games,
+                    })
+                    .collect();
+                entries.push(SampledEntry {
+                    key: hex(key),
+                    period: Key::from_bytes(key)
+                        .and_then(|key| key.month().ok())
+                        .map(|month| month.to_string()),
+                    size: value.len(),
+                    moves: entry.num_moves(),
+                    games: groups.iter().map(|group| group.games).sum(),
+                    groups,
+                });
+            }),
+            FeedSource::Masters => db.masters().sample(n, |key, value| {
+                let mut entry = MastersEntry::default();
+                entry
+                    .extend_from_reader(&mut Cursor::new(value))
+                    .expect("deserialize masters entry");
+                let prepared = entry.prepare();
+                entries.push(SampledEntry {
+                    key: hex(key),
+                    period: Key::from_bytes(key)
+                        .and_then(|key| key.bucket::<Year>().ok())
+                        .map(|year| u16::from(year).to_string()),
+                    size: value.len(),
+                    moves: prepared.moves.len(),
+                    games: prepared.total.total(),
+                    groups: Vec::new(),
+                });
+            }),
+        }
+        .expect("sample entries");
+        entries
+    })
+    .await
+    .expect("blocking sample");
+
+    Json(SampleReport {
+        avg_size: entries
+            .iter()
+            .map(|entry| entry.size)
+            .sum::<usize>()
+            .checked_div(entries.len())
+            .unwrap_or(0),
+        entries,
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn ply(pos: &VariantPosition) -> u32 {
+    (pos.fullmoves().get() - 1) * 2 + u32::from(pos.turn().is_black())
 }
 
 fn finalize_lichess_moves(
@@ -171,7 +900,10 @@ fn finalize_lichess_moves(
     moves
         .into_iter()
         .map(|p| ExplorerMove {
-            stats: p.stats,
+            performance: p.performance(pos.turn()),
+            performance_delta: p.performance_delta(pos.turn()),
+            percentages: Percentages::from_stats(&p.stats),
+            stats: Some(p.stats),
             san: p.uci.to_move(pos).map_or(
                 SanPlus {
                     san: San::Null,
@@ -182,16 +914,86 @@ fn finalize_lichess_moves(
             uci: p.uci,
             average_rating: p.average_rating,
             average_opponent_rating: p.average_opponent_rating,
+            avg_seconds: p.avg_seconds,
+            repeated: Some(p.repeated).filter(|repeated| !repeated.is_empty()),
+            upset_rate: p.upset_rate,
+            avg_remaining_plies: p.avg_remaining_plies,
+            annotations: None,
+            opponent_ratings: Some(
+                p.by_opponent_rating
+                    .into_iter()
+                    .map(|(rating, stats)| OpponentRatingStats { rating, stats })
+                    .collect::<Vec<_>>(),
+            )
+            .filter(|opponent_ratings| !opponent_ratings.is_empty()),
+            speeds: Some(
+                p.by_speed
+                    .into_iter()
+                    .map(|(speed, stats)| SpeedStats { speed, stats })
+                    .collect::<Vec<_>>(),
+            )
+            .filter(|speeds| !speeds.is_empty()),
             game: p.game.and_then(|id| {
                 lichess_db
                     .game(id)
                     .expect("get game")
                     .map(|info| ExplorerGame::from_lichess(id, info))
             }),
+            played: None,
         })
         .collect()
 }
 
+/// Appends legal moves that are not among all `played` moves, which may be
+/// more than the selected `moves`.
+fn append_unplayed_moves(moves: &mut Vec<ExplorerMove>, pos: &VariantPosition, played: &[Uci]) {
+    for m in moves.iter_mut() {
+        m.played = Some(true);
+    }
+    for m in pos.legal_moves() {
+        let uci = Uci::from_chess960(&m);
+        if !played.contains(&uci) {
+            moves.push(ExplorerMove {
+                san: SanPlus::from_move(pos.clone(), &m),
+                uci,
+                average_rating: None,
+                average_opponent_rating: None,
+                avg_seconds: None,
+                stats: Some(Stats::default()),
+                repeated: None,
+                upset_rate: None,
+                avg_remaining_plies: None,
+                annotations: None,
+                opponent_ratings: None,
+                speeds: None,
+                performance: None,
+                performance_delta: None,
+                percentages: None,
+                game: None,
+                played: Some(false),
+            });
+        }
+    }
+}
+
+/// Games of each kind of special move that is legal in the position, over
+/// all `moves`, not only those on the requested page.
+fn special_moves(pos: &VariantPosition, moves: &[PreparedMove]) -> Option<SpecialMoves> {
+    let mut special = SpecialMoves::default();
+    for m in pos.legal_moves() {
+        special.add(&m, 0);
+    }
+    if special.is_empty() {
+        return None;
+    }
+    for p in moves {
+        if let Ok(m) = p.uci.to_move(pos) {
+            special.add(&m, p.stats.total());
+        }
+    }
+    Some(special)
+}
+
 fn finalize_lichess_games(
     games: Vec<(Uci, GameId)>,
     lichess_db: &LichessDatabase,
@@ -210,46 +1012,182 @@ fn finalize_lichess_games(
         .collect()
 }
 
+/// Key prefixes of the player, for the selected colors.
+#[derive(Clone)]
+enum PlayerPrefix {
+    Single(Color, KeyBuilder),
+    Both(ByColor<KeyBuilder>),
+}
+
+impl PlayerPrefix {
+    fn new(player: &UserId, color: PlayerColor) -> PlayerPrefix {
+        match color {
+            PlayerColor::Single(color) => {
+                PlayerPrefix::Single(color, KeyBuilder::player(player, color))
+            }
+            PlayerColor::Both => PlayerPrefix::Both(ByColor {
+                white: KeyBuilder::player(player, Color::White),
+                black: KeyBuilder::player(player, Color::Black),
+            }),
+        }
+    }
+
+    /// Point of view of the results. Combined results are mirrored to the
+    /// point of view of white.
+    fn color(&self) -> Color {
+        match *self {
+            PlayerPrefix::Single(color, _) => color,
+            PlayerPrefix::Both(_) => Color::White,
+        }
+    }
+
+    fn read(
+        &self,
+        lichess_db: &LichessDatabase,
+        variant: Variant,
+        zobrist: u128,
+        filter: &PlayerQueryFilter,
+    ) -> PlayerEntry {
+        match self {
+            PlayerPrefix::Single(_, prefix) => lichess_db.read_player(
+                &prefix.with_zobrist(variant, zobrist),
+                filter.since,
+                filter.until,
+            ),
+            PlayerPrefix::Both(prefixes) => lichess_db.read_player_both(
+                &prefixes.white.with_zobrist(variant, zobrist),
+                &prefixes.black.with_zobrist(variant, zobrist),
+                filter.since,
+                filter.until,
+            ),
+        }
+        .expect("read player")
+    }
+}
+
 struct PlayerStreamState {
     indexing: Option<watch::Receiver<()>>,
-    key: KeyPrefix,
+    prefix: PlayerPrefix,
+    variant: Variant,
+    zobrist: u128,
     db: Arc<Database>,
     filter: PlayerQueryFilter,
     limits: Limits,
     pos: VariantPosition,
     opening: Option<&'static Opening>,
+    book_exit: Option<BookExitStart>,
     first: bool,
     done: bool,
 }
 
+impl PlayerStreamState {
+    fn new(
+        openings: &'static Openings,
+        positions: &PositionCache,
+        db: Arc<Database>,
+        indexing: Option<watch::Receiver<()>>,
+        query: PlayerQuery,
+    ) -> Result<PlayerStreamState, Error> {
+        let player = UserId::from(query.player);
+        let PlayPosition {
+            variant,
+            pos,
+            opening,
+        } = positions.position(query.play, openings)?;
+        let prefix = PlayerPrefix::new(&player, query.color);
+
+        Ok(PlayerStreamState {
+            filter: query.filter,
+            limits: query.limits,
+            db,
+            indexing,
+            opening,
+            zobrist: pos.zobrist_hash(),
+            variant,
+            book_exit: query.book_exit.then(|| BookExitStart {
+                prefix: prefix.clone(),
+                variant,
+                pos: pos.clone(),
+            }),
+            prefix,
+            pos: pos.into_inner(),
+            first: true,
+            done: false,
+        })
+    }
+
+    /// Response with the games of the player that are indexed so far.
+    fn response(&self) -> ExplorerResponse {
+        let lichess_db = self.db.lichess();
+        let mut filtered = self
+            .prefix
+            .read(&lichess_db, self.variant, self.zobrist, &self.filter)
+            .prepare(&self.filter);
+        PreparedMove::sort_by(&mut filtered.moves, self.limits.sort, self.pos.turn());
+
+        let distinct_moves = filtered.moves.len();
+        let special_moves = special_moves(&self.pos, &filtered.moves);
+        let played: Vec<Uci> = filtered.moves.iter().map(|m| m.uci.clone()).collect();
+        let next_moves = self.limits.page_moves(&mut filtered.moves, usize::MAX);
+        filtered
+            .recent_games
+            .truncate(self.limits.recent_games.unwrap_or(usize::MAX));
+        filtered
+            .top_games
+            .truncate(self.limits.top_games.unwrap_or(usize::MAX));
+
+        let mut moves = finalize_lichess_moves(filtered.moves, &self.pos, &lichess_db);
+        if self.limits.include_unplayed && next_moves.is_none() {
+            append_unplayed_moves(&mut moves, &self.pos, &played);
+        }
+
+        let book_exit = self
+            .book_exit
+            .as_ref()
+            .map(|start| book_exit(&lichess_db, start, &self.filter));
+
+        let mut res = ExplorerResponse {
+            total: filtered.total,
+            distinct_moves,
+            branching_percentile: None,
+            avg_remaining_plies: filtered.avg_remaining_plies,
+            special_moves,
+            moves,
+            next_moves_page: next_moves.and_then(|next| next.page),
+            next_moves_offset: next_moves.map(|next| next.offset),
+            recent_games: Some(finalize_lichess_games(filtered.recent_games, &lichess_db)),
+            top_games: Some(finalize_lichess_games(filtered.top_games, &lichess_db)),
+            opening: self.opening,
+            book_exit,
+            history: None,
+            filter: Some(self.filter.canonical()),
+        };
+        if let Some(mask) = self.limits.mask {
+            res.mask(mask);
+        }
+
+        res
+    }
+}
+
 async fn player(
     Extension(openings): Extension<&'static Openings>,
+    Extension(positions): Extension<Arc<PositionCache>>,
     Extension(db): Extension<Arc<Database>>,
     Extension(indexer): Extension<IndexerStub>,
     Query(query): Query<PlayerQuery>,
 ) -> Result<NdJson<impl Stream<Item = ExplorerResponse>>, Error> {
-    let player = UserId::from(query.player);
-    let indexing = indexer.index_player(&player).await;
-    let PlayPosition {
-        variant,
-        pos,
-        opening,
-    } = query.play.position(openings)?;
-    let key = KeyBuilder::player(&player, query.color).with_zobrist(variant, pos.zobrist_hash());
-
-    let state = PlayerStreamState {
-        filter: query.filter,
-        limits: query.limits,
-        db,
-        indexing,
-        opening,
-        key,
-        pos: pos.into_inner(),
-        first: true,
-        done: false,
-    };
+    let indexing = indexer
+        .index_player(&UserId::from(query.player.clone()))
+        .await;
+    let state = PlayerStreamState::new(openings, &positions, db, indexing, query)?;
+    Ok(NdJson(player_stream(state)))
+}
 
-    Ok(NdJson(futures_util::stream::unfold(
+/// Responds with the current results, then again whenever there are new
+/// results while indexing, until indexing is complete.
+fn player_stream(state: PlayerStreamState) -> impl Stream<Item = ExplorerResponse> {
+    futures_util::stream::unfold(
         state,
         |mut state| async move {
             if state.done {
@@ -267,150 +1205,1409 @@ async fn player(
                 None => true,
             };
 
-            let lichess_db = state.db.lichess();
-            let mut filtered = lichess_db
-                .read_player(&state.key, state.filter.since, state.filter.until)
-                .expect("read player")
-                .prepare(&state.filter);
-
-            filtered.moves.truncate(state.limits.moves.unwrap_or(usize::MAX));
-            filtered.recent_games.truncate(state.limits.recent_games);
-
-            Some((
-                ExplorerResponse {
-                    total: filtered.total,
-                    moves: finalize_lichess_moves(filtered.moves, &state.pos, &lichess_db),
-                    recent_games: Some(finalize_lichess_games(filtered.recent_games, &lichess_db)),
-                    top_games: None,
-                    opening: state.opening,
-                },
-                state,
-            ))
+            let res = state.response();
+            Some((res, state))
         },
-    ).dedup_by_key(|res| res.total.total())))
+    ).dedup_by_key(|res| res.total.total())
 }
 
-async fn masters_import(
-    Json(body): Json<MastersGameWithId>,
-    Extension(importer): Extension<MastersImporter>,
-) -> Result<(), Error> {
-    importer.import(body).await
+struct BookExitStart {
+    prefix: PlayerPrefix,
+    variant: Variant,
+    pos: Zobrist<VariantPosition, u128>,
 }
 
-#[serde_as]
-#[derive(Deserialize)]
-struct MastersGameId(#[serde_as(as = "DisplayFromStr")] GameId);
+const MAX_BOOK_EXIT_PLIES: u32 = 20;
 
-async fn masters_pgn(
-    Path(MastersGameId(id)): Path<MastersGameId>,
-    Extension(db): Extension<Arc<Database>>,
-) -> Result<MastersGame, StatusCode> {
-    match db.masters().game(id).expect("get masters game") {
-        Some(game) => Ok(game),
-        None => Err(StatusCode::NOT_FOUND),
+/// Follows the lichess mainline from the starting position and collects the
+/// games of the player that leave it at each ply.
+fn book_exit(
+    lichess_db: &LichessDatabase,
+    start: &BookExitStart,
+    filter: &PlayerQueryFilter,
+) -> BookExit {
+    let mut pos = start.pos.clone();
+    let mut exits = Vec::new();
+    let mut in_book = Stats::default();
+
+    for ply in 0..=MAX_BOOK_EXIT_PLIES {
+        let player = start
+            .prefix
+            .read(lichess_db, start.variant, pos.zobrist_hash(), filter)
+            .prepare(filter);
+        if player.total.is_empty() {
+            break;
+        }
+        if ply == MAX_BOOK_EXIT_PLIES {
+            in_book = player.total;
+            break;
+        }
+
+        let mainline = lichess_db
+            .read_lichess(
+                &KeyBuilder::lichess().with_zobrist(start.variant, pos.zobrist_hash()),
+                Month::default(),
+                Month::max_value(),
+            )
+            .expect("read lichess")
+            .prepare(&LichessQueryFilter::default(), &Limits::default())
+            .moves
+            .into_iter()
+            .next()
+            .and_then(|p| p.uci.to_move(&pos).ok());
+
+        let mut stats = player.total;
+        if let Some(ref m) = mainline {
+            let uci = m.to_uci(CastlingMode::Chess960);
+            if let Some(p) = player.moves.into_iter().find(|p| p.uci == uci) {
+                stats -= p.stats;
+            }
+        }
+        if !stats.is_empty() {
+            let games = stats.total();
+            exits.push(BookExitPly {
+                ply,
+                score: ((start.prefix.color().fold_wb(stats.white, stats.black) * 2 + stats.draws)
+                    * 50
+                    / games) as u8,
+                stats,
+            });
+        }
+
+        match mainline {
+            Some(m) => pos.play_unchecked(&m),
+            None => break,
+        }
+    }
+
+    let exited: u64 = exits.iter().map(|exit| exit.stats.total()).sum();
+    BookExit {
+        average_ply: if exited > 0 {
+            Some(
+                exits
+                    .iter()
+                    .map(|exit| u64::from(exit.ply) * exit.stats.total())
+                    .sum::<u64>() as f64
+                    / exited as f64,
+            )
+        } else {
+            None
+        },
+        exits,
+        in_book,
     }
 }
 
-async fn masters(
+const MAX_PREP_DEPTH: u32 = 16;
+
+async fn player_prep(
     Extension(openings): Extension<&'static Openings>,
     Extension(db): Extension<Arc<Database>>,
-    Query(query): Query<MastersQuery>,
-) -> Result<Json<ExplorerResponse>, Error> {
+    Extension(indexer): Extension<IndexerStub>,
+    Query(query): Query<PrepQuery>,
+) -> Result<Json<PrepResponse>, Error> {
+    let player = UserId::from(query.player);
+    // Report from the data that is already available, but make sure it
+    // will be fresh next time.
+    indexer.index_player(&player).await;
     let PlayPosition {
         variant,
         pos,
         opening,
     } = query.play.position(openings)?;
-    let key = KeyBuilder::masters().with_zobrist(variant, pos.zobrist_hash());
-    let masters_db = db.masters();
-    let mut entry = masters_db
-        .read(key, query.since, query.until)
-        .expect("get masters")
-        .prepare();
+    let prefix = KeyBuilder::player(&player, query.color);
 
-    entry.moves.truncate(query.limits.moves.unwrap_or(12));
-    entry.top_games.truncate(query.limits.top_games);
-
-    Ok(Json(ExplorerResponse {
-        total: entry.total,
-        moves: entry
-            .moves
-            .into_iter()
-            .map(|p| ExplorerMove {
-                san: p.uci.to_move(&pos).map_or(
-                    SanPlus {
-                        san: San::Null,
-                        suffix: None,
-                    },
-                    |m| SanPlus::from_move(pos.clone(), &m),
+    Ok(Json(
+        task::spawn_blocking(move || {
+            let lichess_db = db.lichess();
+            let total = lichess_db
+                .read_player(
+                    &prefix.with_zobrist(variant, pos.zobrist_hash()),
+                    query.filter.since,
+                    query.filter.until,
+                )
+                .expect("read player")
+                .prepare(&query.filter)
+                .total;
+            PrepResponse {
+                moves: prep_moves(
+                    &lichess_db,
+                    &prefix,
+                    variant,
+                    &pos,
+                    query.color,
+                    &query.filter,
+                    query.depth.min(MAX_PREP_DEPTH),
+                    100.0,
                 ),
-                uci: p.uci,
-                average_rating: p.average_rating,
-                average_opponent_rating: p.average_opponent_rating,
+                total,
+                opening,
+            }
+        })
+        .await
+        .expect("blocking player prep"),
+    ))
+}
+
+/// Recursively expands the likely continuations of a player, pruning moves
+/// that are chosen in fewer than 5% of games, or that are reached in fewer
+/// than 1% of games from the root.
+#[allow(clippy::too_many_arguments)]
+fn prep_moves(
+    lichess_db: &LichessDatabase,
+    prefix: &KeyBuilder,
+    variant: Variant,
+    pos: &Zobrist<VariantPosition, u128>,
+    color: Color,
+    filter: &PlayerQueryFilter,
+    depth: u32,
+    reach: f64,
+) -> Vec<PrepMove> {
+    if depth == 0 {
+        return Vec::new();
+    }
+
+    let prepared = lichess_db
+        .read_player(
+            &prefix.with_zobrist(variant, pos.zobrist_hash()),
+            filter.since,
+            filter.until,
+        )
+        .expect("read player")
+        .prepare(filter);
+
+    let total = prepared.total.total();
+    if total == 0 {
+        return Vec::new();
+    }
+
+    prepared
+        .moves
+        .into_iter()
+        .filter_map(|p| {
+            let probability = p.stats.total() as f64 * 100.0 / total as f64;
+            if probability < 5.0 || reach * probability / 100.0 < 1.0 {
+                return None;
+            }
+            let m = p.uci.to_move(pos).ok()?;
+            let san = SanPlus::from_move(pos.clone(), &m);
+            let mut child = pos.clone();
+            child.play_unchecked(&m);
+
+            let games = p.stats.total();
+            let score =
+                (color.fold_wb(p.stats.white, p.stats.black) * 2 + p.stats.draws) * 50 / games;
+            Some(PrepMove {
+                weak: pos.turn() == color && games >= 5 && score < 40,
+                moves: prep_moves(
+                    lichess_db,
+                    prefix,
+                    variant,
+                    &child,
+                    color,
+                    filter,
+                    depth - 1,
+                    reach * probability / 100.0,
+                ),
+                uci: p.uci,
+                san,
                 stats: p.stats,
+                probability: probability as u8,
+                score: score as u8,
+            })
+        })
+        .collect()
+}
+
+async fn masters_import(
+    Json(body): Json<MastersGameWithId>,
+    Extension(importer): Extension<MastersImporter>,
+) -> Result<(), Error> {
+    importer.import(body).await
+}
+
+const MAX_PGN_GAMES: usize = 10_000;
+
+async fn masters_import_pgn(
+    body: Bytes,
+    Extension(importer): Extension<MastersImporter>,
+) -> Result<Json<PgnImportReport>, Error> {
+    let games = task::spawn_blocking(move || read_masters_pgn(&body))
+        .await
+        .expect("blocking read masters pgn");
+    if games.len() > MAX_PGN_GAMES {
+        return Err(Error::PgnTooLarge(games.len(), MAX_PGN_GAMES));
+    }
+
+    let mut report = PgnImportReport::default();
+    for (index, game) in games.into_iter().enumerate() {
+        match game {
+            Ok(game) => {
+                let id = game.id;
+                match importer.import(game).await {
+                    Ok(()) => report.imported.push(id),
+                    Err(err) => report.rejected.push(PgnRejection {
+                        index,
+                        id: Some(id),
+                        error: err.to_string(),
+                    }),
+                }
+            }
+            Err(err) => report.rejected.push(PgnRejection {
+                index,
+                id: None,
+                error: err.to_string(),
+            }),
+        }
+    }
+    Ok(Json(report))
+}
+
+#[serde_as]
+#[derive(Deserialize)]
+struct MastersGameId(#[serde_as(as = "DisplayFromStr")] GameId);
+
+#[serde_as]
+#[derive(Deserialize)]
+struct LichessGameId(#[serde_as(as = "DisplayFromStr")] GameId);
+
+async fn masters_pgn(
+    Path(MastersGameId(id)): Path<MastersGameId>,
+    Extension(db): Extension<Arc<Database>>,
+    Extension(errata): Extension<Arc<Errata>>,
+) -> Result<MastersGame, StatusCode> {
+    match db.masters().game(id).expect("get masters game") {
+        Some(mut game) => {
+            errata.correct_game(id, &mut game);
+            Ok(game)
+        }
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+async fn masters_pgn_bundle(
+    Extension(openings): Extension<&'static Openings>,
+    Extension(positions): Extension<Arc<PositionCache>>,
+    Extension(branching_factors): Extension<&'static BranchingFactors>,
+    Extension(db): Extension<Arc<Database>>,
+    Extension(snapshots): Extension<Arc<Snapshots>>,
+    Extension(errata): Extension<Arc<Errata>>,
+    deadline: Deadline,
+    Query(query): Query<MastersQuery>,
+) -> Result<PgnBundle, Error> {
+    let _cancel = deadline.cancel_on_drop();
+
+    task::spawn_blocking(move || {
+        let db = snapshots.get(&db, query.as_of)?;
+        let res = masters_response(
+            openings,
+            &positions,
+            branching_factors,
+            &db,
+            &errata,
+            &deadline,
+            query,
+            None,
+        )?;
+        let ids: Vec<GameId> = res
+            .top_games
+            .unwrap_or_default()
+            .into_iter()
+            .map(|game| game.row.id)
+            .collect();
+        let games = db
+            .masters()
+            .games(ids.iter().copied())
+            .expect("get masters games");
+        let mut bundle = PgnBundle::default();
+        for (id, game) in ids.into_iter().zip(games) {
+            if let Some(mut game) = game {
+                errata.correct_game(id, &mut game);
+                bundle.push_masters(&game).expect("write masters pgn");
+            }
+        }
+        Ok(bundle)
+    })
+    .await
+    .expect("blocking masters pgn bundle")
+}
+
+fn masters_key_builder(play: &Play) -> KeyBuilder {
+    if play.is_chess960() {
+        KeyBuilder::masters_chess960()
+    } else {
+        KeyBuilder::masters()
+    }
+}
+
+async fn masters(
+    Extension(openings): Extension<&'static Openings>,
+    Extension(positions): Extension<Arc<PositionCache>>,
+    Extension(branching_factors): Extension<&'static BranchingFactors>,
+    Extension(db): Extension<Arc<Database>>,
+    Extension(snapshots): Extension<Arc<Snapshots>>,
+    Extension(errata): Extension<Arc<Errata>>,
+    deadline: Deadline,
+    format: ResponseFormat,
+    revalidation: Revalidation,
+    Query(query): Query<MastersQuery>,
+) -> Result<Conditional<ExplorerBody>, Error> {
+    let _cancel = deadline.cancel_on_drop();
+
+    // Decoding, preparing and serializing large entries is CPU heavy, so keep
+    // it away from the IO workers.
+    task::spawn_blocking(move || {
+        let db = snapshots.get(&db, query.as_of)?;
+        let PlayPosition { variant, pos, .. } = positions.position(query.play.clone(), openings)?;
+        let key = masters_key_builder(&query.play).with_zobrist(variant, pos.zobrist_hash());
+        let tag = revalidation.tag(db.masters().generation(&key), format);
+        if revalidation.is_fresh(tag) {
+            return Ok(Conditional::NotModified(tag));
+        }
+        let res = masters_response(
+            openings,
+            &positions,
+            branching_factors,
+            &db,
+            &errata,
+            &deadline,
+            query,
+            None,
+        )?;
+        Ok::<_, Error>(Conditional::Modified(tag, ExplorerBody::new(res, format)))
+    })
+    .await
+    .expect("blocking masters")
+}
+
+/// Opening tree frontends look up many positions per page, so they can ask
+/// for up to this many in a single request.
+const MAX_BATCH_POSITIONS: usize = 64;
+
+async fn masters_batch(
+    Extension(openings): Extension<&'static Openings>,
+    Extension(positions): Extension<Arc<PositionCache>>,
+    Extension(branching_factors): Extension<&'static BranchingFactors>,
+    Extension(db): Extension<Arc<Database>>,
+    Extension(snapshots): Extension<Arc<Snapshots>>,
+    Extension(errata): Extension<Arc<Errata>>,
+    deadline: Deadline,
+    Json(queries): Json<Vec<MastersQuery>>,
+) -> Result<PreparedJson<Vec<ExplorerResponse>>, Error> {
+    if queries.len() > MAX_BATCH_POSITIONS {
+        return Err(Error::BatchTooLarge(queries.len(), MAX_BATCH_POSITIONS));
+    }
+
+    let _cancel = deadline.cancel_on_drop();
+
+    task::spawn_blocking(move || {
+        let res = queries
+            .into_iter()
+            .map(|query| {
+                let db = snapshots.get(&db, query.as_of)?;
+                masters_response(
+                    openings,
+                    &positions,
+                    branching_factors,
+                    &db,
+                    &errata,
+                    &deadline,
+                    query,
+                    None,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, Error>(PreparedJson::new(res, true))
+    })
+    .await
+    .expect("blocking masters batch")
+}
+
+/// Like `/masters`, but in the private explorer of the tenant of the API
+/// key.
+async fn tenant_masters(
+    headers: HeaderMap,
+    Extension(accounts): Extension<Arc<Accounts>>,
+    Extension(openings): Extension<&'static Openings>,
+    Extension(positions): Extension<Arc<PositionCache>>,
+    Extension(branching_factors): Extension<&'static BranchingFactors>,
+    Extension(db): Extension<Arc<Database>>,
+    Extension(errata): Extension<Arc<Errata>>,
+    deadline: Deadline,
+    Query(query): Query<MastersQuery>,
+) -> Result<Json<ExplorerResponse>, Error> {
+    let (tenant, _) = accounts.tenant(&headers)?;
+    let _cancel = deadline.cancel_on_drop();
+
+    task::spawn_blocking(move || {
+        masters_response(
+            openings,
+            &positions,
+            branching_factors,
+            &db,
+            &errata,
+            &deadline,
+            query,
+            Some(&tenant),
+        )
+        .map(Json)
+    })
+    .await
+    .expect("blocking tenant masters")
+}
+
+async fn tenant_pgn(
+    headers: HeaderMap,
+    Path(MastersGameId(id)): Path<MastersGameId>,
+    Extension(accounts): Extension<Arc<Accounts>>,
+    Extension(db): Extension<Arc<Database>>,
+) -> Result<MastersGame, Error> {
+    let (tenant, _) = accounts.tenant(&headers)?;
+    db.masters()
+        .tenant_game(&tenant, id)
+        .expect("get tenant game")
+        .ok_or(Error::UnknownGame(id))
+}
+
+async fn tenant_import_pgn(
+    headers: HeaderMap,
+    body: Bytes,
+    Extension(accounts): Extension<Arc<Accounts>>,
+    Extension(importer): Extension<MastersImporter>,
+) -> Result<Json<PgnImportReport>, Error> {
+    let (tenant, max_games) = accounts.tenant(&headers)?;
+    let games = task::spawn_blocking(move || read_masters_pgn(&body))
+        .await
+        .expect("blocking read tenant pgn");
+    if games.len() > MAX_PGN_GAMES {
+        return Err(Error::PgnTooLarge(games.len(), MAX_PGN_GAMES));
+    }
+
+    let mut report = PgnImportReport::default();
+    let mut parsed = Vec::new();
+    for (index, game) in games.into_iter().enumerate() {
+        match game {
+            Ok(game) => parsed.push((index, game.id, game)),
+            Err(err) => report.rejected.push(PgnRejection {
+                index,
+                id: None,
+                error: err.to_string(),
+            }),
+        }
+    }
+
+    let (keys, games): (Vec<(usize, GameId)>, Vec<MastersGameWithId>) = parsed
+        .into_iter()
+        .map(|(index, id, game)| ((index, id), game))
+        .unzip();
+    let results = importer.import_tenant(&tenant, max_games, games).await;
+    for ((index, id), res) in keys.into_iter().zip(results) {
+        match res {
+            Ok(()) => report.imported.push(id),
+            Err(err) => report.rejected.push(PgnRejection {
+                index,
+                id: Some(id),
+                error: err.to_string(),
+            }),
+        }
+    }
+    report.rejected.sort_by_key(|rejection| rejection.index);
+    Ok(Json(report))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn masters_response(
+    openings: &'static Openings,
+    positions: &PositionCache,
+    branching_factors: &BranchingFactors,
+    db: &Database,
+    errata: &Errata,
+    deadline: &Deadline,
+    query: MastersQuery,
+    tenant: Option<&TenantId>,
+) -> Result<ExplorerResponse, Error> {
+    let key_builder = match tenant {
+        Some(tenant) => masters_key_builder(&query.play).with_tenant(tenant),
+        None => masters_key_builder(&query.play),
+    };
+    let PlayPosition {
+        variant,
+        pos,
+        opening,
+    } = positions.position(query.play, openings)?;
+
+    let key = key_builder.with_zobrist(variant, pos.zobrist_hash());
+    let masters_db = db.masters();
+    let mut entry = masters_db
+        .read_within(key.clone(), query.since, query.until, deadline)
+        .expect("get masters");
+    deadline.check()?;
+    // Errata and branching factors are about the public masters database.
+    let errata = Some(errata).filter(|_| tenant.is_none());
+    if let Some(errata) = errata {
+        errata.correct_entry(&key, query.since, query.until, &mut entry);
+    }
+    if query.exclude_estimated {
+        entry.exclude_estimated();
+    }
+    let mut entry = entry.prepare();
+    if let Some(eco) = &query.eco {
+        entry.moves.retain(|m| {
+            openings
+                .classify_move(&pos, &m.uci)
+                .or(opening)
+                .map_or(false, |opening| eco.contains(opening.eco()))
+        });
+        entry
+            .top_games
+            .retain(|(uci, _)| entry.moves.iter().any(|m| m.uci == *uci));
+        entry.total = Stats::default();
+        for m in &entry.moves {
+            entry.total += m.stats.clone();
+        }
+    }
+    PreparedMove::sort_by(&mut entry.moves, query.limits.sort, pos.turn());
+
+    let distinct_moves = entry.moves.len();
+    let branching_percentile = tenant
+        .is_none()
+        .then(|| branching_factors.masters_percentile(ply(pos.as_inner()), distinct_moves))
+        .flatten();
+
+    let special_moves = special_moves(pos.as_inner(), &entry.moves);
+    let played: Vec<Uci> = entry.moves.iter().map(|m| m.uci.clone()).collect();
+    let next_moves = query.limits.page_moves(&mut entry.moves, 12);
+    entry
+        .top_games
+        .truncate(query.limits.top_games.unwrap_or(usize::MAX));
+
+    let mut res = ExplorerResponse {
+        total: entry.total,
+        distinct_moves,
+        branching_percentile,
+        avg_remaining_plies: None,
+        special_moves,
+        moves: entry
+            .moves
+            .into_iter()
+            .map(|p| ExplorerMove {
+                performance: p.performance(pos.turn()),
+                performance_delta: p.performance_delta(pos.turn()),
+                percentages: Percentages::from_stats(&p.stats),
+                san: p.uci.to_move(&pos).map_or(
+                    SanPlus {
+                        san: San::Null,
+                        suffix: None,
+                    },
+                    |m| SanPlus::from_move(pos.clone(), &m),
+                ),
+                uci: p.uci,
+                average_rating: p.average_rating,
+                average_opponent_rating: p.average_opponent_rating,
+                avg_seconds: p.avg_seconds,
+                repeated: None,
+                upset_rate: None,
+                avg_remaining_plies: None,
+                annotations: Some(p.annotations).filter(|annotations| !annotations.is_empty()),
+                opponent_ratings: None,
+                speeds: None,
+                stats: Some(p.stats),
                 game: p.game.and_then(|id| {
-                    masters_db
-                        .game(id)
-                        .expect("get masters game")
-                        .map(|info| ExplorerGame::from_masters(id, info))
+                    match tenant {
+                        Some(tenant) => masters_db.tenant_game(tenant, id),
+                        None => masters_db.game(id),
+                    }
+                    .expect("get masters game")
+                    .map(|mut info| {
+                        if let Some(errata) = errata {
+                            errata.correct_game(id, &mut info);
+                        }
+                        info
+                    })
+                    .filter(|info| !query.exclude_estimated || !info.has_estimated_rating())
+                    .map(|info| ExplorerGame::from_masters(id, info))
                 }),
+                played: None,
             })
             .collect(),
+        next_moves_page: next_moves.and_then(|next| next.page),
+        next_moves_offset: next_moves.map(|next| next.offset),
         top_games: Some(
-            masters_db
-                .games(entry.top_games.iter().map(|(_, id)| *id))
-                .expect("get masters games")
-                .into_iter()
-                .zip(entry.top_games.into_iter())
-                .filter_map(|(info, (uci, id))| {
-                    info.map(|info| ExplorerGameWithUci {
-                        uci: uci.clone(),
-                        row: ExplorerGame::from_masters(id, info),
-                    })
+            match tenant {
+                Some(tenant) => {
+                    masters_db.tenant_games(tenant, entry.top_games.iter().map(|(_, id)| *id))
+                }
+                None => masters_db.games(entry.top_games.iter().map(|(_, id)| *id)),
+            }
+            .expect("get masters games")
+            .into_iter()
+            .zip(entry.top_games.into_iter())
+            .filter_map(|(info, (uci, id))| {
+                info.map(|mut info| {
+                    if let Some(errata) = errata {
+                        errata.correct_game(id, &mut info);
+                    }
+                    info
                 })
-                .collect(),
+                .filter(|info| !query.exclude_estimated || !info.has_estimated_rating())
+                .map(|info| ExplorerGameWithUci {
+                    uci: uci.clone(),
+                    row: ExplorerGame::from_masters(id, info),
+                })
+            })
+            .collect(),
         ),
         opening,
         recent_games: None,
-    }))
+        book_exit: None,
+        history: None,
+        filter: None,
+    };
+
+    if query.limits.include_unplayed && next_moves.is_none() {
+        append_unplayed_moves(&mut res.moves, pos.as_inner(), &played);
+    }
+
+    if let Some(mask) = query.limits.mask {
+        res.mask(mask);
+    }
+
+    Ok(res)
+}
+
+#[derive(Deserialize)]
+struct LichessImportQuery {
+    #[serde(default)]
+    source: LichessSource,
 }
 
 async fn lichess_import(
     Json(body): Json<Vec<LichessGameImport>>,
+    Query(query): Query<LichessImportQuery>,
     Extension(importer): Extension<LichessImporter>,
 ) -> Result<(), Error> {
     for game in body {
-        importer.import(game).await?;
+        importer.import(game, query.source).await?;
     }
     Ok(())
 }
 
 async fn lichess(
     Extension(openings): Extension<&'static Openings>,
+    Extension(positions): Extension<Arc<PositionCache>>,
+    Extension(branching_factors): Extension<&'static BranchingFactors>,
     Extension(db): Extension<Arc<Database>>,
+    Extension(snapshots): Extension<Arc<Snapshots>>,
+    deadline: Deadline,
+    format: ResponseFormat,
+    revalidation: Revalidation,
     Query(query): Query<LichessQuery>,
-) -> Result<Json<ExplorerResponse>, Error> {
+) -> Result<Conditional<ExplorerBody>, Error> {
+    let _cancel = deadline.cancel_on_drop();
+
+    task::spawn_blocking(move || {
+        let db = snapshots.get(&db, query.as_of)?;
+        let PlayPosition { variant, pos, .. } = positions.position(query.play.clone(), openings)?;
+        let key = KeyBuilder::lichess().with_zobrist(variant, pos.zobrist_hash());
+        let tag = revalidation.tag(db.lichess().generation(&key), format);
+        if revalidation.is_fresh(tag) {
+            return Ok(Conditional::NotModified(tag));
+        }
+        let res = lichess_response(
+            openings,
+            &positions,
+            branching_factors,
+            &db,
+            &deadline,
+            query,
+        )?;
+        Ok::<_, Error>(Conditional::Modified(tag, ExplorerBody::new(res, format)))
+    })
+    .await
+    .expect("blocking lichess")
+}
+
+async fn lichess_pgn_bundle(
+    Extension(openings): Extension<&'static Openings>,
+    Extension(positions): Extension<Arc<PositionCache>>,
+    Extension(branching_factors): Extension<&'static BranchingFactors>,
+    Extension(db): Extension<Arc<Database>>,
+    Extension(snapshots): Extension<Arc<Snapshots>>,
+    deadline: Deadline,
+    Query(query): Query<LichessQuery>,
+) -> Result<PgnBundle, Error> {
+    let setup = query.play.fen.is_some();
+    let chess960 = query.play.is_chess960();
+    let (pos, moves) = query.play.clone().root_and_moves()?;
+    let root = LichessRoot {
+        pos,
+        setup,
+        chess960,
+        moves,
+    };
+
+    let _cancel = deadline.cancel_on_drop();
+
+    task::spawn_blocking(move || {
+        let db = snapshots.get(&db, query.as_of)?;
+        let res = lichess_response(
+            openings,
+            &positions,
+            branching_factors,
+            &db,
+            &deadline,
+            query,
+        )?;
+        let top_games = res.top_games.unwrap_or_default();
+        let recent_games = res.recent_games.unwrap_or_default();
+        let mut bundle = PgnBundle::default();
+        let mut seen = FxHashSet::default();
+        for game in top_games.iter().chain(&recent_games) {
+            if seen.insert(game.row.id) {
+                bundle.push_lichess(&root, game).expect("write lichess pgn");
+            }
+        }
+        Ok(bundle)
+    })
+    .await
+    .expect("blocking lichess pgn bundle")
+}
+
+async fn lichess_batch(
+    Extension(openings): Extension<&'static Openings>,
+    Extension(positions): Extension<Arc<PositionCache>>,
+    Extension(branching_factors): Extension<&'static BranchingFactors>,
+    Extension(db): Extension<Arc<Database>>,
+    Extension(snapshots): Extension<Arc<Snapshots>>,
+    deadline: Deadline,
+    Json(queries): Json<Vec<LichessQuery>>,
+) -> Result<PreparedJson<Vec<ExplorerResponse>>, Error> {
+    if queries.len() > MAX_BATCH_POSITIONS {
+        return Err(Error::BatchTooLarge(queries.len(), MAX_BATCH_POSITIONS));
+    }
+
+    let _cancel = deadline.cancel_on_drop();
+
+    task::spawn_blocking(move || {
+        let res = queries
+            .into_iter()
+            .map(|query| {
+                let db = snapshots.get(&db, query.as_of)?;
+                lichess_response(
+                    openings,
+                    &positions,
+                    branching_factors,
+                    &db,
+                    &deadline,
+                    query,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, Error>(PreparedJson::new(res, true))
+    })
+    .await
+    .expect("blocking lichess batch")
+}
+
+/// Reads the lichess entry of a position in the range selected by the
+/// filter.
+fn read_lichess_entry(
+    lichess_db: &LichessDatabase,
+    key: &KeyPrefix,
+    filter: &LichessQueryFilter,
+    deadline: &Deadline,
+) -> LichessEntry {
+    match filter.since_week {
+        Some(since_week) => lichess_db
+            .read_lichess_weeks(key, since_week, Week::max_value(), deadline)
+            .expect("get lichess weeks"),
+        None => lichess_db
+            .read_lichess_within(key, filter.since, filter.until, deadline)
+            .expect("get lichess"),
+    }
+}
+
+fn lichess_response(
+    openings: &'static Openings,
+    positions: &PositionCache,
+    branching_factors: &BranchingFactors,
+    db: &Database,
+    deadline: &Deadline,
+    query: LichessQuery,
+) -> Result<ExplorerResponse, Error> {
     let PlayPosition {
         variant,
         pos,
         opening,
-    } = query.play.position(openings)?;
+    } = positions.position(query.play, openings)?;
+
     let key = KeyBuilder::lichess().with_zobrist(variant, pos.zobrist_hash());
     let lichess_db = db.lichess();
-    let mut filtered = lichess_db
-        .read_lichess(&key, query.filter.since, query.filter.until)
-        .expect("get lichess")
-        .prepare(&query.filter);
+    let mut filtered = match lichess_db.prepare_hot(&key, &query.filter, &query.limits) {
+        Some(filtered) => filtered,
+        None => {
+            let entry = read_lichess_entry(&lichess_db, &key, &query.filter, deadline);
+            deadline.check()?;
+            entry.prepare(&query.filter, &query.limits)
+        }
+    };
+    PreparedMove::sort_by(&mut filtered.moves, query.limits.sort, pos.turn());
+
+    let distinct_moves = filtered.moves.len();
+    let branching_percentile =
+        branching_factors.lichess_percentile(ply(pos.as_inner()), distinct_moves);
+
+    let special_moves = special_moves(pos.as_inner(), &filtered.moves);
+    let played: Vec<Uci> = filtered.moves.iter().map(|m| m.uci.clone()).collect();
+    let next_moves = query.limits.page_moves(&mut filtered.moves, 12);
+
+    let mut recent_games = finalize_lichess_games(filtered.recent_games, &lichess_db);
+    recent_games.retain(|game| {
+        query.filter.contains_clock(game.row.clock)
+            && query.filter.contains_players(&game.row.players)
+    });
+    recent_games.truncate(query.limits.recent_games.unwrap_or(usize::MAX));
+
+    let mut top_games = finalize_lichess_games(filtered.top_games, &lichess_db);
+    top_games.retain(|game| {
+        query.filter.contains_clock(game.row.clock)
+            && query.filter.contains_players(&game.row.players)
+    });
+    top_games.truncate(query.limits.top_games.unwrap_or(usize::MAX));
 
-    filtered.moves.truncate(query.limits.moves.unwrap_or(12));
-    filtered.recent_games.truncate(query.limits.recent_games);
-    filtered.top_games.truncate(query.limits.top_games);
+    let mut moves = finalize_lichess_moves(filtered.moves, pos.as_inner(), &lichess_db);
+    for m in &mut moves {
+        if m.game
+            .as_ref()
+            .map_or(false, |game| !query.filter.contains_players(&game.players))
+        {
+            m.game = None;
+        }
+    }
+    if query.limits.include_unplayed && next_moves.is_none() {
+        append_unplayed_moves(&mut moves, pos.as_inner(), &played);
+    }
+
+    let history = if query.history {
+        let history = lichess_db
+            .read_lichess_by_month(&key, query.filter.since, query.filter.until)
+            .expect("get lichess by month")
+            .into_iter()
+            .take_while(|_| !deadline.is_over())
+            .map(|(month, entry)| TrendMonth {
+                month,
+                stats: entry.total(&query.filter),
+            })
+            .filter(|point| !point.stats.is_empty())
+            .collect();
+        deadline.check()?;
+        Some(history)
+    } else {
+        None
+    };
 
-    Ok(Json(ExplorerResponse {
+    let mut res = ExplorerResponse {
         total: filtered.total,
-        moves: finalize_lichess_moves(filtered.moves, pos.as_inner(), &lichess_db),
-        recent_games: Some(finalize_lichess_games(filtered.recent_games, &lichess_db)),
-        top_games: Some(finalize_lichess_games(filtered.top_games, &lichess_db)),
+        distinct_moves,
+        branching_percentile,
+        avg_remaining_plies: filtered.avg_remaining_plies,
+        special_moves,
+        moves,
+        next_moves_page: next_moves.and_then(|next| next.page),
+        next_moves_offset: next_moves.map(|next| next.offset),
+        recent_games: Some(recent_games),
+        top_games: Some(top_games),
         opening,
-    }))
+        book_exit: None,
+        history,
+        filter: Some(query.filter.canonical()),
+    };
+
+    if let Some(mask) = query.limits.mask {
+        res.mask(mask);
+    }
+
+    Ok(res)
+}
+
+async fn lichess_best(
+    Extension(openings): Extension<&'static Openings>,
+    Extension(positions): Extension<Arc<PositionCache>>,
+    Extension(db): Extension<Arc<Database>>,
+    deadline: Deadline,
+    Query(query): Query<LichessBestQuery>,
+) -> Result<Json<Option<BestMove>>, Error> {
+    let PlayPosition { variant, pos, .. } = positions.position(query.play, openings)?;
+
+    let _cancel = deadline.cancel_on_drop();
+
+    task::spawn_blocking(move || {
+        let key = KeyBuilder::lichess().with_zobrist(variant, pos.zobrist_hash());
+        let lichess_db = db.lichess();
+        let best = match lichess_db.with_hot(&key, &query.filter, |entry| {
+            entry.best_move(&query.filter, query.criterion, pos.turn())
+        }) {
+            Some(best) => best,
+            None => {
+                let entry = read_lichess_entry(&lichess_db, &key, &query.filter, &deadline);
+                deadline.check()?;
+                entry.best_move(&query.filter, query.criterion, pos.turn())
+            }
+        };
+
+        Ok::<_, Error>(Json(best.map(|(uci, stats)| BestMove {
+            san: uci.to_move(&pos).map_or(
+                SanPlus {
+                    san: San::Null,
+                    suffix: None,
+                },
+                |m| SanPlus::from_move(pos.as_inner().clone(), &m),
+            ),
+            uci,
+            stats,
+        })))
+    })
+    .await
+    .expect("blocking lichess best")
+}
+
+async fn lichess_trend(
+    Extension(openings): Extension<&'static Openings>,
+    Extension(db): Extension<Arc<Database>>,
+    deadline: Deadline,
+    Query(query): Query<LichessTrendQuery>,
+) -> Result<Json<TrendResponse>, Error> {
+    let PlayPosition { variant, pos, .. } = query.play.position(openings)?;
+    // Normalize castling moves to the stored representation.
+    let uci = match query.uci {
+        Some(ref uci) => Some(uci.to_move(&pos)?.to_uci(CastlingMode::Chess960)),
+        None => None,
+    };
+
+    let _cancel = deadline.cancel_on_drop();
+
+    Ok(Json(
+        task::spawn_blocking(move || {
+            let key = KeyBuilder::lichess().with_zobrist(variant, pos.zobrist_hash());
+            let mut history = Vec::new();
+            let mut by_move: Vec<(Uci, Vec<TrendMonth>)> = Vec::new();
+            for (month, entry) in db
+                .lichess()
+                .read_lichess_by_month(&key, query.filter.since, query.filter.until)
+                .expect("get lichess by month")
+            {
+                if deadline.is_over() {
+                    break;
+                }
+                let prepared = entry.prepare(&query.filter, &Limits::default());
+                let stats = match uci {
+                    Some(ref uci) => prepared
+                        .moves
+                        .iter()
+                        .find(|p| p.uci == *uci)
+                        .map_or_else(Stats::default, |p| p.stats.clone()),
+                    None => prepared.total,
+                };
+                if !stats.is_empty() {
+                    history.push(TrendMonth { month, stats });
+                }
+                if query.by_move {
+                    for p in prepared.moves {
+                        if p.stats.is_empty() {
+                            continue;
+                        }
+                        let point = TrendMonth {
+                            month,
+                            stats: p.stats,
+                        };
+                        match by_move.iter_mut().find(|(uci, _)| *uci == p.uci) {
+                            Some((_, history)) => history.push(point),
+                            None => by_move.push((p.uci, vec![point])),
+                        }
+                    }
+                }
+            }
+            deadline.check()?;
+
+            let moves = query.by_move.then(|| {
+                by_move.sort_by_key(|(_, history)| {
+                    Reverse(history.iter().map(|point| point.stats.total()).sum::<u64>())
+                });
+                by_move
+                    .into_iter()
+                    .map(|(uci, history)| TrendMove {
+                        san: uci.to_move(&pos).map_or(
+                            SanPlus {
+                                san: San::Null,
+                                suffix: None,
+                            },
+                            |m| SanPlus::from_move(pos.as_inner().clone(), &m),
+                        ),
+                        uci,
+                        history,
+                    })
+                    .collect()
+            });
+
+            Ok::<_, Error>(TrendResponse {
+                uci,
+                history,
+                moves,
+            })
+        })
+        .await
+        .expect("blocking lichess trend")?,
+    ))
+}
+
+const GAMES_PAGE_SIZE: usize = 50;
+
+async fn lichess_games(
+    Extension(openings): Extension<&'static Openings>,
+    Extension(positions): Extension<Arc<PositionCache>>,
+    Extension(db): Extension<Arc<Database>>,
+    deadline: Deadline,
+    Query(query): Query<LichessGamesQuery>,
+) -> Result<Json<GamesPage>, Error> {
+    let PlayPosition { variant, pos, .. } = positions.position(query.play, openings)?;
+
+    let _cancel = deadline.cancel_on_drop();
+
+    task::spawn_blocking(move || {
+        let key = KeyBuilder::lichess().with_zobrist(variant, pos.zobrist_hash());
+        let lichess_db = db.lichess();
+
+        // Each month lists its own games, so going back in time yields many
+        // more than fit into a single explorer response.
+        let mut games: Vec<(Uci, GameId)> = lichess_db
+            .read_lichess_by_month(&key, query.filter.since, query.filter.until)
+            .expect("get lichess by month")
+            .into_iter()
+            .rev()
+            .take_while(|_| !deadline.is_over())
+            .flat_map(|(_, entry)| entry.games(&query.filter))
+            .skip(query.page.saturating_mul(GAMES_PAGE_SIZE))
+            .take(GAMES_PAGE_SIZE + 1)
+            .collect();
+        deadline.check()?;
+
+        let next_page = (games.len() > GAMES_PAGE_SIZE).then(|| query.page + 1);
+        games.truncate(GAMES_PAGE_SIZE);
+
+        let mut games = finalize_lichess_games(games, &lichess_db);
+        games.retain(|game| {
+            query.filter.contains_clock(game.row.clock)
+                && query.filter.contains_players(&game.row.players)
+        });
+
+        Ok::<_, Error>(Json(GamesPage { games, next_page }))
+    })
+    .await
+    .expect("blocking lichess games")
+}
+
+const MAX_DEPTH_PROFILE_PLIES: usize = 60;
+
+async fn lichess_depth_profile(
+    Extension(db): Extension<Arc<Database>>,
+    deadline: Deadline,
+    Query(query): Query<LichessDepthProfileQuery>,
+) -> Result<Json<DepthProfile>, Error> {
+    let PlayLine { variant, positions } = query.play.line()?;
+    let filter = Arc::new(query.filter);
+
+    let _cancel = deadline.cancel_on_drop();
+
+    // Read the positions in parallel. Each read is a range scan over the
+    // months of the position.
+    let plies = future::join_all(
+        positions
+            .into_iter()
+            .take(MAX_DEPTH_PROFILE_PLIES + 1)
+            .enumerate()
+            .map(|(ply, (uci, zobrist))| {
+                let db = Arc::clone(&db);
+                let filter = Arc::clone(&filter);
+                let deadline = deadline.clone();
+                task::spawn_blocking(move || {
+                    let key = KeyBuilder::lichess().with_zobrist(variant, zobrist);
+                    let stats = db
+                        .lichess()
+                        .read_lichess_within(&key, filter.since, filter.until, &deadline)
+                        .expect("get lichess")
+                        .total(&filter);
+                    let games = stats.total();
+                    DepthProfilePly {
+                        ply: ply as u32,
+                        uci,
+                        score: (games > 0)
+                            .then(|| ((stats.white * 2 + stats.draws) * 50 / games) as u8),
+                        stats,
+                    }
+                })
+            }),
+    )
+    .await
+    .into_iter()
+    .map(|res| res.expect("blocking depth profile"))
+    .collect();
+    deadline.check()?;
+
+    Ok(Json(DepthProfile { plies }))
+}
+
+const MAX_CONTRIBUTORS_SCANNED: usize = 100_000;
+
+const MAX_CONTRIBUTORS: usize = 100;
+
+async fn player_find(
+    Extension(openings): Extension<&'static Openings>,
+    Extension(db): Extension<Arc<Database>>,
+    Query(query): Query<PlayerFindQuery>,
+) -> Result<Json<Vec<Contributor>>, Error> {
+    let PlayPosition { variant, pos, .. } = query.play.position(openings)?;
+
+    Ok(Json(
+        task::spawn_blocking(move || {
+            let key = KeyBuilder::lichess().with_zobrist(variant, pos.zobrist_hash());
+            let mut contributors: Vec<Contributor> = db
+                .lichess()
+                .read_contributors(&key, MAX_CONTRIBUTORS_SCANNED)
+                .expect("get contributors")
+                .into_iter()
+                .filter(|(_, games)| games.total() >= query.min_games)
+                .map(|(user, games)| Contributor {
+                    id: user.as_lowercase_str().to_owned(),
+                    games,
+                })
+                .collect();
+            contributors.sort_by_key(|contributor| Reverse(contributor.games.total()));
+            contributors.truncate(MAX_CONTRIBUTORS);
+            contributors
+        })
+        .await
+        .expect("blocking player find"),
+    ))
+}
+
+const MAX_PARENTS: usize = 100;
+
+async fn lichess_parents(
+    Extension(openings): Extension<&'static Openings>,
+    Extension(db): Extension<Arc<Database>>,
+    Query(query): Query<ParentsQuery>,
+) -> Result<Json<Vec<Parent>>, Error> {
+    let PlayPosition { variant, pos, .. } = query.play.position(openings)?;
+
+    Ok(Json(
+        task::spawn_blocking(move || {
+            let key = KeyBuilder::lichess().with_zobrist(variant, pos.zobrist_hash());
+            let lichess_db = db.lichess();
+            // Already ordered by number of games.
+            let entries: Vec<ParentMove> = lichess_db
+                .read_parents(&key)
+                .expect("get parents")
+                .parents
+                .into_iter()
+                .filter(|entry| entry.games >= query.min_games)
+                .take(MAX_PARENTS)
+                .collect();
+
+            // Recover the parents by replaying their most common move
+            // orders. The initial position is reached by the empty one.
+            let initial: Zobrist<VariantPosition, u128> =
+                Zobrist::new(VariantPosition::new(variant));
+            let paths: Vec<PathHash> = entries
+                .iter()
+                .map(|entry| {
+                    if entry.zobrist == initial.zobrist_hash() {
+                        Some(PathHash::empty())
+                    } else {
+                        lichess_db
+                            .read_move_orders(
+                                &KeyBuilder::lichess().with_zobrist(variant, entry.zobrist),
+                            )
+                            .expect("get parent move orders")
+                            .paths
+                            .first()
+                            .map(|(path, _)| *path)
+                    }
+                })
+                // Parents without move orders replay to the initial
+                // position, which is rejected below.
+                .map(|path| path.unwrap_or_default())
+                .collect();
+            let moves = lichess_db
+                .read_move_order_paths(&paths)
+                .expect("get parent move order paths");
+
+            entries
+                .into_iter()
+                .zip(moves)
+                .filter_map(|(entry, moves)| {
+                    let mut parent = initial.clone();
+                    for uci in moves? {
+                        let m = uci.to_move(&parent).ok()?;
+                        parent.play_unchecked(&m);
+                    }
+                    if parent.zobrist_hash() != entry.zobrist {
+                        return None;
+                    }
+                    let m = entry.uci.to_move(&parent).ok()?;
+                    Some(Parent {
+                        fen: Epd::from_position(parent.as_inner().clone(), EnPassantMode::Legal),
+                        san: SanPlus::from_move(parent.as_inner().clone(), &m),
+                        opening: openings
+                            .classify_and_play(&mut parent, Vec::new())
+                            .ok()
+                            .flatten(),
+                        uci: entry.uci,
+                        games: entry.games,
+                    })
+                })
+                .collect()
+        })
+        .await
+        .expect("blocking lichess parents"),
+    ))
+}
+
+const MAX_MOVE_ORDERS: usize = 100;
+
+async fn lichess_move_orders(
+    Extension(openings): Extension<&'static Openings>,
+    Extension(db): Extension<Arc<Database>>,
+    Query(query): Query<MoveOrdersQuery>,
+) -> Result<Json<Vec<MoveOrder>>, Error> {
+    let PlayPosition { variant, pos, .. } = query.play.position(openings)?;
+
+    Ok(Json(
+        task::spawn_blocking(move || {
+            let key = KeyBuilder::lichess().with_zobrist(variant, pos.zobrist_hash());
+            let lichess_db = db.lichess();
+            // Already ordered by number of games.
+            let entries: Vec<(PathHash, u64)> = lichess_db
+                .read_move_orders(&key)
+                .expect("get move orders")
+                .paths
+                .into_iter()
+                .filter(|(_, games)| *games >= query.min_games)
+                .take(MAX_MOVE_ORDERS)
+                .collect();
+            let paths: Vec<PathHash> = entries.iter().map(|(path, _)| *path).collect();
+            let moves = lichess_db
+                .read_move_order_paths(&paths)
+                .expect("get move order paths");
+            entries
+                .into_iter()
+                .zip(moves)
+                .filter_map(|((_, games), moves)| {
+                    let moves = moves?;
+                    let mut pos = VariantPosition::new(variant);
+                    let mut san = String::new();
+                    for uci in &moves {
+                        let m = uci.to_move(&pos).ok()?;
+                        if !san.is_empty() {
+                            san.push(' ');
+                        }
+                        if pos.turn() == Color::White {
+                            write!(san, "{}. ", pos.fullmoves()).unwrap();
+                        }
+                        write!(
+                            san,
+                            "{}",
+                            SanPlus::from_move_and_play_unchecked(&mut pos, &m)
+                        )
+                        .unwrap();
+                    }
+                    Some(MoveOrder {
+                        uci: moves,
+                        san,
+                        games,
+                    })
+                })
+                .collect()
+        })
+        .await
+        .expect("blocking lichess move orders"),
+    ))
+}
+
+const MAX_GRAPH_DEPTH: u32 = 8;
+
+async fn export_graph(
+    Extension(openings): Extension<&'static Openings>,
+    Extension(db): Extension<Arc<Database>>,
+    Query(query): Query<GraphQuery>,
+) -> Result<Chunked, Error> {
+    let PlayPosition { variant, pos, .. } = query.play.position(openings)?;
+    let format = query.format;
+
+    Ok(Chunked::spawn_blocking(
+        OpeningGraph::content_type(format),
+        move |sender| {
+            OpeningGraph::export(
+                &db.lichess(),
+                variant,
+                pos,
+                &query.filter,
+                query.depth.min(MAX_GRAPH_DEPTH),
+                query.min_games,
+                format,
+                sender,
+            )
+        },
+    ))
+}
+
+const MAX_THEORY_DEPTH: u32 = 20;
+
+async fn export_theory(
+    Extension(openings): Extension<&'static Openings>,
+    Extension(db): Extension<Arc<Database>>,
+    Query(query): Query<TheoryQuery>,
+) -> Result<Response, Error> {
+    let root = query.play.position(openings)?;
+    let format = query.format;
+
+    Ok(task::spawn_blocking(move || {
+        TheoryTable::build(
+            &db.lichess(),
+            openings,
+            root,
+            &query.filter,
+            query.depth.min(MAX_THEORY_DEPTH),
+            query.min_share,
+        )
+        .render(format)
+    })
+    .await
+    .expect("blocking theory export"))
+}
+
+async fn feed_recent(
+    Extension(db): Extension<Arc<Database>>,
+    Extension(recent_games): Extension<Arc<RecentGames>>,
+    Query(query): Query<FeedQuery>,
+) -> NdJson<impl Stream<Item = ExplorerGame>> {
+    let ids = recent_games.latest(query.source, query.limit);
+    let games: Vec<ExplorerGame> = match query.source {
+        FeedSource::Masters => db
+            .masters()
+            .games(ids.iter().copied())
+            .expect("get masters games")
+            .into_iter()
+            .zip(ids)
+            .filter_map(|(info, id)| info.map(|info| ExplorerGame::from_masters(id, info)))
+            .collect(),
+        FeedSource::Lichess => db
+            .lichess()
+            .games(ids.iter().copied())
+            .expect("get lichess games")
+            .into_iter()
+            .zip(ids)
+            .filter_map(|(info, id)| info.map(|info| ExplorerGame::from_lichess(id, info)))
+            .collect(),
+    };
+    NdJson(futures_util::stream::iter(games))
 }
@@ -0,0 +1,88 @@
+use std::{
+    io::{self, Read, Write},
+    ops::AddAssign,
+};
+
+use serde::{ser::SerializeMap as _, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::{
+    api::{ApiSchema, ObjectSchema, SchemaGenerator},
+    model::{read_uint, write_uint},
+};
+
+/// Move quality glyphs, indexed by their numeric annotation glyph minus one.
+const GLYPHS: [&str; 6] = ["!", "?", "!!", "??", "!?", "?!"];
+
+/// How often a move was annotated with each of the move quality glyphs
+/// (`$1` to `$6`) in curated source material.
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+pub struct Annotations {
+    counts: [u64; 6],
+}
+
+impl Annotations {
+    pub fn new_single(nag: u8) -> Annotations {
+        let mut annotations = Annotations::default();
+        if let Some(count) = usize::from(nag)
+            .checked_sub(1)
+            .and_then(|i| annotations.counts.get_mut(i))
+        {
+            *count = 1;
+        }
+        annotations
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.iter().all(|c| *c == 0)
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Annotations> {
+        let mut annotations = Annotations::default();
+        for count in &mut annotations.counts {
+            *count = read_uint(reader)?;
+        }
+        Ok(annotations)
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for count in self.counts {
+            write_uint(writer, count)?;
+        }
+        Ok(())
+    }
+}
+
+impl AddAssign for Annotations {
+    fn add_assign(&mut self, rhs: Annotations) {
+        for (count, other) in self.counts.iter_mut().zip(rhs.counts) {
+            *count += other;
+        }
+    }
+}
+
+impl Serialize for Annotations {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for (glyph, count) in GLYPHS.iter().zip(self.counts) {
+            if count > 0 {
+                map.serialize_entry(glyph, &count)?;
+            }
+        }
+        map.end()
+    }
+}
+
+impl ApiSchema for Annotations {
+    fn schema(generator: &mut SchemaGenerator) -> Value {
+        generator.component("Annotations", |generator| {
+            let mut object = ObjectSchema::new(Some(
+                "Number of times the move was annotated with each glyph. Glyphs that were never used are omitted.",
+            ));
+            for glyph in GLYPHS {
+                object.property(glyph, u64::schema(generator), None, false);
+            }
+            object.into_value()
+        })
+    }
+}
@@ -0,0 +1,96 @@
+use std::fmt;
+
+use byteorder::{BigEndian, ByteOrder as _};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use sha1::{Digest, Sha1};
+
+use crate::model::{InvalidDate, Month, TenantId};
+
+/// Key of an API key in the database. Only digests of tokens are stored, so
+/// that tokens can not be recovered from a backup.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct ApiKeyId([u8; ApiKeyId::SIZE]);
+
+impl ApiKeyId {
+    pub const SIZE: usize = 20;
+
+    pub fn from_token(token: &str) -> ApiKeyId {
+        ApiKeyId::from_bytes(&Sha1::digest(token.as_bytes()))
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> ApiKeyId {
+        let mut id = [0; ApiKeyId::SIZE];
+        id.copy_from_slice(&buf[..ApiKeyId::SIZE]);
+        ApiKeyId(id)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Key of the requests counted in the given month.
+    pub fn usage_key(&self, month: Month) -> [u8; ApiKeyId::SIZE + 2] {
+        let mut buf = [0; ApiKeyId::SIZE + 2];
+        buf[..ApiKeyId::SIZE].copy_from_slice(&self.0);
+        BigEndian::write_u16(&mut buf[ApiKeyId::SIZE..], u16::from(month));
+        buf
+    }
+
+    /// Month of a key returned by `usage_key()`.
+    pub fn usage_month(buf: &[u8]) -> Result<Month, InvalidDate> {
+        Month::try_from(BigEndian::read_u16(&buf[ApiKeyId::SIZE..]))
+    }
+}
+
+impl fmt::Display for ApiKeyId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Short prefix of the digest, enough to tell keys apart in logs.
+        for byte in &self.0[..4] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// An API key of a client with access beyond the anonymous budget, for
+/// example a bot author.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKey {
+    /// Name of the owner, for auditing.
+    pub name: String,
+    /// Maximum number of requests per calendar month, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly_quota: Option<u64>,
+    /// Private explorer that the key gives access to, if any.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<TenantId>,
+    /// Maximum number of games that can be imported into the private
+    /// explorer, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tenant_games: Option<u64>,
+}
+
+impl ApiKey {
+    pub fn allows(&self, requests: u64) -> bool {
+        self.monthly_quota.map_or(true, |quota| requests < quota)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_key() {
+        let id = ApiKeyId::from_token("secret");
+        let key = id.usage_key("2022-04".parse().unwrap());
+        assert_eq!(ApiKeyId::from_bytes(&key), id);
+        assert_eq!(ApiKeyId::usage_month(&key).unwrap().to_string(), "2022-04");
+        assert!(key < id.usage_key("2022-05".parse().unwrap()));
+        assert_ne!(ApiKeyId::from_token("secret2"), id);
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::{
+    api::{ApiSchema, SchemaGenerator},
+    model::{read_uint, write_uint, Speed},
+};
+
+#[derive(Error, Debug)]
+#[error("invalid clock")]
+pub struct InvalidClock;
+
+/// Initial time and increment in seconds. Deserializes from either
+/// `"180+2"` or `{"initial": 180, "increment": 2}`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "ClockRepr")]
+pub struct Clock {
+    pub initial: u32,
+    pub increment: u32,
+}
+
+impl Clock {
+    /// Estimated duration of a game with 40 moves per side.
+    pub fn estimate(self) -> u64 {
+        u64::from(self.initial) + 40 * u64::from(self.increment)
+    }
+
+    pub fn speed(self) -> Speed {
+        Speed::from_estimate(self.estimate())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Clock> {
+        Ok(Clock {
+            initial: read_uint(reader)?
+                .try_into()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            increment: read_uint(reader)?
+                .try_into()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        })
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_uint(writer, u64::from(self.initial))?;
+        write_uint(writer, u64::from(self.increment))
+    }
+}
+
+impl FromStr for Clock {
+    type Err = InvalidClock;
+
+    fn from_str(s: &str) -> Result<Clock, InvalidClock> {
+        let (initial, increment) = s.split_once('+').ok_or(InvalidClock)?;
+        Ok(Clock {
+            initial: initial.parse().map_err(|_| InvalidClock)?,
+            increment: increment.parse().map_err(|_| InvalidClock)?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ClockRepr {
+    Str(String),
+    Obj { initial: u32, increment: u32 },
+}
+
+impl TryFrom<ClockRepr> for Clock {
+    type Error = InvalidClock;
+
+    fn try_from(repr: ClockRepr) -> Result<Clock, InvalidClock> {
+        match repr {
+            ClockRepr::Str(s) => s.parse(),
+            ClockRepr::Obj { initial, increment } => Ok(Clock { initial, increment }),
+        }
+    }
+}
+
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{}", self.initial, self.increment)
+    }
+}
+
+impl ApiSchema for Clock {
+    fn schema(_: &mut SchemaGenerator) -> Value {
+        json!({ "type": "string", "description": "Initial time and increment in seconds", "example": "180+2" })
+    }
+}
+
+/// Inclusive range of clocks, ordered by estimated duration, for example
+/// `180+0-300+3`.
+#[derive(Debug, Copy, Clone)]
+pub struct ClockRange {
+    pub min: Clock,
+    pub max: Clock,
+}
+
+impl ClockRange {
+    pub fn contains(&self, clock: Clock) -> bool {
+        self.min.estimate() <= clock.estimate() && clock.estimate() <= self.max.estimate()
+    }
+
+    /// Whether any game of the given speed could fall into the range.
+    pub fn overlaps_speed(&self, speed: Speed) -> bool {
+        self.min.speed() <= speed && speed <= self.max.speed()
+    }
+
+    /// Whether all games of the given speed fall into the range, so that
+    /// stats of the speed need not be broken down by clock. Correspondence
+    /// games have no clock, so they are covered whenever they overlap.
+    pub fn covers_speed(&self, speed: Speed) -> bool {
+        let (min, max) = (self.min.estimate(), self.max.estimate());
+        self.overlaps_speed(speed)
+            && (speed == Speed::Correspondence
+                || ((self.min.speed() < speed
+                    || min == 0
+                    || Speed::from_estimate(min - 1) != speed)
+                    && (self.max.speed() > speed || Speed::from_estimate(max + 1) != speed)))
+    }
+}
+
+impl FromStr for ClockRange {
+    type Err = InvalidClock;
+
+    fn from_str(s: &str) -> Result<ClockRange, InvalidClock> {
+        let (min, max) = s.split_once('-').ok_or(InvalidClock)?;
+        let range = ClockRange {
+            min: min.parse()?,
+            max: max.parse()?,
+        };
+        if range.min.estimate() <= range.max.estimate() {
+            Ok(range)
+        } else {
+            Err(InvalidClock)
+        }
+    }
+}
+
+impl ApiSchema for ClockRange {
+    fn schema(_: &mut SchemaGenerator) -> Value {
+        json!({ "type": "string", "example": "180+0-300+3" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_range() {
+        let range: ClockRange = "180+0-479+0".parse().unwrap();
+        assert!(range.contains("180+0".parse().unwrap()));
+        assert!(range.contains("300+0".parse().unwrap()));
+        assert!(range.contains("300+3".parse().unwrap()));
+        assert!(range.contains("399+2".parse().unwrap()));
+        assert!(!range.contains("60+0".parse().unwrap()));
+        assert!(!range.contains("600+0".parse().unwrap()));
+        assert!(range.overlaps_speed(Speed::Blitz));
+        assert!(range.covers_speed(Speed::Blitz));
+        assert!(!range.overlaps_speed(Speed::Bullet));
+        assert!(!range.overlaps_speed(Speed::Rapid));
+
+        assert!("300+0-180+0".parse::<ClockRange>().is_err());
+
+        let range: ClockRange = "180+0-300+3".parse().unwrap();
+        assert!(range.contains("300+0".parse().unwrap()));
+        assert!(!range.contains("300+5".parse().unwrap()));
+        assert!(range.overlaps_speed(Speed::Blitz));
+        assert!(!range.covers_speed(Speed::Blitz));
+
+        let range: ClockRange = "60+0-1500+0".parse().unwrap();
+        assert!(!range.covers_speed(Speed::Bullet));
+        assert!(range.covers_speed(Speed::Blitz));
+        assert!(range.covers_speed(Speed::Rapid));
+        assert!(!range.covers_speed(Speed::Classical));
+
+        let range: ClockRange = "1500+0-86400+0".parse().unwrap();
+        assert!(range.covers_speed(Speed::Classical));
+        assert!(range.covers_speed(Speed::Correspondence));
+    }
+
+    #[test]
+    fn test_deserialize_clock() {
+        let expected = Clock {
+            initial: 180,
+            increment: 2,
+        };
+        assert_eq!(
+            serde_json::from_str::<Clock>(r#""180+2""#).unwrap(),
+            expected
+        );
+        assert_eq!(
+            serde_json::from_str::<Clock>(r#"{"initial": 180, "increment": 2}"#).unwrap(),
+            expected
+        );
+        assert!(serde_json::from_str::<Clock>(r#""180""#).is_err());
+    }
+
+    #[test]
+    fn test_clock_speed() {
+        // Same thresholds as index-pgn.
+        assert_eq!("15+0".parse::<Clock>().unwrap().speed(), Speed::UltraBullet);
+        assert_eq!("60+0".parse::<Clock>().unwrap().speed(), Speed::Bullet);
+        assert_eq!("120+1".parse::<Clock>().unwrap().speed(), Speed::Bullet);
+        assert_eq!("180+2".parse::<Clock>().unwrap().speed(), Speed::Blitz);
+        assert_eq!("480+0".parse::<Clock>().unwrap().speed(), Speed::Rapid);
+        assert_eq!("900+10".parse::<Clock>().unwrap().speed(), Speed::Rapid);
+        assert_eq!("1800+0".parse::<Clock>().unwrap().speed(), Speed::Classical);
+    }
+}
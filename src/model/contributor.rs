@@ -0,0 +1,85 @@
+use std::{
+    io::{self, Read, Write},
+    ops::AddAssign,
+};
+
+use serde::Serialize;
+use shakmaty::Color;
+
+use crate::{
+    api::ApiSchema,
+    model::{read_uint, write_uint},
+};
+
+/// Number of games in which an indexed player reached a position, by the
+/// color of the player.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, ApiSchema)]
+pub struct ContributorEntry {
+    pub white: u64,
+    pub black: u64,
+}
+
+impl ContributorEntry {
+    pub const SIZE_HINT: usize = 2;
+
+    pub fn new_single(color: Color) -> ContributorEntry {
+        ContributorEntry {
+            white: u64::from(color.is_white()),
+            black: u64::from(color.is_black()),
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.white.saturating_add(self.black)
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<ContributorEntry> {
+        Ok(ContributorEntry {
+            white: read_uint(reader)?,
+            black: read_uint(reader)?,
+        })
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_uint(writer, self.white)?;
+        write_uint(writer, self.black)
+    }
+}
+
+impl AddAssign for ContributorEntry {
+    fn add_assign(&mut self, rhs: ContributorEntry) {
+        self.white = self.white.saturating_add(rhs.white);
+        self.black = self.black.saturating_add(rhs.black);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_contributor_entry_roundtrip() {
+        let mut entry = ContributorEntry::new_single(Color::White);
+        entry += ContributorEntry::new_single(Color::Black);
+        entry += ContributorEntry::new_single(Color::Black);
+
+        let mut writer = Cursor::new(Vec::with_capacity(ContributorEntry::SIZE_HINT));
+        ContributorEntry::new_single(Color::White)
+            .write(&mut writer)
+            .unwrap();
+        assert_eq!(
+            writer.position() as usize,
+            ContributorEntry::SIZE_HINT,
+            "optimized for single entries"
+        );
+
+        let mut writer = Cursor::new(Vec::new());
+        entry.write(&mut writer).unwrap();
+        let mut reader = Cursor::new(writer.into_inner());
+        let deserialized = ContributorEntry::read(&mut reader).unwrap();
+        assert_eq!(deserialized, entry);
+        assert_eq!(deserialized.total(), 3);
+    }
+}
@@ -1,14 +1,37 @@
-use std::{cmp::min, convert::TryFrom, fmt, str::FromStr};
-
-use chrono::{DateTime, Datelike as _, Utc};
+use std::{
+    cmp::min,
+    convert::TryFrom,
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Datelike as _, NaiveDate, NaiveDateTime, Utc};
+use serde_json::{json, Value};
 use thiserror::Error;
 
+use crate::api::{ApiSchema, SchemaGenerator};
+
 #[derive(Error, Debug)]
 pub enum InvalidDate {
     #[error("invlaid year")]
     InvalidYear,
     #[error("invalid month")]
     InvalidMonth,
+    #[error("invalid week")]
+    InvalidWeek,
+}
+
+/// Granularity of the time dimension of keys. Buckets are stored as big
+/// endian `u16` suffixes of keys, so that keys of the same position sort
+/// chronologically.
+pub trait TimeBucket: Copy + Ord + Default + Into<u16> + TryFrom<u16, Error = InvalidDate> {
+    fn max_value() -> Self;
+
+    fn from_time_saturating(time: DateTime<Utc>) -> Self;
+
+    #[must_use]
+    fn add_saturating(self, buckets: u16) -> Self;
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -27,6 +50,15 @@ impl LaxDate {
         self.month
             .map(|m| Month(self.year.0 * 12 + u16::from(m) - 1))
     }
+
+    pub fn week(self) -> Option<Week> {
+        NaiveDate::from_ymd_opt(
+            i32::from(self.year.0),
+            u32::from(self.month?),
+            u32::from(self.day?),
+        )
+        .map(Week::from_date_saturating)
+    }
 }
 
 impl FromStr for LaxDate {
@@ -88,6 +120,20 @@ impl Year {
     }
 }
 
+impl TimeBucket for Year {
+    fn max_value() -> Year {
+        Year::max_value()
+    }
+
+    fn from_time_saturating(time: DateTime<Utc>) -> Year {
+        Month::from_time_saturating(time).year()
+    }
+
+    fn add_saturating(self, years: u16) -> Year {
+        self.add_years_saturating(years)
+    }
+}
+
 impl From<Year> for u16 {
     fn from(Year(year): Year) -> u16 {
         year
@@ -106,7 +152,7 @@ impl TryFrom<u16> for Year {
     }
 }
 
-#[derive(Debug, Copy, Clone, Default, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Default, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub struct Month(u16);
 
 impl Month {
@@ -123,6 +169,16 @@ impl Month {
         Month(year * 12 + time.month0() as u16)
     }
 
+    pub fn current() -> Month {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        Month::from_time_saturating(DateTime::from_utc(
+            NaiveDateTime::from_timestamp(secs as i64, 0),
+            Utc,
+        ))
+    }
+
     #[must_use]
     pub fn add_months_saturating(self, months: u16) -> Month {
         min(Month(self.0.saturating_add(months)), Month::max_value())
@@ -133,6 +189,20 @@ impl Month {
     }
 }
 
+impl TimeBucket for Month {
+    fn max_value() -> Month {
+        Month::max_value()
+    }
+
+    fn from_time_saturating(time: DateTime<Utc>) -> Month {
+        Month::from_time_saturating(time)
+    }
+
+    fn add_saturating(self, months: u16) -> Month {
+        self.add_months_saturating(months)
+    }
+}
+
 impl From<Month> for u16 {
     fn from(Month(month): Month) -> u16 {
         month
@@ -178,9 +248,131 @@ impl FromStr for Month {
     }
 }
 
+impl ApiSchema for Month {
+    fn schema(_: &mut SchemaGenerator) -> Value {
+        json!({ "type": "string", "example": "2023-06" })
+    }
+}
+
+/// Days from 0001-01-01 to 1970-01-01.
+const UNIX_EPOCH_DAYS_FROM_CE: i32 = 719_163;
+
+/// Days from 0001-01-01 to Monday 1970-01-05, the start of the first week.
+const FIRST_WEEK_DAYS_FROM_CE: i32 = UNIX_EPOCH_DAYS_FROM_CE + 4;
+
+/// Calendar week, starting on Monday. Fine enough for views of the last few
+/// days, but only maintained for recently imported lichess games.
+#[derive(Debug, Copy, Clone, Default, Ord, PartialOrd, Eq, PartialEq)]
+pub struct Week(u16);
+
+impl Week {
+    pub fn max_value() -> Week {
+        Week(u16::MAX)
+    }
+
+    pub fn from_date_saturating(date: NaiveDate) -> Week {
+        let weeks = (date.num_days_from_ce() - FIRST_WEEK_DAYS_FROM_CE).div_euclid(7);
+        Week(weeks.clamp(0, i32::from(u16::MAX)) as u16)
+    }
+
+    pub fn from_time_saturating(time: DateTime<Utc>) -> Week {
+        Week::from_date_saturating(time.naive_utc().date())
+    }
+
+    pub fn current() -> Week {
+        let days = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() / (24 * 60 * 60));
+        Week((days.saturating_sub(4) / 7).min(u64::from(u16::MAX)) as u16)
+    }
+
+    #[must_use]
+    pub fn add_weeks_saturating(self, weeks: u16) -> Week {
+        Week(self.0.saturating_add(weeks))
+    }
+
+    #[must_use]
+    pub fn sub_weeks_saturating(self, weeks: u16) -> Week {
+        Week(self.0.saturating_sub(weeks))
+    }
+
+    /// The Monday that starts the week.
+    pub fn monday(self) -> NaiveDate {
+        NaiveDate::from_num_days_from_ce_opt(FIRST_WEEK_DAYS_FROM_CE + i32::from(self.0) * 7)
+            .expect("week in range of dates")
+    }
+}
+
+impl TimeBucket for Week {
+    fn max_value() -> Week {
+        Week::max_value()
+    }
+
+    fn from_time_saturating(time: DateTime<Utc>) -> Week {
+        Week::from_time_saturating(time)
+    }
+
+    fn add_saturating(self, weeks: u16) -> Week {
+        self.add_weeks_saturating(weeks)
+    }
+}
+
+impl From<Week> for u16 {
+    fn from(Week(week): Week) -> u16 {
+        week
+    }
+}
+
+impl TryFrom<u16> for Week {
+    type Error = InvalidDate;
+
+    fn try_from(week: u16) -> Result<Week, InvalidDate> {
+        Ok(Week(week))
+    }
+}
+
+impl fmt::Display for Week {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let monday = self.monday();
+        write!(
+            f,
+            "{:04}-{:02}-{:02}",
+            monday.year(),
+            monday.month(),
+            monday.day()
+        )
+    }
+}
+
+impl FromStr for Week {
+    type Err = InvalidDate;
+
+    /// Parses any date, and returns the week that contains it.
+    fn from_str(s: &str) -> Result<Week, InvalidDate> {
+        let mut parts = s.splitn(3, '-');
+        let mut next = || -> Result<u32, InvalidDate> {
+            parts
+                .next()
+                .and_then(|part| part.parse().ok())
+                .ok_or(InvalidDate::InvalidWeek)
+        };
+        let (year, month, day) = (next()?, next()?, next()?);
+        NaiveDate::from_ymd_opt(year as i32, month, day)
+            .filter(|date| date.year() >= 1970)
+            .map(Week::from_date_saturating)
+            .ok_or(InvalidDate::InvalidWeek)
+    }
+}
+
+impl ApiSchema for Week {
+    fn schema(_: &mut SchemaGenerator) -> Value {
+        json!({ "type": "string", "format": "date", "example": "2023-06-12" })
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use quickcheck::{Arbitrary, Gen};
+    use quickcheck::{quickcheck, Arbitrary, Gen};
 
     use super::*;
 
@@ -189,4 +381,29 @@ mod tests {
             Month(u16::arbitrary(g) % (u16::from(Month::max_value()) + 1))
         }
     }
+
+    impl Arbitrary for Week {
+        fn arbitrary(g: &mut Gen) -> Week {
+            Week(u16::arbitrary(g))
+        }
+    }
+
+    quickcheck! {
+        fn test_week_roundtrip(week: Week) -> bool {
+            week.to_string().parse::<Week>().ok() == Some(week)
+        }
+    }
+
+    #[test]
+    fn test_week() {
+        let week: Week = "2022-03-10".parse().unwrap();
+        assert_eq!(week.to_string(), "2022-03-07");
+        assert_eq!(week.add_weeks_saturating(1).to_string(), "2022-03-14");
+        assert_eq!(Week::default().to_string(), "1970-01-05");
+
+        let date: LaxDate = "2022.03.13".parse().unwrap();
+        assert_eq!(date.week(), Some(week));
+        let date: LaxDate = "2022.03.??".parse().unwrap();
+        assert_eq!(date.week(), None);
+    }
 }
@@ -0,0 +1,62 @@
+use std::mem;
+
+use serde::{Deserialize, Serialize};
+use shakmaty::Color;
+
+use crate::model::MastersGame;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CorrectedResult {
+    #[serde(rename = "1-0")]
+    WhiteWins,
+    #[serde(rename = "0-1")]
+    BlackWins,
+    #[serde(rename = "1/2-1/2")]
+    Draw,
+}
+
+impl CorrectedResult {
+    fn winner(self) -> Option<Color> {
+        match self {
+            CorrectedResult::WhiteWins => Some(Color::White),
+            CorrectedResult::BlackWins => Some(Color::Black),
+            CorrectedResult::Draw => None,
+        }
+    }
+}
+
+/// Correction of the metadata of a stored game. The moves can not be
+/// corrected, because they determine the entries the game contributes to.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Erratum {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<CorrectedResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub white_rating: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub black_rating: Option<u16>,
+    /// The players were recorded with the wrong colors. Applied before the
+    /// corrected ratings.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub swap_colors: bool,
+}
+
+impl Erratum {
+    pub fn apply(&self, game: &mut MastersGame) {
+        if self.swap_colors {
+            mem::swap(&mut game.players.white, &mut game.players.black);
+        }
+        if let Some(rating) = self.white_rating {
+            game.players.white.rating = rating;
+            game.players.white.estimated = false;
+        }
+        if let Some(rating) = self.black_rating {
+            game.players.black.rating = rating;
+            game.players.black.estimated = false;
+        }
+        if let Some(result) = self.result {
+            game.winner = result.winner();
+        }
+    }
+}
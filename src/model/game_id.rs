@@ -5,50 +5,121 @@ use std::{
 };
 
 use byteorder::{LittleEndian, ReadBytesExt as _, WriteBytesExt as _};
+use serde_json::{json, Value};
+use smallvec::SmallVec;
 use thiserror::Error;
 
+use crate::api::{ApiSchema, SchemaGenerator};
+
 #[derive(Error, Debug)]
 #[error("invalid game id")]
 pub struct InvalidGameId;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub struct GameId(u64);
+/// Marker in the top byte of the first 6 bytes of an extended id. Lichess
+/// ids are below `62^8`, so their top byte is at most `0xc6`.
+const EXTENDED: u64 = 0xff << 40;
+
+/// Version of the extended format, stored after the marker.
+const EXTENDED_VERSION: u64 = 1;
+
+/// Game id of up to 21 base-62 characters. Lichess ids with exactly 8
+/// characters are stored in 6 bytes, as they always were. Ids of other
+/// sources, like chess.com or over the board events, are stored as a marker
+/// with the format version and length, followed by the number in as few
+/// bytes as its length requires.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct GameId {
+    len: u8,
+    n: u128,
+}
 
 impl GameId {
-    pub const SIZE: usize = 6;
+    /// Size of lichess ids.
+    pub const LICHESS_SIZE: usize = 6;
+
+    const LICHESS_LEN: u8 = 8;
+
+    /// `62^21` is the largest power that fits into 128 bits.
+    pub const MAX_LEN: usize = 21;
+
+    pub fn is_lichess(&self) -> bool {
+        self.len == GameId::LICHESS_LEN
+    }
+
+    /// Stable 64 bit fingerprint. Equal to the stored number for lichess
+    /// ids, so that existing bloom filters and samples remain valid.
+    pub fn fingerprint(&self) -> u64 {
+        if self.is_lichess() {
+            self.n as u64
+        } else {
+            (self.n as u64)
+                ^ ((self.n >> 64) as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+                ^ (u64::from(self.len) << 56)
+        }
+    }
 
-    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
-        let mut buf = [0; Self::SIZE];
+    pub fn to_bytes(&self) -> SmallVec<[u8; GameId::LICHESS_SIZE]> {
+        let mut buf = [0; GameId::LICHESS_SIZE + 16];
         let mut cursor = Cursor::new(&mut buf[..]);
         self.write(&mut cursor).expect("serialize game id");
-        buf
+        let len = cursor.position() as usize;
+        SmallVec::from_slice(&buf[..len])
     }
 
     pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
-        writer.write_u48::<LittleEndian>(self.0)
+        if self.is_lichess() {
+            writer.write_u48::<LittleEndian>(self.n as u64)
+        } else {
+            writer.write_u48::<LittleEndian>(
+                EXTENDED | (EXTENDED_VERSION << 8) | u64::from(self.len),
+            )?;
+            writer.write_all(&self.n.to_le_bytes()[..value_size(self.len)])
+        }
     }
 
     pub fn read<R: Read>(reader: &mut R) -> io::Result<GameId> {
         let n = reader.read_u48::<LittleEndian>()?;
-        if n < 62u64.pow(8) {
-            Ok(GameId(n))
+        if n < 62u64.pow(u32::from(GameId::LICHESS_LEN)) {
+            return Ok(GameId {
+                len: GameId::LICHESS_LEN,
+                n: u128::from(n),
+            });
+        }
+        if n & !0xffff != EXTENDED || (n >> 8) & 0xff != EXTENDED_VERSION {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let len = (n & 0xff) as u8;
+        if len == 0 || usize::from(len) > GameId::MAX_LEN || len == GameId::LICHESS_LEN {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let mut buf = [0; 16];
+        reader.read_exact(&mut buf[..value_size(len)])?;
+        let n = u128::from_le_bytes(buf);
+        if n < 62u128.pow(u32::from(len)) {
+            Ok(GameId { len, n })
         } else {
             Err(io::ErrorKind::InvalidData.into())
         }
     }
 }
 
+/// Number of bytes needed for any number with `len` base-62 digits.
+fn value_size(len: u8) -> usize {
+    let max = 62u128.pow(u32::from(len)) - 1;
+    (128 - max.leading_zeros() as usize + 7) / 8
+}
+
 impl FromStr for GameId {
     type Err = InvalidGameId;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.len() != 8 {
+        if s.is_empty() || s.len() > GameId::MAX_LEN {
             return Err(InvalidGameId);
         }
 
         let mut n = 0;
         for c in s.bytes().rev() {
-            n = u64::from(match c {
+            n = u128::from(match c {
                 b'0'..=b'9' => c - b'0',
                 b'A'..=b'Z' => c - b'A' + 10,
                 b'a'..=b'z' => c - b'a' + 10 + 26,
@@ -56,21 +127,24 @@ impl FromStr for GameId {
             }) + n * 62;
         }
 
-        Ok(GameId(n))
+        Ok(GameId {
+            len: s.len() as u8,
+            n,
+        })
     }
 }
 
 impl fmt::Display for GameId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut n = self.0;
-        for _ in 0..8 {
-            let rem = n % 62;
+        let mut n = self.n;
+        for _ in 0..self.len {
+            let rem = (n % 62) as u8;
             f.write_char(char::from(if rem >= 10 + 26 {
-                (rem - (10 + 26)) as u8 + b'a'
+                rem - (10 + 26) + b'a'
             } else if rem >= 10 {
-                (rem - 10) as u8 + b'A'
+                rem - 10 + b'A'
             } else {
-                rem as u8 + b'0'
+                rem + b'0'
             }))?;
             n /= 62;
         }
@@ -78,6 +152,12 @@ impl fmt::Display for GameId {
     }
 }
 
+impl ApiSchema for GameId {
+    fn schema(_: &mut SchemaGenerator) -> Value {
+        json!({ "type": "string", "pattern": "^[0-9A-Za-z]{1,21}$", "example": "Zomg6a1B" })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use quickcheck::{quickcheck, Arbitrary, Gen};
@@ -86,7 +166,15 @@ mod tests {
 
     impl Arbitrary for GameId {
         fn arbitrary(g: &mut Gen) -> GameId {
-            GameId(u64::arbitrary(g) % 62u64.pow(8))
+            // Mostly lichess ids, like in practice.
+            let len = match u8::arbitrary(g) % 4 {
+                0 => 1 + u8::arbitrary(g) % GameId::MAX_LEN as u8,
+                _ => GameId::LICHESS_LEN,
+            };
+            GameId {
+                len,
+                n: u128::arbitrary(g) % 62u128.pow(u32::from(len)),
+            }
         }
     }
 
@@ -94,5 +182,43 @@ mod tests {
         fn test_game_id_roundtrip(game_id: GameId) -> bool {
             GameId::from_str(&game_id.to_string()).unwrap() == game_id
         }
+
+        fn test_game_id_bytes_roundtrip(game_ids: Vec<GameId>) -> bool {
+            let mut buf = Vec::new();
+            for game_id in &game_ids {
+                game_id.write(&mut buf).unwrap();
+            }
+            let mut reader = Cursor::new(buf);
+            game_ids
+                .iter()
+                .all(|game_id| GameId::read(&mut reader).unwrap() == *game_id)
+                && GameId::read(&mut reader).is_err()
+        }
+    }
+
+    #[test]
+    fn test_lichess_game_id_bytes() {
+        let game_id: GameId = "TJxUmbWK".parse().unwrap();
+        assert!(game_id.is_lichess());
+        assert_eq!(game_id.to_bytes().len(), GameId::LICHESS_SIZE);
+        assert_eq!(game_id.fingerprint(), game_id.n as u64);
+    }
+
+    #[test]
+    fn test_extended_game_id() {
+        for s in ["0", "00000000000", "123456789012", "zzzzzzzzzzzzzzzzzzzzz"] {
+            let game_id: GameId = s.parse().unwrap();
+            assert_eq!(game_id.to_string(), s);
+            assert!(!game_id.is_lichess());
+            let bytes = game_id.to_bytes();
+            assert_eq!(bytes[GameId::LICHESS_SIZE - 1], 0xff);
+            assert_eq!(GameId::read(&mut Cursor::new(&bytes[..])).unwrap(), game_id);
+        }
+        assert_ne!(
+            "0000000000".parse::<GameId>().unwrap(),
+            "000000000".parse::<GameId>().unwrap()
+        );
+        assert!("zzzzzzzzzzzzzzzzzzzzzz".parse::<GameId>().is_err());
+        assert!("".parse::<GameId>().is_err());
     }
 }
@@ -1,10 +1,12 @@
+use std::hash::{Hash, Hasher};
+
 use byteorder::{BigEndian, ByteOrder as _, LittleEndian};
 use sha1::{Digest, Sha1};
 use shakmaty::{variant::Variant, Color};
 
-use crate::model::{Month, UserId, Year};
+use crate::model::{GameId, InvalidDate, Month, TenantId, TimeBucket, UserId, Year};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct KeyBuilder {
     base: u128,
 }
@@ -24,10 +26,26 @@ impl KeyBuilder {
         KeyBuilder { base: 0 }
     }
 
+    /// Chess960 masters games are kept apart from standard games, because
+    /// positions of both can coincide.
+    pub fn masters_chess960() -> KeyBuilder {
+        KeyBuilder {
+            base: 0x2b71_d60e_9a3c_45f8_b1e0_7c94_d25a_6e13,
+        }
+    }
+
     pub fn lichess() -> KeyBuilder {
         KeyBuilder { base: 0 }
     }
 
+    /// Moves the keys into the private namespace of a tenant, so that its
+    /// positions are stored next to, but apart from the public ones.
+    pub fn with_tenant(&self, tenant: &TenantId) -> KeyBuilder {
+        KeyBuilder {
+            base: self.base ^ LittleEndian::read_u128(&tenant.digest()),
+        }
+    }
+
     pub fn with_zobrist(&self, variant: Variant, zobrist: u128) -> KeyPrefix {
         // Zobrist hashes are the opposite of cryptographically secure. An
         // attacker could efficiently construct a position such that a record
@@ -53,26 +71,62 @@ impl KeyBuilder {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct KeyPrefix {
     prefix: [u8; 16],
 }
 
+impl PartialEq for KeyPrefix {
+    fn eq(&self, other: &KeyPrefix) -> bool {
+        self.prefix[..KeyPrefix::SIZE] == other.prefix[..KeyPrefix::SIZE]
+    }
+}
+
+impl Eq for KeyPrefix {}
+
+impl Hash for KeyPrefix {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.prefix[..KeyPrefix::SIZE].hash(state);
+    }
+}
+
 impl KeyPrefix {
     pub const SIZE: usize = 12;
 
-    pub fn with_month(&self, month: Month) -> Key {
+    pub fn with_bucket<T: TimeBucket>(&self, bucket: T) -> Key {
         let mut buf = [0; Key::SIZE];
         buf[..KeyPrefix::SIZE].clone_from_slice(&self.prefix[..KeyPrefix::SIZE]);
-        BigEndian::write_u16(&mut buf[KeyPrefix::SIZE..], u16::from(month));
+        BigEndian::write_u16(&mut buf[KeyPrefix::SIZE..], bucket.into());
         Key(buf)
     }
 
+    pub fn with_month(&self, month: Month) -> Key {
+        self.with_bucket(month)
+    }
+
     pub fn with_year(&self, year: Year) -> Key {
-        let mut buf = [0; Key::SIZE];
-        buf[..KeyPrefix::SIZE].clone_from_slice(&self.prefix[..KeyPrefix::SIZE]);
-        BigEndian::write_u16(&mut buf[KeyPrefix::SIZE..], u16::from(year));
-        Key(buf)
+        self.with_bucket(year)
+    }
+
+    /// Variable length key of a user that reached the position.
+    pub fn with_user(&self, user: &UserId) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(KeyPrefix::SIZE + user.as_lowercase_str().len());
+        buf.extend_from_slice(self.as_bytes());
+        buf.extend_from_slice(user.as_lowercase_str().as_bytes());
+        buf
+    }
+
+    /// Variable length key of a game that reached the position.
+    pub fn with_game(&self, id: GameId) -> Vec<u8> {
+        let bytes = id.to_bytes();
+        let mut buf = Vec::with_capacity(KeyPrefix::SIZE + bytes.len());
+        buf.extend_from_slice(self.as_bytes());
+        buf.extend_from_slice(&bytes);
+        buf
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.prefix[..KeyPrefix::SIZE]
     }
 }
 
@@ -85,6 +139,24 @@ impl Key {
     pub fn into_bytes(self) -> [u8; Self::SIZE] {
         self.0
     }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Key> {
+        buf.try_into().ok().map(Key)
+    }
+
+    pub fn prefix(&self) -> KeyPrefix {
+        let mut prefix = [0; 16];
+        prefix[..KeyPrefix::SIZE].clone_from_slice(&self.0[..KeyPrefix::SIZE]);
+        KeyPrefix { prefix }
+    }
+
+    pub fn bucket<T: TimeBucket>(&self) -> Result<T, InvalidDate> {
+        T::try_from(BigEndian::read_u16(&self.0[KeyPrefix::SIZE..]))
+    }
+
+    pub fn month(&self) -> Result<Month, InvalidDate> {
+        self.bucket()
+    }
 }
 
 #[cfg(test)]
@@ -93,7 +165,7 @@ mod tests {
     use shakmaty::{variant::Variant, Color};
 
     use super::*;
-    use crate::model::UserName;
+    use crate::model::{UserName, Week};
 
     quickcheck! {
         fn test_key_order(a: Month, b: Month) -> bool {
@@ -103,5 +175,16 @@ mod tests {
 
             (a <= b) == (prefix.with_month(a).into_bytes() <= prefix.with_month(b).into_bytes())
         }
+
+        fn test_key_month_roundtrip(month: Month) -> bool {
+            let prefix = KeyBuilder::lichess().with_zobrist(Variant::Chess, 0);
+            let key = Key::from_bytes(&prefix.with_month(month).into_bytes()).unwrap();
+            key.month().unwrap() == month && key.prefix() == prefix
+        }
+
+        fn test_key_week_order(a: Week, b: Week) -> bool {
+            let prefix = KeyBuilder::lichess().with_zobrist(Variant::Chess, 0);
+            (a <= b) == (prefix.with_bucket(a).into_bytes() <= prefix.with_bucket(b).into_bytes())
+        }
     }
 }
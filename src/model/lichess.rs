@@ -1,23 +1,43 @@
 use std::{
     cmp::{max, min, Reverse},
+    collections::hash_map::RandomState,
+    hash::{BuildHasher as _, Hasher as _},
     io::{self, Read, Write},
-    ops::AddAssign,
+    ops::{AddAssign, SubAssign},
     str::FromStr,
 };
 
-use byteorder::{ReadBytesExt as _, WriteBytesExt as _};
-use rustc_hash::FxHashMap;
-use shakmaty::{uci::Uci, Outcome};
+use byteorder::{LittleEndian, ReadBytesExt as _, WriteBytesExt as _};
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde_json::{json, Value};
+use shakmaty::{uci::Uci, Color, Outcome};
 use smallvec::{smallvec, SmallVec};
 
 use crate::{
-    api::LichessQueryFilter,
-    model::{read_uci, read_uint, write_uci, write_uint, BySpeed, GameId, Speed, Stats},
+    api::{
+        ApiSchema, BestCriterion, ExampleGame, LichessQueryFilter, Limits, MoveSort,
+        SchemaGenerator,
+    },
+    model::{
+        read_uci, read_uint, write_uci, write_uint, Annotations, BySpeed, Clock, ClockRange,
+        GameId, Speed, Stats,
+    },
 };
 
 const MAX_LICHESS_GAMES: usize = 8;
 const MAX_TOP_GAMES: usize = 4; // <= MAX_LICHESS_GAMES
 
+/// Size of the bloom filter records written by earlier versions.
+const LEGACY_BLOOM_SIZE: usize = 32;
+
+/// Entries with more games no longer maintain a bloom filter, because it
+/// would report too many false positives.
+const MAX_BLOOM_GAMES: u64 = 96;
+
+/// Number of games kept per group as a uniform sample of all its games,
+/// including those that are no longer listed.
+const MAX_SAMPLE_GAMES: usize = 4;
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
 pub enum RatingGroup {
     GroupLow,
@@ -60,7 +80,21 @@ impl RatingGroup {
         }
     }
 
-    fn select(mover_rating: u16, opponent_rating: u16) -> RatingGroup {
+    /// Lowest average rating in the group.
+    pub fn lower_bound(self) -> u16 {
+        match self {
+            RatingGroup::GroupLow => 0,
+            RatingGroup::Group1600 => 1600,
+            RatingGroup::Group1800 => 1800,
+            RatingGroup::Group2000 => 2000,
+            RatingGroup::Group2200 => 2200,
+            RatingGroup::Group2500 => 2500,
+            RatingGroup::Group2800 => 2800,
+            RatingGroup::Group3200 => 3200,
+        }
+    }
+
+    pub fn select(mover_rating: u16, opponent_rating: u16) -> RatingGroup {
         if (max(mover_rating, opponent_rating) - min(mover_rating, opponent_rating) >= 150) {
             RatingGroup::Group3200
         }
@@ -79,7 +113,17 @@ impl FromStr for RatingGroup {
     }
 }
 
-#[derive(Default)]
+impl ApiSchema for RatingGroup {
+    fn schema(_: &mut SchemaGenerator) -> Value {
+        json!({
+            "type": "integer",
+            "description": "Lower bound of the average rating of a rating group",
+            "enum": RatingGroup::ALL.map(RatingGroup::lower_bound),
+        })
+    }
+}
+
+#[derive(Default, Clone)]
 struct ByRatingGroup<T> {
     group_low: T,
     group_1600: T,
@@ -154,6 +198,16 @@ enum LichessHeader {
         speed: Speed,
         num_games: usize,
     },
+    MoveTime,
+    Repeated,
+    LegacyBloom,
+    Upsets,
+    Sample,
+    RemainingPlies,
+    Bots,
+    RatedGames,
+    Bloom,
+    Clocks,
     End,
 }
 
@@ -162,6 +216,16 @@ impl LichessHeader {
         let n = reader.read_u8()?;
         let speed = match n & 7 {
             0 => return Ok(LichessHeader::End),
+            7 if n == 7 => return Ok(LichessHeader::MoveTime),
+            7 if n == 15 => return Ok(LichessHeader::Repeated),
+            7 if n == 23 => return Ok(LichessHeader::LegacyBloom),
+            7 if n == 31 => return Ok(LichessHeader::Upsets),
+            7 if n == 39 => return Ok(LichessHeader::Sample),
+            7 if n == 47 => return Ok(LichessHeader::RemainingPlies),
+            7 if n == 55 => return Ok(LichessHeader::Bots),
+            7 if n == 63 => return Ok(LichessHeader::RatedGames),
+            7 if n == 71 => return Ok(LichessHeader::Bloom),
+            7 if n == 79 => return Ok(LichessHeader::Clocks),
             1 => Speed::UltraBullet,
             2 => Speed::Bullet,
             3 => Speed::Blitz,
@@ -196,6 +260,16 @@ impl LichessHeader {
     fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         match *self {
             LichessHeader::End => writer.write_u8(0),
+            LichessHeader::MoveTime => writer.write_u8(7),
+            LichessHeader::Repeated => writer.write_u8(15),
+            LichessHeader::LegacyBloom => writer.write_u8(23),
+            LichessHeader::Upsets => writer.write_u8(31),
+            LichessHeader::Sample => writer.write_u8(39),
+            LichessHeader::RemainingPlies => writer.write_u8(47),
+            LichessHeader::Bots => writer.write_u8(55),
+            LichessHeader::RatedGames => writer.write_u8(63),
+            LichessHeader::Bloom => writer.write_u8(71),
+            LichessHeader::Clocks => writer.write_u8(79),
             LichessHeader::Group {
                 speed,
                 rating_group,
@@ -230,23 +304,563 @@ impl LichessHeader {
     }
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+pub struct MoveTime {
+    seconds_sum: u64,
+    samples: u64,
+}
+
+impl MoveTime {
+    pub fn new_single(seconds: Option<u32>) -> MoveTime {
+        MoveTime {
+            seconds_sum: seconds.map_or(0, u64::from),
+            samples: u64::from(seconds.is_some()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples == 0
+    }
+
+    pub fn average_seconds(&self) -> Option<u64> {
+        self.seconds_sum.checked_div(self.samples)
+    }
+
+    fn read<R: Read>(reader: &mut R) -> io::Result<MoveTime> {
+        Ok(MoveTime {
+            seconds_sum: read_uint(reader)?,
+            samples: read_uint(reader)?,
+        })
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_uint(writer, self.seconds_sum)?;
+        write_uint(writer, self.samples)
+    }
+}
+
+impl AddAssign for MoveTime {
+    fn add_assign(&mut self, rhs: MoveTime) {
+        self.seconds_sum += rhs.seconds_sum;
+        self.samples += rhs.samples;
+    }
+}
+
+impl SubAssign for MoveTime {
+    fn sub_assign(&mut self, rhs: MoveTime) {
+        self.seconds_sum = self.seconds_sum.saturating_sub(rhs.seconds_sum);
+        self.samples = self.samples.saturating_sub(rhs.samples);
+    }
+}
+
+/// Games between players of different ratings, and how many of them were won
+/// by the lower rated side.
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+pub struct Upsets {
+    games: u64,
+    upsets: u64,
+}
+
+impl Upsets {
+    pub fn new_single(upset: Option<bool>) -> Upsets {
+        Upsets {
+            games: u64::from(upset.is_some()),
+            upsets: u64::from(upset.unwrap_or(false)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.games == 0
+    }
+
+    pub fn rate(&self) -> Option<f64> {
+        if self.games == 0 {
+            None
+        } else {
+            Some(self.upsets as f64 / self.games as f64)
+        }
+    }
+
+    fn read<R: Read>(reader: &mut R) -> io::Result<Upsets> {
+        Ok(Upsets {
+            games: read_uint(reader)?,
+            upsets: read_uint(reader)?,
+        })
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_uint(writer, self.games)?;
+        write_uint(writer, self.upsets)
+    }
+}
+
+impl AddAssign for Upsets {
+    fn add_assign(&mut self, rhs: Upsets) {
+        self.games = self.games.saturating_add(rhs.games);
+        self.upsets = self.upsets.saturating_add(rhs.upsets);
+    }
+}
+
+impl SubAssign for Upsets {
+    fn sub_assign(&mut self, rhs: Upsets) {
+        self.games = self.games.saturating_sub(rhs.games);
+        self.upsets = self.upsets.saturating_sub(rhs.upsets);
+    }
+}
+
+/// Ratings of both players, over the games where both were rated, so that
+/// the expected score of the move can be compared to the actual score.
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+pub struct RatedGames {
+    games: u64,
+    mover_rating_sum: u64,
+    opponent_rating_sum: u64,
+}
+
+impl RatedGames {
+    pub fn new_single(mover_rating: u16, opponent_rating: u16, rated: bool) -> RatedGames {
+        if rated {
+            RatedGames {
+                games: 1,
+                mover_rating_sum: u64::from(mover_rating),
+                opponent_rating_sum: u64::from(opponent_rating),
+            }
+        } else {
+            RatedGames::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.games == 0
+    }
+
+    /// Expected score of the mover according to the Elo formula, given the
+    /// average ratings of both sides.
+    pub fn expected_score(&self) -> Option<f64> {
+        if self.games == 0 {
+            return None;
+        }
+        let diff =
+            (self.opponent_rating_sum as f64 - self.mover_rating_sum as f64) / self.games as f64;
+        Some(1.0 / (1.0 + 10f64.powf(diff / 400.0)))
+    }
+
+    fn read<R: Read>(reader: &mut R) -> io::Result<RatedGames> {
+        Ok(RatedGames {
+            games: read_uint(reader)?,
+            mover_rating_sum: read_uint(reader)?,
+            opponent_rating_sum: read_uint(reader)?,
+        })
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_uint(writer, self.games)?;
+        write_uint(writer, self.mover_rating_sum)?;
+        write_uint(writer, self.opponent_rating_sum)
+    }
+}
+
+impl AddAssign for RatedGames {
+    fn add_assign(&mut self, rhs: RatedGames) {
+        self.games = self.games.saturating_add(rhs.games);
+        self.mover_rating_sum = self.mover_rating_sum.saturating_add(rhs.mover_rating_sum);
+        self.opponent_rating_sum = self
+            .opponent_rating_sum
+            .saturating_add(rhs.opponent_rating_sum);
+    }
+}
+
+impl SubAssign for RatedGames {
+    fn sub_assign(&mut self, rhs: RatedGames) {
+        self.games = self.games.saturating_sub(rhs.games);
+        self.mover_rating_sum = self.mover_rating_sum.saturating_sub(rhs.mover_rating_sum);
+        self.opponent_rating_sum = self
+            .opponent_rating_sum
+            .saturating_sub(rhs.opponent_rating_sum);
+    }
+}
+
+/// Number of plies that were played after the move, until the end of the
+/// game.
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+pub struct RemainingPlies {
+    plies_sum: u64,
+    games: u64,
+}
+
+impl RemainingPlies {
+    pub fn new_single(plies: Option<u32>) -> RemainingPlies {
+        RemainingPlies {
+            plies_sum: plies.map_or(0, u64::from),
+            games: u64::from(plies.is_some()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.games == 0
+    }
+
+    pub fn average(&self) -> Option<u64> {
+        self.plies_sum.checked_div(self.games)
+    }
+
+    /// Average number of plies from the position before the move, counting
+    /// the move itself.
+    pub fn average_including_move(&self) -> Option<u64> {
+        self.plies_sum
+            .saturating_add(self.games)
+            .checked_div(self.games)
+    }
+
+    fn read<R: Read>(reader: &mut R) -> io::Result<RemainingPlies> {
+        Ok(RemainingPlies {
+            plies_sum: read_uint(reader)?,
+            games: read_uint(reader)?,
+        })
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_uint(writer, self.plies_sum)?;
+        write_uint(writer, self.games)
+    }
+}
+
+impl AddAssign for RemainingPlies {
+    fn add_assign(&mut self, rhs: RemainingPlies) {
+        self.plies_sum = self.plies_sum.saturating_add(rhs.plies_sum);
+        self.games = self.games.saturating_add(rhs.games);
+    }
+}
+
+impl SubAssign for RemainingPlies {
+    fn sub_assign(&mut self, rhs: RemainingPlies) {
+        self.plies_sum = self.plies_sum.saturating_sub(rhs.plies_sum);
+        self.games = self.games.saturating_sub(rhs.games);
+    }
+}
+
+/// Subsets of the stats of a group by the exact clock of the games, so that
+/// stats can also be filtered by time control ranges that cover only part
+/// of a speed. Games without a known clock are not included.
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+pub struct ByClock {
+    /// Ordered by clock.
+    clocks: Vec<ClockStats>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct ClockStats {
+    clock: Clock,
+    stats: Stats,
+    /// Subset of stats from games with a bot account on either side.
+    bots: Stats,
+}
+
+impl ByClock {
+    pub fn new_single(clock: Option<Clock>, stats: &Stats, bot: bool) -> ByClock {
+        ByClock {
+            clocks: clock
+                .map(|clock| ClockStats {
+                    clock,
+                    stats: stats.clone(),
+                    bots: if bot { stats.clone() } else { Stats::default() },
+                })
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clocks.is_empty()
+    }
+
+    /// Stats of the games with a clock in the given range.
+    pub fn filtered_stats(&self, tc: ClockRange, exclude_bots: bool) -> Stats {
+        let mut stats = Stats::default();
+        for entry in &self.clocks {
+            if tc.contains(entry.clock) {
+                stats += entry.stats.clone();
+                if exclude_bots {
+                    stats -= entry.bots.clone();
+                }
+            }
+        }
+        stats
+    }
+
+    fn read<R: Read>(reader: &mut R) -> io::Result<ByClock> {
+        let len = read_uint(reader)? as usize;
+        let mut clocks = Vec::with_capacity(min(len, 64));
+        for _ in 0..len {
+            clocks.push(ClockStats {
+                clock: Clock::read(reader)?,
+                stats: Stats::read(reader)?,
+                bots: Stats::read(reader)?,
+            });
+        }
+        // Keep order even if the record was written out of order.
+        clocks.sort_by_key(|entry| (entry.clock.initial, entry.clock.increment));
+        Ok(ByClock { clocks })
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_uint(writer, self.clocks.len() as u64)?;
+        for entry in &self.clocks {
+            entry.clock.write(writer)?;
+            entry.stats.write(writer)?;
+            entry.bots.write(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl AddAssign for ByClock {
+    fn add_assign(&mut self, rhs: ByClock) {
+        for entry in rhs.clocks {
+            match self
+                .clocks
+                .binary_search_by_key(&(entry.clock.initial, entry.clock.increment), |e| {
+                    (e.clock.initial, e.clock.increment)
+                }) {
+                Ok(idx) => {
+                    self.clocks[idx].stats += entry.stats;
+                    self.clocks[idx].bots += entry.bots;
+                }
+                Err(idx) => self.clocks.insert(idx, entry),
+            }
+        }
+    }
+}
+
+impl SubAssign for ByClock {
+    fn sub_assign(&mut self, rhs: ByClock) {
+        for entry in rhs.clocks {
+            if let Some(existing) = self.clocks.iter_mut().find(|e| e.clock == entry.clock) {
+                existing.stats -= entry.stats;
+                existing.bots -= entry.bots;
+            }
+        }
+        self.clocks.retain(|entry| !entry.stats.is_empty());
+    }
+}
+
+/// Bloom filter of the games that contributed to an entry, including those
+/// that are no longer listed.
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+struct GameBloom {
+    bits: [u64; GameBloom::WORDS],
+    /// Set once the filter no longer covers all games of the entry, either
+    /// because there are too many, or because they were merged without it.
+    saturated: bool,
+}
+
+impl GameBloom {
+    const WORDS: usize = 16;
+    const HASHES: usize = 5;
+
+    fn positions(game: GameId) -> impl Iterator<Item = usize> {
+        let h = mix(game.fingerprint() ^ 0x426c_6f6f_6d21);
+        (0..GameBloom::HASHES).map(move |i| (h >> (10 * i)) as usize % (GameBloom::WORDS * 64))
+    }
+
+    fn insert(&mut self, game: GameId) {
+        for pos in GameBloom::positions(game) {
+            self.bits[pos / 64] |= 1 << (pos % 64);
+        }
+    }
+
+    /// Checks if the game has probably contributed to the entry. Always
+    /// false once the filter is saturated.
+    fn contains(&self, game: GameId) -> bool {
+        !self.saturated
+            && GameBloom::positions(game).all(|pos| self.bits[pos / 64] & (1 << (pos % 64)) != 0)
+    }
+
+    fn read<R: Read>(reader: &mut R) -> io::Result<GameBloom> {
+        let mut bloom = GameBloom::default();
+        for word in &mut bloom.bits {
+            *word = reader.read_u64::<LittleEndian>()?;
+        }
+        Ok(bloom)
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for word in self.bits {
+            writer.write_u64::<LittleEndian>(word)?;
+        }
+        Ok(())
+    }
+}
+
+impl AddAssign for GameBloom {
+    fn add_assign(&mut self, rhs: GameBloom) {
+        for (word, rhs) in self.bits.iter_mut().zip(rhs.bits) {
+            *word |= rhs;
+        }
+        self.saturated |= rhs.saturated;
+    }
+}
+
+#[derive(Default, Debug, Clone)]
 pub struct LichessGroup {
     pub stats: Stats,
     pub games: SmallVec<[(u64, GameId); 1]>,
+    pub move_time: MoveTime,
+    /// Subset of stats where the move returned to a position that was
+    /// already seen earlier in the same game.
+    pub repeated: Stats,
+    /// Subset of stats from games with a bot account on either side.
+    pub bots: Stats,
+    pub upsets: Upsets,
+    pub remaining_plies: RemainingPlies,
+    pub rated_games: RatedGames,
+    pub by_clock: ByClock,
+    /// Games with the lowest sample priorities, which are a uniform sample
+    /// of all games of the group, regardless of the order of merges.
+    pub sample: SmallVec<[GameId; 1]>,
+}
+
+/// Fixed pseudo random priority of a game for sampling.
+fn sample_priority(game: GameId) -> u64 {
+    mix(game.fingerprint())
+}
+
+/// Finalizer of SplitMix64.
+fn mix(mut h: u64) -> u64 {
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+fn merge_sample(sample: &mut SmallVec<[GameId; 1]>, games: impl IntoIterator<Item = GameId>) {
+    for game in games {
+        if !sample.contains(&game) {
+            sample.push(game);
+        }
+    }
+    sample.sort_by_key(|game| sample_priority(*game));
+    sample.truncate(MAX_SAMPLE_GAMES);
+}
+
+/// Picks a game of a sample. The sample is itself uniform, so this is a
+/// uniformly random game.
+fn pick_example(sample: &[GameId], seed: u64) -> Option<GameId> {
+    let first = sample.first()?;
+    let idx = mix(seed ^ sample_priority(*first)) % sample.len() as u64;
+    Some(sample[idx as usize])
+}
+
+/// Sample implied by the listed games. Stored samples are only written when
+/// they differ, which is never the case for single games.
+fn implied_sample<'a>(games: impl IntoIterator<Item = &'a (u64, GameId)>) -> SmallVec<[GameId; 1]> {
+    let mut sample = SmallVec::new();
+    merge_sample(&mut sample, games.into_iter().map(|(_, game)| *game));
+    sample
 }
 
 impl AddAssign for LichessGroup {
     fn add_assign(&mut self, rhs: LichessGroup) {
+        if self.overlaps(&rhs) {
+            return;
+        }
         self.stats += rhs.stats;
         self.games.extend(rhs.games);
+        self.move_time += rhs.move_time;
+        self.repeated += rhs.repeated;
+        self.bots += rhs.bots;
+        self.upsets += rhs.upsets;
+        self.remaining_plies += rhs.remaining_plies;
+        self.rated_games += rhs.rated_games;
+        self.by_clock += rhs.by_clock;
+        merge_sample(&mut self.sample, rhs.sample);
     }
 }
 
-#[derive(Default)]
+impl LichessGroup {
+    /// Checks if any listed game of `rhs` has already been added to this
+    /// group, for example by a retried merge. Such a group is dropped as a
+    /// whole: the outcomes of the overlapping games are unknown, so their
+    /// stats could not be told apart from the rest.
+    fn overlaps(&self, rhs: &LichessGroup) -> bool {
+        rhs.games
+            .iter()
+            .any(|(_, game)| self.games.iter().any(|(_, g)| g == game))
+    }
+
+    /// Checks if the games of `rhs` have probably already been added to
+    /// this group, even though they are no longer listed. Only possible if
+    /// all games of `rhs` are listed.
+    fn overlaps_unlisted(&self, rhs: &LichessGroup, bloom: &GameBloom) -> bool {
+        self.has_unlisted_games()
+            && rhs.games.len() as u64 == rhs.stats.total()
+            && rhs.games.iter().any(|(_, game)| bloom.contains(*game))
+    }
+
+    fn has_unlisted_games(&self) -> bool {
+        self.stats.total() > min(self.games.len(), MAX_LICHESS_GAMES) as u64
+    }
+
+    fn retract(&mut self, rhs: &LichessGroup) {
+        self.stats -= rhs.stats.clone();
+        self.games
+            .retain(|(_, id)| rhs.games.iter().all(|(_, rhs_id)| rhs_id != id));
+        self.sample
+            .retain(|id| rhs.games.iter().all(|(_, rhs_id)| rhs_id != id));
+        self.move_time -= rhs.move_time.clone();
+        self.repeated -= rhs.repeated.clone();
+        self.bots -= rhs.bots.clone();
+        self.upsets -= rhs.upsets.clone();
+        self.remaining_plies -= rhs.remaining_plies.clone();
+        self.rated_games -= rhs.rated_games.clone();
+        self.by_clock -= rhs.by_clock.clone();
+    }
+
+    fn is_empty(&self) -> bool {
+        self.stats.is_empty() && self.games.is_empty()
+    }
+
+    /// Stats, without games of bots if the filter excludes them. If the
+    /// time control range of the filter covers only part of the speed, only
+    /// games with a clock in the range are counted.
+    fn filtered_stats(&self, speed: Speed, filter: &LichessQueryFilter) -> Stats {
+        match filter.tc {
+            Some(tc) if !tc.covers_speed(speed) => {
+                self.by_clock.filtered_stats(tc, filter.exclude_bots)
+            }
+            _ => {
+                let mut stats = self.stats.clone();
+                if filter.exclude_bots {
+                    stats -= self.bots.clone();
+                }
+                stats
+            }
+        }
+    }
+}
+
+/// Optional information about a single move, if known at import time.
+#[derive(Default, Debug, Clone)]
+pub struct MoveDetails {
+    pub seconds: Option<u32>,
+    pub repeats: bool,
+    /// Whether the lower rated side won the game, unless both players had
+    /// the same (or unknown) rating.
+    pub upset: Option<bool>,
+    /// Number of plies that followed the move in the game.
+    pub remaining_plies: Option<u32>,
+    /// Either player is a bot account.
+    pub bot: bool,
+    /// Both players are rated, so that their ratings are recorded for
+    /// expected scores.
+    pub rated: bool,
+    pub clock: Option<Clock>,
+}
+
+#[derive(Default, Clone)]
 pub struct LichessEntry {
     sub_entries: FxHashMap<Uci, BySpeed<ByRatingGroup<LichessGroup>>>,
     max_game_idx: Option<u64>,
+    bloom: GameBloom,
 }
 
 impl LichessEntry {
@@ -259,24 +873,70 @@ impl LichessEntry {
         outcome: Outcome,
         mover_rating: u16,
         opponent_rating: u16,
+        details: MoveDetails,
     ) -> LichessEntry {
         let rating_group = RatingGroup::select(mover_rating, opponent_rating);
         let mut sub_entry: BySpeed<ByRatingGroup<LichessGroup>> = Default::default();
+        let stats = Stats::new_single(outcome, mover_rating);
+        let by_clock = ByClock::new_single(details.clock, &stats, details.bot);
         *sub_entry
             .by_speed_mut(speed)
             .by_rating_group_mut(rating_group) = LichessGroup {
-            stats: Stats::new_single(outcome, mover_rating),
+            sample: smallvec![game_id],
+            repeated: if details.repeats {
+                stats.clone()
+            } else {
+                Stats::default()
+            },
+            bots: if details.bot {
+                stats.clone()
+            } else {
+                Stats::default()
+            },
+            stats,
             games: smallvec![(0, game_id)],
+            move_time: MoveTime::new_single(details.seconds),
+            upsets: Upsets::new_single(details.upset),
+            remaining_plies: RemainingPlies::new_single(details.remaining_plies),
+            rated_games: RatedGames::new_single(mover_rating, opponent_rating, details.rated),
+            by_clock,
         };
         let mut sub_entries = FxHashMap::with_capacity_and_hasher(1, Default::default());
         sub_entries.insert(uci, sub_entry);
         LichessEntry {
             sub_entries,
             max_game_idx: Some(0),
+            bloom: GameBloom::default(),
         }
     }
 
+    /// Adds a serialized entry. Groups with games that have already been
+    /// added, for example by a retried merge, are skipped. Games that are
+    /// still listed are recognized exactly, others by the bloom filter of
+    /// the entry.
     pub fn extend_from_reader<R: Read>(&mut self, reader: &mut R) -> io::Result<()> {
+        let mut added = Added::default();
+        let res = self.extend_records(reader, &mut added);
+
+        // Games that are listed explicitly are added to the bloom filter.
+        // If there are more games than listed, the serialized entry must
+        // have carried its own bloom filter.
+        for game in added.games {
+            self.bloom.insert(game);
+        }
+        match added.bloom {
+            Some(bloom) => self.bloom += bloom,
+            None if added.unlisted => self.bloom.saturated = true,
+            None => (),
+        }
+        if self.total_games() > MAX_BLOOM_GAMES {
+            self.bloom.saturated = true;
+        }
+
+        res
+    }
+
+    fn extend_records<R: Read>(&mut self, reader: &mut R, added: &mut Added) -> io::Result<()> {
         let base_game_idx = self.max_game_idx.map_or(0, |idx| idx + 1);
 
         loop {
@@ -287,29 +947,154 @@ impl LichessEntry {
             };
 
             let sub_entry = self.sub_entries.entry(uci).or_default();
+            // Speed and rating group of the last group record, and whether
+            // it was dropped as a duplicate.
+            let mut last_group: Option<(Speed, RatingGroup, bool)> = None;
 
             loop {
                 match LichessHeader::read(reader) {
                     Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
                     Err(err) => return Err(err),
                     Ok(LichessHeader::End) => break,
+                    Ok(LichessHeader::MoveTime) => {
+                        // Extension record that applies to the group that
+                        // immediately precedes it. Dropped along with the
+                        // group, if that was a duplicate.
+                        let (speed, rating_group, duplicate) = last_group
+                            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+                        let move_time = MoveTime::read(reader)?;
+                        if !duplicate {
+                            sub_entry
+                                .by_speed_mut(speed)
+                                .by_rating_group_mut(rating_group)
+                                .move_time += move_time;
+                        }
+                    }
+                    Ok(LichessHeader::Repeated) => {
+                        let (speed, rating_group, duplicate) = last_group
+                            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+                        let repeated = Stats::read(reader)?;
+                        if !duplicate {
+                            sub_entry
+                                .by_speed_mut(speed)
+                                .by_rating_group_mut(rating_group)
+                                .repeated += repeated;
+                        }
+                    }
+                    Ok(LichessHeader::Bots) => {
+                        let (speed, rating_group, duplicate) = last_group
+                            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+                        let bots = Stats::read(reader)?;
+                        if !duplicate {
+                            sub_entry
+                                .by_speed_mut(speed)
+                                .by_rating_group_mut(rating_group)
+                                .bots += bots;
+                        }
+                    }
+                    Ok(LichessHeader::Upsets) => {
+                        let (speed, rating_group, duplicate) = last_group
+                            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+                        let upsets = Upsets::read(reader)?;
+                        if !duplicate {
+                            sub_entry
+                                .by_speed_mut(speed)
+                                .by_rating_group_mut(rating_group)
+                                .upsets += upsets;
+                        }
+                    }
+                    Ok(LichessHeader::RemainingPlies) => {
+                        let (speed, rating_group, duplicate) = last_group
+                            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+                        let remaining_plies = RemainingPlies::read(reader)?;
+                        if !duplicate {
+                            sub_entry
+                                .by_speed_mut(speed)
+                                .by_rating_group_mut(rating_group)
+                                .remaining_plies += remaining_plies;
+                        }
+                    }
+                    Ok(LichessHeader::RatedGames) => {
+                        let (speed, rating_group, duplicate) = last_group
+                            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+                        let rated_games = RatedGames::read(reader)?;
+                        if !duplicate {
+                            sub_entry
+                                .by_speed_mut(speed)
+                                .by_rating_group_mut(rating_group)
+                                .rated_games += rated_games;
+                        }
+                    }
+                    Ok(LichessHeader::Clocks) => {
+                        let (speed, rating_group, duplicate) = last_group
+                            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+                        let by_clock = ByClock::read(reader)?;
+                        if !duplicate {
+                            sub_entry
+                                .by_speed_mut(speed)
+                                .by_rating_group_mut(rating_group)
+                                .by_clock += by_clock;
+                        }
+                    }
+                    Ok(LichessHeader::Sample) => {
+                        let (speed, rating_group, duplicate) = last_group
+                            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+                        let num_games = read_uint(reader)?;
+                        let mut sample = SmallVec::<[GameId; 1]>::new();
+                        for _ in 0..num_games {
+                            sample.push(GameId::read(reader)?);
+                        }
+                        if !duplicate {
+                            merge_sample(
+                                &mut sub_entry
+                                    .by_speed_mut(speed)
+                                    .by_rating_group_mut(rating_group)
+                                    .sample,
+                                sample,
+                            );
+                        }
+                    }
+                    Ok(LichessHeader::LegacyBloom) => {
+                        // Smaller filter written by earlier versions, with
+                        // too many false positives to be useful. Entries
+                        // that relied on it are treated as saturated.
+                        reader.read_exact(&mut [0; LEGACY_BLOOM_SIZE])?;
+                    }
+                    Ok(LichessHeader::Bloom) => {
+                        // Applies to the entry as a whole.
+                        let bloom = GameBloom::read(reader)?;
+                        *added.bloom.get_or_insert_with(GameBloom::default) += bloom;
+                    }
                     Ok(LichessHeader::Group {
                         speed,
                         rating_group,
                         num_games,
                     }) => {
                         let stats = Stats::read(reader)?;
+                        added.unlisted |= stats.total() > num_games as u64;
                         let mut games = SmallVec::with_capacity(num_games);
                         for _ in 0..num_games {
                             let game_idx = base_game_idx + read_uint(reader)?;
                             self.max_game_idx = Some(max(self.max_game_idx.unwrap_or(0), game_idx));
                             let game = GameId::read(reader)?;
+                            added.games.push(game);
                             games.push((game_idx, game));
                         }
                         let group = sub_entry
                             .by_speed_mut(speed)
                             .by_rating_group_mut(rating_group);
-                        *group += LichessGroup { stats, games };
+                        let added_group = LichessGroup {
+                            stats,
+                            sample: implied_sample(&games),
+                            games,
+                            ..Default::default()
+                        };
+                        let duplicate = group.overlaps(&added_group)
+                            || group.overlaps_unlisted(&added_group, &self.bloom);
+                        if !duplicate {
+                            *group += added_group;
+                        }
+                        last_group = Some((speed, rating_group, duplicate));
                     }
                 }
             }
@@ -317,6 +1102,9 @@ impl LichessEntry {
     }
 
     pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        // If all games are listed, the bloom filter can be rebuilt from them.
+        let with_bloom = !self.bloom.saturated && self.has_unlisted_games();
+
         for (i, (uci, sub_entry)) in self.sub_entries.iter().enumerate() {
             if i > 0 {
                 LichessHeader::End.write(writer)?;
@@ -324,6 +1112,11 @@ impl LichessEntry {
 
             write_uci(writer, uci)?;
 
+            if i == 0 && with_bloom {
+                LichessHeader::Bloom.write(writer)?;
+                self.bloom.write(writer)?;
+            }
+
             sub_entry.as_ref().try_map(|speed, by_rating_group| {
                 by_rating_group.as_ref().try_map(|rating_group, group| {
                     if !group.games.is_empty() || !group.stats.is_empty() {
@@ -336,14 +1129,55 @@ impl LichessEntry {
 
                         group.stats.write(writer)?;
 
-                        for (game_idx, game) in group
-                            .games
-                            .iter()
-                            .skip(group.games.len().saturating_sub(MAX_LICHESS_GAMES))
-                        {
+                        let listed =
+                            &group.games[group.games.len().saturating_sub(MAX_LICHESS_GAMES)..];
+                        for (game_idx, game) in listed {
                             write_uint(writer, *game_idx)?;
                             game.write(writer)?;
                         }
+
+                        if group.sample != implied_sample(listed) {
+                            LichessHeader::Sample.write(writer)?;
+                            write_uint(writer, group.sample.len() as u64)?;
+                            for game in &group.sample {
+                                game.write(writer)?;
+                            }
+                        }
+
+                        if !group.move_time.is_empty() {
+                            LichessHeader::MoveTime.write(writer)?;
+                            group.move_time.write(writer)?;
+                        }
+
+                        if !group.repeated.is_empty() {
+                            LichessHeader::Repeated.write(writer)?;
+                            group.repeated.write(writer)?;
+                        }
+
+                        if !group.bots.is_empty() {
+                            LichessHeader::Bots.write(writer)?;
+                            group.bots.write(writer)?;
+                        }
+
+                        if !group.upsets.is_empty() {
+                            LichessHeader::Upsets.write(writer)?;
+                            group.upsets.write(writer)?;
+                        }
+
+                        if !group.remaining_plies.is_empty() {
+                            LichessHeader::RemainingPlies.write(writer)?;
+                            group.remaining_plies.write(writer)?;
+                        }
+
+                        if !group.rated_games.is_empty() {
+                            LichessHeader::RatedGames.write(writer)?;
+                            group.rated_games.write(writer)?;
+                        }
+
+                        if !group.by_clock.is_empty() {
+                            LichessHeader::Clocks.write(writer)?;
+                            group.by_clock.write(writer)?;
+                        }
                     }
 
                     Ok::<_, io::Error>(())
@@ -354,21 +1188,255 @@ impl LichessEntry {
         Ok(())
     }
 
-    pub fn prepare(self, filter: &LichessQueryFilter) -> PreparedResponse {
-        let mut total = Stats::default();
+    fn groups(&self) -> impl Iterator<Item = &LichessGroup> {
+        self.sub_entries.values().flat_map(|sub_entry| {
+            Speed::ALL.into_iter().flat_map(move |speed| {
+                RatingGroup::ALL.into_iter().map(move |rating_group| {
+                    sub_entry.by_speed(speed).by_rating_group(rating_group)
+                })
+            })
+        })
+    }
+
+    fn total_games(&self) -> u64 {
+        self.groups().map(|group| group.stats.total()).sum()
+    }
+
+    fn has_unlisted_games(&self) -> bool {
+        self.groups().any(LichessGroup::has_unlisted_games)
+    }
+
+    /// Number of games by speed and rating group, over all moves.
+    pub fn games_by_group(&self) -> Vec<(Speed, RatingGroup, u64)> {
+        let mut games = Vec::new();
+        for speed in Speed::ALL {
+            for rating_group in RatingGroup::ALL {
+                let total: u64 = self
+                    .sub_entries
+                    .values()
+                    .map(|sub_entry| {
+                        sub_entry
+                            .by_speed(speed)
+                            .by_rating_group(rating_group)
+                            .stats
+                            .total()
+                    })
+                    .sum();
+                if total > 0 {
+                    games.push((speed, rating_group, total));
+                }
+            }
+        }
+        games
+    }
+
+    pub fn num_moves(&self) -> usize {
+        self.sub_entries.len()
+    }
+
+    /// Sum of the stats of all moves, restricted to the speeds and rating
+    /// groups selected by the filter. Cheaper than a full
+    /// [`LichessEntry::prepare()`].
+    pub fn total(&self, filter: &LichessQueryFilter) -> Stats {
+        let mut total = Stats::default();
+        for sub_entry in self.sub_entries.values() {
+            for speed in Speed::ALL {
+                if filter.contains_speed(speed) {
+                    for rating_group in RatingGroup::ALL {
+                        if filter.contains_rating_group(rating_group) {
+                            total += sub_entry
+                                .by_speed(speed)
+                                .by_rating_group(rating_group)
+                                .filtered_stats(speed, filter);
+                        }
+                    }
+                }
+            }
+        }
+        total
+    }
+
+    /// All games that are listed for the speeds and rating groups selected
+    /// by the filter, most recent first.
+    pub fn games(&self, filter: &LichessQueryFilter) -> Vec<(Uci, GameId)> {
+        let mut games = Vec::new();
+        for (uci, sub_entry) in &self.sub_entries {
+            for speed in Speed::ALL {
+                if filter.contains_speed(speed) {
+                    for rating_group in RatingGroup::ALL {
+                        if filter.contains_rating_group(rating_group) {
+                            games.extend(
+                                sub_entry
+                                    .by_speed(speed)
+                                    .by_rating_group(rating_group)
+                                    .games
+                                    .iter()
+                                    .map(|(idx, game)| (*idx, uci.to_owned(), *game)),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        games.sort_by_key(|(idx, _, _)| Reverse(*idx));
+        games
+            .into_iter()
+            .map(|(_, uci, game)| (uci, game))
+            .collect()
+    }
+
+    /// Removes the contribution of a single game, given as the entry that was
+    /// created with `new_single()` when it was imported.
+    pub fn retract(&mut self, single: &LichessEntry) {
+        for (uci, single_sub_entry) in &single.sub_entries {
+            if let Some(sub_entry) = self.sub_entries.get_mut(uci) {
+                let mut empty = true;
+                for speed in Speed::ALL {
+                    for rating_group in RatingGroup::ALL {
+                        let group = sub_entry
+                            .by_speed_mut(speed)
+                            .by_rating_group_mut(rating_group);
+                        group.retract(
+                            single_sub_entry
+                                .by_speed(speed)
+                                .by_rating_group(rating_group),
+                        );
+                        empty &= group.is_empty();
+                    }
+                }
+                if empty {
+                    self.sub_entries.remove(uci);
+                }
+            }
+        }
+    }
+
+    /// Removes references to the given games, for example after they were
+    /// retracted or erased. Stats are left as they are. Returns true if any
+    /// reference was removed.
+    pub fn prune_games(&mut self, ids: &FxHashSet<GameId>) -> bool {
+        let mut pruned = false;
+        for sub_entry in self.sub_entries.values_mut() {
+            for speed in Speed::ALL {
+                for rating_group in RatingGroup::ALL {
+                    let games = &mut sub_entry
+                        .by_speed_mut(speed)
+                        .by_rating_group_mut(rating_group)
+                        .games;
+                    let len = games.len();
+                    games.retain(|(_, id)| !ids.contains(id));
+                    pruned |= games.len() != len;
+
+                    let sample = &mut sub_entry
+                        .by_speed_mut(speed)
+                        .by_rating_group_mut(rating_group)
+                        .sample;
+                    let len = sample.len();
+                    sample.retain(|id| !ids.contains(id));
+                    pruned |= sample.len() != len;
+                }
+            }
+        }
+        pruned
+    }
+
+    /// Drops listed games that would not be written anyway, so that entries
+    /// that are kept in memory and merged repeatedly do not grow without
+    /// bound.
+    pub fn truncate_games(&mut self) {
+        for sub_entry in self.sub_entries.values_mut() {
+            for speed in Speed::ALL {
+                for rating_group in RatingGroup::ALL {
+                    let games = &mut sub_entry
+                        .by_speed_mut(speed)
+                        .by_rating_group_mut(rating_group)
+                        .games;
+                    games.drain(..games.len().saturating_sub(MAX_LICHESS_GAMES));
+                }
+            }
+        }
+    }
+
+    /// Selects a single move by the given criterion, without preparing the
+    /// others. Ties are broken by popularity, and finally by UCI notation.
+    pub fn best_move(
+        &self,
+        filter: &LichessQueryFilter,
+        criterion: BestCriterion,
+        turn: Color,
+    ) -> Option<(Uci, Stats)> {
+        self.sub_entries
+            .iter()
+            .filter_map(|(uci, sub_entry)| {
+                let mut stats = Stats::default();
+                for speed in Speed::ALL {
+                    if filter.contains_speed(speed) {
+                        for rating_group in RatingGroup::ALL {
+                            if filter.contains_rating_group(rating_group) {
+                                stats += sub_entry
+                                    .by_speed(speed)
+                                    .by_rating_group(rating_group)
+                                    .filtered_stats(speed, filter);
+                            }
+                        }
+                    }
+                }
+                (!stats.is_empty() && filter.has_min_games(stats.total())).then(|| (uci, stats))
+            })
+            .max_by_key(|(uci, stats)| {
+                (
+                    match criterion {
+                        BestCriterion::Score => stats.score_permille(turn),
+                        BestCriterion::Popularity => Some(stats.total()),
+                    },
+                    stats.total(),
+                    Reverse(uci.to_string()),
+                )
+            })
+            .map(|(uci, stats)| (uci.to_owned(), stats))
+    }
+
+    /// Unless limited otherwise, selects up to `MAX_TOP_GAMES` top games and
+    /// fills the rest with recent games. Requested limits are bounded by the
+    /// number of games stored per group.
+    pub fn prepare(self, filter: &LichessQueryFilter, limits: &Limits) -> PreparedResponse {
+        let max_top_games = limits
+            .top_games
+            .map_or(MAX_TOP_GAMES, |n| min(n, MAX_LICHESS_GAMES));
+
+        let mut total = Stats::default();
+        let mut total_remaining_plies = RemainingPlies::default();
         let mut moves = Vec::with_capacity(self.sub_entries.len());
         let mut recent_games: Vec<(RatingGroup, Speed, u64, Uci, GameId)> = Vec::new();
+        let seed = filter
+            .seed
+            .unwrap_or_else(|| RandomState::new().build_hasher().finish());
 
         for (uci, sub_entry) in self.sub_entries {
             let mut latest_game: Option<(u64, GameId)> = None;
+            let mut sample: SmallVec<[GameId; 1]> = SmallVec::new();
             let mut stats = Stats::default();
+            let mut by_speed: BySpeed<Stats> = BySpeed::default();
+            let mut move_time = MoveTime::default();
+            let mut repeated = Stats::default();
+            let mut upsets = Upsets::default();
+            let mut remaining_plies = RemainingPlies::default();
+            let mut rated_games = RatedGames::default();
 
             for rating_group in RatingGroup::ALL {
                 if filter.contains_rating_group(rating_group) {
                     for speed in Speed::ALL {
                         if filter.contains_speed(speed) {
                             let group = sub_entry.by_speed(speed).by_rating_group(rating_group);
-                            stats += group.stats.to_owned();
+                            let group_stats = group.filtered_stats(speed, filter);
+                            stats += group_stats.clone();
+                            *by_speed.by_speed_mut(speed) += group_stats;
+                            move_time += group.move_time.to_owned();
+                            repeated += group.repeated.to_owned();
+                            upsets += group.upsets.to_owned();
+                            remaining_plies += group.remaining_plies.to_owned();
+                            rated_games += group.rated_games.to_owned();
+                            merge_sample(&mut sample, group.sample.iter().copied());
 
                             for (idx, game) in group.games.iter().copied() {
                                 if latest_game.map_or(true, |(latest_idx, _game)| latest_idx < idx)
@@ -385,20 +1453,43 @@ impl LichessEntry {
                 }
             }
 
-            if !stats.is_empty() || latest_game.is_some() {
+            if (!stats.is_empty() || latest_game.is_some()) && filter.has_min_games(stats.total()) {
                 moves.push(PreparedMove {
                     uci,
                     stats: stats.clone(),
                     average_rating: stats.average_rating(),
                     average_opponent_rating: None,
-                    game: latest_game.filter(|_| stats.is_single()).map(|(_, id)| id),
+                    avg_seconds: move_time.average_seconds(),
+                    repeated,
+                    upset_rate: upsets.rate(),
+                    avg_remaining_plies: remaining_plies.average(),
+                    expected_score: rated_games.expected_score(),
+                    annotations: Annotations::default(),
+                    by_opponent_rating: Vec::new(),
+                    by_speed: if filter.group_by_speed {
+                        Speed::ALL
+                            .into_iter()
+                            .map(|speed| (speed, by_speed.by_speed(speed).clone()))
+                            .filter(|(_, stats)| !stats.is_empty())
+                            .collect()
+                    } else {
+                        Vec::new()
+                    },
+                    latest_idx: latest_game.map(|(idx, _)| idx),
+                    game: match filter.example_game {
+                        ExampleGame::Single => {
+                            latest_game.filter(|_| stats.is_single()).map(|(_, id)| id)
+                        }
+                        ExampleGame::Random => pick_example(&sample, seed),
+                    },
                 });
             }
 
             total += stats;
+            total_remaining_plies += remaining_plies;
         }
 
-        moves.sort_by_key(|row| Reverse(row.stats.total()));
+        PreparedMove::sort(&mut moves);
 
         // Split out top games from recent games.
         let top_games = if let Some(top_group) = filter.top_group() {
@@ -408,9 +1499,9 @@ impl LichessEntry {
                     Reverse(*idx),
                 )
             });
-            let mut top_games = Vec::with_capacity(MAX_TOP_GAMES);
+            let mut top_games = Vec::with_capacity(max_top_games);
             recent_games.retain(|(rating_group, speed, _, uci, game)| {
-                if top_games.len() < MAX_TOP_GAMES
+                if top_games.len() < max_top_games
                     && *rating_group >= top_group
                     && *speed != Speed::Correspondence
                 {
@@ -427,10 +1518,17 @@ impl LichessEntry {
 
         // Prepare recent games.
         recent_games.sort_by_key(|(_, _, idx, _, _)| Reverse(*idx));
-        recent_games.truncate(MAX_LICHESS_GAMES - top_games.len());
+        recent_games.truncate(
+            limits
+                .recent_games
+                .map_or(MAX_LICHESS_GAMES - top_games.len(), |n| {
+                    min(n, MAX_LICHESS_GAMES)
+                }),
+        );
 
         PreparedResponse {
             total,
+            avg_remaining_plies: total_remaining_plies.average_including_move(),
             moves,
             top_games,
             recent_games: recent_games
@@ -441,21 +1539,102 @@ impl LichessEntry {
     }
 }
 
-#[derive(Debug)]
+/// Games added by a single call to [`LichessEntry::extend_from_reader()`].
+#[derive(Default)]
+struct Added {
+    games: Vec<GameId>,
+    /// Some group had more games than it listed.
+    unlisted: bool,
+    bloom: Option<GameBloom>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct PreparedResponse {
     pub total: Stats,
+    /// Average number of plies played from the position until the end of
+    /// the game, if known.
+    pub avg_remaining_plies: Option<u64>,
     pub moves: Vec<PreparedMove>,
     pub recent_games: Vec<(Uci, GameId)>,
     pub top_games: Vec<(Uci, GameId)>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct PreparedMove {
     pub uci: Uci,
     pub stats: Stats,
     pub game: Option<GameId>,
     pub average_rating: Option<u64>,
     pub average_opponent_rating: Option<u64>,
+    pub avg_seconds: Option<u64>,
+    pub repeated: Stats,
+    pub upset_rate: Option<f64>,
+    pub avg_remaining_plies: Option<u64>,
+    /// Expected score of the side to move, from the ratings of both
+    /// players, if known.
+    pub expected_score: Option<f64>,
+    pub annotations: Annotations,
+    /// Stats by lower bound of the opponent rating bucket, if requested.
+    pub by_opponent_rating: Vec<(u16, Stats)>,
+    /// Stats by speed, if requested.
+    pub by_speed: Vec<(Speed, Stats)>,
+    /// Index of the most recent game with this move, if known.
+    pub latest_idx: Option<u64>,
+}
+
+impl PreparedMove {
+    /// Sorts the most popular moves first. Ties are broken by the score
+    /// from the point of view of white, and finally by UCI notation, so that
+    /// equal queries always produce identical responses.
+    pub fn sort(moves: &mut [PreparedMove]) {
+        moves.sort_by_cached_key(|m| {
+            (
+                Reverse(m.stats.total()),
+                // Proportional to the score, given equal totals.
+                Reverse(
+                    m.stats
+                        .white
+                        .saturating_mul(2)
+                        .saturating_add(m.stats.draws),
+                ),
+                m.uci.to_string(),
+            )
+        });
+    }
+
+    /// Reorders moves that are already sorted by popularity. The sort is
+    /// stable, so popularity still breaks ties.
+    pub fn sort_by(moves: &mut [PreparedMove], sort: MoveSort, turn: Color) {
+        match sort {
+            MoveSort::Games => (),
+            MoveSort::Winrate => moves.sort_by_cached_key(|m| Reverse(m.score_permille(turn))),
+            MoveSort::AverageRating => moves.sort_by_cached_key(|m| Reverse(m.average_rating)),
+            MoveSort::Performance => moves.sort_by_cached_key(|m| Reverse(m.performance(turn))),
+            MoveSort::Recent => moves.sort_by_cached_key(|m| Reverse(m.latest_idx)),
+        }
+    }
+
+    /// Score of the side to move in thousandths, if there are any games.
+    fn score_permille(&self, turn: Color) -> Option<u64> {
+        self.stats.score_permille(turn)
+    }
+
+    /// Performance rating of the side to move, using the linear
+    /// approximation of 800 points between a score of 0% and 100%.
+    pub fn performance(&self, turn: Color) -> Option<i64> {
+        let rating = self.average_opponent_rating.or(self.average_rating)?;
+        let score = self.score_permille(turn)?;
+        Some(rating as i64 + (score as i64 - 500) * 4 / 5)
+    }
+
+    /// Actual minus expected score of the side to move, rounded to three
+    /// decimals. Positive if the move performs better than the ratings of
+    /// the players who chose it would suggest.
+    pub fn performance_delta(&self, turn: Color) -> Option<f64> {
+        let expected = self.expected_score?;
+        let score = self.score_permille(turn)?;
+        Some(((score as f64 - expected * 1000.0).round()) / 1000.0)
+    }
 }
 
 #[cfg(test)]
@@ -465,7 +1644,7 @@ mod tests {
     use shakmaty::{Color, Square};
 
     use super::*;
-    use crate::model::Month;
+    use crate::{api::SpeedFilter, model::Month};
 
     #[test]
     fn test_lichess_entry() {
@@ -483,6 +1662,7 @@ mod tests {
             Outcome::Draw,
             2000,
             2200,
+            MoveDetails::default(),
         );
 
         let mut cursor = Cursor::new(Vec::new());
@@ -517,6 +1697,7 @@ mod tests {
             },
             2000,
             2200,
+            MoveDetails::default(),
         );
 
         let mut cursor = Cursor::new(Vec::new());
@@ -538,14 +1719,31 @@ mod tests {
 
         assert_eq!(deserialized.sub_entries.len(), 2);
         assert_eq!(deserialized.max_game_idx, Some(1));
+        assert_eq!(
+            deserialized.games(&LichessQueryFilter::default()),
+            &[
+                (uci_b.clone(), "bbbbbbbb".parse().unwrap()),
+                (uci_a.clone(), "aaaaaaaa".parse().unwrap()),
+            ]
+        );
 
         // Run query.
-        let res = deserialized.prepare(&LichessQueryFilter {
-            speeds: None,
-            ratings: Some(vec![RatingGroup::Group2000]),
-            since: Month::default(),
-            until: Month::max_value(),
-        });
+        let res = deserialized.prepare(
+            &LichessQueryFilter {
+                speed: SpeedFilter::default(),
+                ratings: Some(vec![RatingGroup::Group2000]),
+                since: Month::default(),
+                until: Month::max_value(),
+                since_week: None,
+                tc: None,
+                min_games: None,
+                exclude_bots: false,
+                group_by_speed: false,
+                example_game: ExampleGame::Single,
+                seed: None,
+            },
+            &Limits::default(),
+        );
         assert_eq!(
             res.recent_games,
             &[
@@ -554,4 +1752,876 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_move_time() {
+        let uci = Uci::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+
+        let mut entry = LichessEntry::default();
+        for (id, seconds) in [("aaaaaaaa", Some(3)), ("bbbbbbbb", Some(7)), ("cccccccc", None)] {
+            let mut cursor = Cursor::new(Vec::new());
+            LichessEntry::new_single(
+                uci.clone(),
+                Speed::Rapid,
+                id.parse().unwrap(),
+                Outcome::Draw,
+                1900,
+                1900,
+                MoveDetails {
+                    seconds,
+                    repeats: seconds.is_none(),
+                    upset: seconds.map(|s| s > 5),
+                    remaining_plies: seconds.map(|s| s * 10),
+                    bot: false,
+                    rated: false,
+                    clock: None,
+                },
+            )
+            .write(&mut cursor)
+            .unwrap();
+            entry
+                .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+                .unwrap();
+        }
+
+        // Roundtrip the combined entry.
+        let mut cursor = Cursor::new(Vec::new());
+        entry.write(&mut cursor).unwrap();
+        let mut deserialized = LichessEntry::default();
+        deserialized
+            .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+            .unwrap();
+
+        let res = deserialized.prepare(
+            &LichessQueryFilter {
+                speed: SpeedFilter::default(),
+                ratings: None,
+                since: Month::default(),
+                until: Month::max_value(),
+                since_week: None,
+                tc: None,
+                min_games: None,
+                exclude_bots: false,
+                group_by_speed: false,
+                example_game: ExampleGame::Single,
+                seed: None,
+            },
+            &Limits::default(),
+        );
+        assert_eq!(res.moves.len(), 1);
+        assert_eq!(res.moves[0].stats.draws, 3);
+        assert_eq!(res.moves[0].avg_seconds, Some(5));
+        assert_eq!(res.moves[0].repeated.draws, 1);
+        assert_eq!(res.moves[0].upset_rate, Some(0.5));
+        assert_eq!(res.moves[0].avg_remaining_plies, Some(50));
+        assert_eq!(res.avg_remaining_plies, Some(51));
+    }
+
+    #[test]
+    fn test_average_rating_merge_order() {
+        let uci = Uci::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+
+        // Heterogeneous groups: many low rated blitz games, one high rated
+        // classical game.
+        let mut singles = Vec::new();
+        for (i, id) in ["aaaaaaaa", "bbbbbbbb", "cccccccc"].iter().enumerate() {
+            let mut cursor = Cursor::new(Vec::new());
+            LichessEntry::new_single(
+                uci.clone(),
+                Speed::Blitz,
+                id.parse().unwrap(),
+                Outcome::Draw,
+                1600 + i as u16 * 2,
+                1600,
+                MoveDetails::default(),
+            )
+            .write(&mut cursor)
+            .unwrap();
+            singles.push(cursor.into_inner());
+        }
+        let mut cursor = Cursor::new(Vec::new());
+        LichessEntry::new_single(
+            uci,
+            Speed::Classical,
+            "dddddddd".parse().unwrap(),
+            Outcome::Draw,
+            2598,
+            2600,
+            MoveDetails::default(),
+        )
+        .write(&mut cursor)
+        .unwrap();
+        singles.push(cursor.into_inner());
+
+        let filter = LichessQueryFilter {
+            speed: SpeedFilter::default(),
+            ratings: None,
+            since: Month::default(),
+            until: Month::max_value(),
+            since_week: None,
+            tc: None,
+            min_games: None,
+            exclude_bots: false,
+            group_by_speed: false,
+            example_game: ExampleGame::Single,
+            seed: None,
+        };
+
+        let mut averages = Vec::new();
+        for order in [[0, 1, 2, 3], [3, 2, 1, 0], [1, 3, 0, 2]] {
+            let mut entry = LichessEntry::default();
+            for i in order {
+                entry
+                    .extend_from_reader(&mut Cursor::new(&singles[i]))
+                    .unwrap();
+            }
+            let total = entry.total(&filter);
+            let res = entry.prepare(&filter, &Limits::default());
+            assert_eq!(total, res.total);
+            averages.push(res.moves[0].average_rating);
+        }
+
+        assert_eq!(averages, vec![Some(1851); 3]);
+
+        let mut entry = LichessEntry::default();
+        for single in &singles {
+            entry.extend_from_reader(&mut Cursor::new(single)).unwrap();
+        }
+        let classical = LichessQueryFilter {
+            speed: SpeedFilter {
+                speeds: Some(vec![Speed::Classical]),
+                speed_groups: None,
+            },
+            ..filter
+        };
+        assert_eq!(entry.total(&classical).draws, 1);
+
+        let popular = LichessQueryFilter {
+            min_games: Some(2),
+            ..classical
+        };
+        let res = entry.prepare(&popular, &Limits::default());
+        assert!(res.moves.is_empty());
+        assert_eq!(res.total.draws, 1);
+
+        let mut entry = LichessEntry::default();
+        for single in &singles {
+            entry.extend_from_reader(&mut Cursor::new(single)).unwrap();
+        }
+        let grouped = LichessQueryFilter {
+            group_by_speed: true,
+            ..LichessQueryFilter::default()
+        };
+        let res = entry.prepare(&grouped, &Limits::default());
+        assert_eq!(
+            res.moves[0]
+                .by_speed
+                .iter()
+                .map(|(speed, stats)| (*speed, stats.draws))
+                .collect::<Vec<_>>(),
+            [(Speed::Blitz, 3), (Speed::Classical, 1)]
+        );
+    }
+
+    #[test]
+    fn test_sample_merge_order() {
+        let uci = Uci::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+        let ids: Vec<GameId> = (0..20)
+            .map(|i| format!("game{:04}", i).parse().unwrap())
+            .collect();
+        let serialized = |order: &mut dyn Iterator<Item = &GameId>| {
+            let mut entry = LichessEntry::default();
+            for id in order {
+                let mut cursor = Cursor::new(Vec::new());
+                LichessEntry::new_single(
+                    uci.clone(),
+                    Speed::Blitz,
+                    *id,
+                    Outcome::Draw,
+                    2000,
+                    2000,
+                    MoveDetails::default(),
+                )
+                .write(&mut cursor)
+                .unwrap();
+                entry
+                    .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+                    .unwrap();
+            }
+            let mut cursor = Cursor::new(Vec::new());
+            entry.write(&mut cursor).unwrap();
+            let mut deserialized = LichessEntry::default();
+            deserialized
+                .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+                .unwrap();
+            deserialized
+        };
+
+        let forward = serialized(&mut ids.iter());
+        let backward = serialized(&mut ids.iter().rev());
+        let sample = |entry: &LichessEntry| {
+            entry.sub_entries[&uci]
+                .by_speed(Speed::Blitz)
+                .by_rating_group(RatingGroup::Group2000)
+                .sample
+                .clone()
+        };
+        assert_eq!(sample(&forward), sample(&backward));
+        assert_eq!(
+            sample(&forward),
+            implied_sample(&ids.iter().map(|id| (0, *id)).collect::<Vec<_>>())
+        );
+
+        let random = LichessQueryFilter {
+            example_game: ExampleGame::Random,
+            seed: Some(42),
+            ..LichessQueryFilter::default()
+        };
+        let forward = forward.prepare(&random, &Limits::default());
+        let backward = backward.prepare(&random, &Limits::default());
+        assert!(forward.moves[0].game.is_some());
+        assert_eq!(forward.moves[0].game, backward.moves[0].game);
+    }
+
+    #[test]
+    fn test_merge_skips_listed_games() {
+        let uci = Uci::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+        let single = |id: &str| {
+            let mut cursor = Cursor::new(Vec::new());
+            LichessEntry::new_single(
+                uci.clone(),
+                Speed::Blitz,
+                id.parse().unwrap(),
+                Outcome::Draw,
+                1900,
+                1900,
+                MoveDetails::default(),
+            )
+            .write(&mut cursor)
+            .unwrap();
+            cursor.into_inner()
+        };
+        let roundtrip = |entry: &LichessEntry| {
+            let mut cursor = Cursor::new(Vec::new());
+            entry.write(&mut cursor).unwrap();
+            let mut deserialized = LichessEntry::default();
+            deserialized
+                .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+                .unwrap();
+            deserialized
+        };
+
+        // Distinct games are never mistaken for duplicates.
+        let ids: Vec<String> = (0..100).map(|i| format!("game{:04}", i)).collect();
+        let mut entry = LichessEntry::default();
+        for id in &ids {
+            entry
+                .extend_from_reader(&mut Cursor::new(single(id)))
+                .unwrap();
+        }
+        let mut entry = roundtrip(&entry);
+        assert_eq!(entry.total(&LichessQueryFilter::default()).draws, 100);
+
+        // Games that are still listed are skipped.
+        entry
+            .extend_from_reader(&mut Cursor::new(single(&ids[99])))
+            .unwrap();
+        assert_eq!(entry.total(&LichessQueryFilter::default()).draws, 100);
+
+        // Games that are no longer listed can not be told apart, once there
+        // are too many games for the bloom filter.
+        entry
+            .extend_from_reader(&mut Cursor::new(single(&ids[0])))
+            .unwrap();
+        assert_eq!(entry.total(&LichessQueryFilter::default()).draws, 101);
+
+        // With fewer games, the bloom filter also recognizes games that are
+        // no longer listed.
+        let mut entry = LichessEntry::default();
+        for id in &ids[..40] {
+            entry
+                .extend_from_reader(&mut Cursor::new(single(id)))
+                .unwrap();
+        }
+        let mut entry = roundtrip(&entry);
+        assert!(!entry.bloom.saturated);
+        for id in &ids[..40] {
+            entry
+                .extend_from_reader(&mut Cursor::new(single(id)))
+                .unwrap();
+        }
+        assert_eq!(entry.total(&LichessQueryFilter::default()).draws, 40);
+        for id in &ids[40..] {
+            entry
+                .extend_from_reader(&mut Cursor::new(single(id)))
+                .unwrap();
+        }
+        assert_eq!(entry.total(&LichessQueryFilter::default()).draws, 100);
+
+        // Merging an entry with unlisted games but without a bloom filter
+        // saturates the filter, rather than trusting it.
+        let mut entry = LichessEntry::default();
+        for id in &ids[..20] {
+            entry
+                .extend_from_reader(&mut Cursor::new(single(id)))
+                .unwrap();
+        }
+        let mut entry = roundtrip(&entry);
+        entry.bloom.saturated = true;
+        let mut entry = roundtrip(&entry);
+        assert!(entry.bloom.saturated);
+        entry
+            .extend_from_reader(&mut Cursor::new(single(&ids[0])))
+            .unwrap();
+        assert_eq!(entry.total(&LichessQueryFilter::default()).draws, 21);
+    }
+
+    #[test]
+    fn test_retract() {
+        let uci = Uci::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+        let single = |id: &str| {
+            LichessEntry::new_single(
+                uci.clone(),
+                Speed::Blitz,
+                id.parse().unwrap(),
+                Outcome::Draw,
+                2000,
+                2000,
+                MoveDetails {
+                    seconds: Some(2),
+                    ..MoveDetails::default()
+                },
+            )
+        };
+
+        let mut entry = LichessEntry::default();
+        for id in ["aaaaaaaa", "bbbbbbbb"] {
+            let mut cursor = Cursor::new(Vec::new());
+            single(id).write(&mut cursor).unwrap();
+            entry
+                .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+                .unwrap();
+        }
+
+        entry.retract(&single("aaaaaaaa"));
+        let group = entry.sub_entries[&uci]
+            .by_speed(Speed::Blitz)
+            .by_rating_group(RatingGroup::Group2000);
+        assert_eq!(group.stats.draws, 1);
+        assert_eq!(group.games.len(), 1);
+        assert_eq!(group.move_time.average_seconds(), Some(2));
+
+        entry.retract(&single("bbbbbbbb"));
+        assert!(entry.sub_entries.is_empty());
+    }
+
+    #[test]
+    fn test_exclude_bots() {
+        let uci = Uci::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+
+        let mut entry = LichessEntry::default();
+        for (id, outcome, bot) in [
+            ("aaaaaaaa", Outcome::Draw, false),
+            (
+                "bbbbbbbb",
+                Outcome::Decisive {
+                    winner: Color::White,
+                },
+                true,
+            ),
+            (
+                "cccccccc",
+                Outcome::Decisive {
+                    winner: Color::Black,
+                },
+                true,
+            ),
+        ] {
+            let mut cursor = Cursor::new(Vec::new());
+            LichessEntry::new_single(
+                uci.clone(),
+                Speed::Blitz,
+                id.parse().unwrap(),
+                outcome,
+                2000,
+                2000,
+                MoveDetails {
+                    bot,
+                    ..MoveDetails::default()
+                },
+            )
+            .write(&mut cursor)
+            .unwrap();
+            entry
+                .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+                .unwrap();
+        }
+
+        let mut cursor = Cursor::new(Vec::new());
+        entry.write(&mut cursor).unwrap();
+        let mut deserialized = LichessEntry::default();
+        deserialized
+            .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+            .unwrap();
+
+        let all = deserialized.total(&LichessQueryFilter::default());
+        assert_eq!((all.white, all.draws, all.black), (1, 1, 1));
+
+        let without_bots = LichessQueryFilter {
+            exclude_bots: true,
+            ..LichessQueryFilter::default()
+        };
+        deserialized.retract(&LichessEntry::new_single(
+            uci,
+            Speed::Blitz,
+            "bbbbbbbb".parse().unwrap(),
+            Outcome::Decisive {
+                winner: Color::White,
+            },
+            2000,
+            2000,
+            MoveDetails {
+                bot: true,
+                ..MoveDetails::default()
+            },
+        ));
+        assert_eq!(deserialized.total(&without_bots).total(), 1);
+        assert_eq!(deserialized.total(&LichessQueryFilter::default()).black, 1);
+
+        let res = deserialized.prepare(&without_bots, &Limits::default());
+        assert_eq!(res.total.total(), 1);
+        assert_eq!(res.total.draws, 1);
+        assert_eq!(res.moves[0].stats.draws, 1);
+    }
+
+    #[test]
+    fn test_clock_range_filter() {
+        let uci = Uci::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+        let mut entry = LichessEntry::default();
+        for (id, clock, outcome, bot) in [
+            (
+                "aaaaaaaa",
+                Some("180+0"),
+                Outcome::Decisive {
+                    winner: Color::White,
+                },
+                false,
+            ),
+            ("bbbbbbbb", Some("180+0"), Outcome::Draw, true),
+            (
+                "cccccccc",
+                Some("300+3"),
+                Outcome::Decisive {
+                    winner: Color::Black,
+                },
+                false,
+            ),
+            ("dddddddd", None, Outcome::Draw, false),
+        ] {
+            let mut cursor = Cursor::new(Vec::new());
+            LichessEntry::new_single(
+                uci.clone(),
+                Speed::Blitz,
+                id.parse().unwrap(),
+                outcome,
+                2000,
+                2000,
+                MoveDetails {
+                    bot,
+                    clock: clock.map(|clock| clock.parse().unwrap()),
+                    ..MoveDetails::default()
+                },
+            )
+            .write(&mut cursor)
+            .unwrap();
+            entry
+                .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+                .unwrap();
+        }
+
+        let mut cursor = Cursor::new(Vec::new());
+        entry.write(&mut cursor).unwrap();
+        let mut deserialized = LichessEntry::default();
+        deserialized
+            .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+            .unwrap();
+
+        // Covers all of blitz, including the game without a known clock.
+        let all_blitz = LichessQueryFilter {
+            tc: Some("180+0-479+0".parse().unwrap()),
+            ..LichessQueryFilter::default()
+        };
+        assert_eq!(deserialized.total(&all_blitz).total(), 4);
+
+        // Part of blitz.
+        let short_blitz = LichessQueryFilter {
+            tc: Some("180+0-300+0".parse().unwrap()),
+            ..LichessQueryFilter::default()
+        };
+        let total = deserialized.total(&short_blitz);
+        assert_eq!((total.white, total.draws, total.black), (1, 1, 0));
+        let without_bots = LichessQueryFilter {
+            exclude_bots: true,
+            ..short_blitz
+        };
+        assert_eq!(deserialized.total(&without_bots).white, 1);
+        assert_eq!(deserialized.total(&without_bots).total(), 1);
+
+        let res = deserialized.clone().prepare(
+            &LichessQueryFilter {
+                tc: Some("180+0-300+3".parse().unwrap()),
+                ..LichessQueryFilter::default()
+            },
+            &Limits::default(),
+        );
+        assert_eq!(res.total.total(), 3);
+        assert_eq!(res.moves[0].stats.black, 1);
+
+        deserialized.retract(&LichessEntry::new_single(
+            uci,
+            Speed::Blitz,
+            "cccccccc".parse().unwrap(),
+            Outcome::Decisive {
+                winner: Color::Black,
+            },
+            2000,
+            2000,
+            MoveDetails {
+                clock: Some("300+3".parse().unwrap()),
+                ..MoveDetails::default()
+            },
+        ));
+        let total = deserialized.total(&LichessQueryFilter {
+            tc: Some("300+0-479+0".parse().unwrap()),
+            ..LichessQueryFilter::default()
+        });
+        assert!(total.is_empty());
+    }
+
+    #[test]
+    fn test_performance_delta() {
+        let uci = Uci::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+        let mut entry = LichessEntry::default();
+        for (id, outcome, rated) in [
+            (
+                "aaaaaaaa",
+                Outcome::Decisive {
+                    winner: Color::White,
+                },
+                true,
+            ),
+            ("bbbbbbbb", Outcome::Draw, true),
+            ("cccccccc", Outcome::Draw, false),
+        ] {
+            let mut cursor = Cursor::new(Vec::new());
+            LichessEntry::new_single(
+                uci.clone(),
+                Speed::Blitz,
+                id.parse().unwrap(),
+                outcome,
+                1500,
+                1900,
+                MoveDetails {
+                    rated,
+                    ..MoveDetails::default()
+                },
+            )
+            .write(&mut cursor)
+            .unwrap();
+            entry
+                .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+                .unwrap();
+        }
+
+        let mut cursor = Cursor::new(Vec::new());
+        entry.write(&mut cursor).unwrap();
+        let mut deserialized = LichessEntry::default();
+        deserialized
+            .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+            .unwrap();
+
+        // Scored 2/3, where 1/11 was expected from the rated games.
+        let res = deserialized.prepare(&LichessQueryFilter::default(), &Limits::default());
+        assert_eq!(res.moves[0].performance_delta(Color::White), Some(0.575));
+    }
+
+    #[test]
+    fn test_prune_games() {
+        let uci = Uci::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+        let mut entry = LichessEntry::default();
+        for id in ["aaaaaaaa", "bbbbbbbb"] {
+            let mut cursor = Cursor::new(Vec::new());
+            LichessEntry::new_single(
+                uci.clone(),
+                Speed::Blitz,
+                id.parse().unwrap(),
+                Outcome::Draw,
+                2000,
+                2000,
+                MoveDetails::default(),
+            )
+            .write(&mut cursor)
+            .unwrap();
+            entry
+                .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+                .unwrap();
+        }
+
+        let ids: FxHashSet<GameId> = ["aaaaaaaa".parse().unwrap()].into_iter().collect();
+        assert!(entry.prune_games(&ids));
+        assert!(!entry.prune_games(&ids));
+        let group = entry.sub_entries[&uci]
+            .by_speed(Speed::Blitz)
+            .by_rating_group(RatingGroup::Group2000);
+        assert_eq!(group.stats.draws, 2);
+        assert_eq!(
+            group.games[..],
+            [(1, "bbbbbbbb".parse::<GameId>().unwrap())]
+        );
+    }
+
+    #[test]
+    fn test_truncate_games() {
+        let uci = Uci::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+        let mut entry = LichessEntry::default();
+        for i in 0..12 {
+            let mut cursor = Cursor::new(Vec::new());
+            LichessEntry::new_single(
+                uci.clone(),
+                Speed::Blitz,
+                format!("game{:04}", i).parse().unwrap(),
+                Outcome::Draw,
+                2000,
+                2000,
+                MoveDetails::default(),
+            )
+            .write(&mut cursor)
+            .unwrap();
+            entry
+                .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+                .unwrap();
+        }
+
+        let mut before = Vec::new();
+        entry.write(&mut before).unwrap();
+        entry.truncate_games();
+        let mut after = Vec::new();
+        entry.write(&mut after).unwrap();
+        assert_eq!(before, after);
+
+        let group = entry.sub_entries[&uci]
+            .by_speed(Speed::Blitz)
+            .by_rating_group(RatingGroup::Group2000);
+        assert_eq!(group.games.len(), MAX_LICHESS_GAMES);
+        assert_eq!(group.games[0].1, "game0004".parse().unwrap());
+        assert_eq!(group.stats.draws, 12);
+    }
+
+    #[test]
+    fn test_retried_merge() {
+        let uci = Uci::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+        let serialized = |ids: &[&str]| {
+            let mut entry = LichessEntry::default();
+            for id in ids {
+                let mut cursor = Cursor::new(Vec::new());
+                LichessEntry::new_single(
+                    uci.clone(),
+                    Speed::Blitz,
+                    id.parse().unwrap(),
+                    Outcome::Draw,
+                    2000,
+                    2000,
+                    MoveDetails {
+                        seconds: Some(4),
+                        repeats: true,
+                        upset: None,
+                        remaining_plies: None,
+                        bot: false,
+                        rated: false,
+                        clock: Some("180+0".parse().unwrap()),
+                    },
+                )
+                .write(&mut cursor)
+                .unwrap();
+                entry
+                    .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+                    .unwrap();
+            }
+            let mut cursor = Cursor::new(Vec::new());
+            entry.write(&mut cursor).unwrap();
+            cursor.into_inner()
+        };
+
+        // The same serialized entry merged twice.
+        let both = serialized(&["aaaaaaaa", "bbbbbbbb"]);
+        let mut entry = LichessEntry::default();
+        entry.extend_from_reader(&mut Cursor::new(&both)).unwrap();
+        entry.extend_from_reader(&mut Cursor::new(&both)).unwrap();
+        let group = entry.sub_entries[&uci]
+            .by_speed(Speed::Blitz)
+            .by_rating_group(RatingGroup::Group2000);
+        assert_eq!(group.stats.draws, 2);
+        assert_eq!(group.games.len(), 2);
+        assert_eq!(group.repeated.draws, 2);
+        assert_eq!(group.move_time.average_seconds(), Some(4));
+
+        // Partial overlap is rejected as a whole, rather than double counted.
+        entry
+            .extend_from_reader(&mut Cursor::new(serialized(&["bbbbbbbb", "cccccccc"])))
+            .unwrap();
+        let group = entry.sub_entries[&uci]
+            .by_speed(Speed::Blitz)
+            .by_rating_group(RatingGroup::Group2000);
+        assert_eq!(group.stats.draws, 2);
+        assert_eq!(group.games.len(), 2);
+        assert_eq!(group.repeated.draws, 2);
+
+        // Disjoint games are added.
+        entry
+            .extend_from_reader(&mut Cursor::new(serialized(&["cccccccc", "dddddddd"])))
+            .unwrap();
+        let group = entry.sub_entries[&uci]
+            .by_speed(Speed::Blitz)
+            .by_rating_group(RatingGroup::Group2000);
+        assert_eq!(group.stats.draws, 4);
+        assert_eq!(group.games.len(), 4);
+    }
+
+    #[test]
+    fn test_sort_moves() {
+        let prepared = |uci: &str, white: u64, draws: u64, black: u64| PreparedMove {
+            uci: uci.parse().unwrap(),
+            stats: Stats {
+                white,
+                draws,
+                black,
+                ..Stats::default()
+            },
+            game: None,
+            average_rating: None,
+            average_opponent_rating: None,
+            avg_seconds: None,
+            repeated: Stats::default(),
+            upset_rate: None,
+            avg_remaining_plies: None,
+            expected_score: None,
+            annotations: Annotations::default(),
+            by_opponent_rating: Vec::new(),
+            by_speed: Vec::new(),
+            latest_idx: None,
+        };
+
+        let mut moves = vec![
+            prepared("g1f3", 1, 1, 1),
+            prepared("d2d4", 1, 1, 1),
+            prepared("c2c4", 0, 1, 2),
+            prepared("e2e4", 2, 1, 1),
+            prepared("b1c3", 3, 0, 0),
+        ];
+        PreparedMove::sort(&mut moves);
+        assert_eq!(
+            moves.iter().map(|m| m.uci.to_string()).collect::<Vec<_>>(),
+            ["e2e4", "b1c3", "d2d4", "g1f3", "c2c4"]
+        );
+
+        PreparedMove::sort_by(&mut moves, MoveSort::Winrate, Color::Black);
+        assert_eq!(
+            moves.iter().map(|m| m.uci.to_string()).collect::<Vec<_>>(),
+            ["c2c4", "d2d4", "g1f3", "e2e4", "b1c3"]
+        );
+    }
+
+    #[test]
+    fn test_best_move() {
+        let mut entry = LichessEntry::default();
+        for (uci, id, winner) in [
+            ("e2e4", "aaaaaaaa", Color::White),
+            ("e2e4", "bbbbbbbb", Color::Black),
+            ("d2d4", "cccccccc", Color::White),
+        ] {
+            let mut cursor = Cursor::new(Vec::new());
+            LichessEntry::new_single(
+                uci.parse().unwrap(),
+                Speed::Blitz,
+                id.parse().unwrap(),
+                Outcome::Decisive { winner },
+                2000,
+                2000,
+                MoveDetails::default(),
+            )
+            .write(&mut cursor)
+            .unwrap();
+            entry
+                .extend_from_reader(&mut Cursor::new(cursor.into_inner()))
+                .unwrap();
+        }
+
+        let best = |filter: &LichessQueryFilter, criterion| {
+            entry
+                .best_move(filter, criterion, Color::White)
+                .map(|(uci, _)| uci.to_string())
+        };
+        let filter = LichessQueryFilter::default();
+        assert_eq!(best(&filter, BestCriterion::Score).as_deref(), Some("d2d4"));
+        assert_eq!(
+            best(&filter, BestCriterion::Popularity).as_deref(),
+            Some("e2e4")
+        );
+
+        let filter = LichessQueryFilter {
+            min_games: Some(2),
+            ..LichessQueryFilter::default()
+        };
+        assert_eq!(best(&filter, BestCriterion::Score).as_deref(), Some("e2e4"));
+
+        let filter = LichessQueryFilter {
+            min_games: Some(3),
+            ..LichessQueryFilter::default()
+        };
+        assert_eq!(best(&filter, BestCriterion::Score), None);
+    }
 }
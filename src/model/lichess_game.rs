@@ -7,7 +7,10 @@ use byteorder::{LittleEndian, ReadBytesExt as _, WriteBytesExt as _};
 use serde::{Deserialize, Serialize};
 use shakmaty::{ByColor, Color, Outcome};
 
-use crate::model::{read_uint, write_uint, Mode, Month, Speed};
+use crate::{
+    api::ApiSchema,
+    model::{read_uint, write_uint, Clock, LichessSource, Mode, Month, Speed},
+};
 
 #[derive(Debug)]
 pub struct LichessGame {
@@ -18,6 +21,9 @@ pub struct LichessGame {
     pub month: Month,
     pub indexed_player: ByColor<bool>,
     pub indexed_lichess: bool,
+    /// Source that the game was imported from, if `indexed_lichess`.
+    pub source: LichessSource,
+    pub clock: Option<Clock>,
 }
 
 impl LichessGame {
@@ -48,7 +54,16 @@ impl LichessGame {
         self.players.white.write(writer)?;
         self.players.black.write(writer)?;
         writer.write_u16::<LittleEndian>(u16::from(self.month))?;
-        writer.write_u8(if self.indexed_lichess { 1 } else { 0 })
+        writer.write_u8(
+            u8::from(self.indexed_lichess)
+                | (u8::from(self.players.white.bot) << 1)
+                | (u8::from(self.players.black.bot) << 2)
+                | (self.source.to_u8() << 3),
+        )?;
+        if let Some(clock) = self.clock {
+            clock.write(writer)?;
+        }
+        Ok(())
     }
 
     pub fn read<R: Read>(reader: &mut R) -> io::Result<LichessGame> {
@@ -77,7 +92,7 @@ impl LichessGame {
             white: (byte >> 6) & 1 == 1,
             black: (byte >> 7) & 1 == 1,
         };
-        let players = ByColor {
+        let mut players = ByColor {
             white: GamePlayer::read(reader)?,
             black: GamePlayer::read(reader)?,
         };
@@ -85,7 +100,19 @@ impl LichessGame {
             .read_u16::<LittleEndian>()?
             .try_into()
             .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
-        let indexed_lichess = reader.read_u8()? != 0;
+        let flags = reader.read_u8()?;
+        let indexed_lichess = flags & 1 != 0;
+        players.white.bot = (flags >> 1) & 1 == 1;
+        players.black.bot = (flags >> 2) & 1 == 1;
+        // Games imported before sources were separated are from the default
+        // source.
+        let source = LichessSource::from_u8((flags >> 3) & 3)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+        let clock = match Clock::read(reader) {
+            Ok(clock) => Some(clock),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(err) => return Err(err),
+        };
         Ok(LichessGame {
             outcome,
             speed,
@@ -94,17 +121,70 @@ impl LichessGame {
             month,
             indexed_player,
             indexed_lichess,
+            source,
+            clock,
         })
     }
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, ApiSchema, Debug)]
 pub struct GamePlayer {
     pub name: String,
+    #[serde(default)]
     pub rating: u16,
+    /// Marker on imported games. Not stored.
+    #[serde(default, skip_serializing)]
+    pub provisional: bool,
+    /// Estimate for historical masters games without Elo, used in place of
+    /// a missing rating on import. Not stored.
+    #[serde(default, rename = "estimatedRating", skip_serializing)]
+    pub estimated_rating: Option<RatingEstimate>,
+    /// The rating was estimated rather than measured.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub estimated: bool,
+    /// The player is a bot account. Only known for lichess games.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bot: bool,
+}
+
+/// Either an estimated rating, or a title that stands in for one.
+#[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum RatingEstimate {
+    Rating(u16),
+    Category(RatingCategory),
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RatingCategory {
+    Gm,
+    Im,
+    Fm,
+}
+
+impl RatingEstimate {
+    pub fn rating(self) -> u16 {
+        match self {
+            RatingEstimate::Rating(rating) => rating,
+            RatingEstimate::Category(RatingCategory::Gm) => 2500,
+            RatingEstimate::Category(RatingCategory::Im) => 2400,
+            RatingEstimate::Category(RatingCategory::Fm) => 2300,
+        }
+    }
 }
 
 impl GamePlayer {
+    /// Fills in a missing rating from the estimate, if any.
+    pub fn apply_estimated_rating(&mut self) {
+        if self.rating == 0 {
+            if let Some(estimate) = self.estimated_rating.take() {
+                self.rating = estimate.rating();
+                self.estimated = true;
+            }
+        }
+    }
+
     fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         write_uint(writer, self.name.len() as u64)?;
         writer.write_all(self.name.as_bytes())?;
@@ -120,6 +200,56 @@ impl GamePlayer {
             name: String::from_utf8(buf)
                 .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
             rating: reader.read_u16::<LittleEndian>()?,
+            provisional: false,
+            estimated_rating: None,
+            estimated: false,
+            bot: false,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_lichess_game_source() {
+        let player = |name: &str| GamePlayer {
+            name: name.to_owned(),
+            rating: 2000,
+            provisional: false,
+            estimated_rating: None,
+            estimated: false,
+            bot: false,
+        };
+        let game = LichessGame {
+            outcome: Outcome::Draw,
+            speed: Speed::Blitz,
+            mode: Mode::Rated,
+            players: ByColor {
+                white: player("white"),
+                black: player("black"),
+            },
+            month: "2022-03".parse().unwrap(),
+            indexed_player: ByColor::default(),
+            indexed_lichess: true,
+            source: LichessSource::Manual,
+            clock: None,
+        };
+
+        let mut cursor = Cursor::new(Vec::new());
+        game.write(&mut cursor).unwrap();
+        let mut buf = cursor.into_inner();
+        let deserialized = LichessGame::read(&mut Cursor::new(&buf)).unwrap();
+        assert!(deserialized.indexed_lichess);
+        assert_eq!(deserialized.source, LichessSource::Manual);
+
+        // Written before sources were separated.
+        let flags = buf.len() - 1;
+        buf[flags] &= 0b111;
+        let deserialized = LichessGame::read(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(deserialized.source, LichessSource::Dump);
+    }
+}
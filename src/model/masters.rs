@@ -2,6 +2,7 @@ use std::{
     cmp::Reverse,
     io,
     io::{Cursor, Read, Write},
+    mem,
     ops::AddAssign,
 };
 
@@ -13,12 +14,19 @@ use byteorder::{LittleEndian, ReadBytesExt as _, WriteBytesExt as _};
 use rustc_hash::FxHashMap;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr, SpaceSeparator, StringWithSeparator};
-use shakmaty::{san::SanPlus, uci::Uci, ByColor, Chess, Color, Outcome};
+use shakmaty::{
+    fen::Fen,
+    san::SanPlus,
+    uci::Uci,
+    variant::{Variant, VariantPosition},
+    ByColor, CastlingMode, Color, Outcome, PositionError,
+};
 use smallvec::{smallvec, SmallVec};
 
 use crate::{
     model::{
-        read_uci, write_uci, GameId, GamePlayer, LaxDate, PreparedMove, PreparedResponse, Stats,
+        read_uci, write_uci, Annotations, GameId, GamePlayer, KeyBuilder, LaxDate, PreparedMove,
+        PreparedResponse, Stats,
     },
     util::ByColorDef,
 };
@@ -33,7 +41,7 @@ pub struct MastersGameWithId {
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MastersGame {
     pub event: String,
     pub site: String,
@@ -46,14 +54,63 @@ pub struct MastersGame {
     pub winner: Option<Color>,
     #[serde_as(as = "StringWithSeparator<SpaceSeparator, Uci>")]
     pub moves: Vec<Uci>,
+    /// Numeric annotation glyph for each move, or 0 if the move is not
+    /// annotated.
+    #[serde_as(as = "Option<StringWithSeparator<SpaceSeparator, u8>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nags: Option<Vec<u8>>,
+    /// Starting position of Chess960 games.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fen: Option<Fen>,
 }
 
 impl MastersGame {
+    /// Fills in missing ratings of historical games from estimates.
+    pub fn apply_estimated_ratings(&mut self) {
+        self.players.white.apply_estimated_rating();
+        self.players.black.apply_estimated_rating();
+    }
+
+    pub fn is_chess960(&self) -> bool {
+        self.fen.is_some()
+    }
+
+    pub fn initial_position(&self) -> Result<VariantPosition, PositionError<VariantPosition>> {
+        match self.fen {
+            Some(ref fen) => VariantPosition::from_setup(
+                Variant::Chess,
+                fen.clone().into_setup(),
+                CastlingMode::Chess960,
+            ),
+            None => Ok(VariantPosition::new(Variant::Chess)),
+        }
+    }
+
+    pub fn key_builder(&self) -> KeyBuilder {
+        if self.is_chess960() {
+            KeyBuilder::masters_chess960()
+        } else {
+            KeyBuilder::masters()
+        }
+    }
+
+    pub fn has_estimated_rating(&self) -> bool {
+        self.players.white.estimated || self.players.black.estimated
+    }
+
+    pub fn nag(&self, ply: usize) -> Option<u8> {
+        self.nags
+            .as_ref()
+            .and_then(|nags| nags.get(ply).copied())
+            .filter(|nag| *nag != 0)
+    }
+
     fn outcome(&self) -> Outcome {
         Outcome::from_winner(self.winner)
     }
 
-    fn write_pgn<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    pub fn write_pgn<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         writeln!(writer, "[Event \"{}\"]", self.event)?;
         writeln!(writer, "[Site \"{}\"]", self.site)?;
         writeln!(writer, "[Date \"{}\"]", self.date)?;
@@ -63,9 +120,16 @@ impl MastersGame {
         writeln!(writer, "[Result \"{}\"]", self.outcome())?;
         writeln!(writer, "[WhiteElo \"{}\"]", self.players.white.rating)?;
         writeln!(writer, "[BlackElo \"{}\"]", self.players.black.rating)?;
+        if let Some(ref fen) = self.fen {
+            writeln!(writer, "[Variant \"Chess960\"]")?;
+            writeln!(writer, "[FEN \"{}\"]", fen)?;
+            writeln!(writer, "[SetUp \"1\"]")?;
+        }
         writeln!(writer)?;
 
-        let mut pos = Chess::default();
+        let mut pos = self
+            .initial_position()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
 
         for (i, uci) in self.moves.iter().enumerate() {
             let m = uci
@@ -79,6 +143,9 @@ impl MastersGame {
             }
             let san = SanPlus::from_move_and_play_unchecked(&mut pos, &m);
             write!(writer, " {}", san)?;
+            if let Some(nag) = self.nag(i) {
+                write!(writer, " ${}", nag)?;
+            }
         }
 
         if !self.moves.is_empty() {
@@ -104,15 +171,24 @@ impl IntoResponse for MastersGame {
 pub struct MastersGroup {
     pub stats: Stats,
     pub games: SmallVec<[(u16, GameId); 1]>,
+    pub annotations: Annotations,
+    /// Part of `stats` from games with estimated ratings.
+    pub estimated: Stats,
 }
 
 impl AddAssign for MastersGroup {
     fn add_assign(&mut self, rhs: MastersGroup) {
         self.stats += rhs.stats;
         self.games.extend(rhs.games);
+        self.annotations += rhs.annotations;
+        self.estimated += rhs.estimated;
     }
 }
 
+// High bits of the number of games, which never exceeds 15.
+const HAS_ANNOTATIONS: u8 = 0x80;
+const HAS_ESTIMATED: u8 = 0x40;
+
 #[derive(Default, Debug)]
 pub struct MastersEntry {
     pub groups: FxHashMap<Uci, MastersGroup>,
@@ -127,13 +203,22 @@ impl MastersEntry {
         outcome: Outcome,
         mover_rating: u16,
         opponent_rating: u16,
+        nag: Option<u8>,
+        estimated: bool,
     ) -> MastersEntry {
+        let stats = Stats::new_single(outcome, mover_rating);
         let mut groups = FxHashMap::with_capacity_and_hasher(1, Default::default());
         groups.insert(
             uci,
             MastersGroup {
-                stats: Stats::new_single(outcome, mover_rating),
+                estimated: if estimated {
+                    stats.clone()
+                } else {
+                    Stats::default()
+                },
+                stats,
                 games: smallvec![(mover_rating.saturating_add(opponent_rating), id)],
+                annotations: nag.map_or_else(Annotations::default, Annotations::new_single),
             },
         );
         MastersEntry { groups }
@@ -151,13 +236,22 @@ impl MastersEntry {
 
             group.stats += Stats::read(reader)?;
 
-            let num_games = usize::from(reader.read_u8()?);
+            let header = reader.read_u8()?;
+            let num_games = usize::from(header & !(HAS_ANNOTATIONS | HAS_ESTIMATED));
             group.games.reserve_exact(num_games);
             for _ in 0..num_games {
                 group
                     .games
                     .push((reader.read_u16::<LittleEndian>()?, GameId::read(reader)?));
             }
+
+            if header & HAS_ANNOTATIONS != 0 {
+                group.annotations += Annotations::read(reader)?;
+            }
+
+            if header & HAS_ESTIMATED != 0 {
+                group.estimated += Stats::read(reader)?;
+            }
         }
     }
 
@@ -179,7 +273,13 @@ impl MastersEntry {
             } else {
                 group.games.iter().filter(|g| top_games.contains(g)).count()
             };
-            writer.write_u8(num_games as u8)?;
+            let has_annotations = !group.annotations.is_empty();
+            let has_estimated = !group.estimated.is_empty();
+            writer.write_u8(
+                num_games as u8
+                    | if has_annotations { HAS_ANNOTATIONS } else { 0 }
+                    | if has_estimated { HAS_ESTIMATED } else { 0 },
+            )?;
             for (sort_key, id) in group
                 .games
                 .iter()
@@ -188,10 +288,49 @@ impl MastersEntry {
                 writer.write_u16::<LittleEndian>(*sort_key)?;
                 id.write(writer)?;
             }
+
+            if has_annotations {
+                group.annotations.write(writer)?;
+            }
+
+            if has_estimated {
+                group.estimated.write(writer)?;
+            }
         }
         Ok(())
     }
 
+    /// Replaces the contribution of a single game, given as entries created
+    /// with `new_single()` before and after the correction. Annotations are
+    /// left untouched.
+    pub fn correct(&mut self, before: MastersEntry, after: MastersEntry) {
+        for (uci, before) in before.groups {
+            if let Some(group) = self.groups.get_mut(&uci) {
+                group.stats -= before.stats;
+                group.estimated -= before.estimated;
+                group
+                    .games
+                    .retain(|(_, id)| before.games.iter().all(|(_, before_id)| before_id != id));
+            }
+        }
+        for (uci, after) in after.groups {
+            let group = self.groups.entry(uci).or_default();
+            group.stats += after.stats;
+            group.estimated += after.estimated;
+            group.games.extend(after.games);
+        }
+    }
+
+    /// Removes the contributions of games with estimated ratings from the
+    /// stats. Their game ids may remain, so callers should also filter
+    /// games by `MastersGame::has_estimated_rating()`.
+    pub fn exclude_estimated(&mut self) {
+        self.groups.retain(|_, group| {
+            group.stats -= mem::take(&mut group.estimated);
+            !group.stats.is_empty()
+        });
+    }
+
     fn total(&self) -> Stats {
         let mut sum = Stats::default();
         for group in self.groups.values() {
@@ -225,15 +364,25 @@ impl MastersEntry {
                     uci,
                     average_rating: group.stats.average_rating(),
                     average_opponent_rating: None,
+                    avg_seconds: None,
+                    repeated: Stats::default(),
+                    upset_rate: None,
+                    avg_remaining_plies: None,
+                    expected_score: None,
+                    annotations: group.annotations,
+                    by_opponent_rating: Vec::new(),
+                    by_speed: Vec::new(),
+                    latest_idx: None,
                     game: single_game,
                     stats: group.stats,
                 }
             })
             .collect();
-        moves.sort_by_key(|m| Reverse(m.stats.total()));
+        PreparedMove::sort(&mut moves);
 
         PreparedResponse {
             total,
+            avg_remaining_plies: None,
             moves,
             top_games: top_games
                 .into_iter()
@@ -260,7 +409,7 @@ mod tests {
             promotion: None,
         };
         let game = "aaaaaaaa".parse().unwrap();
-        let a = MastersEntry::new_single(uci.clone(), game, Outcome::Draw, 1600, 1700);
+        let a = MastersEntry::new_single(uci.clone(), game, Outcome::Draw, 1600, 1700, None, false);
 
         let mut writer = Cursor::new(Vec::with_capacity(MastersEntry::SIZE_HINT));
         a.write(&mut writer).unwrap();
@@ -278,4 +427,186 @@ mod tests {
         assert_eq!(group.stats.draws, 1);
         assert_eq!(group.games[0], (1600 + 1700, game));
     }
+
+    #[test]
+    fn test_masters_entry_annotations() {
+        let uci = Uci::Normal {
+            from: Square::G1,
+            to: Square::F3,
+            promotion: None,
+        };
+        let mut a = MastersEntry::new_single(
+            uci.clone(),
+            "aaaaaaaa".parse().unwrap(),
+            Outcome::Draw,
+            2600,
+            2700,
+            Some(6),
+            false,
+        );
+        let b = MastersEntry::new_single(
+            uci.clone(),
+            "bbbbbbbb".parse().unwrap(),
+            Outcome::Decisive {
+                winner: Color::White,
+            },
+            2650,
+            2500,
+            None,
+            false,
+        );
+        for (uci, group) in b.groups {
+            *a.groups.entry(uci).or_default() += group;
+        }
+
+        let mut writer = Cursor::new(Vec::new());
+        a.write(&mut writer).unwrap();
+
+        let mut reader = Cursor::new(writer.into_inner());
+        let mut deserialized = MastersEntry::default();
+        deserialized.extend_from_reader(&mut reader).unwrap();
+
+        let group = deserialized.groups.get(&uci).unwrap();
+        assert_eq!(group.games.len(), 2);
+        assert_eq!(group.annotations, Annotations::new_single(6));
+    }
+
+    #[test]
+    fn test_exclude_estimated() {
+        let uci = Uci::Normal {
+            from: Square::C2,
+            to: Square::C4,
+            promotion: None,
+        };
+        let mut entry = MastersEntry::new_single(
+            uci.clone(),
+            "aaaaaaaa".parse().unwrap(),
+            Outcome::Draw,
+            2600,
+            2700,
+            None,
+            false,
+        );
+        for (uci, group) in MastersEntry::new_single(
+            uci.clone(),
+            "bbbbbbbb".parse().unwrap(),
+            Outcome::Draw,
+            2500,
+            2400,
+            None,
+            true,
+        )
+        .groups
+        {
+            *entry.groups.entry(uci).or_default() += group;
+        }
+
+        let mut writer = Cursor::new(Vec::new());
+        entry.write(&mut writer).unwrap();
+        let mut deserialized = MastersEntry::default();
+        deserialized
+            .extend_from_reader(&mut Cursor::new(writer.into_inner()))
+            .unwrap();
+        assert_eq!(deserialized.groups.get(&uci).unwrap().games.len(), 2);
+        assert_eq!(deserialized.groups.get(&uci).unwrap().estimated.draws, 1);
+
+        deserialized.exclude_estimated();
+        let group = deserialized.groups.get(&uci).unwrap();
+        assert_eq!(group.stats.draws, 1);
+        assert_eq!(group.stats.average_rating(), Some(2600));
+    }
+
+    #[test]
+    fn test_estimated_ratings() {
+        let mut game: MastersGame = serde_json::from_str(
+            r#"{
+                "event": "Hastings",
+                "site": "Hastings ENG",
+                "date": "1954.12.29",
+                "round": "1",
+                "white": { "name": "Keres, Paul", "estimatedRating": "gm" },
+                "black": { "name": "Alexander, Conel Hugh O'Donel", "estimatedRating": 2450 },
+                "winner": "white",
+                "moves": "e2e4"
+            }"#,
+        )
+        .unwrap();
+        assert!(!game.has_estimated_rating());
+
+        game.apply_estimated_ratings();
+        assert!(game.has_estimated_rating());
+        assert_eq!(game.players.white.rating, 2500);
+        assert_eq!(game.players.black.rating, 2450);
+
+        let stored = serde_json::to_string(&game).unwrap();
+        let game: MastersGame = serde_json::from_str(&stored).unwrap();
+        assert!(game.players.white.estimated);
+        assert!(game.players.black.estimated);
+    }
+
+    #[test]
+    fn test_chess960_game() {
+        let game: MastersGame = serde_json::from_str(
+            r#"{
+                "event": "FIDE World Fischer Random Chess Championship",
+                "site": "Reykjavik ISL",
+                "date": "2022.10.30",
+                "round": "1.1",
+                "white": { "name": "Carlsen, Magnus", "rating": 2856 },
+                "black": { "name": "Nakamura, Hikaru", "rating": 2768 },
+                "winner": null,
+                "fen": "bbqnnrkr/pppppppp/8/8/8/8/PPPPPPPP/BBQNNRKR w HFhf - 0 1",
+                "moves": "d1e3 b7b6"
+            }"#,
+        )
+        .unwrap();
+        assert!(game.is_chess960());
+
+        let mut pgn = Vec::new();
+        game.write_pgn(&mut pgn).unwrap();
+        let pgn = String::from_utf8(pgn).unwrap();
+        assert!(pgn.contains("[Variant \"Chess960\"]"));
+        assert!(pgn.ends_with("1. Ne3 b6 1/2-1/2\n"));
+    }
+
+    #[test]
+    fn test_masters_entry_correct() {
+        let uci = Uci::Normal {
+            from: Square::D2,
+            to: Square::D4,
+            promotion: None,
+        };
+        let a = "aaaaaaaa".parse().unwrap();
+        let b = "bbbbbbbb".parse().unwrap();
+        let mut entry =
+            MastersEntry::new_single(uci.clone(), a, Outcome::Draw, 2600, 2700, Some(1), false);
+        for (uci, group) in
+            MastersEntry::new_single(uci.clone(), b, Outcome::Draw, 2500, 2400, None, false).groups
+        {
+            *entry.groups.entry(uci).or_default() += group;
+        }
+
+        entry.correct(
+            MastersEntry::new_single(uci.clone(), a, Outcome::Draw, 2600, 2700, None, false),
+            MastersEntry::new_single(
+                uci.clone(),
+                a,
+                Outcome::Decisive {
+                    winner: Color::White,
+                },
+                2700,
+                2600,
+                None,
+                false,
+            ),
+        );
+
+        let group = entry.groups.get(&uci).unwrap();
+        assert_eq!(group.stats.white, 1);
+        assert_eq!(group.stats.draws, 1);
+        assert_eq!(group.stats.average_rating(), Some(2600));
+        assert_eq!(group.games.len(), 2);
+        assert!(group.games.contains(&(2700 + 2600, a)));
+        assert_eq!(group.annotations, Annotations::new_single(1));
+    }
 }
@@ -1,27 +1,51 @@
+mod annotations;
+mod api_key;
+mod clock;
+mod contributor;
 mod date;
+mod erratum;
 mod game_id;
 mod key;
 mod lichess;
 mod lichess_game;
 mod masters;
 mod mode;
+mod move_order;
+mod parent;
 mod player;
+mod shadow;
+mod source;
 mod speed;
 mod stats;
+mod tenant;
+mod tracked;
 mod uci;
 mod uint;
 mod user;
 
-pub use date::{LaxDate, Month, Year};
+pub use annotations::Annotations;
+pub use api_key::{ApiKey, ApiKeyId};
+pub use clock::{Clock, ClockRange, InvalidClock};
+pub use contributor::ContributorEntry;
+pub use date::{InvalidDate, LaxDate, Month, TimeBucket, Week, Year};
+pub use erratum::{CorrectedResult, Erratum};
 pub use game_id::{GameId, InvalidGameId};
 pub use key::{Key, KeyBuilder, KeyPrefix};
-pub use lichess::{LichessEntry, LichessGroup, PreparedMove, PreparedResponse, RatingGroup};
-pub use lichess_game::{GamePlayer, LichessGame};
+pub use lichess::{
+    LichessEntry, LichessGroup, MoveDetails, MoveTime, PreparedMove, PreparedResponse, RatingGroup,
+    RemainingPlies, Upsets,
+};
+pub use lichess_game::{GamePlayer, LichessGame, RatingCategory, RatingEstimate};
 pub use masters::{MastersEntry, MastersGame, MastersGameWithId};
 pub use mode::{ByMode, Mode};
+pub use move_order::{MoveOrderEntry, MoveOrderStep, PathHash};
+pub use parent::{ParentEntry, ParentMove};
 pub use player::{IndexRun, PlayerEntry, PlayerStatus};
-pub use speed::{BySpeed, Speed};
-pub use stats::Stats;
+pub use source::{InvalidLichessSource, LichessSource};
+pub use speed::{BySpeed, Speed, SpeedGroup};
+pub use stats::{saturated, Stats};
+pub use tenant::{InvalidTenantId, TenantId};
+pub use tracked::TrackedImport;
 pub use uci::{read_uci, write_uci};
 pub use uint::{read_uint, write_uint};
 pub use user::{UserId, UserName};
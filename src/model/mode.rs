@@ -3,7 +3,9 @@ use std::{ops::AddAssign, str::FromStr};
 use serde::Serialize;
 use thiserror::Error;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+use crate::api::ApiSchema;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, ApiSchema)]
 #[serde(rename_all = "camelCase")]
 pub enum Mode {
     Rated,
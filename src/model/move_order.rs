@@ -0,0 +1,218 @@
+use std::{
+    cmp::Reverse,
+    io::{self, Read, Write},
+    ops::AddAssign,
+};
+
+use rustc_hash::FxHashMap;
+use sha1::{Digest, Sha1};
+use shakmaty::uci::Uci;
+
+use crate::model::{read_uci, read_uint, write_uci, write_uint};
+
+/// Digest of a sequence of moves from the initial position. The digest of a
+/// sequence is derived from the digest of the sequence without its last
+/// move, so that sequences can be stored as steps.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct PathHash([u8; PathHash::SIZE]);
+
+impl PathHash {
+    pub const SIZE: usize = 12;
+
+    /// Digest of the empty sequence.
+    pub fn empty() -> PathHash {
+        PathHash::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == PathHash::empty()
+    }
+
+    #[must_use]
+    pub fn then(&self, uci: &Uci) -> PathHash {
+        let mut hash = Sha1::new();
+        hash.update(self.0);
+        hash.update(uci.to_string());
+        let mut buf = [0; PathHash::SIZE];
+        buf.copy_from_slice(&hash.finalize()[..PathHash::SIZE]);
+        PathHash(buf)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<PathHash> {
+        let mut buf = [0; PathHash::SIZE];
+        reader.read_exact(&mut buf)?;
+        Ok(PathHash(buf))
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.0)
+    }
+}
+
+/// Last move of a sequence of moves, stored by the digest of the sequence.
+/// Following the steps back to the empty sequence recovers all moves.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MoveOrderStep {
+    pub prev: PathHash,
+    pub uci: Uci,
+}
+
+impl MoveOrderStep {
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<MoveOrderStep> {
+        let prev = PathHash::read(reader)?;
+        let uci = read_uci(reader)?;
+        Ok(MoveOrderStep { prev, uci })
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.prev.write(writer)?;
+        write_uci(writer, &self.uci)
+    }
+}
+
+/// The most common sequences of moves from the initial position that
+/// reached a position, and the number of games that played them.
+///
+/// At most [`MoveOrderEntry::MAX_PATHS`] sequences are kept. Entries are
+/// merged like the summaries of the SpaceSaving algorithm: a sequence that
+/// is missing from a full entry is assumed to have been played as often as
+/// the least common sequence of that entry. So sequences that are played
+/// often are never lost, but the number of games of rare sequences may be
+/// overestimated.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MoveOrderEntry {
+    pub paths: Vec<(PathHash, u64)>,
+}
+
+impl MoveOrderEntry {
+    pub const MAX_PATHS: usize = 128;
+
+    pub fn new_single(path: PathHash) -> MoveOrderEntry {
+        MoveOrderEntry {
+            paths: vec![(path, 1)],
+        }
+    }
+
+    /// Number of games assumed for sequences that are not listed.
+    fn missing_games(&self) -> u64 {
+        if self.paths.len() < MoveOrderEntry::MAX_PATHS {
+            0
+        } else {
+            self.paths
+                .iter()
+                .map(|(_, games)| *games)
+                .min()
+                .unwrap_or(0)
+        }
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<MoveOrderEntry> {
+        let len = read_uint(reader)? as usize;
+        let mut paths = Vec::with_capacity(len.min(MoveOrderEntry::MAX_PATHS));
+        for _ in 0..len {
+            let path = PathHash::read(reader)?;
+            paths.push((path, read_uint(reader)?));
+        }
+        Ok(MoveOrderEntry { paths })
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_uint(writer, self.paths.len() as u64)?;
+        for (path, games) in &self.paths {
+            path.write(writer)?;
+            write_uint(writer, *games)?;
+        }
+        Ok(())
+    }
+}
+
+impl AddAssign for MoveOrderEntry {
+    fn add_assign(&mut self, rhs: MoveOrderEntry) {
+        let missing_lhs = self.missing_games();
+        let missing_rhs = rhs.missing_games();
+
+        let mut merged: FxHashMap<PathHash, (Option<u64>, Option<u64>)> = FxHashMap::default();
+        for (path, games) in self.paths.drain(..) {
+            merged.entry(path).or_default().0 = Some(games);
+        }
+        for (path, games) in rhs.paths {
+            merged.entry(path).or_default().1 = Some(games);
+        }
+
+        self.paths = merged
+            .into_iter()
+            .map(|(path, (lhs, rhs))| {
+                let games = lhs
+                    .unwrap_or(missing_lhs)
+                    .saturating_add(rhs.unwrap_or(missing_rhs));
+                (path, games)
+            })
+            .collect();
+        self.paths
+            .sort_unstable_by_key(|(path, games)| (Reverse(*games), *path));
+        self.paths.truncate(MoveOrderEntry::MAX_PATHS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn path(moves: &[&str]) -> PathHash {
+        moves.iter().fold(PathHash::empty(), |path, uci| {
+            path.then(&uci.parse().unwrap())
+        })
+    }
+
+    #[test]
+    fn test_move_order_entry_roundtrip() {
+        let a = path(&["e2e4", "c7c5", "g1f3"]);
+        let b = path(&["g1f3", "c7c5", "e2e4"]);
+        assert_ne!(a, b);
+
+        let mut entry = MoveOrderEntry::new_single(a);
+        entry += MoveOrderEntry::new_single(b);
+        entry += MoveOrderEntry::new_single(a);
+
+        let mut writer = Cursor::new(Vec::new());
+        entry.write(&mut writer).unwrap();
+        let mut reader = Cursor::new(writer.into_inner());
+        let deserialized = MoveOrderEntry::read(&mut reader).unwrap();
+        assert_eq!(deserialized, entry);
+        assert_eq!(deserialized.paths, vec![(a, 2), (b, 1)]);
+    }
+
+    #[test]
+    fn test_move_order_entry_space_saving() {
+        let mut entry = MoveOrderEntry::default();
+        for i in 0..MoveOrderEntry::MAX_PATHS {
+            entry += MoveOrderEntry::new_single(PathHash([i as u8 + 1; PathHash::SIZE]));
+        }
+        assert_eq!(entry.paths.len(), MoveOrderEntry::MAX_PATHS);
+
+        // A newcomer displaces one of the rarest sequences, instead of
+        // starting from scratch in every merge.
+        let newcomer = path(&["d2d4"]);
+        entry += MoveOrderEntry::new_single(newcomer);
+        assert_eq!(entry.paths[0], (newcomer, 2));
+        assert_eq!(entry.paths.len(), MoveOrderEntry::MAX_PATHS);
+    }
+
+    #[test]
+    fn test_move_order_step_roundtrip() {
+        let step = MoveOrderStep {
+            prev: path(&["e2e4"]),
+            uci: "e7e5".parse().unwrap(),
+        };
+        let mut writer = Cursor::new(Vec::new());
+        step.write(&mut writer).unwrap();
+        let mut reader = Cursor::new(writer.into_inner());
+        assert_eq!(MoveOrderStep::read(&mut reader).unwrap(), step);
+    }
+}
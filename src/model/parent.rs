@@ -0,0 +1,139 @@
+use std::{
+    cmp::Reverse,
+    io::{self, Read, Write},
+    ops::AddAssign,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt as _, WriteBytesExt as _};
+use shakmaty::uci::Uci;
+
+use crate::model::{read_uci, read_uint, write_uci, write_uint};
+
+/// Positions that lead into a position with a single move, and the number
+/// of games that reached the position this way. Parents are identified by
+/// their zobrist hash. The parent itself can be recovered by replaying one
+/// of its move orders.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ParentEntry {
+    pub parents: Vec<ParentMove>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParentMove {
+    pub zobrist: u128,
+    pub uci: Uci,
+    pub games: u64,
+}
+
+impl ParentEntry {
+    /// Positions rarely have this many parents. If they do, the least
+    /// common ones are dropped.
+    pub const MAX_PARENTS: usize = 256;
+
+    pub fn new_single(zobrist: u128, uci: Uci) -> ParentEntry {
+        ParentEntry {
+            parents: vec![ParentMove {
+                zobrist,
+                uci,
+                games: 1,
+            }],
+        }
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<ParentEntry> {
+        let len = read_uint(reader)? as usize;
+        let mut parents = Vec::with_capacity(len.min(ParentEntry::MAX_PARENTS));
+        for _ in 0..len {
+            let zobrist = reader.read_u128::<LittleEndian>()?;
+            let uci = read_uci(reader)?;
+            let games = read_uint(reader)?;
+            parents.push(ParentMove {
+                zobrist,
+                uci,
+                games,
+            });
+        }
+        Ok(ParentEntry { parents })
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_uint(writer, self.parents.len() as u64)?;
+        for parent in &self.parents {
+            writer.write_u128::<LittleEndian>(parent.zobrist)?;
+            write_uci(writer, &parent.uci)?;
+            write_uint(writer, parent.games)?;
+        }
+        Ok(())
+    }
+}
+
+impl AddAssign for ParentEntry {
+    fn add_assign(&mut self, rhs: ParentEntry) {
+        for parent in rhs.parents {
+            match self
+                .parents
+                .iter_mut()
+                .find(|p| p.zobrist == parent.zobrist && p.uci == parent.uci)
+            {
+                Some(existing) => existing.games = existing.games.saturating_add(parent.games),
+                None => self.parents.push(parent),
+            }
+        }
+        self.parents
+            .sort_by_key(|parent| (Reverse(parent.games), parent.zobrist));
+        self.parents.truncate(ParentEntry::MAX_PARENTS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use shakmaty::Square;
+
+    use super::*;
+
+    #[test]
+    fn test_parent_entry_roundtrip() {
+        let e4 = Uci::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+        let d4 = Uci::Normal {
+            from: Square::D2,
+            to: Square::D4,
+            promotion: None,
+        };
+        let mut entry = ParentEntry::new_single(1, e4.clone());
+        entry += ParentEntry::new_single(2, d4.clone());
+        entry += ParentEntry::new_single(2, d4.clone());
+        entry += ParentEntry::new_single(1, d4.clone());
+
+        let mut writer = Cursor::new(Vec::new());
+        entry.write(&mut writer).unwrap();
+        let mut reader = Cursor::new(writer.into_inner());
+        let deserialized = ParentEntry::read(&mut reader).unwrap();
+        assert_eq!(deserialized, entry);
+        assert_eq!(
+            deserialized.parents,
+            vec![
+                ParentMove {
+                    zobrist: 2,
+                    uci: d4.clone(),
+                    games: 2
+                },
+                ParentMove {
+                    zobrist: 1,
+                    uci: e4,
+                    games: 1
+                },
+                ParentMove {
+                    zobrist: 1,
+                    uci: d4,
+                    games: 1
+                },
+            ]
+        );
+    }
+}
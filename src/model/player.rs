@@ -1,25 +1,42 @@
 use std::{
     cmp::{max, min, Reverse},
+    collections::BTreeMap,
     fmt,
     io::{self, Read, Write},
+    mem,
+    ops::AddAssign,
     time::{Duration, SystemTime},
 };
 
 use byteorder::{ReadBytesExt as _, WriteBytesExt as _};
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use shakmaty::{uci::Uci, Outcome};
 use smallvec::{smallvec, SmallVec};
 
 use crate::{
     api::PlayerQueryFilter,
     model::{
-        read_uci, read_uint, write_uci, write_uint, ByMode, BySpeed, GameId, LichessGroup, Mode,
+        read_uci, read_uint, write_uci, write_uint, Annotations, ByMode, BySpeed, GameId, Mode,
         PreparedMove, PreparedResponse, Speed, Stats,
     },
 };
 
 const MAX_PLAYER_GAMES: usize = 8; // must fit into 4 bits
 
+/// Games against the highest rated opponents, kept per group and selected
+/// for responses.
+const MAX_TOP_GAMES: usize = 4; // must fit into 4 bits
+
+const OPPONENT_BUCKET_WIDTH: u16 = 200;
+const NUM_OPPONENT_BUCKETS: u8 = 16; // must fit into 5 bits
+
+fn opponent_bucket(opponent_rating: u16) -> u8 {
+    min(
+        opponent_rating / OPPONENT_BUCKET_WIDTH,
+        u16::from(NUM_OPPONENT_BUCKETS - 1),
+    ) as u8
+}
+
 #[derive(Debug, Eq, PartialEq)]
 enum Header {
     Group {
@@ -27,6 +44,12 @@ enum Header {
         speed: Speed,
         num_games: usize,
     },
+    Opponents {
+        bucket: u8,
+    },
+    TopGames {
+        num_games: usize,
+    },
     End,
 }
 
@@ -36,6 +59,14 @@ impl Header {
         Ok(Header::Group {
             speed: match n & 7 {
                 0 => return Ok(Header::End),
+                7 if n >> 3 < NUM_OPPONENT_BUCKETS => {
+                    return Ok(Header::Opponents { bucket: n >> 3 })
+                }
+                7 => {
+                    return Ok(Header::TopGames {
+                        num_games: usize::from((n >> 3) - NUM_OPPONENT_BUCKETS),
+                    })
+                }
                 1 => Speed::UltraBullet,
                 2 => Speed::Bullet,
                 3 => Speed::Blitz,
@@ -52,6 +83,8 @@ impl Header {
     fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         writer.write_u8(match *self {
             Header::End => 0,
+            Header::Opponents { bucket } => 7 | (bucket << 3),
+            Header::TopGames { num_games } => 7 | ((NUM_OPPONENT_BUCKETS + num_games as u8) << 3),
             Header::Group {
                 mode,
                 speed,
@@ -71,14 +104,58 @@ impl Header {
     }
 }
 
+#[derive(Default, Debug)]
+struct PlayerGroup {
+    stats: Stats,
+    games: SmallVec<[(u64, GameId); 1]>,
+    /// Subsets of stats by opponent rating bucket. Games without a known
+    /// opponent rating are not included.
+    by_opponent: BTreeMap<u8, Stats>,
+    /// Games against the highest rated opponents, with the opponent rating,
+    /// highest first.
+    top_games: SmallVec<[(u16, GameId); 1]>,
+}
+
+impl AddAssign for PlayerGroup {
+    fn add_assign(&mut self, rhs: PlayerGroup) {
+        self.stats += rhs.stats;
+        self.games.extend(rhs.games);
+        for (bucket, stats) in rhs.by_opponent {
+            *self.by_opponent.entry(bucket).or_default() += stats;
+        }
+        for (rating, game) in rhs.top_games {
+            if self.top_games.iter().all(|(_, g)| *g != game) {
+                self.top_games.push((rating, game));
+            }
+        }
+        self.top_games.sort_by_key(|(rating, _)| Reverse(*rating));
+        self.top_games.truncate(MAX_TOP_GAMES);
+    }
+}
+
+impl PlayerGroup {
+    /// The top game of a single game group is implied by its stats, so that
+    /// it does not need to be stored separately.
+    fn implied_top_games(&self) -> SmallVec<[(u16, GameId); 1]> {
+        match (self.stats.is_single(), &self.games[..]) {
+            (true, [(_, game)]) => self
+                .stats
+                .average_rating()
+                .map(|rating| smallvec![(rating as u16, *game)])
+                .unwrap_or_default(),
+            _ => SmallVec::new(),
+        }
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct PlayerEntry {
-    sub_entries: FxHashMap<Uci, BySpeed<ByMode<LichessGroup>>>,
+    sub_entries: FxHashMap<Uci, BySpeed<ByMode<PlayerGroup>>>,
     max_game_idx: Option<u64>,
 }
 
 impl PlayerEntry {
-    pub const SIZE_HINT: usize = 13;
+    pub const SIZE_HINT: usize = 17;
 
     pub fn new_single(
         uci: Uci,
@@ -88,10 +165,21 @@ impl PlayerEntry {
         outcome: Outcome,
         opponent_rating: u16,
     ) -> PlayerEntry {
-        let mut sub_entry: BySpeed<ByMode<LichessGroup>> = Default::default();
-        *sub_entry.by_speed_mut(speed).by_mode_mut(mode) = LichessGroup {
-            stats: Stats::new_single(outcome, opponent_rating),
+        let stats = Stats::new_single(outcome, opponent_rating);
+        let mut by_opponent = BTreeMap::new();
+        if opponent_rating > 0 {
+            by_opponent.insert(opponent_bucket(opponent_rating), stats.clone());
+        }
+        let mut sub_entry: BySpeed<ByMode<PlayerGroup>> = Default::default();
+        *sub_entry.by_speed_mut(speed).by_mode_mut(mode) = PlayerGroup {
+            stats,
             games: smallvec![(0, game_id)],
+            by_opponent,
+            top_games: if opponent_rating > 0 {
+                smallvec![(opponent_rating, game_id)]
+            } else {
+                SmallVec::new()
+            },
         };
         let mut sub_entries = FxHashMap::with_capacity_and_hasher(1, Default::default());
         sub_entries.insert(uci, sub_entry);
@@ -113,12 +201,38 @@ impl PlayerEntry {
             };
 
             let sub_entry = self.sub_entries.entry(uci).or_default();
+            let mut last_group = None;
 
             loop {
                 match Header::read(reader) {
                     Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
                     Err(err) => return Err(err),
                     Ok(Header::End) => break,
+                    Ok(Header::Opponents { bucket }) => {
+                        // Extension record that applies to the group that
+                        // immediately precedes it.
+                        let (speed, mode) = last_group
+                            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+                        *sub_entry
+                            .by_speed_mut(speed)
+                            .by_mode_mut(mode)
+                            .by_opponent
+                            .entry(bucket)
+                            .or_default() += Stats::read(reader)?;
+                    }
+                    Ok(Header::TopGames { num_games }) => {
+                        let (speed, mode) = last_group
+                            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+                        let mut top_games = SmallVec::with_capacity(num_games);
+                        for _ in 0..num_games {
+                            let rating = min(read_uint(reader)?, u64::from(u16::MAX)) as u16;
+                            top_games.push((rating, GameId::read(reader)?));
+                        }
+                        *sub_entry.by_speed_mut(speed).by_mode_mut(mode) += PlayerGroup {
+                            top_games,
+                            ..PlayerGroup::default()
+                        };
+                    }
                     Ok(Header::Group {
                         speed,
                         mode,
@@ -132,8 +246,14 @@ impl PlayerEntry {
                             let game = GameId::read(reader)?;
                             games.push((game_idx, game));
                         }
-                        let group = sub_entry.by_speed_mut(speed).by_mode_mut(mode);
-                        *group += LichessGroup { stats, games };
+                        let mut new_group = PlayerGroup {
+                            stats,
+                            games,
+                            ..PlayerGroup::default()
+                        };
+                        new_group.top_games = new_group.implied_top_games();
+                        *sub_entry.by_speed_mut(speed).by_mode_mut(mode) += new_group;
+                        last_group = Some((speed, mode));
                     }
                 }
             }
@@ -168,6 +288,20 @@ impl PlayerEntry {
                             write_uint(writer, *game_idx)?;
                             game.write(writer)?;
                         }
+
+                        for (bucket, stats) in &group.by_opponent {
+                            Header::Opponents { bucket: *bucket }.write(writer)?;
+                            stats.write(writer)?;
+                        }
+
+                        if group.top_games != group.implied_top_games() {
+                            let num_games = min(group.top_games.len(), MAX_TOP_GAMES);
+                            Header::TopGames { num_games }.write(writer)?;
+                            for (rating, game) in &group.top_games[..num_games] {
+                                write_uint(writer, u64::from(*rating))?;
+                                game.write(writer)?;
+                            }
+                        }
                     }
 
                     Ok::<_, io::Error>(())
@@ -178,21 +312,78 @@ impl PlayerEntry {
         Ok(())
     }
 
+    /// Merges an entry that was read after this one, so that its games are
+    /// considered more recent.
+    pub fn append(&mut self, other: PlayerEntry) {
+        let base_game_idx = self.max_game_idx.map_or(0, |idx| idx + 1);
+        for (uci, mut sub_entry) in other.sub_entries {
+            let ours = self.sub_entries.entry(uci).or_default();
+            for speed in Speed::ALL {
+                for mode in Mode::ALL {
+                    let mut group = mem::take(sub_entry.by_speed_mut(speed).by_mode_mut(mode));
+                    for (game_idx, _) in &mut group.games {
+                        *game_idx += base_game_idx;
+                    }
+                    *ours.by_speed_mut(speed).by_mode_mut(mode) += group;
+                }
+            }
+        }
+        if let Some(max_game_idx) = other.max_game_idx {
+            self.max_game_idx = Some(base_game_idx + max_game_idx);
+        }
+    }
+
+    /// Removes references to the given games, leaving stats as they are.
+    /// Returns true if any reference was removed.
+    pub fn prune_games(&mut self, ids: &FxHashSet<GameId>) -> bool {
+        let mut pruned = false;
+        for sub_entry in self.sub_entries.values_mut() {
+            for speed in Speed::ALL {
+                for mode in Mode::ALL {
+                    let games = &mut sub_entry.by_speed_mut(speed).by_mode_mut(mode).games;
+                    let len = games.len();
+                    games.retain(|(_, id)| !ids.contains(id));
+                    pruned |= games.len() != len;
+
+                    let top_games = &mut sub_entry.by_speed_mut(speed).by_mode_mut(mode).top_games;
+                    let len = top_games.len();
+                    top_games.retain(|(_, id)| !ids.contains(id));
+                    pruned |= top_games.len() != len;
+                }
+            }
+        }
+        pruned
+    }
+
+    /// Swaps the results of white and black, to combine the entries of both
+    /// colors from the point of view of the player.
+    pub fn mirror(&mut self) {
+        for sub_entry in self.sub_entries.values_mut() {
+            for speed in Speed::ALL {
+                for mode in Mode::ALL {
+                    let group = sub_entry.by_speed_mut(speed).by_mode_mut(mode);
+                    group.stats.mirror();
+                    for stats in group.by_opponent.values_mut() {
+                        stats.mirror();
+                    }
+                }
+            }
+        }
+    }
+
     pub fn prepare(self, filter: &PlayerQueryFilter) -> PreparedResponse {
         let mut total = Stats::default();
         let mut moves = Vec::with_capacity(self.sub_entries.len());
         let mut recent_games: Vec<(u64, Uci, GameId)> = Vec::new();
+        let mut top_games: Vec<(u16, Uci, GameId)> = Vec::new();
 
         for (uci, sub_entry) in self.sub_entries {
             let mut latest_game: Option<(u64, GameId)> = None;
             let mut stats = Stats::default();
+            let mut by_opponent: BTreeMap<u8, Stats> = BTreeMap::new();
 
             for speed in Speed::ALL {
-                if filter
-                    .speeds
-                    .as_ref()
-                    .map_or(true, |speeds| speeds.contains(&speed))
-                {
+                if filter.contains_speed(speed) {
                     for mode in Mode::ALL {
                         if filter
                             .modes
@@ -202,6 +393,13 @@ impl PlayerEntry {
                             let group = sub_entry.by_speed(speed).by_mode(mode);
                             stats += group.stats.to_owned();
 
+                            if filter.opponent_ratings {
+                                for (bucket, bucket_stats) in &group.by_opponent {
+                                    *by_opponent.entry(*bucket).or_default() +=
+                                        bucket_stats.to_owned();
+                                }
+                            }
+
                             for (idx, game) in group.games.iter().copied() {
                                 if latest_game.map_or(true, |(latest_idx, _game)| latest_idx < idx)
                                 {
@@ -216,36 +414,63 @@ impl PlayerEntry {
                                     .copied()
                                     .map(|(idx, game)| (idx, uci.to_owned(), game)),
                             );
+                            top_games.extend(
+                                group
+                                    .top_games
+                                    .iter()
+                                    .copied()
+                                    .map(|(rating, game)| (rating, uci.to_owned(), game)),
+                            );
                         }
                     }
                 }
             }
 
-            if !stats.is_empty() || latest_game.is_some() {
+            if (!stats.is_empty() || latest_game.is_some()) && filter.has_min_games(stats.total()) {
                 moves.push(PreparedMove {
                     uci,
                     stats: stats.clone(),
                     average_rating: None,
                     average_opponent_rating: stats.average_rating(),
+                    avg_seconds: None,
+                    repeated: Stats::default(),
+                    upset_rate: None,
+                    avg_remaining_plies: None,
+                    expected_score: None,
+                    annotations: Annotations::default(),
+                    by_opponent_rating: by_opponent
+                        .into_iter()
+                        .map(|(bucket, stats)| (u16::from(bucket) * OPPONENT_BUCKET_WIDTH, stats))
+                        .collect(),
+                    by_speed: Vec::new(),
+                    latest_idx: latest_game.map(|(idx, _)| idx),
                     game: latest_game.filter(|_| stats.is_single()).map(|(_, id)| id),
                 });
-
-                total += stats;
             }
+
+            total += stats;
         }
 
-        moves.sort_by_key(|row| Reverse(row.stats.total()));
+        PreparedMove::sort(&mut moves);
+        top_games.sort_by_key(|(rating, _, _)| Reverse(*rating));
+        top_games.truncate(MAX_TOP_GAMES);
+        // Like for lichess, top games are not repeated as recent games.
+        recent_games.retain(|(_, _, game)| top_games.iter().all(|(_, _, top)| top != game));
         recent_games.sort_by_key(|(idx, _, _)| Reverse(*idx));
 
         PreparedResponse {
             total,
+            avg_remaining_plies: None,
             moves,
             recent_games: recent_games
                 .into_iter()
                 .map(|(_, uci, game)| (uci, game))
                 .take(MAX_PLAYER_GAMES as usize)
                 .collect(),
-            top_games: Vec::new(),
+            top_games: top_games
+                .into_iter()
+                .map(|(_, uci, game)| (uci, game))
+                .collect(),
         }
     }
 }
@@ -366,6 +591,7 @@ mod tests {
     use shakmaty::{Color, Square};
 
     use super::*;
+    use crate::{api::SpeedFilter, model::Month};
 
     #[test]
     fn test_header_roundtrip() {
@@ -375,6 +601,8 @@ mod tests {
                 speed: Speed::Correspondence,
                 num_games: 15,
             },
+            Header::Opponents { bucket: 15 },
+            Header::TopGames { num_games: 15 },
             Header::End,
         ];
 
@@ -474,6 +702,9 @@ mod tests {
         assert_eq!(group.stats.black, 1);
         assert_eq!(group.stats.average_rating(), Some(1700));
         assert_eq!(group.games.len(), 2);
+        assert_eq!(group.by_opponent.len(), 2);
+        assert_eq!(group.by_opponent.get(&8).unwrap().white, 1);
+        assert_eq!(group.by_opponent.get(&9).unwrap().black, 1);
 
         // Roundtrip the combined entry.
         let mut cursor = Cursor::new(Vec::new());
@@ -484,5 +715,83 @@ mod tests {
             .unwrap();
         assert_eq!(deserialized.sub_entries.len(), 2);
         assert_eq!(deserialized.max_game_idx, Some(2));
+        let group = deserialized
+            .sub_entries
+            .get(&uci_ab)
+            .unwrap()
+            .by_speed(Speed::Bullet)
+            .by_mode(Mode::Rated);
+        assert_eq!(
+            group.top_games[..],
+            [
+                (1800, "bbbbbbbb".parse::<GameId>().unwrap()),
+                (1600, "aaaaaaaa".parse::<GameId>().unwrap())
+            ]
+        );
+
+        let prepared = deserialized.prepare(&PlayerQueryFilter {
+            modes: None,
+            speed: SpeedFilter::default(),
+            since: Month::default(),
+            until: Month::max_value(),
+            opponent_ratings: false,
+            min_games: None,
+        });
+        assert_eq!(prepared.top_games.len(), 3);
+        assert_eq!(
+            prepared.top_games[0].1,
+            "bbbbbbbb".parse::<GameId>().unwrap()
+        );
+        assert!(prepared.recent_games.is_empty());
+    }
+
+    #[test]
+    fn test_append_mirrored() {
+        let uci = Uci::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+
+        // Won with white.
+        let mut entry = PlayerEntry::new_single(
+            uci.clone(),
+            Speed::Blitz,
+            Mode::Rated,
+            "aaaaaaaa".parse().unwrap(),
+            Outcome::Decisive {
+                winner: Color::White,
+            },
+            1600,
+        );
+
+        // Won with black.
+        let mut black = PlayerEntry::new_single(
+            uci.clone(),
+            Speed::Blitz,
+            Mode::Rated,
+            "bbbbbbbb".parse().unwrap(),
+            Outcome::Decisive {
+                winner: Color::Black,
+            },
+            1800,
+        );
+        black.mirror();
+        entry.append(black);
+
+        assert_eq!(entry.max_game_idx, Some(1));
+        let group = entry
+            .sub_entries
+            .get(&uci)
+            .unwrap()
+            .by_speed(Speed::Blitz)
+            .by_mode(Mode::Rated);
+        assert_eq!(group.stats.white, 2);
+        assert_eq!(group.stats.black, 0);
+        assert_eq!(group.by_opponent.get(&9).unwrap().white, 1);
+        assert_eq!(
+            group.games.iter().map(|(idx, _)| *idx).collect::<Vec<_>>(),
+            [0, 1]
+        );
     }
 }
@@ -0,0 +1,19 @@
+use std::io::{self, Read, Write};
+
+use crate::model::LichessEntry;
+
+/// Experimental storage format for lichess entries, written in parallel
+/// with the production format if shadow writes are enabled.
+///
+/// To try a new format on a live instance, change both methods, enable
+/// shadow writes, and compare the prepared responses of both formats before
+/// migrating. Currently identical to the production format.
+impl LichessEntry {
+    pub fn extend_from_shadow_reader<R: Read>(&mut self, reader: &mut R) -> io::Result<()> {
+        self.extend_from_reader(reader)
+    }
+
+    pub fn write_shadow<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.write(writer)
+    }
+}
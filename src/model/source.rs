@@ -0,0 +1,61 @@
+use std::str::FromStr;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Origin of imported lichess games. Each source is stored separately and
+/// merged at query time, so that the data of a botched import can be
+/// dropped wholesale.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LichessSource {
+    /// Monthly database dumps.
+    Dump,
+    /// Games ingested shortly after they were played.
+    Live,
+    /// Games imported by hand.
+    Manual,
+}
+
+impl LichessSource {
+    pub const ALL: [LichessSource; 3] = [
+        LichessSource::Dump,
+        LichessSource::Live,
+        LichessSource::Manual,
+    ];
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            LichessSource::Dump => 0,
+            LichessSource::Live => 1,
+            LichessSource::Manual => 2,
+        }
+    }
+
+    pub fn from_u8(n: u8) -> Option<LichessSource> {
+        LichessSource::ALL.into_iter().find(|s| s.to_u8() == n)
+    }
+}
+
+impl Default for LichessSource {
+    fn default() -> LichessSource {
+        LichessSource::Dump
+    }
+}
+
+impl FromStr for LichessSource {
+    type Err = InvalidLichessSource;
+
+    fn from_str(s: &str) -> Result<LichessSource, InvalidLichessSource> {
+        Ok(match s {
+            "dump" => LichessSource::Dump,
+            "live" => LichessSource::Live,
+            "manual" => LichessSource::Manual,
+            _ => return Err(InvalidLichessSource),
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("invalid lichess source")]
+pub struct InvalidLichessSource;
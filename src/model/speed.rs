@@ -3,7 +3,9 @@ use std::{ops::AddAssign, str::FromStr};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone, Deserialize, Serialize, Ord, PartialOrd)]
+use crate::api::ApiSchema;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Deserialize, Serialize, ApiSchema, Ord, PartialOrd)]
 #[serde(rename_all = "camelCase")]
 pub enum Speed {
     UltraBullet,
@@ -23,6 +25,24 @@ impl Speed {
         Speed::Classical,
         Speed::Correspondence,
     ];
+
+    /// Selects the speed from the estimated game duration in seconds, the
+    /// same way lila does.
+    pub fn from_estimate(seconds: u64) -> Speed {
+        if seconds < 30 {
+            Speed::UltraBullet
+        } else if seconds < 180 {
+            Speed::Bullet
+        } else if seconds < 480 {
+            Speed::Blitz
+        } else if seconds < 1500 {
+            Speed::Rapid
+        } else if seconds < 21_600 {
+            Speed::Classical
+        } else {
+            Speed::Correspondence
+        }
+    }
 }
 
 impl FromStr for Speed {
@@ -45,7 +65,40 @@ impl FromStr for Speed {
 #[error("invalid speed")]
 pub struct InvalidSpeed;
 
-#[derive(Debug, Default)]
+/// Coarse groups of speeds, to shorten common filters.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Deserialize, Serialize, ApiSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum SpeedGroup {
+    /// Ultra bullet, bullet and blitz.
+    Fast,
+    /// Rapid, classical and correspondence.
+    Slow,
+}
+
+impl SpeedGroup {
+    pub const ALL: [SpeedGroup; 2] = [SpeedGroup::Fast, SpeedGroup::Slow];
+
+    pub fn speeds(self) -> &'static [Speed] {
+        match self {
+            SpeedGroup::Fast => &[Speed::UltraBullet, Speed::Bullet, Speed::Blitz],
+            SpeedGroup::Slow => &[Speed::Rapid, Speed::Classical, Speed::Correspondence],
+        }
+    }
+}
+
+impl FromStr for SpeedGroup {
+    type Err = InvalidSpeed;
+
+    fn from_str(s: &str) -> Result<SpeedGroup, InvalidSpeed> {
+        Ok(match s {
+            "fast" => SpeedGroup::Fast,
+            "slow" => SpeedGroup::Slow,
+            _ => return Err(InvalidSpeed),
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct BySpeed<T> {
     pub ultra_bullet: T,
     pub bullet: T,
@@ -114,3 +167,23 @@ impl<T: AddAssign> AddAssign for BySpeed<T> {
         self.correspondence += rhs.correspondence;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speed_groups_partition_speeds() {
+        for speed in Speed::ALL {
+            assert_eq!(
+                SpeedGroup::ALL
+                    .into_iter()
+                    .filter(|group| group.speeds().contains(&speed))
+                    .count(),
+                1,
+                "{:?} in exactly one group",
+                speed
+            );
+        }
+    }
+}
@@ -1,26 +1,66 @@
 use std::{
     io::{self, Read, Write},
-    ops::AddAssign,
+    mem,
+    ops::{AddAssign, SubAssign},
+    sync::atomic::{AtomicU64, Ordering},
 };
 
+use byteorder::ReadBytesExt as _;
 use serde::Serialize;
 use shakmaty::{Color, Outcome};
 
-use crate::model::{read_uint, write_uint};
+use crate::{
+    api::ApiSchema,
+    model::{read_uint, write_uint},
+};
+
+/// Non-canonical encoding of 0, which is never produced by `write_uint`.
+/// Marks stats with an explicit rating count.
+const EXPLICIT_RATING_COUNT: [u8; 2] = [0x80, 0x00];
+
+static SATURATED: AtomicU64 = AtomicU64::new(0);
+
+/// Number of additions that saturated instead of overflowing, since the
+/// start of the process.
+pub fn saturated() -> u64 {
+    SATURATED.load(Ordering::Relaxed)
+}
+
+fn add_saturating(a: &mut u64, b: u64) {
+    *a = a.checked_add(b).unwrap_or_else(|| {
+        if SATURATED.fetch_add(1, Ordering::Relaxed) == 0 {
+            log::warn!("stats saturated");
+        }
+        u64::MAX
+    });
+}
 
-#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
+/// Game counts with the sum (not the average) of ratings, so that merging
+/// is associative and commutative, and averages are only derived when
+/// reading.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, ApiSchema)]
 pub struct Stats {
     #[serde(skip)]
     pub rating_sum: u64,
+    /// Number of games with a known rating, which contribute to
+    /// `rating_sum`.
+    #[serde(skip)]
+    pub rating_count: u64,
     pub white: u64,
     pub draws: u64,
     pub black: u64,
 }
 
 impl Stats {
+    /// Swaps the results of white and black.
+    pub fn mirror(&mut self) {
+        mem::swap(&mut self.white, &mut self.black);
+    }
+
     pub fn new_single(outcome: Outcome, rating: u16) -> Stats {
         Stats {
             rating_sum: u64::from(rating),
+            rating_count: if rating > 0 { 1 } else { 0 },
             white: if outcome.winner() == Some(Color::White) {
                 1
             } else {
@@ -38,16 +78,29 @@ impl Stats {
 
 impl AddAssign for Stats {
     fn add_assign(&mut self, rhs: Stats) {
-        self.rating_sum += rhs.rating_sum;
-        self.white += rhs.white;
-        self.draws += rhs.draws;
-        self.black += rhs.black;
+        add_saturating(&mut self.rating_sum, rhs.rating_sum);
+        add_saturating(&mut self.rating_count, rhs.rating_count);
+        add_saturating(&mut self.white, rhs.white);
+        add_saturating(&mut self.draws, rhs.draws);
+        add_saturating(&mut self.black, rhs.black);
+    }
+}
+
+impl SubAssign for Stats {
+    fn sub_assign(&mut self, rhs: Stats) {
+        self.rating_sum = self.rating_sum.saturating_sub(rhs.rating_sum);
+        self.rating_count = self.rating_count.saturating_sub(rhs.rating_count);
+        self.white = self.white.saturating_sub(rhs.white);
+        self.draws = self.draws.saturating_sub(rhs.draws);
+        self.black = self.black.saturating_sub(rhs.black);
     }
 }
 
 impl Stats {
     pub fn total(&self) -> u64 {
-        self.white + self.draws + self.black
+        self.white
+            .saturating_add(self.draws)
+            .saturating_add(self.black)
     }
 
     pub fn is_empty(&self) -> bool {
@@ -59,41 +112,76 @@ impl Stats {
     }
 
     pub fn average_rating(&self) -> Option<u64> {
-        self.rating_sum.checked_div(self.total())
+        self.rating_sum.checked_div(self.rating_count)
+    }
+
+    /// Score of the given side in thousandths, if there are any games.
+    pub fn score_permille(&self, color: Color) -> Option<u64> {
+        let wins = match color {
+            Color::White => self.white,
+            Color::Black => self.black,
+        };
+        let points = u128::from(wins) * 2 + u128::from(self.draws);
+        (points * 500)
+            .checked_div(u128::from(self.total()))
+            .map(|score| score as u64)
     }
 
     pub fn read<R: Read>(reader: &mut R) -> io::Result<Stats> {
-        let rating_sum = read_uint(reader)?;
-        Ok(match read_uint(reader)? {
+        // Without an explicit rating count, every game has a rating (legacy
+        // format).
+        let (rating_sum, rating_count) = match reader.read_u8()? {
+            0x80 => match reader.read_u8()? {
+                0 => (read_uint(reader)?, Some(read_uint(reader)?)),
+                second => (
+                    read_uint(&mut (&[0x80, second][..]).chain(&mut *reader))?,
+                    None,
+                ),
+            },
+            first => (read_uint(&mut (&[first][..]).chain(&mut *reader))?, None),
+        };
+        let mut stats = match read_uint(reader)? {
             0 => Stats {
                 rating_sum,
+                rating_count: 0,
                 white: 1,
                 draws: 0,
                 black: 0,
             },
             1 => Stats {
                 rating_sum,
+                rating_count: 0,
                 white: 0,
                 draws: 0,
                 black: 1,
             },
             2 => Stats {
                 rating_sum,
+                rating_count: 0,
                 white: 0,
                 draws: 1,
                 black: 0,
             },
             white_plus_three => Stats {
                 rating_sum,
+                rating_count: 0,
                 white: white_plus_three - 3,
                 draws: read_uint(reader)?,
                 black: read_uint(reader)?,
             },
-        })
+        };
+        stats.rating_count = rating_count.unwrap_or_else(|| stats.total());
+        Ok(stats)
     }
 
     pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
-        write_uint(writer, self.rating_sum)?;
+        if self.rating_count != self.total() {
+            writer.write_all(&EXPLICIT_RATING_COUNT)?;
+            write_uint(writer, self.rating_sum)?;
+            write_uint(writer, self.rating_count)?;
+        } else {
+            write_uint(writer, self.rating_sum)?;
+        }
         match *self {
             Stats {
                 white: 1,
@@ -119,7 +207,12 @@ impl Stats {
                 black,
                 ..
             } => {
-                write_uint(writer, white + 3)?;
+                write_uint(
+                    writer,
+                    white
+                        .checked_add(3)
+                        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?,
+                )?;
                 write_uint(writer, draws)?;
                 write_uint(writer, black)
             }
@@ -138,7 +231,8 @@ mod tests {
     impl Arbitrary for Stats {
         fn arbitrary(g: &mut Gen) -> Self {
             Stats {
-                rating_sum: u64::from(u32::arbitrary(g)),
+                rating_sum: u64::arbitrary(g),
+                rating_count: u64::arbitrary(g),
                 white: u64::from(u32::arbitrary(g)),
                 draws: u64::from(u32::arbitrary(g)),
                 black: u64::from(u32::arbitrary(g)),
@@ -155,4 +249,114 @@ mod tests {
             Stats::read(&mut cursor).unwrap() == stats
         }
     }
+
+    fn merged(parts: &[Stats]) -> Stats {
+        let mut acc = Stats::default();
+        for part in parts {
+            acc += part.clone();
+        }
+        acc
+    }
+
+    fn single(outcome: Outcome, rating: u16) -> Stats {
+        Stats::new_single(outcome, rating)
+    }
+
+    quickcheck! {
+        fn test_merge_order(ratings: Vec<u16>) -> bool {
+            let parts: Vec<Stats> = ratings
+                .iter()
+                .map(|rating| single(Outcome::Draw, *rating))
+                .collect();
+            let mut reversed = parts.clone();
+            reversed.reverse();
+
+            // Merge pairwise first, as happens when groups are combined
+            // before being merged into the total.
+            let pairwise: Vec<Stats> = parts.chunks(2).map(merged).collect();
+
+            let forward = merged(&parts);
+            forward == merged(&reversed) && forward == merged(&pairwise)
+        }
+    }
+
+    #[test]
+    fn test_merge_heterogeneous_groups() {
+        // A large group of low rated games and a small group of high rated
+        // games. Averaging averages would give 2000 instead of 1600.
+        let low = merged(&vec![single(Outcome::Draw, 1500); 9]);
+        let high = single(Outcome::Draw, 2500);
+        let unrated = single(Outcome::Draw, 0);
+
+        let mut a = low.clone();
+        a += high.clone();
+        a += unrated.clone();
+
+        let mut b = unrated;
+        b += high;
+        b += low;
+
+        assert_eq!(a, b);
+        assert_eq!(a.average_rating(), Some(1600));
+        assert_eq!(b.average_rating(), Some(1600));
+
+        // Stable across a roundtrip through storage.
+        let mut cursor = Cursor::new(Vec::new());
+        a.write(&mut cursor).unwrap();
+        let mut cursor = Cursor::new(cursor.into_inner());
+        assert_eq!(
+            Stats::read(&mut cursor).unwrap().average_rating(),
+            Some(1600)
+        );
+    }
+
+    #[test]
+    fn test_legacy_format() {
+        // Rating sum 128 starts with the same byte as the marker.
+        let mut cursor = Cursor::new(vec![0x80, 0x01, 3 + 2, 1, 0]);
+        assert_eq!(
+            Stats::read(&mut cursor).unwrap(),
+            Stats {
+                rating_sum: 128,
+                rating_count: 3,
+                white: 2,
+                draws: 1,
+                black: 0,
+            }
+        );
+
+        let mut cursor = Cursor::new(vec![0x7f, 2]);
+        let stats = Stats::read(&mut cursor).unwrap();
+        assert_eq!(stats.draws, 1);
+        assert_eq!(stats.average_rating(), Some(0x7f));
+    }
+
+    #[test]
+    fn test_unrated() {
+        let mut stats = Stats::new_single(Outcome::Draw, 0);
+        stats += Stats::new_single(Outcome::Draw, 2000);
+        assert_eq!(stats.total(), 2);
+        assert_eq!(stats.average_rating(), Some(2000));
+
+        let mut cursor = Cursor::new(Vec::new());
+        stats.write(&mut cursor).unwrap();
+        let mut cursor = Cursor::new(cursor.into_inner());
+        assert_eq!(Stats::read(&mut cursor).unwrap(), stats);
+    }
+
+    #[test]
+    fn test_saturation() {
+        let mut stats = Stats {
+            rating_sum: u64::MAX - 1,
+            rating_count: u64::MAX - 1,
+            white: u64::MAX,
+            draws: 0,
+            black: 0,
+        };
+        stats += Stats::new_single(Outcome::Draw, 2500);
+        assert_eq!(stats.rating_sum, u64::MAX);
+        assert_eq!(stats.rating_count, u64::MAX);
+        assert_eq!(stats.total(), u64::MAX);
+        assert!(saturated() > 0);
+    }
 }
@@ -0,0 +1,93 @@
+use std::{fmt, str::FromStr};
+
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use crate::{
+    api::{ApiSchema, SchemaGenerator},
+    model::GameId,
+};
+
+/// A club or organization that hosts a private explorer in a deployment
+/// shared with other tenants. Its positions and games are kept apart from
+/// the public databases and from other tenants.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct TenantId(String);
+
+impl TenantId {
+    pub const MAX_LEN: usize = 32;
+
+    /// Size of the prefix that keeps the games of a tenant together.
+    pub const DIGEST_SIZE: usize = 16;
+
+    pub fn digest(&self) -> [u8; TenantId::DIGEST_SIZE] {
+        let mut hash = Sha1::new();
+        hash.update(b"tenant:");
+        hash.update(self.0.as_bytes());
+        let mut digest = [0; TenantId::DIGEST_SIZE];
+        digest.copy_from_slice(&hash.finalize()[..TenantId::DIGEST_SIZE]);
+        digest
+    }
+
+    /// Key of a game of the tenant.
+    pub fn game_key(&self, id: GameId) -> Vec<u8> {
+        let id = id.to_bytes();
+        let mut buf = Vec::with_capacity(TenantId::DIGEST_SIZE + id.len());
+        buf.extend_from_slice(&self.digest());
+        buf.extend_from_slice(&id);
+        buf
+    }
+}
+
+impl fmt::Display for TenantId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("invalid tenant")]
+pub struct InvalidTenantId;
+
+impl FromStr for TenantId {
+    type Err = InvalidTenantId;
+
+    fn from_str(s: &str) -> Result<TenantId, InvalidTenantId> {
+        if !s.is_empty()
+            && s.len() <= TenantId::MAX_LEN
+            && s.bytes()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'-' || c == b'_')
+        {
+            Ok(TenantId(s.to_owned()))
+        } else {
+            Err(InvalidTenantId)
+        }
+    }
+}
+
+impl ApiSchema for TenantId {
+    fn schema(_: &mut SchemaGenerator) -> Value {
+        json!({ "type": "string", "pattern": "^[0-9a-z_-]{1,32}$" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenant_id() {
+        assert!("chess-club_42".parse::<TenantId>().is_ok());
+        assert!("".parse::<TenantId>().is_err());
+        assert!("Club".parse::<TenantId>().is_err());
+        assert!("a".repeat(33).parse::<TenantId>().is_err());
+
+        let a: TenantId = "a".parse().unwrap();
+        let b: TenantId = "b".parse().unwrap();
+        assert_ne!(a.digest(), b.digest());
+        let id: GameId = "Zomg6a1B".parse().unwrap();
+        assert!(a.game_key(id).starts_with(&a.digest()));
+        assert_ne!(a.game_key(id), b.game_key(id));
+    }
+}
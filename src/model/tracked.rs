@@ -0,0 +1,123 @@
+use std::{
+    convert::TryFrom,
+    io::{self, Cursor, Read, Write},
+};
+
+use crate::model::{read_uint, write_uint, Key, LichessEntry, LichessSource, Week};
+
+/// Contributions of a recently imported lichess game, kept for a while so
+/// that they can be retracted if the game turns out to have been aborted.
+#[derive(Default)]
+pub struct TrackedImport {
+    /// Seconds since the Unix epoch.
+    pub imported_at: u64,
+    /// Week, if the game was also merged into weekly keys.
+    pub week: Option<Week>,
+    pub entries: Vec<(Key, LichessEntry)>,
+    /// Source that the entries were merged into.
+    pub source: LichessSource,
+}
+
+impl TrackedImport {
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<TrackedImport> {
+        let imported_at = read_uint(reader)?;
+        let week = match read_uint(reader)? {
+            0 => None,
+            n => Some(
+                u16::try_from(n - 1)
+                    .ok()
+                    .and_then(|n| Week::try_from(n).ok())
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?,
+            ),
+        };
+        let num_entries = read_uint(reader)? as usize;
+        let mut entries = Vec::with_capacity(num_entries);
+        for _ in 0..num_entries {
+            let mut key = [0; Key::SIZE];
+            reader.read_exact(&mut key)?;
+            let mut buf = vec![0; read_uint(reader)? as usize];
+            reader.read_exact(&mut buf)?;
+            let mut entry = LichessEntry::default();
+            entry.extend_from_reader(&mut Cursor::new(buf))?;
+            entries.push((Key::from_bytes(&key).expect("lichess key size"), entry));
+        }
+        // Imports tracked before sources were separated are from the
+        // default source.
+        let source = match read_uint(reader) {
+            Ok(n) => u8::try_from(n)
+                .ok()
+                .and_then(LichessSource::from_u8)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => LichessSource::default(),
+            Err(err) => return Err(err),
+        };
+        Ok(TrackedImport {
+            imported_at,
+            week,
+            entries,
+            source,
+        })
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_uint(writer, self.imported_at)?;
+        write_uint(
+            writer,
+            self.week.map_or(0, |week| u64::from(u16::from(week)) + 1),
+        )?;
+        write_uint(writer, self.entries.len() as u64)?;
+        for (key, entry) in &self.entries {
+            writer.write_all(&key.clone().into_bytes())?;
+            let mut cursor = Cursor::new(Vec::with_capacity(LichessEntry::SIZE_HINT));
+            entry.write(&mut cursor)?;
+            let buf = cursor.into_inner();
+            write_uint(writer, buf.len() as u64)?;
+            writer.write_all(&buf)?;
+        }
+        write_uint(writer, u64::from(self.source.to_u8()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::{uci::Uci, Outcome, Square};
+
+    use super::*;
+    use crate::model::{KeyBuilder, MoveDetails, Speed};
+
+    #[test]
+    fn test_tracked_import_roundtrip() {
+        let key = KeyBuilder::lichess()
+            .with_zobrist(shakmaty::variant::Variant::Chess, 0x1234)
+            .with_month("2022-02".parse().unwrap());
+        let entry = LichessEntry::new_single(
+            Uci::Normal {
+                from: Square::E2,
+                to: Square::E4,
+                promotion: None,
+            },
+            Speed::Blitz,
+            "aaaaaaaa".parse().unwrap(),
+            Outcome::Draw,
+            1800,
+            1900,
+            MoveDetails::default(),
+        );
+        let tracked = TrackedImport {
+            imported_at: 1_650_000_000,
+            week: Some(Week::try_from(2726).unwrap()),
+            entries: vec![(key.clone(), entry)],
+            source: LichessSource::Live,
+        };
+
+        let mut cursor = Cursor::new(Vec::new());
+        tracked.write(&mut cursor).unwrap();
+        let deserialized = TrackedImport::read(&mut Cursor::new(cursor.into_inner())).unwrap();
+
+        assert_eq!(deserialized.imported_at, tracked.imported_at);
+        assert_eq!(deserialized.week, tracked.week);
+        assert_eq!(deserialized.entries.len(), 1);
+        assert_eq!(deserialized.entries[0].0, key);
+        assert_eq!(deserialized.source, LichessSource::Live);
+    }
+}
@@ -1,7 +1,10 @@
 use std::{fmt, str::FromStr};
 
+use serde_json::{json, Value};
 use thiserror::Error;
 
+use crate::api::{ApiSchema, SchemaGenerator};
+
 #[derive(Debug, Clone)]
 pub struct UserName(String);
 
@@ -45,6 +48,12 @@ impl FromStr for UserName {
     }
 }
 
+impl ApiSchema for UserName {
+    fn schema(_: &mut SchemaGenerator) -> Value {
+        json!({ "type": "string", "pattern": "^[0-9A-Za-z_-]{1,30}$" })
+    }
+}
+
 impl PartialEq for UserName {
     fn eq(&self, other: &UserName) -> bool {
         self.0.eq_ignore_ascii_case(&other.0)
@@ -11,14 +11,24 @@ use shakmaty::{
     CastlingMode, Chess, Position,
 };
 
-use crate::api::Error;
+use crate::api::{ApiSchema, Error};
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, ApiSchema, Debug)]
 pub struct Opening {
     eco: String,
     name: String,
 }
 
+impl Opening {
+    pub fn eco(&self) -> &str {
+        &self.eco
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
 #[serde_as]
 #[derive(Deserialize)]
 struct OpeningRecord {
@@ -67,7 +77,13 @@ impl Openings {
         root: &mut Zobrist<VariantPosition, u128>,
         play: Vec<Uci>,
     ) -> Result<Option<&Opening>, Error> {
-        let mut opening = None;
+        // The root position itself may be named, for example when given
+        // as a FEN.
+        let mut opening = if opening_sensible(root.as_inner().variant()) {
+            self.data.get(&root.zobrist_hash())
+        } else {
+            None
+        };
 
         for uci in play {
             let m = uci.to_move(root)?;
@@ -80,6 +96,18 @@ impl Openings {
 
         Ok(opening)
     }
+
+    /// Opening of the position reached by playing `uci`, or of the position
+    /// itself, if either is named.
+    pub fn classify_move(
+        &self,
+        pos: &Zobrist<VariantPosition, u128>,
+        uci: &Uci,
+    ) -> Option<&Opening> {
+        self.classify_and_play(&mut pos.clone(), vec![uci.to_owned()])
+            .ok()
+            .flatten()
+    }
 }
 
 const TSV_DATA: [&[u8]; 5] = [
@@ -0,0 +1,414 @@
+use std::{io, io::Write as _, mem};
+
+use axum::{
+    body,
+    response::{IntoResponse, Response},
+};
+use pgn_reader::{BufferedReader, Nag, RawHeader, SanPlus, Skip, Visitor};
+use sha1::{Digest, Sha1};
+use shakmaty::{
+    fen::Fen,
+    san::SanError,
+    uci::Uci,
+    variant::{Variant, VariantPosition},
+    ByColor, CastlingMode, Color, EnPassantMode, Outcome, Position, PositionError,
+};
+use thiserror::Error;
+
+use crate::{
+    api::ExplorerGameWithUci,
+    model::{GameId, GamePlayer, LaxDate, MastersGame, MastersGameWithId},
+};
+
+#[derive(Error, Debug)]
+pub enum PgnError {
+    #[error("missing header {0}")]
+    MissingHeader(&'static str),
+    #[error("invalid header {0}")]
+    InvalidHeader(&'static str),
+    #[error("unfinished game")]
+    Unfinished,
+    #[error("invalid position: {0}")]
+    PositionError(#[from] PositionError<VariantPosition>),
+    #[error("illegal move {0}: {1}")]
+    IllegalMove(String, SanError),
+}
+
+/// Example games of a position, concatenated into a single PGN, so that they
+/// can be opened in a GUI.
+#[derive(Default)]
+pub struct PgnBundle {
+    buf: Vec<u8>,
+}
+
+impl PgnBundle {
+    pub fn push_masters(&mut self, game: &MastersGame) -> io::Result<()> {
+        game.write_pgn(&mut self.buf)?;
+        writeln!(self.buf)
+    }
+
+    /// Lichess games are stored without their moves, so only the moves
+    /// leading to the position and the move played from there are known.
+    /// The game links to the full game instead.
+    pub fn push_lichess(
+        &mut self,
+        root: &LichessRoot,
+        game: &ExplorerGameWithUci,
+    ) -> io::Result<()> {
+        let row = &game.row;
+        let outcome = Outcome::from_winner(row.winner);
+        writeln!(self.buf, "[Event \"?\"]")?;
+        writeln!(self.buf, "[Site \"https://lichess.org/{}\"]", row.id)?;
+        match row.month {
+            Some(month) => writeln!(
+                self.buf,
+                "[Date \"{}.??\"]",
+                month.to_string().replace('-', ".")
+            )?,
+            None => writeln!(self.buf, "[Date \"{}.??.??\"]", u16::from(row.year))?,
+        }
+        writeln!(self.buf, "[White \"{}\"]", row.players.white.name)?;
+        writeln!(self.buf, "[Black \"{}\"]", row.players.black.name)?;
+        writeln!(self.buf, "[Result \"{}\"]", outcome)?;
+        writeln!(self.buf, "[WhiteElo \"{}\"]", row.players.white.rating)?;
+        writeln!(self.buf, "[BlackElo \"{}\"]", row.players.black.rating)?;
+        if let Some(clock) = row.clock {
+            writeln!(self.buf, "[TimeControl \"{}\"]", clock)?;
+        }
+        if let Some(variant) = root.variant_header() {
+            writeln!(self.buf, "[Variant \"{}\"]", variant)?;
+        }
+        if root.setup {
+            writeln!(
+                self.buf,
+                "[FEN \"{}\"]",
+                Fen::from_position(root.pos.clone(), EnPassantMode::Legal)
+            )?;
+            writeln!(self.buf, "[SetUp \"1\"]")?;
+        }
+        writeln!(self.buf)?;
+
+        let mut pos = root.pos.clone();
+        for (i, uci) in root.moves.iter().chain(Some(&game.uci)).enumerate() {
+            let m = uci
+                .to_move(&pos)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            if i > 0 {
+                write!(self.buf, " ")?;
+            }
+            if pos.turn() == Color::White {
+                write!(self.buf, "{}. ", pos.fullmoves())?;
+            } else if i == 0 {
+                write!(self.buf, "{}... ", pos.fullmoves())?;
+            }
+            let san = SanPlus::from_move_and_play_unchecked(&mut pos, &m);
+            write!(self.buf, "{}", san)?;
+        }
+        writeln!(self.buf, " {}", outcome)?;
+        writeln!(self.buf)
+    }
+}
+
+impl IntoResponse for PgnBundle {
+    fn into_response(self) -> Response {
+        Response::builder()
+            .header(axum::http::header::CONTENT_TYPE, "application/x-chess-pgn")
+            .body(body::boxed(body::Full::from(self.buf)))
+            .unwrap()
+    }
+}
+
+/// Where lichess games in a `PgnBundle` start.
+pub struct LichessRoot {
+    pub pos: VariantPosition,
+    /// Whether the root is not the usual starting position.
+    pub setup: bool,
+    pub chess960: bool,
+    /// Moves from the root to the position.
+    pub moves: Vec<Uci>,
+}
+
+impl LichessRoot {
+    fn variant_header(&self) -> Option<&'static str> {
+        Some(match self.pos.variant() {
+            Variant::Chess if self.chess960 => "Chess960",
+            Variant::Chess => return None,
+            Variant::Antichess => "Antichess",
+            Variant::Atomic => "Atomic",
+            Variant::Crazyhouse => "Crazyhouse",
+            Variant::Horde => "Horde",
+            Variant::KingOfTheHill => "King of the Hill",
+            Variant::RacingKings => "Racing Kings",
+            Variant::ThreeCheck => "Three-check",
+        })
+    }
+}
+
+/// Reads all games of a multi-game PGN. Games that cannot be imported are
+/// returned as errors, without affecting the following games.
+pub fn read_masters_pgn(pgn: &[u8]) -> Vec<Result<MastersGameWithId, PgnError>> {
+    let mut reader = BufferedReader::new_cursor(pgn);
+    let mut visitor = MastersVisitor::default();
+    let mut games = Vec::new();
+    while let Some(game) = reader
+        .read_game(&mut visitor)
+        .expect("read pgn from memory")
+    {
+        games.push(game);
+    }
+    games
+}
+
+#[derive(Default)]
+struct Headers {
+    event: Option<String>,
+    site: Option<String>,
+    date: Option<String>,
+    round: Option<String>,
+    white: Option<String>,
+    black: Option<String>,
+    white_elo: Option<String>,
+    black_elo: Option<String>,
+    result: Option<String>,
+    fen: Option<String>,
+    lichess_id: Option<String>,
+}
+
+impl Headers {
+    fn game(&self) -> Result<MastersGame, PgnError> {
+        Ok(MastersGame {
+            event: self.event.clone().unwrap_or_default(),
+            site: self.site.clone().unwrap_or_default(),
+            date: self
+                .date
+                .as_deref()
+                .ok_or(PgnError::MissingHeader("Date"))?
+                .parse::<LaxDate>()
+                .map_err(|_| PgnError::InvalidHeader("Date"))?,
+            round: self.round.clone().unwrap_or_default(),
+            players: ByColor {
+                white: player(&self.white, &self.white_elo, "WhiteElo")?,
+                black: player(&self.black, &self.black_elo, "BlackElo")?,
+            },
+            winner: match self.result.as_deref() {
+                Some("1-0") => Some(Color::White),
+                Some("0-1") => Some(Color::Black),
+                Some("1/2-1/2") => None,
+                Some("*") => return Err(PgnError::Unfinished),
+                Some(_) => return Err(PgnError::InvalidHeader("Result")),
+                None => return Err(PgnError::MissingHeader("Result")),
+            },
+            moves: Vec::new(),
+            nags: None,
+            fen: match self.fen.as_deref() {
+                None | Some("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1") => None,
+                Some(fen) => Some(
+                    fen.parse::<Fen>()
+                        .map_err(|_| PgnError::InvalidHeader("FEN"))?,
+                ),
+            },
+        })
+    }
+
+    fn id(&self, game: &MastersGame) -> Result<GameId, PgnError> {
+        match self.lichess_id {
+            Some(ref id) => id.parse().map_err(|_| PgnError::InvalidHeader("LichessId")),
+            None => Ok(deterministic_id(game)),
+        }
+    }
+}
+
+/// Missing ratings are left for the rating estimates and checks of the
+/// importer.
+fn player(
+    name: &Option<String>,
+    elo: &Option<String>,
+    elo_header: &'static str,
+) -> Result<GamePlayer, PgnError> {
+    Ok(GamePlayer {
+        name: name.clone().unwrap_or_default(),
+        rating: match elo.as_deref() {
+            None | Some("") | Some("?") | Some("-") => 0,
+            Some(elo) => elo
+                .parse()
+                .map_err(|_| PgnError::InvalidHeader(elo_header))?,
+        },
+        provisional: false,
+        estimated_rating: None,
+        estimated: false,
+        bot: false,
+    })
+}
+
+/// Derives a stable id from the game itself, so that submitting the same
+/// file again is recognized as a duplicate.
+fn deterministic_id(game: &MastersGame) -> GameId {
+    const BASE62: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let digest = Sha1::digest(&serde_json::to_vec(game).expect("serialize masters game"));
+    digest[..8]
+        .iter()
+        .map(|byte| char::from(BASE62[usize::from(*byte) % BASE62.len()]))
+        .collect::<String>()
+        .parse()
+        .expect("base62 game id")
+}
+
+struct Current {
+    game: MastersGame,
+    pos: VariantPosition,
+    nags: Vec<u8>,
+}
+
+struct MastersVisitor {
+    headers: Headers,
+    current: Result<Current, PgnError>,
+}
+
+impl Default for MastersVisitor {
+    fn default() -> MastersVisitor {
+        MastersVisitor {
+            headers: Headers::default(),
+            current: Err(PgnError::Unfinished),
+        }
+    }
+}
+
+impl Visitor for MastersVisitor {
+    type Result = Result<MastersGameWithId, PgnError>;
+
+    fn begin_game(&mut self) {
+        self.headers = Headers::default();
+        self.current = Err(PgnError::Unfinished);
+    }
+
+    fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
+        let header = match key {
+            b"Event" => &mut self.headers.event,
+            b"Site" => &mut self.headers.site,
+            b"Date" => &mut self.headers.date,
+            b"Round" => &mut self.headers.round,
+            b"White" => &mut self.headers.white,
+            b"Black" => &mut self.headers.black,
+            b"WhiteElo" => &mut self.headers.white_elo,
+            b"BlackElo" => &mut self.headers.black_elo,
+            b"Result" => &mut self.headers.result,
+            b"FEN" => &mut self.headers.fen,
+            b"LichessId" => &mut self.headers.lichess_id,
+            _ => return,
+        };
+        *header = Some(value.decode_utf8_lossy().into_owned());
+    }
+
+    fn end_headers(&mut self) -> Skip {
+        self.current = self.headers.game().and_then(|game| {
+            Ok(Current {
+                pos: game.initial_position()?,
+                game,
+                nags: Vec::new(),
+            })
+        });
+        Skip(self.current.is_err())
+    }
+
+    fn san(&mut self, san_plus: SanPlus) {
+        let err = match self.current {
+            Ok(ref mut current) => match san_plus.san.to_move(&current.pos) {
+                Ok(m) => {
+                    current
+                        .game
+                        .moves
+                        .push(m.to_uci(CastlingMode::from_chess960(current.game.is_chess960())));
+                    current.nags.push(0);
+                    current.pos.play_unchecked(&m);
+                    return;
+                }
+                Err(err) => err,
+            },
+            Err(_) => return,
+        };
+        self.current = Err(PgnError::IllegalMove(san_plus.to_string(), err));
+    }
+
+    fn nag(&mut self, nag: Nag) {
+        if let Ok(ref mut current) = self.current {
+            if let Some(last) = current.nags.last_mut() {
+                *last = nag.0;
+            }
+        }
+    }
+
+    fn begin_variation(&mut self) -> Skip {
+        Skip(true) // stay in the mainline
+    }
+
+    fn end_game(&mut self) -> Self::Result {
+        let Current { mut game, nags, .. } =
+            mem::replace(&mut self.current, Err(PgnError::Unfinished))?;
+        if nags.iter().any(|nag| *nag != 0) {
+            game.nags = Some(nags);
+        }
+        Ok(MastersGameWithId {
+            id: self.headers.id(&game)?,
+            game,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_masters_pgn() {
+        let pgn = br#"[Event "Test"]
+[Site "Test"]
+[Date "2022.10.30"]
+[Round "1"]
+[White "Carlsen, Magnus"]
+[Black "Nakamura, Hikaru"]
+[Result "1-0"]
+[WhiteElo "2856"]
+[BlackElo "2768"]
+
+1. e4 e5 2. Nf3 (2. f4) Nc6 3. Bb5 a6?! 4. O-O 1-0
+
+[Event "Test"]
+[Date "2022.10.30"]
+[White "Nakamura, Hikaru"]
+[Black "Carlsen, Magnus"]
+[Result "*"]
+
+1. d4 *
+
+[Event "Test"]
+[Date "2022.10.31"]
+[White "Nakamura, Hikaru"]
+[Black "Carlsen, Magnus"]
+[Result "0-1"]
+[LichessId "aaaaaaaa"]
+
+1. d4 Qxh2 0-1
+"#;
+
+        let games = read_masters_pgn(pgn);
+        assert_eq!(games.len(), 3);
+
+        let first = games[0].as_ref().unwrap();
+        assert_eq!(first.game.winner, Some(Color::White));
+        assert_eq!(first.game.players.black.rating, 2768);
+        assert_eq!(
+            first
+                .game
+                .moves
+                .iter()
+                .map(|uci| uci.to_string())
+                .collect::<Vec<_>>(),
+            ["e2e4", "e7e5", "g1f3", "b8c6", "f1b5", "a7a6", "e1g1"]
+        );
+        assert_eq!(first.game.nag(5), Some(6));
+        assert_eq!(deterministic_id(&first.game), first.id);
+
+        assert!(matches!(games[1], Err(PgnError::Unfinished)));
+        assert!(matches!(games[2], Err(PgnError::IllegalMove(_, _))));
+    }
+}
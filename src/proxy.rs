@@ -0,0 +1,312 @@
+use std::{
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Instant, SystemTime},
+};
+
+use async_channel::TrySendError;
+use axum::{
+    extract::ConnectInfo,
+    http::{HeaderMap, HeaderValue, Request},
+    response::Response,
+};
+use clap::Parser;
+use serde::Serialize;
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWriteExt as _, BufWriter},
+    task::JoinHandle,
+};
+use tower::{Layer, Service};
+
+use crate::shaping::Shaper;
+
+const X_REQUEST_ID: &str = "x-request-id";
+
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+#[derive(Parser, Clone)]
+pub struct ProxyOpt {
+    /// Number of reverse proxies in front of the server, each appending the
+    /// address of its peer to X-Forwarded-For. Earlier entries can be
+    /// forged by clients. 0 to use the peer address.
+    #[clap(long = "trusted-proxies", default_value = "1")]
+    trusted_proxies: usize,
+    /// Append structured access logs to this file, as newline delimited
+    /// JSON.
+    #[clap(long = "access-log")]
+    access_log: Option<PathBuf>,
+}
+
+/// Address of the client, as reported by the trusted proxies. Empty if
+/// unknown.
+#[derive(Debug, Clone)]
+pub struct ClientAddr(pub String);
+
+/// Identifies a request in logs and responses. Taken from the X-Request-Id
+/// header set by the reverse proxy, if any, so that requests can be traced
+/// across instances.
+#[derive(Debug, Clone)]
+pub struct RequestId(String);
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+struct RequestIds {
+    instance: u32,
+    counter: AtomicU64,
+}
+
+impl RequestIds {
+    fn new() -> RequestIds {
+        RequestIds {
+            // Tells apart instances and restarts.
+            instance: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("duration since unix epoch")
+                .subsec_nanos(),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self, headers: &HeaderMap) -> RequestId {
+        match headers
+            .get(X_REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        {
+            Some(id) => RequestId(id.to_owned()),
+            None => RequestId(format!(
+                "{:08x}-{:x}",
+                self.instance,
+                self.counter.fetch_add(1, Ordering::Relaxed)
+            )),
+        }
+    }
+}
+
+fn client_addr(trusted_proxies: usize, peer: Option<SocketAddr>, headers: &HeaderMap) -> String {
+    let forwarded: Vec<&str> = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .collect();
+    if trusted_proxies == 0 || forwarded.is_empty() {
+        return peer.map_or(String::new(), |peer| peer.ip().to_string());
+    }
+    // Requests that passed through fewer proxies than expected were not
+    // forwarded by all of them, so the first entry is the client.
+    forwarded[forwarded.len().saturating_sub(trusted_proxies)].to_owned()
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AccessLogEntry {
+    /// Milliseconds since the unix epoch.
+    time: u64,
+    request_id: String,
+    client: String,
+    identity: String,
+    method: String,
+    uri: String,
+    status: u16,
+    /// Until the response headers are ready. Streamed bodies may take
+    /// longer.
+    latency_ms: f64,
+}
+
+/// Writes access log entries in the background, so that requests never wait
+/// for the disk.
+#[derive(Clone)]
+struct AccessLog {
+    tx: Option<async_channel::Sender<AccessLogEntry>>,
+}
+
+impl AccessLog {
+    fn spawn(path: Option<PathBuf>) -> (AccessLog, Option<JoinHandle<()>>) {
+        match path {
+            Some(path) => {
+                let (tx, rx) = async_channel::bounded(10_000);
+                let join_handle = tokio::spawn(async move {
+                    let file = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&path)
+                        .await
+                        .expect("open access log");
+                    let mut writer = BufWriter::new(file);
+                    while let Ok(entry) = rx.recv().await {
+                        let mut line = serde_json::to_vec(&entry).expect("serialize access log");
+                        line.push(b'\n');
+                        writer.write_all(&line).await.expect("write access log");
+                        if rx.is_empty() {
+                            writer.flush().await.expect("flush access log");
+                        }
+                    }
+                });
+                (AccessLog { tx: Some(tx) }, Some(join_handle))
+            }
+            None => (AccessLog { tx: None }, None),
+        }
+    }
+
+    fn record(&self, entry: AccessLogEntry) {
+        if let Some(ref tx) = self.tx {
+            match tx.try_send(entry) {
+                Ok(()) => (),
+                Err(TrySendError::Full(entry)) => {
+                    log::warn!(
+                        "dropping access log of {}, because queue is full",
+                        entry.request_id
+                    )
+                }
+                Err(TrySendError::Closed(_)) => panic!("access log writer died"),
+            }
+        }
+    }
+}
+
+/// Determines the client address and request id of each request, and
+/// writes access logs. Must be outside of the `ShapingLayer`, which limits
+/// clients by address.
+#[derive(Clone)]
+pub struct ProxyLayer {
+    trusted_proxies: usize,
+    request_ids: Arc<RequestIds>,
+    access_log: AccessLog,
+    shaper: Arc<Shaper>,
+}
+
+impl ProxyLayer {
+    pub fn spawn(opt: ProxyOpt, shaper: Arc<Shaper>) -> (ProxyLayer, Option<JoinHandle<()>>) {
+        let (access_log, join_handle) = AccessLog::spawn(opt.access_log);
+        (
+            ProxyLayer {
+                trusted_proxies: opt.trusted_proxies,
+                request_ids: Arc::new(RequestIds::new()),
+                access_log,
+                shaper,
+            },
+            join_handle,
+        )
+    }
+}
+
+impl<S> Layer<S> for ProxyLayer {
+    type Service = Proxy<S>;
+
+    fn layer(&self, inner: S) -> Proxy<S> {
+        Proxy {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Proxy<S> {
+    inner: S,
+    layer: ProxyLayer,
+}
+
+impl<S, B> Service<Request<B>> for Proxy<S>
+where
+    S: Service<Request<B>, Response = Response>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let started_at = Instant::now();
+        let request_id = self.layer.request_ids.get(req.headers());
+        let peer = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(peer)| *peer);
+        let client = client_addr(self.layer.trusted_proxies, peer, req.headers());
+        let identity = self.layer.shaper.identity(req.headers());
+        let method = req.method().to_string();
+        let uri = req
+            .uri()
+            .path_and_query()
+            .map_or_else(|| req.uri().path().to_owned(), ToString::to_string);
+
+        req.extensions_mut().insert(ClientAddr(client.clone()));
+        req.extensions_mut().insert(request_id.clone());
+
+        let future = self.inner.call(req);
+        let access_log = self.layer.access_log.clone();
+        Box::pin(async move {
+            let mut res = future.await?;
+            let status = res.status();
+            if status.is_server_error() {
+                log::error!("request {} to {} failed with {}", request_id, uri, status);
+            }
+            if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+                res.headers_mut().insert(X_REQUEST_ID, value);
+            }
+            access_log.record(AccessLogEntry {
+                time: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .expect("duration since unix epoch")
+                    .as_millis() as u64,
+                request_id: request_id.0,
+                client,
+                identity,
+                method,
+                uri,
+                status: status.as_u16(),
+                latency_ms: started_at.elapsed().as_secs_f64() * 1000.0,
+            });
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarded(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let peer = Some("10.0.0.1:4321".parse().unwrap());
+        assert_eq!(client_addr(1, peer, &HeaderMap::new()), "10.0.0.1");
+        assert_eq!(client_addr(1, peer, &forwarded("1.2.3.4")), "1.2.3.4");
+        assert_eq!(
+            client_addr(1, peer, &forwarded("6.6.6.6, 1.2.3.4")),
+            "1.2.3.4"
+        );
+        assert_eq!(
+            client_addr(2, peer, &forwarded("6.6.6.6, 1.2.3.4, 10.0.0.2")),
+            "1.2.3.4"
+        );
+        assert_eq!(client_addr(3, peer, &forwarded("1.2.3.4")), "1.2.3.4");
+        assert_eq!(client_addr(0, peer, &forwarded("1.2.3.4")), "10.0.0.1");
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use async_channel::TrySendError;
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tokio::{task::JoinHandle, time::timeout};
+
+use crate::{
+    api::ApiSchema,
+    model::{GameId, Speed},
+};
+
+const MAX_BATCH: usize = 256;
+
+#[derive(Parser, Clone)]
+pub struct PublisherOpt {
+    /// Webhook that receives batches of events about imported games as
+    /// application/x-ndjson.
+    #[clap(long = "publish-webhook")]
+    webhook: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ApiSchema, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ImportSource {
+    Masters,
+    Lichess,
+    Player,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportEvent {
+    pub source: ImportSource,
+    #[serde_as(as = "DisplayFromStr")]
+    pub id: GameId,
+    pub keys: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<Speed>,
+    pub rating_bucket: u16,
+}
+
+impl ImportEvent {
+    pub fn rating_bucket(white: u16, black: u16) -> u16 {
+        (white / 2 + black / 2) / 100 * 100
+    }
+}
+
+#[derive(Clone)]
+pub struct Publisher {
+    tx: Option<async_channel::Sender<ImportEvent>>,
+}
+
+impl Publisher {
+    pub fn spawn(opt: PublisherOpt) -> (Publisher, Option<JoinHandle<()>>) {
+        match opt.webhook {
+            Some(webhook) => {
+                let (tx, rx) = async_channel::bounded(MAX_BATCH * 16);
+                let join_handle = tokio::spawn(
+                    PublisherActor {
+                        client: reqwest::Client::builder()
+                            .timeout(Duration::from_secs(10))
+                            .build()
+                            .expect("reqwest client"),
+                        webhook,
+                        rx,
+                    }
+                    .run(),
+                );
+                (Publisher { tx: Some(tx) }, Some(join_handle))
+            }
+            None => (Publisher { tx: None }, None),
+        }
+    }
+
+    pub fn publish(&self, event: ImportEvent) {
+        if let Some(ref tx) = self.tx {
+            match tx.try_send(event) {
+                Ok(()) => (),
+                Err(TrySendError::Full(event)) => {
+                    log::warn!("dropping event for {}, because queue is full", event.id)
+                }
+                Err(TrySendError::Closed(_)) => panic!("publisher died"),
+            }
+        }
+    }
+}
+
+struct PublisherActor {
+    client: reqwest::Client,
+    webhook: String,
+    rx: async_channel::Receiver<ImportEvent>,
+}
+
+impl PublisherActor {
+    async fn run(self) {
+        while let Ok(event) = self.rx.recv().await {
+            // Collect more events that arrive shortly after.
+            let mut batch = vec![event];
+            while batch.len() < MAX_BATCH {
+                match timeout(Duration::from_secs(1), self.rx.recv()).await {
+                    Ok(Ok(event)) => batch.push(event),
+                    Ok(Err(_)) | Err(_) => break,
+                }
+            }
+
+            let mut body = Vec::new();
+            for event in &batch {
+                serde_json::to_writer(&mut body, event).expect("serialize event");
+                body.push(b'\n');
+            }
+
+            match self
+                .client
+                .post(&self.webhook)
+                .header("Content-Type", "application/x-ndjson")
+                .body(body)
+                .send()
+                .await
+                .and_then(|res| res.error_for_status())
+            {
+                Ok(_) => log::debug!("published {} events", batch.len()),
+                Err(err) => log::error!("failed to publish {} events: {}", batch.len(), err),
+            }
+        }
+    }
+}
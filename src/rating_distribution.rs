@@ -0,0 +1,89 @@
+use std::sync::Mutex;
+
+use crate::{
+    api::{RatingDistributionMeta, RatingGroupMeta},
+    model::{BySpeed, RatingGroup, Speed},
+};
+
+const BUCKET_WIDTH: u16 = 100;
+
+/// Buckets of average ratings from 0 up to 3000 and above.
+const NUM_BUCKETS: usize = 31;
+
+/// Ratings of the lichess games imported since startup, by speed, to check
+/// how well the rating groups partition the actual games.
+#[derive(Default)]
+pub struct RatingDistribution {
+    by_speed: Mutex<BySpeed<SpeedRatings>>,
+}
+
+#[derive(Default)]
+struct SpeedRatings {
+    buckets: [u64; NUM_BUCKETS],
+    by_rating_group: [u64; RatingGroup::ALL.len()],
+}
+
+impl RatingDistribution {
+    pub fn record(&self, speed: Speed, white_rating: u16, black_rating: u16) {
+        let avg = white_rating / 2 + black_rating / 2;
+        let bucket = usize::from(avg / BUCKET_WIDTH).min(NUM_BUCKETS - 1);
+        let rating_group = RatingGroup::select(white_rating, black_rating);
+
+        let mut guard = self.by_speed.lock().expect("rating distribution");
+        let ratings = guard.by_speed_mut(speed);
+        ratings.buckets[bucket] += 1;
+        ratings.by_rating_group[rating_group as usize] += 1;
+    }
+
+    pub fn summarize(&self) -> Vec<RatingDistributionMeta> {
+        let guard = self.by_speed.lock().expect("rating distribution");
+        Speed::ALL
+            .into_iter()
+            .map(|speed| {
+                let ratings = guard.by_speed(speed);
+                RatingDistributionMeta {
+                    speed,
+                    games: ratings.buckets.iter().sum(),
+                    bucket_width: BUCKET_WIDTH,
+                    buckets: ratings.buckets.to_vec(),
+                    rating_groups: RatingGroup::ALL
+                        .into_iter()
+                        .map(|rating_group| RatingGroupMeta {
+                            lower_bound: rating_group.lower_bound(),
+                            games: ratings.by_rating_group[rating_group as usize],
+                        })
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rating_distribution() {
+        let distribution = RatingDistribution::default();
+        distribution.record(Speed::Blitz, 1500, 1700);
+        distribution.record(Speed::Blitz, 3300, 3100);
+        distribution.record(Speed::Rapid, 0, 0);
+
+        let summary = distribution.summarize();
+        let blitz = summary
+            .iter()
+            .find(|meta| meta.speed == Speed::Blitz)
+            .unwrap();
+        assert_eq!(blitz.games, 2);
+        assert_eq!(blitz.buckets[16], 1);
+        assert_eq!(blitz.buckets[NUM_BUCKETS - 1], 1);
+        assert_eq!(blitz.rating_groups.iter().map(|g| g.games).sum::<u64>(), 2);
+
+        let rapid = summary
+            .iter()
+            .find(|meta| meta.speed == Speed::Rapid)
+            .unwrap();
+        assert_eq!(rapid.buckets[0], 1);
+    }
+}
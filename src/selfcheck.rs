@@ -0,0 +1,190 @@
+use std::io::Cursor;
+
+use clap::{ArgEnum, Parser};
+
+use crate::{
+    db::{lichess_cf_name, Database},
+    model::{
+        GameId, Key, LichessEntry, LichessGame, LichessSource, MastersEntry, MastersGame, Month,
+        PlayerEntry, TimeBucket, Week, Year,
+    },
+};
+
+#[derive(Parser, Clone)]
+pub struct SelfCheckOpt {
+    /// What to do if entries sampled on startup can not be decoded, for
+    /// example because the data directory was written by an incompatible
+    /// version.
+    #[clap(long = "self-check", arg_enum, default_value = "warn")]
+    strictness: Strictness,
+    /// Number of random keys to sample from each column family.
+    #[clap(long = "self-check-samples", default_value = "64")]
+    samples: usize,
+}
+
+#[derive(ArgEnum, Copy, Clone, Debug, Eq, PartialEq)]
+enum Strictness {
+    /// Skip the check.
+    Off,
+    /// Log invalid entries, but start anyway.
+    Warn,
+    /// Refuse to start if any sampled entry is invalid.
+    Strict,
+}
+
+/// Encoding of keys and values of a column family.
+#[derive(Copy, Clone)]
+enum Format {
+    Masters,
+    Lichess,
+    LichessWeek,
+    Player,
+    MastersGame,
+    LichessGame,
+}
+
+impl Format {
+    fn check(self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        let mut cursor = Cursor::new(value);
+        match self {
+            Format::Masters => {
+                check_bucket::<Year>(key)?;
+                MastersEntry::default()
+                    .extend_from_reader(&mut cursor)
+                    .map_err(|err| format!("masters entry: {}", err))
+            }
+            Format::Lichess => {
+                check_bucket::<Month>(key)?;
+                LichessEntry::default()
+                    .extend_from_reader(&mut cursor)
+                    .map_err(|err| format!("lichess entry: {}", err))
+            }
+            Format::LichessWeek => {
+                check_bucket::<Week>(key)?;
+                LichessEntry::default()
+                    .extend_from_reader(&mut cursor)
+                    .map_err(|err| format!("lichess entry: {}", err))
+            }
+            Format::Player => {
+                check_bucket::<Month>(key)?;
+                PlayerEntry::default()
+                    .extend_from_reader(&mut cursor)
+                    .map_err(|err| format!("player entry: {}", err))
+            }
+            Format::MastersGame => {
+                check_game_id(key)?;
+                serde_json::from_slice::<MastersGame>(value)
+                    .map(|_| ())
+                    .map_err(|err| format!("masters game: {}", err))
+            }
+            Format::LichessGame => {
+                check_game_id(key)?;
+                LichessGame::read(&mut cursor)
+                    .map(|_| ())
+                    .map_err(|err| format!("lichess game: {}", err))
+            }
+        }
+    }
+}
+
+fn check_bucket<T: TimeBucket>(key: &[u8]) -> Result<(), String> {
+    Key::from_bytes(key)
+        .ok_or_else(|| format!("key of {} bytes", key.len()))?
+        .bucket::<T>()
+        .map(|_| ())
+        .map_err(|err| format!("key: {}", err))
+}
+
+fn check_game_id(key: &[u8]) -> Result<(), String> {
+    let mut cursor = Cursor::new(key);
+    GameId::read(&mut cursor).map_err(|err| format!("game id: {}", err))?;
+    if cursor.position() as usize != key.len() {
+        return Err(format!("game id of {} bytes", key.len()));
+    }
+    Ok(())
+}
+
+fn columns() -> Vec<(&'static str, Format)> {
+    let mut columns = vec![
+        ("masters", Format::Masters),
+        ("masters_game", Format::MastersGame),
+        ("lichess_week", Format::LichessWeek),
+        ("lichess_game", Format::LichessGame),
+        ("player", Format::Player),
+    ];
+    columns.extend(
+        LichessSource::ALL
+            .into_iter()
+            .map(|source| (lichess_cf_name(source), Format::Lichess)),
+    );
+    columns
+}
+
+/// Decodes entries at random keys of each column family, so that a data
+/// directory in an incompatible format is noticed before serving garbage.
+/// Exits the process if the check is strict and any entry is invalid.
+pub fn run(db: &Database, opt: &SelfCheckOpt) {
+    if opt.strictness == Strictness::Off {
+        return;
+    }
+
+    let mut total_sampled = 0;
+    let mut total_invalid = 0;
+    for (cf, format) in columns() {
+        let mut sampled = 0;
+        let mut invalid = 0;
+        db.sample_cf(cf, opt.samples, |key, value| {
+            sampled += 1;
+            if let Err(err) = format.check(key, value) {
+                invalid += 1;
+                log::error!(
+                    "self check: invalid entry in {} at key {:02x?}: {}",
+                    cf,
+                    key,
+                    err
+                );
+            }
+        })
+        .expect("sample for self check");
+        if invalid > 0 {
+            log::error!(
+                "self check: {} of {} entries in {} invalid",
+                invalid,
+                sampled,
+                cf
+            );
+        }
+        total_sampled += sampled;
+        total_invalid += invalid;
+    }
+
+    if total_invalid == 0 {
+        log::info!("self check: {} sampled entries valid", total_sampled);
+    } else if opt.strictness == Strictness::Strict {
+        log::error!(
+            "self check: {} of {} sampled entries invalid, refusing to start",
+            total_invalid,
+            total_sampled
+        );
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::variant::Variant;
+
+    use super::*;
+    use crate::model::KeyBuilder;
+
+    #[test]
+    fn test_check_keys() {
+        let key = KeyBuilder::lichess()
+            .with_zobrist(Variant::Chess, 0)
+            .with_month(Month::default())
+            .into_bytes();
+        assert!(Format::Lichess.check(&key, &[]).is_ok());
+        assert!(Format::Lichess.check(&key[1..], &[]).is_err());
+        assert!(Format::LichessGame.check(&key, &[]).is_err());
+    }
+}
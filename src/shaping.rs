@@ -0,0 +1,430 @@
+use std::{
+    collections::HashSet,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{HeaderMap, Request, StatusCode},
+    response::{IntoResponse as _, Response},
+};
+use clap::Parser;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
+
+use crate::{
+    accounts::{bearer_token, Accounts},
+    model::ApiKeyId,
+    proxy::ClientAddr,
+};
+
+const MAX_CLIENTS: usize = 100_000;
+
+/// Paths that are not publicly reachable through the reverse proxy.
+const EXEMPT_PREFIXES: [&str; 4] = ["/monitor/", "/admin/", "/import/", "/compact"];
+
+/// Requests that do not count towards the quota of an API key, so that
+/// clients can always find out why they are rejected.
+const UNMETERED_PATHS: [&str; 1] = ["/account"];
+
+#[derive(Parser, Clone)]
+pub struct ShapingOpt {
+    /// Comma separated tokens of authenticated clients, like the lichess
+    /// frontend, sent as bearer tokens.
+    #[clap(
+        long = "api-tokens",
+        env = "EXPLORER_API_TOKENS",
+        use_value_delimiter = true
+    )]
+    api_tokens: Vec<String>,
+    /// Sustained requests per second of each anonymous client.
+    #[clap(long = "anonymous-rate", default_value = "4")]
+    anonymous_rate: f64,
+    /// Burst allowance of each anonymous client.
+    #[clap(long = "anonymous-burst", default_value = "20")]
+    anonymous_burst: f64,
+    /// Maximum number of concurrent anonymous requests.
+    #[clap(long = "anonymous-concurrency", default_value = "64")]
+    anonymous_concurrency: usize,
+    /// Sustained requests per second of each authenticated client.
+    #[clap(long = "authenticated-rate", default_value = "500")]
+    authenticated_rate: f64,
+    /// Burst allowance of each authenticated client.
+    #[clap(long = "authenticated-burst", default_value = "2000")]
+    authenticated_burst: f64,
+    /// Maximum number of concurrent authenticated requests.
+    #[clap(long = "authenticated-concurrency", default_value = "512")]
+    authenticated_concurrency: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Budget {
+    pub rate: f64,
+    pub burst: f64,
+    pub concurrency: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Budgets {
+    pub anonymous: Budget,
+    pub authenticated: Budget,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash)]
+enum ClientClass {
+    Anonymous,
+    Authenticated,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn new(budget: &Budget, now: Instant) -> Bucket {
+        Bucket {
+            tokens: budget.burst,
+            updated_at: now,
+        }
+    }
+
+    fn refilled(&self, budget: &Budget, now: Instant) -> f64 {
+        (self.tokens + now.duration_since(self.updated_at).as_secs_f64() * budget.rate)
+            .min(budget.burst)
+    }
+
+    fn is_full(&self, budget: &Budget, now: Instant) -> bool {
+        self.refilled(budget, now) >= budget.burst
+    }
+
+    /// Refills the bucket for the elapsed time, then takes a token if one
+    /// is left.
+    fn take(&mut self, budget: &Budget, now: Instant) -> bool {
+        self.tokens = self.refilled(budget, now);
+        self.updated_at = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Separate rate and concurrency budgets for anonymous and authenticated
+/// clients, with a token bucket per client for fairness. Clients with API
+/// keys are authenticated, but limited by their monthly quota.
+pub struct Shaper {
+    api_tokens: HashSet<String>,
+    accounts: Arc<Accounts>,
+    budgets: RwLock<Budgets>,
+    buckets: Mutex<FxHashMap<(ClientClass, String), Bucket>>,
+    in_flight_anonymous: Arc<AtomicUsize>,
+    in_flight_authenticated: Arc<AtomicUsize>,
+}
+
+impl Shaper {
+    pub fn new(opt: ShapingOpt, accounts: Arc<Accounts>) -> Shaper {
+        Shaper {
+            api_tokens: opt.api_tokens.into_iter().collect(),
+            accounts,
+            budgets: RwLock::new(Budgets {
+                anonymous: Budget {
+                    rate: opt.anonymous_rate,
+                    burst: opt.anonymous_burst,
+                    concurrency: opt.anonymous_concurrency,
+                },
+                authenticated: Budget {
+                    rate: opt.authenticated_rate,
+                    burst: opt.authenticated_burst,
+                    concurrency: opt.authenticated_concurrency,
+                },
+            }),
+            buckets: Mutex::new(FxHashMap::default()),
+            in_flight_anonymous: Arc::new(AtomicUsize::new(0)),
+            in_flight_authenticated: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn budgets(&self) -> Budgets {
+        *self.budgets.read().expect("budgets")
+    }
+
+    pub fn set_budgets(&self, budgets: Budgets) {
+        *self.budgets.write().expect("budgets") = budgets;
+    }
+
+    /// Also returns the API key that the request is metered against, if
+    /// any. Anonymous clients with an unknown address are not told apart.
+    fn classify(
+        &self,
+        headers: &HeaderMap,
+        addr: Option<String>,
+    ) -> (ClientClass, Option<String>, Option<ApiKeyId>) {
+        if let Some(token) = bearer_token(headers) {
+            if self.api_tokens.contains(token) {
+                return (ClientClass::Authenticated, Some(token.to_owned()), None);
+            }
+            if let Some(id) = self.accounts.authenticate(token) {
+                return (ClientClass::Authenticated, Some(id.to_string()), Some(id));
+            }
+        }
+        (ClientClass::Anonymous, addr, None)
+    }
+
+    /// Identity of the client for access logs, without giving away tokens.
+    pub fn identity(&self, headers: &HeaderMap) -> String {
+        match self.classify(headers, None) {
+            (ClientClass::Anonymous, _, _) => "anonymous".to_owned(),
+            (ClientClass::Authenticated, _, Some(id)) => format!("key:{}", id),
+            (ClientClass::Authenticated, _, None) => "internal".to_owned(),
+        }
+    }
+
+    fn admit(
+        &self,
+        headers: &HeaderMap,
+        addr: Option<String>,
+        metered: bool,
+    ) -> Result<Permit, Rejection> {
+        let (class, client, api_key) = self.classify(headers, addr);
+        let budgets = self.budgets();
+        let (budget, in_flight) = match class {
+            ClientClass::Anonymous => (budgets.anonymous, &self.in_flight_anonymous),
+            ClientClass::Authenticated => (budgets.authenticated, &self.in_flight_authenticated),
+        };
+
+        // Without a client to charge, only the concurrency budget of the
+        // class applies.
+        if let Some(client) = client {
+            let now = Instant::now();
+            let mut buckets = self.buckets.lock().expect("buckets");
+            if buckets.len() >= MAX_CLIENTS {
+                // Forget clients that have fully recovered.
+                buckets.retain(|_, bucket| !bucket.is_full(&budget, now));
+            }
+            let bucket = buckets
+                .entry((class, client))
+                .or_insert_with(|| Bucket::new(&budget, now));
+            if !bucket.take(&budget, now) {
+                return Err(Rejection::RateLimited);
+            }
+        }
+
+        let permit =
+            Permit::acquire(in_flight, budget.concurrency).ok_or(Rejection::RateLimited)?;
+
+        match api_key {
+            Some(id) if metered && !self.accounts.consume(id) => Err(Rejection::QuotaExhausted),
+            _ => Ok(permit),
+        }
+    }
+}
+
+enum Rejection {
+    RateLimited,
+    QuotaExhausted,
+}
+
+impl Rejection {
+    fn into_response(self) -> Response {
+        match self {
+            Rejection::RateLimited => StatusCode::TOO_MANY_REQUESTS.into_response(),
+            Rejection::QuotaExhausted => {
+                (StatusCode::TOO_MANY_REQUESTS, "monthly quota exhausted").into_response()
+            }
+        }
+    }
+}
+
+struct Permit {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Permit {
+    fn acquire(in_flight: &Arc<AtomicUsize>, concurrency: usize) -> Option<Permit> {
+        if in_flight.fetch_add(1, Ordering::AcqRel) >= concurrency {
+            in_flight.fetch_sub(1, Ordering::AcqRel);
+            return None;
+        }
+        Some(Permit {
+            in_flight: Arc::clone(in_flight),
+        })
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn is_exempt(path: &str) -> bool {
+    EXEMPT_PREFIXES
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+}
+
+/// Address of the client as reported by the trusted proxies, or else the
+/// address of the peer, if known.
+fn client_addr<B>(req: &Request<B>) -> Option<String> {
+    req.extensions()
+        .get::<ClientAddr>()
+        .map(|ClientAddr(addr)| addr.clone())
+        .filter(|addr| !addr.is_empty())
+        .or_else(|| {
+            req.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(peer)| peer.ip().to_string())
+        })
+}
+
+#[derive(Clone)]
+pub struct ShapingLayer {
+    shaper: Arc<Shaper>,
+}
+
+impl ShapingLayer {
+    pub fn new(shaper: Arc<Shaper>) -> ShapingLayer {
+        ShapingLayer { shaper }
+    }
+}
+
+impl<S> Layer<S> for ShapingLayer {
+    type Service = Shaping<S>;
+
+    fn layer(&self, inner: S) -> Shaping<S> {
+        Shaping {
+            inner,
+            shaper: Arc::clone(&self.shaper),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Shaping<S> {
+    inner: S,
+    shaper: Arc<Shaper>,
+}
+
+impl<S, B> Service<Request<B>> for Shaping<S>
+where
+    S: Service<Request<B>, Response = Response>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let path = req.uri().path();
+        if is_exempt(path) {
+            return Box::pin(self.inner.call(req));
+        }
+
+        let metered = !UNMETERED_PATHS.contains(&path);
+        match self.shaper.admit(req.headers(), client_addr(&req), metered) {
+            Ok(permit) => {
+                let future = self.inner.call(req);
+                Box::pin(async move {
+                    let res = future.await;
+                    drop(permit);
+                    res
+                })
+            }
+            Err(rejection) => Box::pin(async { Ok(rejection.into_response()) }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let budget = Budget {
+            rate: 2.0,
+            burst: 3.0,
+            concurrency: 1,
+        };
+        let start = Instant::now();
+        let mut bucket = Bucket::new(&budget, start);
+        assert!(bucket.is_full(&budget, start));
+        assert!(bucket.take(&budget, start));
+        assert!(bucket.take(&budget, start));
+        assert!(bucket.take(&budget, start));
+        assert!(!bucket.take(&budget, start), "burst exhausted");
+        assert!(!bucket.is_full(&budget, start));
+
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.take(&budget, later), "refilled one token");
+        assert!(!bucket.take(&budget, later));
+
+        let much_later = later + Duration::from_secs(60);
+        assert!(bucket.is_full(&budget, much_later), "capped at burst");
+        for _ in 0..3 {
+            assert!(bucket.take(&budget, much_later));
+        }
+        assert!(!bucket.take(&budget, much_later));
+    }
+
+    #[test]
+    fn test_permit() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let first = Permit::acquire(&in_flight, 2).unwrap();
+        let second = Permit::acquire(&in_flight, 2).unwrap();
+        assert!(Permit::acquire(&in_flight, 2).is_none());
+        assert_eq!(in_flight.load(Ordering::Acquire), 2);
+
+        drop(first);
+        assert_eq!(in_flight.load(Ordering::Acquire), 1);
+        let third = Permit::acquire(&in_flight, 2).unwrap();
+        drop(second);
+        drop(third);
+        assert_eq!(in_flight.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn test_is_exempt() {
+        assert!(is_exempt("/monitor/db/stats"));
+        assert!(is_exempt("/admin/budgets"));
+        assert!(is_exempt("/import/masters"));
+        assert!(is_exempt("/compact"));
+        assert!(!is_exempt("/masters"));
+        assert!(!is_exempt("/lichess"));
+        assert!(!is_exempt("/account"));
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let mut req = Request::new(());
+        assert_eq!(client_addr(&req), None);
+
+        req.extensions_mut()
+            .insert(ConnectInfo("10.0.0.1:4321".parse::<SocketAddr>().unwrap()));
+        assert_eq!(client_addr(&req).as_deref(), Some("10.0.0.1"));
+
+        req.extensions_mut().insert(ClientAddr(String::new()));
+        assert_eq!(client_addr(&req).as_deref(), Some("10.0.0.1"));
+
+        req.extensions_mut()
+            .insert(ClientAddr("1.2.3.4".to_owned()));
+        assert_eq!(client_addr(&req).as_deref(), Some("1.2.3.4"));
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rustc_hash::FxHashMap;
+
+use crate::{
+    api::Error,
+    db::{Database, DbOpt},
+    model::Month,
+};
+
+/// Historical views of the database, answered from the checkpoints in the
+/// backup directory. Checkpoints are opened read-only when first requested
+/// and then kept open.
+pub struct Snapshots {
+    opt: DbOpt,
+    open: Mutex<FxHashMap<PathBuf, Arc<Database>>>,
+}
+
+impl Snapshots {
+    pub fn new(opt: &DbOpt) -> Snapshots {
+        Snapshots {
+            opt: opt.clone(),
+            open: Mutex::new(FxHashMap::default()),
+        }
+    }
+
+    /// Selects the live database, or the last checkpoint that was created
+    /// before the end of the given month.
+    pub fn get(&self, live: &Arc<Database>, as_of: Option<Month>) -> Result<Arc<Database>, Error> {
+        let as_of = match as_of {
+            Some(as_of) => as_of,
+            None => return Ok(Arc::clone(live)),
+        };
+        let path = self.checkpoint(as_of)?;
+
+        let mut open = self.open.lock().expect("snapshots");
+        if let Some(db) = open.get(&path) {
+            return Ok(Arc::clone(db));
+        }
+        log::info!("opening snapshot {} as of {}", path.display(), as_of);
+        let db =
+            Arc::new(Database::open(&path, &self.opt.snapshot()).map_err(Error::SnapshotFailed)?);
+        open.insert(path, Arc::clone(&db));
+        Ok(db)
+    }
+
+    fn checkpoint(&self, as_of: Month) -> Result<PathBuf, Error> {
+        let dir = self.opt.backup_dir().ok_or(Error::SnapshotsNotConfigured)?;
+        let mut best = None;
+        for entry in fs::read_dir(dir).map_err(|_| Error::NoSnapshot(as_of))? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            let created = match entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("checkpoint-"))
+                .and_then(|secs| secs.parse::<i64>().ok())
+            {
+                Some(created) => created,
+                None => continue,
+            };
+            let month = match NaiveDateTime::from_timestamp_opt(created, 0) {
+                Some(time) => Month::from_time_saturating(DateTime::from_utc(time, Utc)),
+                None => continue,
+            };
+            if month <= as_of && best.as_ref().map_or(true, |(best, _)| created > *best) {
+                best = Some((created, entry.path()));
+            }
+        }
+        best.map(|(_, path)| path).ok_or(Error::NoSnapshot(as_of))
+    }
+}
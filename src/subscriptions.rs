@@ -0,0 +1,266 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Extension,
+    },
+    response::IntoResponse,
+};
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use shakmaty::{san::San, uci::Uci, variant::VariantPosition, zobrist::Zobrist, Color, Outcome};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    api::{ApiSchema, Error, Play, PlayerColor, PositionCache},
+    masters_key_builder,
+    model::{GameId, KeyBuilder, KeyPrefix, Stats, UserId, UserName},
+    opening::Openings,
+    publisher::ImportSource,
+};
+
+/// Number of imported games that a slow subscriber may fall behind, before
+/// it is told to look up its positions again.
+const CAPACITY: usize = 1024;
+
+const MAX_SUBSCRIPTIONS: usize = 64;
+
+/// Positions that an import or indexing run merged a single game into.
+#[derive(Debug)]
+pub struct PositionUpdates {
+    pub source: ImportSource,
+    pub id: GameId,
+    pub outcome: Outcome,
+    /// The move that was played from each position.
+    pub moves: FxHashMap<KeyPrefix, Uci>,
+}
+
+/// Fans out updates to clients that are subscribed to positions over
+/// WebSockets, so that analysis boards do not have to poll.
+#[derive(Clone)]
+pub struct Subscriptions {
+    tx: broadcast::Sender<Arc<PositionUpdates>>,
+}
+
+impl Default for Subscriptions {
+    fn default() -> Subscriptions {
+        Subscriptions {
+            tx: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl Subscriptions {
+    /// Returns false if nobody is listening, so that importers can avoid
+    /// collecting updates.
+    pub fn is_watched(&self) -> bool {
+        self.tx.receiver_count() > 0
+    }
+
+    pub fn notify(&self, updates: PositionUpdates) {
+        if !updates.moves.is_empty() {
+            // Fails only if nobody is listening.
+            let _ = self.tx.send(Arc::new(updates));
+        }
+    }
+}
+
+#[derive(Deserialize, ApiSchema, Debug)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum ClientMessage {
+    Subscribe(SubscribeRequest),
+    Unsubscribe { id: String },
+}
+
+#[serde_as]
+#[derive(Deserialize, ApiSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscribeRequest {
+    /// Chosen by the client, to tell updates of its subscriptions apart.
+    id: String,
+    source: ImportSource,
+    #[serde(flatten)]
+    play: Play,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    player: Option<UserName>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    color: Option<PlayerColor>,
+}
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ServerMessage {
+    #[serde(rename_all = "camelCase")]
+    Update {
+        id: String,
+        source: ImportSource,
+        #[serde_as(as = "DisplayFromStr")]
+        game: GameId,
+        #[serde_as(as = "DisplayFromStr")]
+        uci: Uci,
+        #[serde_as(as = "Option<DisplayFromStr>")]
+        san: Option<San>,
+        #[serde(flatten)]
+        stats: Stats,
+    },
+    /// Updates were dropped, so the client should look up its positions
+    /// again.
+    Lagged { missed: u64 },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        error: String,
+    },
+}
+
+struct Subscription {
+    id: String,
+    source: ImportSource,
+    pos: Zobrist<VariantPosition, u128>,
+    /// Two for players of both colors.
+    prefixes: Vec<KeyPrefix>,
+}
+
+pub async fn handler(
+    ws: WebSocketUpgrade,
+    Extension(openings): Extension<&'static Openings>,
+    Extension(positions): Extension<Arc<PositionCache>>,
+    Extension(subscriptions): Extension<Subscriptions>,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| {
+        Session {
+            openings,
+            positions,
+            subscriptions: Vec::new(),
+        }
+        .run(socket, subscriptions.tx.subscribe())
+    })
+}
+
+struct Session {
+    openings: &'static Openings,
+    positions: Arc<PositionCache>,
+    subscriptions: Vec<Subscription>,
+}
+
+impl Session {
+    async fn run(
+        mut self,
+        mut socket: WebSocket,
+        mut rx: broadcast::Receiver<Arc<PositionUpdates>>,
+    ) {
+        loop {
+            let replies = tokio::select! {
+                msg = socket.recv() => match msg {
+                    Some(Ok(Message::Text(text))) => self.handle(&text),
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => Vec::new(),
+                },
+                updates = rx.recv() => match updates {
+                    Ok(updates) => self.updates(&updates),
+                    Err(RecvError::Lagged(missed)) => vec![ServerMessage::Lagged { missed }],
+                    Err(RecvError::Closed) => break,
+                },
+            };
+            for reply in replies {
+                let text = serde_json::to_string(&reply).expect("serialize server message");
+                if socket.send(Message::Text(text)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, text: &str) -> Vec<ServerMessage> {
+        match serde_json::from_str(text) {
+            Ok(ClientMessage::Subscribe(req)) => {
+                let id = req.id.clone();
+                match self.subscribe(req) {
+                    Ok(()) => Vec::new(),
+                    Err(err) => vec![ServerMessage::Error {
+                        id: Some(id),
+                        error: err.to_string(),
+                    }],
+                }
+            }
+            Ok(ClientMessage::Unsubscribe { id }) => {
+                self.subscriptions.retain(|sub| sub.id != id);
+                Vec::new()
+            }
+            Err(err) => vec![ServerMessage::Error {
+                id: None,
+                error: format!("bad request: {}", err),
+            }],
+        }
+    }
+
+    fn subscribe(&mut self, req: SubscribeRequest) -> Result<(), Error> {
+        self.subscriptions.retain(|sub| sub.id != req.id);
+        if self.subscriptions.len() >= MAX_SUBSCRIPTIONS {
+            return Err(Error::TooManySubscriptions(MAX_SUBSCRIPTIONS));
+        }
+
+        let masters_key_builder = masters_key_builder(&req.play);
+        let pos = self.positions.position(req.play, self.openings)?;
+        let zobrist = pos.pos.zobrist_hash();
+        let prefixes = match req.source {
+            ImportSource::Masters => vec![masters_key_builder.with_zobrist(pos.variant, zobrist)],
+            ImportSource::Lichess => {
+                vec![KeyBuilder::lichess().with_zobrist(pos.variant, zobrist)]
+            }
+            ImportSource::Player => {
+                let (player, color) = match (req.player, req.color) {
+                    (Some(player), Some(color)) => (UserId::from(player), color),
+                    _ => return Err(Error::MissingPlayer),
+                };
+                let colors = match color {
+                    PlayerColor::Single(color) => vec![color],
+                    PlayerColor::Both => vec![Color::White, Color::Black],
+                };
+                colors
+                    .into_iter()
+                    .map(|color| {
+                        KeyBuilder::player(&player, color).with_zobrist(pos.variant, zobrist)
+                    })
+                    .collect()
+            }
+        };
+
+        self.subscriptions.push(Subscription {
+            id: req.id,
+            source: req.source,
+            pos: pos.pos,
+            prefixes,
+        });
+        Ok(())
+    }
+
+    fn updates(&self, updates: &PositionUpdates) -> Vec<ServerMessage> {
+        self.subscriptions
+            .iter()
+            .filter(|sub| sub.source == updates.source)
+            .filter_map(|sub| {
+                let uci = sub
+                    .prefixes
+                    .iter()
+                    .find_map(|prefix| updates.moves.get(prefix))?;
+                Some(ServerMessage::Update {
+                    id: sub.id.clone(),
+                    source: updates.source,
+                    game: updates.id,
+                    uci: uci.clone(),
+                    san: uci
+                        .to_move(&sub.pos)
+                        .ok()
+                        .map(|m| San::from_move(&sub.pos, &m)),
+                    stats: Stats::new_single(updates.outcome, 0),
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,237 @@
+use std::fmt::Write as _;
+
+use axum::{
+    body,
+    response::{IntoResponse as _, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use shakmaty::{
+    fen::Fen, san::SanPlus, uci::Uci, variant::VariantPosition, zobrist::Zobrist, Color,
+    EnPassantMode, Position as _,
+};
+
+use crate::{
+    api::{ApiSchema, LichessQueryFilter, Limits, Percentages, PlayPosition, TheoryFormat},
+    db::LichessDatabase,
+    model::{KeyBuilder, Stats},
+    opening::{Opening, Openings},
+};
+
+const MAX_POSITIONS: usize = 1000;
+
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TheoryLine {
+    #[serde_as(as = "DisplayFromStr")]
+    uci: Uci,
+    #[serde_as(as = "DisplayFromStr")]
+    san: SanPlus,
+    #[serde(flatten)]
+    stats: Stats,
+    /// Fraction of the games in the previous position, rounded to three
+    /// decimals.
+    share: f64,
+    opening: Option<&'static Opening>,
+    moves: Vec<TheoryLine>,
+}
+
+/// All continuations from a root position that were chosen in at least a
+/// given share of the games, as the skeleton of an opening survey.
+#[serde_as]
+#[derive(Serialize, ApiSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TheoryTable {
+    #[serde_as(as = "DisplayFromStr")]
+    fen: Fen,
+    #[serde(flatten)]
+    total: Stats,
+    opening: Option<&'static Opening>,
+    moves: Vec<TheoryLine>,
+    #[serde(skip)]
+    root: VariantPosition,
+}
+
+impl TheoryTable {
+    /// Expands positions breadth first, so that the limit on the number of
+    /// positions cuts off the deepest lines.
+    pub fn build(
+        lichess_db: &LichessDatabase,
+        openings: &'static Openings,
+        root: PlayPosition<'static>,
+        filter: &LichessQueryFilter,
+        depth: u32,
+        min_share: f64,
+    ) -> TheoryTable {
+        let PlayPosition {
+            variant,
+            pos,
+            opening,
+        } = root;
+
+        let mut table = TheoryTable {
+            fen: Fen::from_position(pos.as_inner().clone(), EnPassantMode::Legal),
+            total: Stats::default(),
+            opening,
+            moves: Vec::new(),
+            root: pos.as_inner().clone(),
+        };
+
+        // Lines with the index of the line they continue, which always comes
+        // first.
+        let mut lines: Vec<(Option<usize>, TheoryLine)> = Vec::new();
+        let mut layer: Vec<(
+            Option<usize>,
+            Zobrist<VariantPosition, u128>,
+            Option<&Opening>,
+        )> = vec![(None, pos, opening)];
+        let mut expanded = 0;
+
+        for _ in 0..depth {
+            let mut next_layer = Vec::new();
+
+            for (parent, pos, opening) in layer {
+                if expanded >= MAX_POSITIONS {
+                    break;
+                }
+                expanded += 1;
+
+                let prepared = lichess_db
+                    .read_lichess(
+                        &KeyBuilder::lichess().with_zobrist(variant, pos.zobrist_hash()),
+                        filter.since,
+                        filter.until,
+                    )
+                    .expect("get lichess")
+                    .prepare(filter, &Limits::default());
+                let total = prepared.total.total();
+                if parent.is_none() {
+                    table.total = prepared.total;
+                }
+
+                for p in prepared.moves {
+                    let games = p.stats.total();
+                    if games == 0 || (games as f64) < min_share * total as f64 {
+                        continue;
+                    }
+                    let m = match p.uci.to_move(&pos) {
+                        Ok(m) => m,
+                        Err(_) => continue,
+                    };
+                    let san = SanPlus::from_move(pos.clone(), &m);
+                    let opening = openings.classify_move(&pos, &p.uci).or(opening);
+                    let mut child = pos.clone();
+                    child.play_unchecked(&m);
+
+                    lines.push((
+                        parent,
+                        TheoryLine {
+                            uci: p.uci,
+                            san,
+                            stats: p.stats,
+                            share: (games as f64 * 1000.0 / total as f64).round() / 1000.0,
+                            opening,
+                            moves: Vec::new(),
+                        },
+                    ));
+                    next_layer.push((Some(lines.len() - 1), child, opening));
+                }
+            }
+
+            layer = next_layer;
+        }
+
+        // Attach lines to the lines they continue, starting from the end, so
+        // that only complete lines are moved.
+        while let Some((parent, line)) = lines.pop() {
+            let moves = match parent {
+                Some(parent) => &mut lines[parent].1.moves,
+                None => &mut table.moves,
+            };
+            moves.insert(0, line);
+        }
+
+        table
+    }
+
+    pub fn render(self, format: TheoryFormat) -> Response {
+        match format {
+            TheoryFormat::Json => Json(self).into_response(),
+            TheoryFormat::Markdown => Response::builder()
+                .header(
+                    axum::http::header::CONTENT_TYPE,
+                    "text/markdown; charset=utf-8",
+                )
+                .body(body::boxed(body::Full::from(self.to_markdown())))
+                .unwrap(),
+        }
+    }
+
+    /// One row for each line, in depth first order, so that each line is
+    /// followed by its continuations.
+    fn to_markdown(&self) -> String {
+        let mut md = String::new();
+        writeln!(md, "# {}", self.fen).unwrap();
+        writeln!(md).unwrap();
+        writeln!(
+            md,
+            "| Line | Opening | Games | Share | White | Draws | Black |"
+        )
+        .unwrap();
+        writeln!(md, "| --- | --- | ---: | ---: | ---: | ---: | ---: |").unwrap();
+        let mut prefix = String::new();
+        for line in &self.moves {
+            self.write_rows(&mut md, &mut prefix, 0, line);
+        }
+        md
+    }
+
+    fn write_rows(&self, md: &mut String, prefix: &mut String, ply: u32, line: &TheoryLine) {
+        let len = prefix.len();
+        let turn = if ply % 2 == 0 {
+            self.root.turn()
+        } else {
+            !self.root.turn()
+        };
+        let number =
+            self.root.fullmoves().get() + (ply + u32::from(self.root.turn() == Color::Black)) / 2;
+        if !prefix.is_empty() {
+            prefix.push(' ');
+        }
+        match turn {
+            Color::White => write!(prefix, "{}. {}", number, line.san).unwrap(),
+            Color::Black if ply == 0 => write!(prefix, "{}... {}", number, line.san).unwrap(),
+            Color::Black => write!(prefix, "{}", line.san).unwrap(),
+        }
+
+        let percentages = Percentages::from_stats(&line.stats);
+        let percent = |select: fn(&Percentages) -> f64| {
+            percentages
+                .as_ref()
+                .map_or(String::new(), |p| format!("{:.1}%", select(p)))
+        };
+        writeln!(
+            md,
+            "| {} | {} | {} | {:.1}% | {} | {} | {} |",
+            prefix,
+            line.opening.map_or(String::new(), |opening| format!(
+                "{} {}",
+                opening.eco(),
+                opening.name()
+            )),
+            line.stats.total(),
+            line.share * 100.0,
+            percent(|p| p.white_percent),
+            percent(|p| p.draw_percent),
+            percent(|p| p.black_percent),
+        )
+        .unwrap();
+
+        for continuation in &line.moves {
+            self.write_rows(md, prefix, ply + 1, continuation);
+        }
+        prefix.truncate(len);
+    }
+}
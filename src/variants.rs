@@ -0,0 +1,111 @@
+use std::{cmp::Reverse, sync::Mutex};
+
+use rustc_hash::FxHashMap;
+use shakmaty::{
+    uci::Uci,
+    variant::{Variant, VariantPosition},
+    zobrist::Zobrist,
+};
+
+use crate::{
+    api::{LichessQueryFilter, Limits, VariantFirstMove, VariantMeta},
+    db::LichessDatabase,
+    model::{KeyBuilder, Month},
+};
+
+const VARIANTS: [Variant; 8] = [
+    Variant::Chess,
+    Variant::Antichess,
+    Variant::Atomic,
+    Variant::Crazyhouse,
+    Variant::Horde,
+    Variant::KingOfTheHill,
+    Variant::RacingKings,
+    Variant::ThreeCheck,
+];
+
+const MAX_FIRST_MOVES: usize = 5;
+
+/// Summary of lichess games per variant. Only games from the initial
+/// position of the variant are counted.
+pub struct VariantStats {
+    by_variant: Mutex<FxHashMap<Variant, VariantSummary>>,
+}
+
+#[derive(Default)]
+struct VariantSummary {
+    games: u64,
+    first_moves: FxHashMap<Uci, u64>,
+    since: Option<Month>,
+    until: Option<Month>,
+}
+
+impl VariantSummary {
+    fn add(&mut self, month: Month, uci: Uci, games: u64) {
+        self.games += games;
+        *self.first_moves.entry(uci).or_default() += games;
+        self.since = Some(self.since.map_or(month, |since| since.min(month)));
+        self.until = Some(self.until.map_or(month, |until| until.max(month)));
+    }
+}
+
+impl VariantStats {
+    /// Initializes the counters from the entries of the initial positions.
+    pub fn load(lichess_db: &LichessDatabase) -> VariantStats {
+        let filter = LichessQueryFilter::default();
+        let mut by_variant = FxHashMap::default();
+
+        for variant in VARIANTS {
+            let pos: Zobrist<_, u128> = Zobrist::new(VariantPosition::new(variant));
+            let key = KeyBuilder::lichess().with_zobrist(variant, pos.zobrist_hash());
+            let summary: &mut VariantSummary = by_variant.entry(variant).or_default();
+            for (month, entry) in lichess_db
+                .read_lichess_by_month(&key, Month::default(), Month::max_value())
+                .expect("get variant start entries")
+            {
+                for p in entry.prepare(&filter, &Limits::default()).moves {
+                    summary.add(month, p.uci, p.stats.total());
+                }
+            }
+        }
+
+        VariantStats {
+            by_variant: Mutex::new(by_variant),
+        }
+    }
+
+    pub fn record(&self, variant: Variant, month: Month, first_move: Uci) {
+        let mut guard = self.by_variant.lock().expect("variant stats");
+        guard.entry(variant).or_default().add(month, first_move, 1);
+    }
+
+    pub fn summarize(&self) -> Vec<VariantMeta> {
+        let guard = self.by_variant.lock().expect("variant stats");
+        VARIANTS
+            .into_iter()
+            .map(|variant| {
+                let summary = guard.get(&variant);
+                let mut first_moves: Vec<VariantFirstMove> = summary
+                    .map(|s| {
+                        s.first_moves
+                            .iter()
+                            .map(|(uci, games)| VariantFirstMove {
+                                uci: uci.to_owned(),
+                                games: *games,
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                first_moves.sort_by_key(|m| Reverse(m.games));
+                first_moves.truncate(MAX_FIRST_MOVES);
+                VariantMeta {
+                    variant: variant.uci(),
+                    games: summary.map_or(0, |s| s.games),
+                    first_moves,
+                    since: summary.and_then(|s| s.since),
+                    until: summary.and_then(|s| s.until),
+                }
+            })
+            .collect()
+    }
+}